@@ -0,0 +1,20 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate hermes;
+
+use hermes::dns::buffer::{BytePacketBuffer, VectorPacketBuffer};
+use hermes::dns::protocol::DnsPacket;
+
+// Parsing arbitrary input should only ever fail with an error, never panic
+fuzz_target!(|data: &[u8]| {
+    // The fixed size buffer used for UDP packets
+    let mut byte_buffer = BytePacketBuffer::new();
+    let len = if data.len() < 512 { data.len() } else { 512 };
+    byte_buffer.buf[0..len].copy_from_slice(&data[0..len]);
+    let _ = DnsPacket::from_buffer(&mut byte_buffer);
+
+    // The growable buffer, which is bounded by the input length instead
+    let mut vector_buffer = VectorPacketBuffer::new();
+    vector_buffer.buffer.extend_from_slice(data);
+    let _ = DnsPacket::from_buffer(&mut vector_buffer);
+});
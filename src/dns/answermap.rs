@@ -0,0 +1,323 @@
+//! static answers loaded from JSON, for testing against canned responses
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Result, Error, ErrorKind, Read};
+use std::path::Path;
+
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::json::Json;
+
+use dns::import::build_record;
+use dns::protocol::{DnsPacket, DnsRecord, QueryType, ResultCode, TransientTtl};
+
+/// Answers to return verbatim, by name and type
+///
+/// The JSON is a list of entries like
+///
+/// ```json
+/// [{"qname": "www.example.com", "qtype": "A", "answers": [
+///     {"type": "CNAME", "domain": "www.example.com", "host": "example.com", "ttl": 300},
+///     {"type": "A", "domain": "example.com", "host": "192.0.2.1", "ttl": 60}
+/// ]}]
+/// ```
+///
+/// where the answers use the same format as records in the web API. Names and
+/// types that aren't listed are answered with NXDOMAIN.
+#[derive(Default)]
+pub struct AnswerMap {
+    answers: HashMap<(String, QueryType), Vec<DnsRecord>>
+}
+
+impl AnswerMap {
+    pub fn new() -> AnswerMap {
+        AnswerMap {
+            answers: HashMap::new()
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<AnswerMap> {
+        let mut data = String::new();
+        let _ = try!(try!(File::open(path)).read_to_string(&mut data));
+
+        AnswerMap::parse(&data)
+    }
+
+    pub fn parse(data: &str) -> Result<AnswerMap> {
+        let json = match Json::from_str(data) {
+            Ok(x) => x,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, format!("Invalid JSON: {}", e)))
+        };
+
+        let entries = match json.as_array() {
+            Some(x) => x,
+            None => return Err(Error::new(ErrorKind::InvalidData, "Expected a list of entries"))
+        };
+
+        let mut map = AnswerMap::new();
+        for (idx, entry) in entries.iter().enumerate() {
+            let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, format!("{} in entry {}", msg, idx + 1));
+
+            let qname = match entry.find("qname").and_then(|x| x.as_string()) {
+                Some(x) => x,
+                None => return Err(invalid("Missing qname"))
+            };
+
+            let qtype = match entry.find("qtype").and_then(|x| x.as_string()) {
+                Some(x) => try!(x.parse::<QueryType>()),
+                None => return Err(invalid("Missing qtype"))
+            };
+
+            let answers = match entry.find("answers").and_then(|x| x.as_array()) {
+                Some(x) => x,
+                None => return Err(invalid("Missing answers"))
+            };
+
+            let mut records = Vec::new();
+            for answer in answers {
+                match rr_from_json(answer) {
+                    Ok(rec) => records.push(rec),
+                    Err(e) => return Err(invalid(&e.to_string()))
+                }
+            }
+
+            map.insert(qname, qtype, records);
+        }
+
+        Ok(map)
+    }
+
+    pub fn insert(&mut self, qname: &str, qtype: QueryType, answers: Vec<DnsRecord>) {
+        self.answers.insert((qname.to_lowercase(), qtype), answers);
+    }
+
+    pub fn len(&self) -> usize {
+        self.answers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.answers.is_empty()
+    }
+
+    /// Build the response to a query, which is NXDOMAIN unless the name and
+    /// type are in the map
+    pub fn lookup(&self, qname: &str, qtype: QueryType) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.authoritative_answer = true;
+
+        match self.answers.get(&(qname.to_lowercase(), qtype)) {
+            Some(answers) => packet.answers = answers.clone(),
+            None => packet.header.rescode = ResultCode::NXDOMAIN
+        }
+
+        packet
+    }
+}
+
+/// Parse a record in the format produced by `rr_to_json`
+fn rr_from_json(json: &Json) -> Result<DnsRecord> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+
+    let rtype = match json.find("type").and_then(|x| x.as_string()) {
+        Some(x) => x.to_uppercase(),
+        None => return Err(invalid("Missing record type"))
+    };
+
+    let domain = match json.find("domain").and_then(|x| x.as_string()) {
+        Some(x) => x.to_string(),
+        None => return Err(invalid("Missing record domain"))
+    };
+
+    let ttl = match json.find("ttl").and_then(|x| x.as_u64()) {
+        Some(x) if x <= u32::max_value() as u64 => x as u32,
+        _ => return Err(invalid("Missing or invalid record TTL"))
+    };
+
+    let host = json.find("host").and_then(|x| x.as_string()).unwrap_or("");
+    let field = |name: &str| json.find(name).and_then(|x| x.as_u64()).map(|x| x as u16);
+
+    match rtype.as_str() {
+        // The data of TXT records is a list of character strings
+        "TXT" | "SPF" => {
+            let data = match json.find("txt").and_then(|x| x.as_array()) {
+                Some(x) => x.iter().filter_map(|x| x.as_string()).map(|x| x.to_string()).collect(),
+                None => return Err(invalid("Missing txt"))
+            };
+
+            Ok(if rtype == "TXT" {
+                DnsRecord::TXT { domain: domain, data: data, ttl: TransientTtl(ttl) }
+            } else {
+                DnsRecord::SPF { domain: domain, data: data, ttl: TransientTtl(ttl) }
+            })
+        },
+
+        // The priority of MX records precedes the host
+        "MX" => {
+            let parts = host.splitn(2, ' ').collect::<Vec<&str>>();
+            match (parts.get(0).and_then(|x| x.parse::<u16>().ok()), parts.get(1)) {
+                (Some(priority), Some(host)) => build_record(&domain, &rtype, ttl, host, Some(priority)),
+                _ => Err(invalid("Invalid host for MX record"))
+            }
+        },
+
+        // The data of DHCID records is opaque, and given as base64
+        "DHCID" => {
+            match json.find("data").and_then(|x| x.as_string()).and_then(|x| x.from_base64().ok()) {
+                Some(data) => Ok(DnsRecord::DHCID { domain: domain, data: data, ttl: TransientTtl(ttl) }),
+                None => Err(invalid("Missing or invalid data for DHCID record"))
+            }
+        },
+
+        // The fingerprint of SSHFP records is given in hex
+        "SSHFP" => {
+            let algorithm = json.find("algorithm").and_then(|x| x.as_u64());
+            let fp_type = json.find("fp_type").and_then(|x| x.as_u64());
+            match (algorithm, fp_type, json.find("fingerprint").and_then(|x| x.as_string())) {
+                (Some(algorithm), Some(fp_type), Some(fingerprint))
+                    if algorithm <= 255 && fp_type <= 255 && fingerprint.from_hex().is_ok() => Ok(DnsRecord::SSHFP {
+                    domain: domain,
+                    algorithm: algorithm as u8,
+                    fp_type: fp_type as u8,
+                    fingerprint: fingerprint.to_lowercase(),
+                    ttl: TransientTtl(ttl)
+                }),
+                _ => Err(invalid("Missing or invalid algorithm, fp_type or fingerprint for SSHFP record"))
+            }
+        },
+
+        // As is the certificate association data of TLSA records
+        "TLSA" => {
+            let num = |key: &str| json.find(key).and_then(|x| x.as_u64()).and_then(|x| if x <= 255 { Some(x as u8) } else { None });
+            match (num("usage"), num("selector"), num("matching_type"), json.find("cert_data").and_then(|x| x.as_string())) {
+                (Some(usage), Some(selector), Some(matching_type), Some(cert_data)) if cert_data.from_hex().is_ok() => Ok(DnsRecord::TLSA {
+                    domain: domain,
+                    usage: usage,
+                    selector: selector,
+                    matching_type: matching_type,
+                    cert_data: cert_data.to_lowercase(),
+                    ttl: TransientTtl(ttl)
+                }),
+                _ => Err(invalid("Missing or invalid usage, selector, matching_type or cert_data for TLSA record"))
+            }
+        },
+
+        "CAA" => {
+            let flags = json.find("flags").and_then(|x| x.as_u64()).unwrap_or(0);
+            match (json.find("tag").and_then(|x| x.as_string()), json.find("value").and_then(|x| x.as_string())) {
+                (Some(tag), Some(value)) if flags <= 255 && !tag.is_empty() => Ok(DnsRecord::CAA {
+                    domain: domain,
+                    flags: flags as u8,
+                    tag: tag.to_string(),
+                    value: value.to_string(),
+                    ttl: TransientTtl(ttl)
+                }),
+                _ => Err(invalid("Missing or invalid flags, tag or value for CAA record"))
+            }
+        },
+
+        "NAPTR" => {
+            let string = |name: &str| json.find(name).and_then(|x| x.as_string()).map(|x| x.to_string());
+            match (field("order"), field("preference"), string("flags"), string("services"), string("regexp"), string("replacement")) {
+                (Some(order), Some(preference), Some(flags), Some(services), Some(regexp), Some(replacement)) => Ok(DnsRecord::NAPTR {
+                    domain: domain,
+                    order: order,
+                    preference: preference,
+                    flags: flags,
+                    services: services,
+                    regexp: regexp,
+                    replacement: replacement,
+                    ttl: TransientTtl(ttl)
+                }),
+                _ => Err(invalid("Missing order, preference, flags, services, regexp or replacement for NAPTR record"))
+            }
+        },
+
+        "SRV" => {
+            match (field("priority"), field("weight"), field("port")) {
+                (Some(priority), Some(weight), Some(port)) => Ok(DnsRecord::SRV {
+                    domain: domain,
+                    priority: priority,
+                    weight: weight,
+                    port: port,
+                    host: host.to_string(),
+                    ttl: TransientTtl(ttl)
+                }),
+                _ => Err(invalid("Missing priority, weight or port for SRV record"))
+            }
+        },
+
+        _ => build_record(&domain, &rtype, ttl, host, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_answer_map() {
+        let map = AnswerMap::parse(r#"[
+            {"qname": "www.example.com", "qtype": "A", "answers": [
+                {"type": "CNAME", "domain": "www.example.com", "host": "example.com", "ttl": 300},
+                {"type": "A", "domain": "example.com", "host": "192.0.2.1", "ttl": 60}
+            ]},
+            {"qname": "example.com", "qtype": "MX", "answers": [
+                {"id": 3, "type": "MX", "domain": "example.com", "host": "10 mail.example.com", "ttl": 3600}
+            ]},
+            {"qname": "example.com", "qtype": "TXT", "answers": [
+                {"type": "TXT", "domain": "example.com", "txt": ["v=spf1 -all"], "ttl": 3600}
+            ]},
+            {"qname": "_sip._udp.example.com", "qtype": "SRV", "answers": [
+                {"type": "SRV", "domain": "_sip._udp.example.com", "host": "sip.example.com",
+                 "priority": 10, "weight": 5, "port": 5060, "ttl": 3600}
+            ]}
+        ]"#).unwrap();
+
+        assert_eq!(4, map.len());
+
+        let packet = map.lookup("WWW.example.com", QueryType::A);
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert_eq!(2, packet.answers.len());
+        assert_eq!(DnsRecord::CNAME {
+            domain: "www.example.com".to_string(),
+            host: "example.com".to_string(),
+            ttl: TransientTtl(300)
+        }, packet.answers[0]);
+        assert_eq!(60, packet.answers[1].get_ttl());
+
+        match map.lookup("example.com", QueryType::MX).answers[0] {
+            DnsRecord::MX { priority, ref host, .. } => {
+                assert_eq!(10, priority);
+                assert_eq!("mail.example.com", host);
+            },
+            _ => panic!()
+        }
+
+        match map.lookup("example.com", QueryType::TXT).answers[0] {
+            DnsRecord::TXT { ref data, .. } => assert_eq!(vec!["v=spf1 -all".to_string()], *data),
+            _ => panic!()
+        }
+
+        assert_eq!(1, map.lookup("_sip._udp.example.com", QueryType::SRV).answers.len());
+
+        // Anything else doesn't exist
+        assert_eq!(ResultCode::NXDOMAIN, map.lookup("www.example.com", QueryType::AAAA).header.rescode);
+        assert_eq!(ResultCode::NXDOMAIN, map.lookup("www.google.com", QueryType::A).header.rescode);
+    }
+
+    #[test]
+    fn test_parse_answer_map_errors() {
+        assert!(AnswerMap::parse("{}").is_err());
+        assert!(AnswerMap::parse(r#"[{"qtype": "A", "answers": []}]"#).is_err());
+        assert!(AnswerMap::parse(r#"[{"qname": "a.com", "qtype": "BOGUS", "answers": []}]"#).is_err());
+
+        match AnswerMap::parse(r#"[{"qname": "a.com", "qtype": "A", "answers": [
+            {"type": "A", "domain": "a.com", "host": "not an address", "ttl": 60}
+        ]}]"#) {
+            Ok(_) => panic!(),
+            Err(e) => assert_eq!("Invalid address for A record in entry 1", e.to_string())
+        }
+    }
+}
@@ -1,13 +1,118 @@
 //! contains the data store for local zones
 
-use std::collections::{BTreeMap,BTreeSet};
+use std::collections::{BTreeMap,BTreeSet,VecDeque};
 use std::sync::{RwLock, LockResult, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::io::{Write,Result,Error,ErrorKind};
 use std::fs::File;
 use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 
-use dns::buffer::{VectorPacketBuffer, PacketBuffer, StreamPacketBuffer};
+use rand::random;
+
+use dns::buffer::{VectorPacketBuffer, PacketBuffer, StreamPacketBuffer, MAX_NAME_LEN};
+use dns::health::HealthCheck;
+use dns::netutil::Network;
 use dns::protocol::{DnsPacket,DnsRecord,QueryType,ResultCode,TransientTtl};
+use dns::tsig::TsigKey;
+use dns::zonefile::{export_zone, import_zone};
+
+/// How a pool is picked when answering for a name with records in several
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum PoolPolicy {
+    /// Pick at random, in proportion to the weights of the pools
+    Weighted,
+
+    /// Take turns between the pools
+    RoundRobin,
+
+    /// Pick the first pool with a network containing the client, falling
+    /// back to a weighted pick
+    Subnet
+}
+
+impl PoolPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            PoolPolicy::Weighted => "weighted",
+            PoolPolicy::RoundRobin => "round-robin",
+            PoolPolicy::Subnet => "subnet"
+        }
+    }
+
+    fn to_num(&self) -> u8 {
+        match *self {
+            PoolPolicy::Weighted => 0,
+            PoolPolicy::RoundRobin => 1,
+            PoolPolicy::Subnet => 2
+        }
+    }
+
+    fn from_num(num: u8) -> PoolPolicy {
+        match num {
+            1 => PoolPolicy::RoundRobin,
+            2 => PoolPolicy::Subnet,
+            _ => PoolPolicy::Weighted
+        }
+    }
+}
+
+impl Default for PoolPolicy {
+    fn default() -> Self {
+        PoolPolicy::Weighted
+    }
+}
+
+impl FromStr for PoolPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<PoolPolicy> {
+        match s {
+            "weighted" => Ok(PoolPolicy::Weighted),
+            "round-robin" => Ok(PoolPolicy::RoundRobin),
+            "subnet" => Ok(PoolPolicy::Subnet),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("Invalid pool policy {}", s)))
+        }
+    }
+}
+
+/// A named group of records which are answered together
+///
+/// When a name has records in several pools, only those of one pool are
+/// returned for each query, along with any records of the name that aren't
+/// in a pool.
+#[derive(Clone,Debug,Default)]
+pub struct Pool {
+    pub weight: u32,
+
+    /// Clients answered from this pool by the subnet policy
+    pub networks: Vec<Network>,
+
+    pub records: BTreeSet<DnsRecord>
+}
+
+impl Pool {
+    pub fn new(weight: u32) -> Pool {
+        Pool {
+            weight: weight,
+            networks: Vec::new(),
+            records: BTreeSet::new()
+        }
+    }
+}
+
+/// Upper bound on the number of changes kept in the journal of a zone
+pub const MAX_JOURNAL_ENTRIES: usize = 100;
+
+/// A change to a zone, taking it from one serial to the next
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct JournalEntry {
+    pub serial_before: u32,
+    pub serial_after: u32,
+    pub deleted: Vec<DnsRecord>,
+    pub added: Vec<DnsRecord>
+}
 
 #[derive(Clone,Debug,Default)]
 pub struct Zone {
@@ -19,7 +124,44 @@ pub struct Zone {
     pub retry: u32,
     pub expire: u32,
     pub minimum: u32,
-    pub records: BTreeSet<DnsRecord>
+    pub records: BTreeSet<DnsRecord>,
+
+    /// TTL for records added without one, like BIND's `$TTL`. When unset, the
+    /// server wide default applies.
+    pub default_ttl: Option<u32>,
+
+    /// Networks allowed to transfer the zone. When unset, the server wide
+    /// list applies.
+    pub allow_transfer: Option<Vec<Network>>,
+
+    /// Names of the TSIG keys allowed to transfer the zone, from any network.
+    /// When unset, the server wide list applies.
+    pub allow_transfer_keys: Option<Vec<String>>,
+
+    /// Pools of records, by name
+    pub pools: BTreeMap<String, Pool>,
+
+    pub pool_policy: PoolPolicy,
+
+    /// Health checks of A and AAAA records, which are left out of answers
+    /// while failing
+    pub health_checks: BTreeMap<DnsRecord, HealthCheck>,
+
+    /// Whether owner names are lowercased when records are added. When unset,
+    /// the server wide setting applies.
+    pub lowercase_names: Option<bool>,
+
+    /// The primary server to transfer the zone from, for secondary zones
+    pub primary: Option<SocketAddr>,
+
+    /// The TSIG key used to sign transfers from the primary, and to verify
+    /// its responses
+    pub primary_key: Option<TsigKey>,
+
+    /// The most recent changes to the zone, oldest first, used to answer
+    /// IXFR requests. This is only kept in memory, so transfers after a
+    /// restart fall back to AXFR.
+    pub journal: VecDeque<JournalEntry>
 }
 
 impl Zone {
@@ -33,28 +175,468 @@ impl Zone {
             retry: 0,
             expire: 0,
             minimum: 0,
-            records: BTreeSet::new()
+            records: BTreeSet::new(),
+            default_ttl: None,
+            allow_transfer: None,
+            allow_transfer_keys: None,
+            pools: BTreeMap::new(),
+            pool_policy: PoolPolicy::Weighted,
+            health_checks: BTreeMap::new(),
+            lowercase_names: None,
+            primary: None,
+            primary_key: None,
+            journal: VecDeque::new()
         }
     }
 
     pub fn add_record(&mut self, rec: &DnsRecord) -> bool {
-        self.records.insert(rec.clone())
+        let lowercase = self.lowercase_names.unwrap_or(false);
+        self.insert_record(rec, lowercase)
+    }
+
+    fn insert_record(&mut self, rec: &DnsRecord, lowercase: bool) -> bool {
+        if lowercase {
+            self.records.insert(lowercase_owner(rec))
+        } else {
+            self.records.insert(rec.clone())
+        }
+    }
+
+    /// Lowercase the owner names of all records in the zone, merging records
+    /// that turn out to be identical
+    pub fn lowercase_records(&mut self) {
+        self.records = self.records.iter().map(lowercase_owner).collect();
+
+        for pool in self.pools.values_mut() {
+            pool.records = pool.records.iter().map(lowercase_owner).collect();
+        }
+
+        self.health_checks = self.health_checks.iter()
+            .map(|(rec, check)| (lowercase_owner(rec), check.clone()))
+            .collect();
+    }
+
+    /// Move a record of the zone to the pool `name`, which is created with a
+    /// weight of one if it doesn't exist
+    pub fn assign_pool(&mut self, name: &str, rec: &DnsRecord) {
+        for pool in self.pools.values_mut() {
+            pool.records.remove(rec);
+        }
+
+        self.pools.entry(name.to_string())
+            .or_insert_with(|| Pool::new(1))
+            .records.insert(rec.clone());
+    }
+
+    /// The name of the pool that `rec` belongs to, if any
+    pub fn get_pool(&self, rec: &DnsRecord) -> Option<&str> {
+        self.pools.iter()
+            .find(|&(_, pool)| pool.records.contains(rec))
+            .map(|(name, _)| name.as_str())
     }
 
     pub fn delete_record(&mut self, rec: &DnsRecord) -> bool {
+        for pool in self.pools.values_mut() {
+            pool.records.remove(rec);
+        }
+        self.health_checks.remove(rec);
+
         self.records.remove(rec)
     }
+
+    /// Advance the serial by one, wrapping around as allowed by the serial
+    /// number arithmetic of RFC 1982
+    pub fn increment_serial(&mut self) {
+        self.serial = self.serial.wrapping_add(1);
+    }
+
+    /// Note a change made through the API, bumping the serial if
+    /// `auto_serial` is set
+    ///
+    /// Changes that leave the serial as it is can't be told apart by
+    /// secondaries, so the journal is cleared instead and they fall back to
+    /// a full transfer.
+    fn record_change(&mut self, auto_serial: bool, deleted: Vec<DnsRecord>, added: Vec<DnsRecord>) {
+        if auto_serial {
+            let serial_before = self.serial;
+            self.increment_serial();
+            self.journal_change(serial_before, deleted, added);
+        } else {
+            self.journal.clear();
+        }
+    }
+
+    /// Add a change to the journal, as having taken the zone from
+    /// `serial_before` to the current serial
+    ///
+    /// The oldest change is dropped once there are more than
+    /// `MAX_JOURNAL_ENTRIES`.
+    pub fn journal_change(&mut self,
+                          serial_before: u32,
+                          deleted: Vec<DnsRecord>,
+                          added: Vec<DnsRecord>) {
+        self.journal.push_back(JournalEntry {
+            serial_before: serial_before,
+            serial_after: self.serial,
+            deleted: deleted,
+            added: added
+        });
+
+        while self.journal.len() > MAX_JOURNAL_ENTRIES {
+            self.journal.pop_front();
+        }
+    }
+
+    /// The SOA record of the zone
+    pub fn soa_record(&self) -> DnsRecord {
+        self.soa_record_with_serial(self.serial)
+    }
+
+    fn soa_record_with_serial(&self, serial: u32) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: self.domain.clone(),
+            m_name: self.m_name.clone(),
+            r_name: self.r_name.clone(),
+            serial: serial,
+            refresh: self.refresh,
+            retry: self.retry,
+            expire: self.expire,
+            minimum: self.minimum,
+            ttl: TransientTtl(self.minimum)
+        }
+    }
+
+    /// Check if `client` may transfer the zone, using `default` if the zone
+    /// has no list of its own
+    pub fn is_transfer_allowed(&self, client: &IpAddr, default: &[Network]) -> bool {
+        let allowed = match self.allow_transfer {
+            Some(ref x) => x,
+            None => default
+        };
+
+        allowed.iter().any(|x| x.contains(client))
+    }
+
+    /// Check if a transfer signed with the TSIG key `key` is allowed, using
+    /// `default` if the zone has no list of its own
+    pub fn is_transfer_key_allowed(&self, key: &str, default: &[String]) -> bool {
+        let allowed = match self.allow_transfer_keys {
+            Some(ref x) => x,
+            None => default
+        };
+
+        allowed.iter().any(|x| x.eq_ignore_ascii_case(key))
+    }
+
+    /// Narrow `answers` down to the records of a single pool, when they span
+    /// several, picked according to the policy of the zone
+    ///
+    /// `turn` is used by the round robin policy. Returns whether a pool was
+    /// picked.
+    pub fn select_pool(&self, answers: &mut Vec<DnsRecord>, client: &IpAddr, turn: usize) -> bool {
+        // The owner names of answers may have been given the case of the
+        // query, so records are matched to pools regardless of case
+        let keys = answers.iter().map(lowercase_owner).collect::<Vec<DnsRecord>>();
+
+        let candidates = self.pools.values()
+            .map(|pool| (pool, pool.records.iter().map(lowercase_owner).collect::<BTreeSet<DnsRecord>>()))
+            .filter(|&(_, ref records)| keys.iter().any(|x| records.contains(x)))
+            .collect::<Vec<(&Pool, BTreeSet<DnsRecord>)>>();
+
+        if candidates.len() < 2 {
+            return false;
+        }
+
+        let by_subnet = if self.pool_policy == PoolPolicy::Subnet {
+            candidates.iter().position(|&(pool, _)| pool.networks.iter().any(|x| x.contains(client)))
+        } else {
+            None
+        };
+
+        let picked = match (self.pool_policy, by_subnet) {
+            (_, Some(x)) => x,
+            (PoolPolicy::RoundRobin, None) => turn % candidates.len(),
+            _ => {
+                let weights = candidates.iter().map(|&(pool, _)| pool.weight).collect::<Vec<u32>>();
+                pick_weighted(&weights, random::<u32>())
+            }
+        };
+
+        let picked = &candidates[picked].1;
+        let kept = answers.drain(..)
+            .zip(keys.into_iter())
+            .filter(|&(_, ref key)| picked.contains(key) || !candidates.iter().any(|&(_, ref records)| records.contains(key)))
+            .map(|(rec, _)| rec)
+            .collect::<Vec<DnsRecord>>();
+        *answers = kept;
+
+        true
+    }
+
+    pub fn is_secondary(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    /// Check if `name` is the domain of the zone, or a name below it
+    pub fn contains_name(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        let domain = self.domain.to_lowercase();
+
+        name == domain || domain.is_empty() || name.ends_with(&format!(".{}", domain))
+    }
+
+    /// Returns the records of the zone in DNSSEC canonical order
+    ///
+    /// This gives a stable ordering independent of how the records were
+    /// inserted, and should be used whenever the zone contents are presented
+    /// or serialized.
+    pub fn sorted_records(&self) -> Vec<&DnsRecord> {
+        let mut records = self.records.iter().collect::<Vec<&DnsRecord>>();
+        records.sort_by(|a, b| a.cmp_canonical(b));
+
+        records
+    }
+
+    /// Check if `name` exists in the zone, either by having records of its
+    /// own, or by having records below it as an empty non-terminal
+    ///
+    /// The apex always exists. Names are compared without regard to case.
+    pub fn has_name(&self, name: &str) -> bool {
+        if name.eq_ignore_ascii_case(&self.domain) {
+            return true;
+        }
+
+        let name = name.to_lowercase();
+        let suffix = format!(".{}", name);
+        self.records.iter()
+            .filter_map(|x| x.get_domain())
+            .map(|x| x.to_lowercase())
+            .any(|x| x == name || x.ends_with(&suffix))
+    }
+
+    /// Find the wildcard to answer `qname` from, for a name that doesn't
+    /// exist in the zone
+    ///
+    /// As described in RFC 4592, only the wildcard directly below the
+    /// closest encloser of `qname`, which is the closest parent that exists,
+    /// applies. Wildcards further up are blocked by it.
+    pub fn find_wildcard(&self, qname: &str) -> Option<String> {
+        let mut encloser = qname;
+        while !encloser.eq_ignore_ascii_case(&self.domain) {
+            encloser = match encloser.find('.') {
+                Some(idx) => &encloser[idx+1..],
+                None => {
+                    encloser = "";
+                    break;
+                }
+            };
+
+            if self.has_name(encloser) {
+                break;
+            }
+        }
+
+        let wildcard = if encloser.is_empty() {
+            "*".to_string()
+        } else {
+            format!("*.{}", encloser)
+        };
+
+        if self.records.iter().any(|x| x.get_domain().map_or(false, |x| x.eq_ignore_ascii_case(&wildcard))) {
+            Some(wildcard)
+        } else {
+            None
+        }
+    }
+
+    /// Find the DNAME record redirecting `qname`, if the name is below one
+    ///
+    /// Only DNAME records strictly above `qname` apply, and the one closest
+    /// to the apex is used, since that's where the lookup is redirected first.
+    pub fn find_dname(&self, qname: &str) -> Option<&DnsRecord> {
+        let mut result = None;
+
+        let mut owner = qname;
+        while !owner.eq_ignore_ascii_case(&self.domain) {
+            owner = match owner.find('.') {
+                Some(idx) => &owner[idx+1..],
+                None => break
+            };
+
+            let dname = self.records.iter().find(|x| {
+                x.get_querytype() == QueryType::DNAME &&
+                x.get_domain().map_or(false, |x| x.eq_ignore_ascii_case(owner))
+            });
+
+            if dname.is_some() {
+                result = dname;
+            }
+        }
+
+        result
+    }
+
+    /// Returns the records of the zone in the order of an AXFR response
+    ///
+    /// The SOA comes first and last, with every other record in between in
+    /// canonical order. ANAME records are left out, since they're flattened
+    /// when answering queries.
+    pub fn transfer_records(&self) -> Vec<DnsRecord> {
+        let soa = self.soa_record();
+
+        let mut records = vec![soa.clone()];
+        records.extend(self.sorted_records().into_iter()
+                       .filter(|x| x.get_querytype() != QueryType::SOA && x.get_querytype() != QueryType::ANAME)
+                       .cloned());
+        records.push(soa);
+
+        records
+    }
+
+    /// Returns the changes made to the zone since `serial`, in the order of
+    /// an IXFR response as described in RFC 1995
+    ///
+    /// Each change is given as the SOA of the serial before it followed by
+    /// the deleted records, and the SOA of the serial after it followed by
+    /// the added records, with the current SOA first and last. A client that
+    /// is up to date gets the current SOA alone. Returns `None` if the
+    /// journal doesn't reach back to `serial`, in which case a full transfer
+    /// is needed.
+    pub fn incremental_transfer_records(&self, serial: u32) -> Option<Vec<DnsRecord>> {
+        let soa = self.soa_record();
+        if serial == self.serial {
+            return Some(vec![soa]);
+        }
+
+        let start = match self.journal.iter().position(|x| x.serial_before == serial) {
+            Some(x) => x,
+            None => return None
+        };
+
+        let is_transferred = |x: &&DnsRecord| x.get_querytype() != QueryType::ANAME;
+
+        let mut records = vec![soa.clone()];
+        let mut current = serial;
+        for entry in self.journal.iter().skip(start) {
+            if entry.serial_before != current {
+                return None;
+            }
+
+            records.push(self.soa_record_with_serial(entry.serial_before));
+            records.extend(entry.deleted.iter().filter(&is_transferred).cloned());
+            records.push(self.soa_record_with_serial(entry.serial_after));
+            records.extend(entry.added.iter().filter(&is_transferred).cloned());
+
+            current = entry.serial_after;
+        }
+
+        if current != self.serial {
+            return None;
+        }
+
+        records.push(soa);
+
+        Some(records)
+    }
+
+    /// Read a zone from a master file, as described for `import_zone`
+    pub fn from_zonefile(text: &str) -> Result<Zone> {
+        import_zone(text)
+    }
+
+    /// Write the zone as a master file, as described for `export_zone`
+    pub fn to_zonefile(&self) -> String {
+        export_zone(self)
+    }
 }
 
-#[derive(Default)]
+fn is_address_record(rec: &DnsRecord) -> bool {
+    match *rec {
+        DnsRecord::A { .. } | DnsRecord::AAAA { .. } => true,
+        _ => false
+    }
+}
+
+/// Pick an index into `weights` using the random value `r`, with a chance
+/// proportional to the weight. If all weights are zero, each is equally
+/// likely.
+fn pick_weighted(weights: &[u32], r: u32) -> usize {
+    let total = weights.iter().fold(0, |acc, x| acc + *x as u64);
+    if total == 0 {
+        return r as usize % weights.len();
+    }
+
+    let mut r = r as u64 % total;
+    for (i, weight) in weights.iter().enumerate() {
+        if r < *weight as u64 {
+            return i;
+        }
+        r -= *weight as u64;
+    }
+
+    weights.len() - 1
+}
+
+fn write_string<T: PacketBuffer>(buffer: &mut T, s: &str) -> Result<()> {
+    try!(buffer.write_u8(s.len() as u8));
+    for b in s.as_bytes() {
+        try!(buffer.write_u8(*b));
+    }
+
+    Ok(())
+}
+
+fn read_string<T: PacketBuffer>(buffer: &mut T) -> Result<String> {
+    let len = try!(buffer.read()) as usize;
+    let pos = buffer.pos();
+    let s = String::from_utf8_lossy(try!(buffer.get_range(pos, len))).to_string();
+    try!(buffer.step(len));
+
+    Ok(s)
+}
+
+fn lowercase_owner(rec: &DnsRecord) -> DnsRecord {
+    let mut rec = rec.clone();
+    if let Some(domain) = rec.get_domain() {
+        rec.set_domain(&domain.to_lowercase());
+    }
+    rec
+}
+
+/// Default upper bound on the number of zones held by the authority
+pub const DEFAULT_MAX_ZONES: usize = 10_000;
+
+/// Default upper bound on the number of records held by a single zone
+pub const DEFAULT_MAX_RECORDS_PER_ZONE: usize = 100_000;
+
 pub struct Zones {
-    zones: BTreeMap<String, Zone>
+    zones: BTreeMap<String, Zone>,
+    pub max_zones: usize,
+    pub max_records_per_zone: usize,
+
+    /// Whether owner names are lowercased when records are added to zones
+    /// that don't say otherwise
+    pub lowercase_names: bool,
+
+    /// Whether the serial of a zone is incremented whenever a record is added
+    /// or deleted, so that secondaries pick up the change
+    pub auto_serial: bool
+}
+
+impl Default for Zones {
+    fn default() -> Self {
+        Zones::new()
+    }
 }
 
 impl<'a> Zones {
     pub fn new() -> Zones {
         Zones {
-            zones: BTreeMap::new()
+            zones: BTreeMap::new(),
+            max_zones: DEFAULT_MAX_ZONES,
+            max_records_per_zone: DEFAULT_MAX_RECORDS_PER_ZONE,
+            lowercase_names: false,
+            auto_serial: true
         }
     }
 
@@ -85,15 +667,75 @@ impl<'a> Zones {
             zone.minimum = try!(buffer.read_u32());
 
             let record_count = try!(buffer.read_u32());
+            if record_count as usize > self.max_records_per_zone {
+                return Err(Error::new(ErrorKind::Other,
+                                      format!("Record limit of {} exceeded for zone {}",
+                                              self.max_records_per_zone,
+                                              zone.domain)));
+            }
 
             for _ in 0..record_count {
                 let rr = try!(DnsRecord::read(&mut buffer));
                 zone.add_record(&rr);
             }
 
+            // Older zone files end after the records, in which case the
+            // stream reads as zeroes and no default TTL is set
+            if try!(buffer.read()) == 1 {
+                zone.default_ttl = Some(try!(buffer.read_u32()));
+            }
+
+            if try!(buffer.read()) == 1 {
+                let mut allow_transfer = Vec::new();
+                for _ in 0..try!(buffer.read_u16()) {
+                    allow_transfer.push(try!(try!(read_string(&mut buffer)).parse::<Network>()));
+                }
+                zone.allow_transfer = Some(allow_transfer);
+            }
+
+            zone.pool_policy = PoolPolicy::from_num(try!(buffer.read()));
+            for _ in 0..try!(buffer.read_u16()) {
+                let name = try!(read_string(&mut buffer));
+                let mut pool = Pool::new(try!(buffer.read_u32()));
+
+                for _ in 0..try!(buffer.read_u16()) {
+                    pool.networks.push(try!(try!(read_string(&mut buffer)).parse::<Network>()));
+                }
+
+                for _ in 0..try!(buffer.read_u32()) {
+                    pool.records.insert(try!(DnsRecord::read(&mut buffer)));
+                }
+
+                zone.pools.insert(name, pool);
+            }
+
+            for _ in 0..try!(buffer.read_u16()) {
+                let rec = try!(DnsRecord::read(&mut buffer));
+                let check = try!(try!(read_string(&mut buffer)).parse::<HealthCheck>());
+                zone.health_checks.insert(rec, check);
+            }
+
+            zone.lowercase_names = match try!(buffer.read()) {
+                1 => Some(false),
+                2 => Some(true),
+                _ => None
+            };
+
+            if try!(buffer.read()) == 1 {
+                let mut allow_transfer_keys = Vec::new();
+                for _ in 0..try!(buffer.read_u16()) {
+                    allow_transfer_keys.push(try!(read_string(&mut buffer)));
+                }
+                zone.allow_transfer_keys = Some(allow_transfer_keys);
+            }
+
+            if zone.lowercase_names.unwrap_or(self.lowercase_names) {
+                zone.lowercase_records();
+            }
+
             println!("Loaded zone {} with {} records", zone.domain, record_count);
 
-            self.zones.insert(zone.domain.clone(), zone);
+            try!(self.add_zone(zone));
         }
 
         Ok(())
@@ -122,10 +764,75 @@ impl<'a> Zones {
             let _ = buffer.write_u32(zone.minimum);
             let _ = buffer.write_u32(zone.records.len() as u32);
 
-            for rec in &zone.records {
+            for rec in zone.sorted_records() {
                 let _ = rec.write(&mut buffer);
             }
 
+            match zone.default_ttl {
+                Some(ttl) => {
+                    let _ = buffer.write_u8(1);
+                    let _ = buffer.write_u32(ttl);
+                },
+                None => {
+                    let _ = buffer.write_u8(0);
+                }
+            }
+
+            match zone.allow_transfer {
+                Some(ref networks) => {
+                    let _ = buffer.write_u8(1);
+                    let _ = buffer.write_u16(networks.len() as u16);
+                    for network in networks {
+                        let _ = write_string(&mut buffer, &network.to_string());
+                    }
+                },
+                None => {
+                    let _ = buffer.write_u8(0);
+                }
+            }
+
+            let _ = buffer.write_u8(zone.pool_policy.to_num());
+            let _ = buffer.write_u16(zone.pools.len() as u16);
+            for (name, pool) in &zone.pools {
+                let _ = write_string(&mut buffer, name);
+                let _ = buffer.write_u32(pool.weight);
+
+                let _ = buffer.write_u16(pool.networks.len() as u16);
+                for network in &pool.networks {
+                    let _ = write_string(&mut buffer, &network.to_string());
+                }
+
+                let _ = buffer.write_u32(pool.records.len() as u32);
+                for rec in &pool.records {
+                    let _ = rec.write(&mut buffer);
+                }
+            }
+
+            let _ = buffer.write_u16(zone.health_checks.len() as u16);
+            for (rec, check) in &zone.health_checks {
+                let _ = rec.write(&mut buffer);
+                let _ = write_string(&mut buffer, &check.to_string());
+            }
+
+            let _ = buffer.write_u8(match zone.lowercase_names {
+                None => 0,
+                Some(false) => 1,
+                Some(true) => 2
+            });
+
+            match zone.allow_transfer_keys {
+                Some(ref keys) => {
+                    let _ = buffer.write_u8(1);
+                    let _ = buffer.write_u16(keys.len() as u16);
+                    for key in keys {
+                        let _ = write_string(&mut buffer, key);
+                    }
+                },
+                None => {
+                    let _ = buffer.write_u8(0);
+                }
+            }
+
             let _ = zone_file.write(&buffer.buffer[0..buffer.pos]);
         }
 
@@ -137,9 +844,112 @@ impl<'a> Zones {
         self.zones.values().collect()
     }
 
-    pub fn add_zone(&mut self, zone: Zone)
+    /// Add a zone, replacing any existing zone for the same domain
+    ///
+    /// Fails if adding a new zone would exceed `max_zones`, or if the zone
+    /// has more records than `max_records_per_zone`.
+    pub fn add_zone(&mut self, zone: Zone) -> Result<()>
     {
+        if !self.zones.contains_key(&zone.domain) && self.zones.len() >= self.max_zones {
+            return Err(Error::new(ErrorKind::Other,
+                                  format!("Zone limit of {} reached", self.max_zones)));
+        }
+
+        if zone.records.len() > self.max_records_per_zone {
+            return Err(Error::new(ErrorKind::Other,
+                                  format!("Record limit of {} exceeded for zone {}",
+                                          self.max_records_per_zone,
+                                          zone.domain)));
+        }
+
         self.zones.insert(zone.domain.clone(), zone);
+
+        Ok(())
+    }
+
+    /// Add a record to the zone for `domain`
+    ///
+    /// Fails with `NotFound` if there's no such zone, and with `Other` if the
+    /// record would push the zone past `max_records_per_zone`.
+    pub fn add_record(&mut self, domain: &str, rec: &DnsRecord) -> Result<bool>
+    {
+        let max_records = self.max_records_per_zone;
+        let lowercase_names = self.lowercase_names;
+        let auto_serial = self.auto_serial;
+
+        let zone = match self.zones.get_mut(domain) {
+            Some(x) => x,
+            None => return Err(Error::new(ErrorKind::NotFound, "Zone not found"))
+        };
+
+        let lowercase = zone.lowercase_names.unwrap_or(lowercase_names);
+        let rec = if lowercase { lowercase_owner(rec) } else { rec.clone() };
+
+        if !zone.records.contains(&rec) && zone.records.len() >= max_records {
+            return Err(Error::new(ErrorKind::Other,
+                                  format!("Record limit of {} reached for zone {}", max_records, domain)));
+        }
+
+        let added = zone.insert_record(&rec, false);
+        if added {
+            zone.record_change(auto_serial, Vec::new(), vec![rec]);
+        }
+
+        Ok(added)
+    }
+
+    /// Remove a record from the zone for `domain`
+    ///
+    /// Returns whether the record was present, and fails with `NotFound` if
+    /// there's no such zone.
+    pub fn delete_record(&mut self, domain: &str, rec: &DnsRecord) -> Result<bool>
+    {
+        let lowercase_names = self.lowercase_names;
+        let auto_serial = self.auto_serial;
+
+        let zone = match self.zones.get_mut(domain) {
+            Some(x) => x,
+            None => return Err(Error::new(ErrorKind::NotFound, "Zone not found"))
+        };
+
+        let lowercase = zone.lowercase_names.unwrap_or(lowercase_names);
+        let rec = if lowercase { lowercase_owner(rec) } else { rec.clone() };
+
+        let deleted = zone.delete_record(&rec);
+        if deleted {
+            zone.record_change(auto_serial, vec![rec], Vec::new());
+        }
+
+        Ok(deleted)
+    }
+
+    /// Find the most specific zone containing `qname`
+    ///
+    /// Zones are matched on whole labels, so `example.com` contains
+    /// `www.example.com` but not `badexample.com`. If no zone matches, we're
+    /// not authoritative for the name at all.
+    pub fn best_matching_zone(&'a self, qname: &str) -> Option<&'a Zone>
+    {
+        let qname = qname.to_lowercase();
+
+        let mut best_match: Option<&Zone> = None;
+        for zone in self.zones.values() {
+            let domain = zone.domain.to_lowercase();
+
+            let is_match = qname == domain ||
+                           domain.is_empty() ||
+                           qname.ends_with(&format!(".{}", domain));
+            if !is_match {
+                continue;
+            }
+
+            match best_match {
+                Some(x) if x.domain.len() >= zone.domain.len() => {},
+                _ => best_match = Some(zone)
+            }
+        }
+
+        best_match
     }
 
     pub fn get_zone(&'a self, domain: &str) -> Option<&'a Zone>
@@ -155,19 +965,35 @@ impl<'a> Zones {
 
 #[derive(Default)]
 pub struct Authority {
-    zones: RwLock<Zones>
+    zones: RwLock<Zones>,
+
+    /// Incremented whenever the zones might have changed
+    generation: AtomicUsize,
+
+    /// Incremented for every pick of a pool, for the round robin policy
+    pool_turn: AtomicUsize,
+
+    /// Incremented for every answer with addresses to rotate
+    rotate_turn: AtomicUsize,
+
+    /// Records failing their health checks
+    down: RwLock<BTreeSet<DnsRecord>>
 }
 
 impl Authority {
     pub fn new() -> Authority {
         Authority {
-            zones: RwLock::new(Zones::new())
+            zones: RwLock::new(Zones::new()),
+            generation: AtomicUsize::new(0),
+            pool_turn: AtomicUsize::new(0),
+            rotate_turn: AtomicUsize::new(0),
+            down: RwLock::new(BTreeSet::new())
         }
     }
 
     pub fn load(&self) -> Result<()>
     {
-        let mut zones = match self.zones.write() {
+        let mut zones = match self.write() {
             Ok(x) => x,
             Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
         };
@@ -184,76 +1010,836 @@ impl Authority {
             None => return None
         };
 
-        let mut best_match = None;
-        for zone in zones.zones() {
-            if !qname.ends_with(&zone.domain) {
-                continue;
-            }
+        // Not authoritative for this name, so leave it to the caller
+        let zone = match zones.best_matching_zone(qname) {
+            Some(x) => x,
+            None => return None
+        };
+
+        let mut packet = DnsPacket::new();
+        packet.header.authoritative_answer = true;
+
+        // Names below a DNAME are answered with the DNAME, along with a CNAME
+        // to the same name below its target, as described in RFC 6672
+        if let Some(dname) = zone.find_dname(qname) {
+            packet.answers.push(dname.clone());
 
-            if let Some((len, _)) = best_match {
-                if len < zone.domain.len() {
-                    best_match = Some((zone.domain.len(), zone));
+            if let DnsRecord::DNAME { ref domain, ref host, ttl } = *dname {
+                let prefix = &qname[..qname.len() - domain.len() - 1];
+                let target = if host.is_empty() {
+                    prefix.to_string()
+                } else {
+                    format!("{}.{}", prefix, host)
+                };
+
+                // The new name can't be longer than any other domain name,
+                // which takes two more bytes in wire format than written out
+                if target.len() + 2 > MAX_NAME_LEN {
+                    packet.header.rescode = ResultCode::YXDOMAIN;
+                } else {
+                    packet.answers.push(DnsRecord::CNAME {
+                        domain: qname.to_string(),
+                        host: target,
+                        ttl: ttl
+                    });
                 }
             }
-            else {
-                best_match = Some((zone.domain.len(), zone));
-            }
+
+            return Some(packet);
         }
 
-        let zone = match best_match {
-            Some((_, zone)) => zone,
-            None => return None
+        // Names that don't exist are answered from a matching wildcard, if
+        // there is one
+        let owner = if zone.has_name(qname) {
+            Some(qname.to_string())
+        } else {
+            zone.find_wildcard(qname)
         };
 
-        let mut packet = DnsPacket::new();
-        packet.header.authoritative_answer = true;
-
+        let name_exists = owner.is_some();
         for rec in &zone.records {
             let domain = match rec.get_domain() {
                 Some(x) => x,
                 None => continue
             };
 
-            if &domain != qname {
+            if !owner.as_ref().map_or(false, |x| x.eq_ignore_ascii_case(&domain)) {
                 continue;
             }
 
+            // ANAME records are never handed out as is, but are included in
+            // answers to address queries for the resolver to flatten. ANY
+            // queries get every other record for the name.
             let rtype = rec.get_querytype();
-            if qtype == rtype || (qtype == QueryType::A &&
-                                  rtype == QueryType::CNAME) {
+            let is_address = qtype == QueryType::A || qtype == QueryType::AAAA;
+            let is_match = if rtype == QueryType::ANAME {
+                is_address
+            } else {
+                qtype == rtype || qtype == QueryType::ANY ||
+                    (qtype == QueryType::A && rtype == QueryType::CNAME)
+            };
+
+            if is_match {
 
                 packet.answers.push(rec.clone());
             }
 
         }
 
+        // Addresses failing their health checks are left out, unless every
+        // address is failing
+        if let Ok(down) = self.down.read() {
+            if packet.answers.iter().any(|x| down.contains(x)) &&
+               packet.answers.iter().any(|x| !down.contains(x) && is_address_record(x)) {
+                packet.answers.retain(|x| !down.contains(x));
+            }
+        }
+
+        // Records synthesized from a wildcard carry the name of the query
+        for rec in &mut packet.answers {
+            rec.set_domain(qname);
+        }
+
         if packet.answers.is_empty() {
-            packet.header.rescode = ResultCode::NXDOMAIN;
-
-            packet.authorities.push(DnsRecord::SOA {
-                domain: zone.domain.clone(),
-                m_name: zone.m_name.clone(),
-                r_name: zone.r_name.clone(),
-                serial: zone.serial,
-                refresh: zone.refresh,
-                retry: zone.retry,
-                expire: zone.expire,
-                minimum: zone.minimum,
-                ttl: TransientTtl(zone.minimum)
-            });
+            // Authoritative, but with nothing to return. The name only
+            // doesn't exist if there are no records of any type for it.
+            if !name_exists {
+                packet.header.rescode = ResultCode::NXDOMAIN;
+            }
+
+            packet.authorities.push(zone.soa_record());
         }
 
         Some(packet)
     }
 
+    /// Replace the set of records failing their health checks
+    ///
+    /// Changes bump the generation, since they affect the answers.
+    pub fn set_down(&self, records: BTreeSet<DnsRecord>)
+    {
+        let mut down = match self.down.write() {
+            Ok(x) => x,
+            Err(_) => return
+        };
+
+        if *down != records {
+            *down = records;
+            self.generation.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Check if `rec` is failing its health check
+    pub fn is_down(&self, rec: &DnsRecord) -> bool
+    {
+        self.down.read().map(|x| x.contains(rec)).unwrap_or(false)
+    }
+
+    /// Narrow the answers in `packet` for `qname` down to a single pool, for
+    /// a query from `client`
+    ///
+    /// Returns whether a pool was picked, in which case the answer varies
+    /// between queries.
+    pub fn select_pool(&self, packet: &mut DnsPacket, qname: &str, client: &IpAddr) -> bool
+    {
+        let zones = match self.zones.read().ok() {
+            Some(x) => x,
+            None => return false
+        };
+
+        let zone = match zones.best_matching_zone(qname) {
+            Some(x) => x,
+            None => return false
+        };
+
+        let turn = self.pool_turn.fetch_add(1, Ordering::Relaxed);
+        zone.select_pool(&mut packet.answers, client, turn)
+    }
+
+    /// Rotate the addresses in the answers of `packet`, so that consecutive
+    /// answers start with the next address
+    ///
+    /// Returns whether there was anything to rotate, in which case the answer
+    /// varies between queries.
+    pub fn rotate_answers(&self, packet: &mut DnsPacket) -> bool
+    {
+        let count = |qtype| packet.answers.iter().filter(|x| x.get_querytype() == qtype).count();
+        if count(QueryType::A) < 2 && count(QueryType::AAAA) < 2 {
+            return false;
+        }
+
+        let turn = self.rotate_turn.fetch_add(1, Ordering::Relaxed);
+        packet.rotate_answers(turn);
+
+        true
+    }
+
     pub fn read(&self) -> LockResult<RwLockReadGuard<Zones>>
     {
         self.zones.read()
     }
 
+    /// Acquire write access to the zones
+    ///
+    /// Any write access is assumed to modify the zones, and bumps the
+    /// generation. This happens once the lock is held, so that anything
+    /// derived from the zones after reading the generation is either from
+    /// before the change, and tagged with an older generation, or blocked
+    /// until the change is complete.
     pub fn write(&self) -> LockResult<RwLockWriteGuard<Zones>>
     {
-        self.zones.write()
+        let zones = self.zones.write();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+
+        zones
+    }
+
+    /// The current generation of the zones
+    ///
+    /// Use this to invalidate data derived from the zones, such as cached
+    /// responses.
+    pub fn generation(&self) -> usize
+    {
+        self.generation.load(Ordering::SeqCst)
     }
 }
 
+#[cfg(test)]
+mod tests {
+
+    use std::io::ErrorKind;
+
+    use dns::protocol::{DnsRecord, TransientTtl};
+
+    use super::*;
+
+    fn build_a(domain: &str, addr: &str) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            addr: addr.parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }
+    }
+
+    #[test]
+    fn test_sorted_records() {
+        let mut zone = Zone::new("example.com".to_string(), String::new(), String::new());
+
+        // The example ordering from RFC 4034 section 6.1, with an additional
+        // record type and address to exercise the tie breakers
+        let names = ["example.com",
+                     "a.example.com",
+                     "yljkjljk.a.example.com",
+                     "z.a.example.com",
+                     "zabc.a.example.com",
+                     "z.example.com",
+                     "*.z.example.com"];
+
+        for name in names.iter().rev() {
+            zone.add_record(&build_a(name, "127.0.0.1"));
+        }
+
+        zone.add_record(&build_a("a.example.com", "10.0.0.1"));
+        zone.add_record(&DnsRecord::NS {
+            domain: "a.example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: TransientTtl(3600)
+        });
+
+        let sorted = zone.sorted_records();
+
+        let expected = vec![build_a("example.com", "127.0.0.1"),
+                            build_a("a.example.com", "10.0.0.1"),
+                            build_a("a.example.com", "127.0.0.1"),
+                            DnsRecord::NS {
+                                domain: "a.example.com".to_string(),
+                                host: "ns1.example.com".to_string(),
+                                ttl: TransientTtl(3600)
+                            },
+                            build_a("yljkjljk.a.example.com", "127.0.0.1"),
+                            build_a("z.a.example.com", "127.0.0.1"),
+                            build_a("zabc.a.example.com", "127.0.0.1"),
+                            build_a("z.example.com", "127.0.0.1"),
+                            build_a("*.z.example.com", "127.0.0.1")];
+
+        assert_eq!(expected.len(), sorted.len());
+        for (a, b) in expected.iter().zip(sorted.iter()) {
+            assert_eq!(a, *b);
+        }
+    }
+
+    #[test]
+    fn test_zone_limit() {
+        let mut zones = Zones::new();
+        zones.max_zones = 2;
+
+        assert!(zones.add_zone(Zone::new("a.com".to_string(), String::new(), String::new())).is_ok());
+        assert!(zones.add_zone(Zone::new("b.com".to_string(), String::new(), String::new())).is_ok());
+
+        // Replacing an existing zone doesn't count against the limit
+        assert!(zones.add_zone(Zone::new("a.com".to_string(), String::new(), String::new())).is_ok());
+
+        match zones.add_zone(Zone::new("c.com".to_string(), String::new(), String::new())) {
+            Ok(_) => panic!(),
+            Err(e) => {
+                assert_eq!(ErrorKind::Other, e.kind());
+                assert_eq!("Zone limit of 2 reached", e.to_string());
+            }
+        }
+
+        assert_eq!(2, zones.zones().len());
+        assert!(zones.get_zone("c.com").is_none());
+    }
+
+    #[test]
+    fn test_record_limit() {
+        let mut zones = Zones::new();
+        zones.max_records_per_zone = 2;
+
+        zones.add_zone(Zone::new("a.com".to_string(), String::new(), String::new())).unwrap();
+
+        assert!(zones.add_record("a.com", &build_a("a.com", "127.0.0.1")).unwrap());
+        assert!(zones.add_record("a.com", &build_a("www.a.com", "127.0.0.1")).unwrap());
+
+        // Re-adding an existing record is still allowed
+        assert!(!zones.add_record("a.com", &build_a("a.com", "127.0.0.1")).unwrap());
+
+        match zones.add_record("a.com", &build_a("mail.a.com", "127.0.0.1")) {
+            Ok(_) => panic!(),
+            Err(e) => {
+                assert_eq!(ErrorKind::Other, e.kind());
+                assert_eq!("Record limit of 2 reached for zone a.com", e.to_string());
+            }
+        }
+
+        match zones.add_record("b.com", &build_a("b.com", "127.0.0.1")) {
+            Ok(_) => panic!(),
+            Err(e) => assert_eq!(ErrorKind::NotFound, e.kind())
+        }
+
+        assert_eq!(2, zones.get_zone("a.com").unwrap().records.len());
+    }
+
+    #[test]
+    fn test_import_record_limit() {
+        let mut zones = Zones::new();
+        zones.max_records_per_zone = 2;
+
+        let zone = import_zone("$TTL 1h
+$ORIGIN example.org.
+@   IN  SOA ns1.example.org. hostmaster.example.org. 1 3600 900 604800 3600
+    IN  NS      ns1
+ns1 IN  A       192.0.2.1
+www IN  A       192.0.2.2
+").unwrap();
+        assert_eq!(3, zone.records.len());
+
+        // A zone added as a whole can't bypass the limit either
+        match zones.add_zone(zone) {
+            Ok(_) => panic!(),
+            Err(e) => {
+                assert_eq!(ErrorKind::Other, e.kind());
+                assert_eq!("Record limit of 2 exceeded for zone example.org", e.to_string());
+            }
+        }
+
+        assert!(zones.get_zone("example.org").is_none());
+    }
+
+    #[test]
+    fn test_delete_record() {
+        let mut zones = Zones::new();
+        zones.add_zone(Zone::new("a.com".to_string(), String::new(), String::new())).unwrap();
+
+        assert!(zones.add_record("a.com", &build_a("www.a.com", "127.0.0.1")).unwrap());
+        assert!(zones.add_record("a.com", &build_a("www.a.com", "127.0.0.2")).unwrap());
+
+        assert!(zones.delete_record("a.com", &build_a("www.a.com", "127.0.0.1")).unwrap());
+
+        {
+            let zone = zones.get_zone("a.com").unwrap();
+            assert_eq!(1, zone.records.len());
+            assert!(!zone.records.contains(&build_a("www.a.com", "127.0.0.1")));
+            assert!(zone.records.contains(&build_a("www.a.com", "127.0.0.2")));
+        }
+
+        // Deleting again finds nothing
+        assert!(!zones.delete_record("a.com", &build_a("www.a.com", "127.0.0.1")).unwrap());
+
+        match zones.delete_record("b.com", &build_a("b.com", "127.0.0.1")) {
+            Ok(_) => panic!(),
+            Err(e) => assert_eq!(ErrorKind::NotFound, e.kind())
+        }
+    }
+
+    #[test]
+    fn test_auto_serial() {
+        let mut zones = Zones::new();
+        zones.add_zone(Zone::new("a.com".to_string(), String::new(), String::new())).unwrap();
+
+        let serial = |zones: &Zones| zones.get_zone("a.com").unwrap().serial;
+
+        assert!(zones.add_record("a.com", &build_a("www.a.com", "127.0.0.1")).unwrap());
+        assert_eq!(1, serial(&zones));
+        assert!(zones.add_record("a.com", &build_a("www.a.com", "127.0.0.2")).unwrap());
+        assert_eq!(2, serial(&zones));
+
+        // Nothing changes when the record is already present, or missing
+        assert!(!zones.add_record("a.com", &build_a("www.a.com", "127.0.0.2")).unwrap());
+        assert!(!zones.delete_record("a.com", &build_a("www.a.com", "127.0.0.3")).unwrap());
+        assert_eq!(2, serial(&zones));
+
+        assert!(zones.delete_record("a.com", &build_a("www.a.com", "127.0.0.1")).unwrap());
+        assert_eq!(3, serial(&zones));
+
+        // The serial wraps around to zero
+        let mut zone = Zone::new("b.com".to_string(), String::new(), String::new());
+        zone.serial = u32::max_value();
+        zones.add_zone(zone).unwrap();
+        assert!(zones.add_record("b.com", &build_a("b.com", "127.0.0.1")).unwrap());
+        assert_eq!(0, zones.get_zone("b.com").unwrap().serial);
+
+        // With auto increment disabled, the serial is left alone
+        zones.auto_serial = false;
+        assert!(zones.add_record("a.com", &build_a("mail.a.com", "127.0.0.1")).unwrap());
+        assert!(zones.delete_record("a.com", &build_a("www.a.com", "127.0.0.2")).unwrap());
+        assert_eq!(3, serial(&zones));
+    }
+
+    #[test]
+    fn test_zonefile() {
+        let text = "$ORIGIN example.com.
+$TTL 1h
+@ IN SOA ns1 admin ( 2018010101 3h 15m 1w 5m )
+  IN NS ns1
+  IN MX 10 mail
+www IN A 127.0.0.1
+    IN AAAA ::1
+ftp 60 IN CNAME www
+";
+
+        let zone = Zone::from_zonefile(text).unwrap();
+        assert_eq!(5, zone.records.len());
+
+        // Exporting and importing again leaves the zone as it was, and the
+        // export is stable
+        let exported = zone.to_zonefile();
+        let reimported = Zone::from_zonefile(&exported).unwrap();
+
+        assert_eq!(zone.records, reimported.records);
+        assert_eq!(zone.soa_record(), reimported.soa_record());
+        assert_eq!(exported, reimported.to_zonefile());
+
+        assert!(exported.lines().any(|x| x == "ftp.example.com. 60 IN CNAME www.example.com."));
+    }
+
+    #[test]
+    fn test_lowercase_names() {
+        let mut zone = Zone::new("example.com".to_string(), String::new(), String::new());
+
+        // Without normalization, case variants are kept apart
+        assert!(zone.add_record(&build_a("WWW.example.com", "127.0.0.1")));
+        assert!(zone.add_record(&build_a("www.Example.com", "127.0.0.1")));
+        assert_eq!(2, zone.records.len());
+
+        // Normalizing merges them into a single record
+        zone.lowercase_records();
+        assert_eq!(1, zone.records.len());
+        assert!(zone.records.contains(&build_a("www.example.com", "127.0.0.1")));
+
+        zone.lowercase_names = Some(true);
+        assert!(!zone.add_record(&build_a("Www.EXAMPLE.com", "127.0.0.1")));
+        assert_eq!(1, zone.records.len());
+
+        // The server wide setting applies unless the zone overrides it
+        let mut zones = Zones::new();
+        zones.lowercase_names = true;
+        zones.add_zone(Zone::new("a.com".to_string(), String::new(), String::new())).unwrap();
+
+        assert!(zones.add_record("a.com", &build_a("WWW.a.com", "127.0.0.1")).unwrap());
+        assert!(!zones.add_record("a.com", &build_a("www.A.com", "127.0.0.1")).unwrap());
+        assert_eq!(1, zones.get_zone("a.com").unwrap().records.len());
+
+        zones.get_zone_mut("a.com").unwrap().lowercase_names = Some(false);
+        assert!(zones.add_record("a.com", &build_a("WWW.a.com", "127.0.0.1")).unwrap());
+        assert_eq!(2, zones.get_zone("a.com").unwrap().records.len());
+    }
+
+    #[test]
+    fn test_best_matching_zone() {
+        let mut zones = Zones::new();
+        zones.add_zone(Zone::new("example.com".to_string(), String::new(), String::new())).unwrap();
+        zones.add_zone(Zone::new("sub.example.com".to_string(), String::new(), String::new())).unwrap();
+
+        assert_eq!("example.com", zones.best_matching_zone("example.com").unwrap().domain);
+        assert_eq!("example.com", zones.best_matching_zone("www.example.com").unwrap().domain);
+        assert_eq!("example.com", zones.best_matching_zone("WWW.Example.COM").unwrap().domain);
+
+        // The longest suffix wins
+        assert_eq!("sub.example.com", zones.best_matching_zone("sub.example.com").unwrap().domain);
+        assert_eq!("sub.example.com", zones.best_matching_zone("a.sub.example.com").unwrap().domain);
+
+        // Suffixes only match on label boundaries
+        assert!(zones.best_matching_zone("badexample.com").is_none());
+        assert!(zones.best_matching_zone("com").is_none());
+        assert!(zones.best_matching_zone("google.com").is_none());
+    }
+
+    fn build_pools(policy: PoolPolicy) -> Authority {
+        let authority = Authority::new();
+
+        {
+            let mut zones = authority.write().unwrap();
+
+            let mut zone = Zone::new("example.com".to_string(), String::new(), String::new());
+            zone.pool_policy = policy;
+            zone.add_record(&build_a("www.example.com", "10.0.0.1"));
+            zone.add_record(&build_a("www.example.com", "10.0.1.1"));
+            zone.add_record(&build_a("www.example.com", "10.0.2.1"));
+            zone.assign_pool("east", &build_a("www.example.com", "10.0.0.1"));
+            zone.assign_pool("west", &build_a("www.example.com", "10.0.1.1"));
+
+            zone.pools.get_mut("east").unwrap().weight = 3;
+            zone.pools.get_mut("west").unwrap().networks = vec!["192.0.2.0/24".parse().unwrap()];
+
+            zones.add_zone(zone).unwrap();
+        }
+
+        authority
+    }
+
+    /// Query for www.example.com, returning the pooled address that was picked
+    fn query_pool(authority: &Authority, client: &str) -> String {
+        let mut packet = authority.query("www.example.com", QueryType::A).unwrap();
+        assert!(authority.select_pool(&mut packet, "www.example.com", &client.parse().unwrap()));
+
+        // The record outside of the pools is always included
+        assert_eq!(2, packet.answers.len());
+        assert!(packet.answers.contains(&build_a("www.example.com", "10.0.2.1")));
+
+        packet.answers.iter()
+            .filter_map(|x| match *x {
+                DnsRecord::A { addr, .. } if addr.to_string() != "10.0.2.1" => Some(addr.to_string()),
+                _ => None
+            })
+            .next()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_weighted_pools() {
+        let authority = build_pools(PoolPolicy::Weighted);
+
+        let total = 10_000;
+        let east = (0..total).filter(|_| query_pool(&authority, "198.51.100.1") == "10.0.0.1").count();
+
+        // The weights are 3 to 1
+        let ratio = east as f64 / total as f64;
+        assert!(ratio > 0.72 && ratio < 0.78, "ratio was {}", ratio);
+
+        // Names without pooled records are left alone
+        let mut packet = authority.query("example.com", QueryType::A).unwrap();
+        assert!(!authority.select_pool(&mut packet, "example.com", &"198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_pools_mixed_case() {
+        let authority = build_pools(PoolPolicy::Weighted);
+
+        // Answers to a mixed case query have the owner names of the query,
+        // which still match the records of the pools
+        let mut packet = authority.query("www.example.com", QueryType::A).unwrap();
+        for rec in &mut packet.answers {
+            rec.set_domain("WwW.ExAmPlE.cOm");
+        }
+
+        assert!(authority.select_pool(&mut packet, "www.example.com", &"198.51.100.1".parse().unwrap()));
+        assert_eq!(2, packet.answers.len());
+        assert!(packet.answers.contains(&build_a("WwW.ExAmPlE.cOm", "10.0.2.1")));
+    }
+
+    #[test]
+    fn test_pool_policies() {
+        let authority = build_pools(PoolPolicy::RoundRobin);
+        let picks = (0..4).map(|_| query_pool(&authority, "198.51.100.1")).collect::<Vec<String>>();
+        assert_eq!(picks[0], picks[2]);
+        assert_eq!(picks[1], picks[3]);
+        assert!(picks[0] != picks[1]);
+
+        // Clients in the network of a pool are answered from it, regardless
+        // of the weights
+        let authority = build_pools(PoolPolicy::Subnet);
+        for _ in 0..100 {
+            assert_eq!("10.0.1.1", query_pool(&authority, "192.0.2.10"));
+        }
+
+        assert_eq!(0, pick_weighted(&[0, 0], 2));
+        assert_eq!(1, pick_weighted(&[1, 2], 1));
+        assert_eq!(1, pick_weighted(&[0, 2], 0));
+    }
+
+    #[test]
+    fn test_query_rescodes() {
+        let authority = Authority::new();
+
+        {
+            let mut zones = authority.write().unwrap();
+            zones.add_zone(Zone::new("example.com".to_string(),
+                                     "ns1.example.com".to_string(),
+                                     "admin.example.com".to_string())).unwrap();
+            zones.add_record("example.com", &build_a("www.example.com", "127.0.0.1")).unwrap();
+        }
+
+        // Not authoritative for the suffix, so the caller decides what to do
+        assert!(authority.query("www.google.com", QueryType::A).is_none());
+        assert!(authority.query("wwwexample.com", QueryType::A).is_none());
+
+        // Authoritative, and the record exists
+        let packet = authority.query("www.example.com", QueryType::A).unwrap();
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert_eq!(1, packet.answers.len());
+
+        // Authoritative, but the name is absent
+        let packet = authority.query("mail.example.com", QueryType::A).unwrap();
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(ResultCode::NXDOMAIN, packet.header.rescode);
+        assert_eq!(0, packet.answers.len());
+        match packet.authorities[0] {
+            DnsRecord::SOA { ref domain, .. } => assert_eq!("example.com", domain),
+            _ => panic!()
+        }
+
+        // Authoritative, and the name exists but has no records of this type
+        let packet = authority.query("www.example.com", QueryType::MX).unwrap();
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert_eq!(0, packet.answers.len());
+        assert_eq!(1, packet.authorities.len());
+    }
+
+    #[test]
+    fn test_query_any() {
+        let authority = Authority::new();
+
+        {
+            let mut zones = authority.write().unwrap();
+            zones.add_zone(Zone::new("example.com".to_string(),
+                                     "ns1.example.com".to_string(),
+                                     "admin.example.com".to_string())).unwrap();
+            zones.add_record("example.com", &build_a("example.com", "127.0.0.1")).unwrap();
+            zones.add_record("example.com", &DnsRecord::MX {
+                domain: "example.com".to_string(),
+                priority: 10,
+                host: "mail.example.com".to_string(),
+                ttl: TransientTtl(3600)
+            }).unwrap();
+            zones.add_record("example.com", &DnsRecord::txt("example.com", "v=spf1 -all", 3600)).unwrap();
+            zones.add_record("example.com", &build_a("www.example.com", "127.0.0.2")).unwrap();
+        }
+
+        // Every record for the name is returned, whatever its type
+        let packet = authority.query("example.com", QueryType::ANY).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert_eq!(3, packet.answers.len());
+
+        let mut types = packet.answers.iter().map(|x| x.get_querytype()).collect::<Vec<QueryType>>();
+        types.sort_by_key(|x| x.to_num());
+        assert_eq!(vec![QueryType::A, QueryType::MX, QueryType::TXT], types);
+        assert!(packet.answers.iter().all(|x| x.get_domain() == Some("example.com".to_string())));
+
+        // and names without records are still NXDOMAIN
+        let packet = authority.query("mail.example.com", QueryType::ANY).unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, packet.header.rescode);
+        assert!(packet.answers.is_empty());
+    }
+
+    #[test]
+    fn test_wildcards() {
+        let authority = Authority::new();
+
+        {
+            let mut zones = authority.write().unwrap();
+            zones.add_zone(Zone::new("example.com".to_string(),
+                                     "ns1.example.com".to_string(),
+                                     "admin.example.com".to_string())).unwrap();
+            zones.add_record("example.com", &build_a("*.example.com", "127.0.0.1")).unwrap();
+            zones.add_record("example.com", &build_a("www.example.com", "127.0.0.2")).unwrap();
+            zones.add_record("example.com", &build_a("host.sub.example.com", "127.0.0.3")).unwrap();
+        }
+
+        // A name without records of its own is answered from the wildcard,
+        // with the name of the query
+        let packet = authority.query("foo.example.com", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert_eq!(vec![build_a("foo.example.com", "127.0.0.1")], packet.answers);
+        assert_eq!(Some("foo.example.com".to_string()), packet.answers[0].get_domain());
+
+        // as are names several labels below it
+        let packet = authority.query("a.b.example.com", QueryType::A).unwrap();
+        assert_eq!(vec![build_a("a.b.example.com", "127.0.0.1")], packet.answers);
+
+        // The wildcard exists, but doesn't have records of every type
+        let packet = authority.query("foo.example.com", QueryType::MX).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert!(packet.answers.is_empty());
+
+        // An exact match takes precedence
+        let packet = authority.query("www.example.com", QueryType::A).unwrap();
+        assert_eq!(vec![build_a("www.example.com", "127.0.0.2")], packet.answers);
+
+        // and the wildcard doesn't fill in other types for it
+        let packet = authority.query("www.example.com", QueryType::MX).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert!(packet.answers.is_empty());
+
+        // A name existing only through a more specific name below it isn't
+        // matched, and neither are the names below it, since it's a closer
+        // encloser than the apex
+        let packet = authority.query("sub.example.com", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert!(packet.answers.is_empty());
+
+        let packet = authority.query("other.sub.example.com", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, packet.header.rescode);
+        assert!(packet.answers.is_empty());
+
+        // The wildcard itself can be queried as well
+        let packet = authority.query("*.example.com", QueryType::A).unwrap();
+        assert_eq!(vec![build_a("*.example.com", "127.0.0.1")], packet.answers);
+    }
+
+    #[test]
+    fn test_wildcards_mixed_case_zone() {
+        let authority = Authority::new();
+
+        {
+            let mut zones = authority.write().unwrap();
+            zones.add_zone(Zone::new("Example.com".to_string(),
+                                     "ns1.example.com".to_string(),
+                                     "admin.example.com".to_string())).unwrap();
+            zones.add_record("Example.com", &build_a("*.example.com", "127.0.0.1")).unwrap();
+            zones.add_zone(Zone::new("Example.org".to_string(),
+                                     "ns1.example.org".to_string(),
+                                     "admin.example.org".to_string())).unwrap();
+        }
+
+        // The apex is found as the closest encloser regardless of its case
+        let packet = authority.query("foo.example.com", QueryType::A).unwrap();
+        assert_eq!(vec![build_a("foo.example.com", "127.0.0.1")], packet.answers);
+
+        // and the search ends there even when nothing in the zone matches
+        let packet = authority.query("foo.example.org", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, packet.header.rescode);
+        assert!(packet.answers.is_empty());
+    }
+
+    #[test]
+    fn test_query_mixed_case_names() {
+        let authority = Authority::new();
+
+        let dname = DnsRecord::DNAME {
+            domain: "B.Example.com".to_string(),
+            host: "example.net".to_string(),
+            ttl: TransientTtl(300)
+        };
+
+        {
+            let mut zones = authority.write().unwrap();
+            zones.add_zone(Zone::new("Example.com".to_string(),
+                                     "ns1.example.com".to_string(),
+                                     "admin.example.com".to_string())).unwrap();
+            zones.add_record("Example.com", &build_a("WWW.Example.com", "127.0.0.1")).unwrap();
+            zones.add_record("Example.com", &build_a("host.Sub.Example.com", "127.0.0.2")).unwrap();
+            zones.add_record("Example.com", &dname).unwrap();
+        }
+
+        // Owners are matched regardless of the case of either name
+        let packet = authority.query("www.example.com", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert_eq!(vec![build_a("www.example.com", "127.0.0.1")], packet.answers);
+
+        // as are empty non-terminals and the apex
+        let packet = authority.query("sub.example.com", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert!(packet.answers.is_empty());
+
+        let packet = authority.query("example.com", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+
+        // and DNAME records
+        let packet = authority.query("a.b.example.com", QueryType::A).unwrap();
+        assert_eq!(2, packet.answers.len());
+        assert_eq!(dname, packet.answers[0]);
+        match packet.answers[1] {
+            DnsRecord::CNAME { ref host, .. } => assert_eq!("a.example.net", host),
+            _ => panic!()
+        }
+
+        let packet = authority.query("other.example.com", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, packet.header.rescode);
+    }
+
+    #[test]
+    fn test_dname() {
+        let authority = Authority::new();
+
+        let dname = DnsRecord::DNAME {
+            domain: "b.example.com".to_string(),
+            host: "example.net".to_string(),
+            ttl: TransientTtl(300)
+        };
+
+        {
+            let mut zones = authority.write().unwrap();
+            zones.add_zone(Zone::new("example.com".to_string(),
+                                     "ns1.example.com".to_string(),
+                                     "admin.example.com".to_string())).unwrap();
+            zones.add_record("example.com", &dname).unwrap();
+            zones.add_record("example.com", &build_a("b.example.com", "127.0.0.1")).unwrap();
+        }
+
+        // Names below the DNAME get a CNAME to the same name below the target
+        let packet = authority.query("a.b.example.com", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert_eq!(vec![dname.clone(), DnsRecord::CNAME {
+            domain: "a.b.example.com".to_string(),
+            host: "a.example.net".to_string(),
+            ttl: TransientTtl(300)
+        }], packet.answers);
+        assert_eq!(300, packet.answers[1].get_ttl());
+
+        let packet = authority.query("x.y.b.example.com", QueryType::MX).unwrap();
+        match packet.answers[1] {
+            DnsRecord::CNAME { ref host, .. } => assert_eq!("x.y.example.net", host),
+            _ => panic!()
+        }
+
+        // The owner of the DNAME isn't redirected itself
+        let packet = authority.query("b.example.com", QueryType::A).unwrap();
+        assert_eq!(vec![build_a("b.example.com", "127.0.0.1")], packet.answers);
+
+        let packet = authority.query("b.example.com", QueryType::DNAME).unwrap();
+        assert_eq!(vec![dname.clone()], packet.answers);
+
+        // A name which would become too long can't be redirected
+        let long_name = format!("{}.{}.{}.b.example.com", "a".repeat(63), "b".repeat(63), "c".repeat(63));
+        let packet = authority.query(&long_name, QueryType::A).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+
+        {
+            let mut zones = authority.write().unwrap();
+            zones.add_record("example.com", &DnsRecord::DNAME {
+                domain: "long.example.com".to_string(),
+                host: format!("{}.example.net", "d".repeat(63)),
+                ttl: TransientTtl(300)
+            }).unwrap();
+        }
+
+        let long_name = format!("{}.{}.{}.long.example.com", "a".repeat(63), "b".repeat(63), "c".repeat(63));
+        let packet = authority.query(&long_name, QueryType::A).unwrap();
+        assert_eq!(ResultCode::YXDOMAIN, packet.header.rescode);
+        assert_eq!(1, packet.answers.len());
+        assert_eq!(QueryType::DNAME, packet.answers[0].get_querytype());
+    }
+}
@@ -0,0 +1,451 @@
+//! blocking of unwanted domains, with configurable responses
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead,BufReader,Write,Result,Error,ErrorKind};
+use std::net::Ipv4Addr;
+use std::path::{Path,PathBuf};
+use std::str::FromStr;
+use std::sync::{RwLock, LockResult, RwLockReadGuard, RwLockWriteGuard};
+
+use dns::protocol::{DnsPacket,DnsQuestion,DnsRecord,QueryType,ResultCode,TransientTtl};
+
+/// TTL used for synthesized sinkhole records
+pub const SINKHOLE_TTL: u32 = 300;
+
+/// Name of the list holding domains blocked at runtime through the API
+pub const RUNTIME_LIST_NAME: &'static str = "runtime";
+
+/// The response returned for a query to a blocked name
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum BlockPolicy {
+    /// Answer with NXDOMAIN, claiming that the name doesn't exist
+    Nxdomain,
+
+    /// Refuse to answer the query
+    Refused,
+
+    /// Answer with NOERROR but without any records
+    NoData,
+
+    /// Answer A queries with the specified address, and anything else with
+    /// an empty NOERROR response
+    Sinkhole {
+        addr: Ipv4Addr
+    }
+}
+
+impl Default for BlockPolicy {
+    fn default() -> Self {
+        BlockPolicy::Nxdomain
+    }
+}
+
+impl FromStr for BlockPolicy {
+    type Err = Error;
+
+    /// Parse a policy from one of `nxdomain`, `refused`, `nodata` or
+    /// `sinkhole:<ipv4 address>`
+    fn from_str(s: &str) -> Result<BlockPolicy> {
+        match s.to_lowercase().as_str() {
+            "nxdomain" => Ok(BlockPolicy::Nxdomain),
+            "refused" => Ok(BlockPolicy::Refused),
+            "nodata" => Ok(BlockPolicy::NoData),
+            x if x.starts_with("sinkhole:") => {
+                match x["sinkhole:".len()..].parse::<Ipv4Addr>() {
+                    Ok(addr) => Ok(BlockPolicy::Sinkhole { addr: addr }),
+                    Err(_) => Err(Error::new(ErrorKind::InvalidInput, "Invalid sinkhole address"))
+                }
+            },
+            _ => Err(Error::new(ErrorKind::InvalidInput, "Unknown block policy"))
+        }
+    }
+}
+
+impl fmt::Display for BlockPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BlockPolicy::Nxdomain => write!(f, "nxdomain"),
+            BlockPolicy::Refused => write!(f, "refused"),
+            BlockPolicy::NoData => write!(f, "nodata"),
+            BlockPolicy::Sinkhole { addr } => write!(f, "sinkhole:{}", addr)
+        }
+    }
+}
+
+impl BlockPolicy {
+
+    /// Fill in `packet` as the response to `question` according to the policy
+    pub fn apply(&self, question: &DnsQuestion, packet: &mut DnsPacket) {
+        match *self {
+            BlockPolicy::Nxdomain => {
+                packet.header.rescode = ResultCode::NXDOMAIN;
+            },
+            BlockPolicy::Refused => {
+                packet.header.rescode = ResultCode::REFUSED;
+            },
+            BlockPolicy::NoData => {
+                packet.header.rescode = ResultCode::NOERROR;
+            },
+            BlockPolicy::Sinkhole { addr } => {
+                packet.header.rescode = ResultCode::NOERROR;
+
+                if question.qtype == QueryType::A {
+                    packet.answers.push(DnsRecord::A {
+                        domain: question.name.clone(),
+                        addr: addr,
+                        ttl: TransientTtl(SINKHOLE_TTL)
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// A named list of blocked domains
+///
+/// Blocking a domain also blocks all of its subdomains. A list may override
+/// the default policy of the `Blocklists` it belongs to.
+#[derive(Clone,Debug,Default)]
+pub struct Blocklist {
+    pub name: String,
+    pub domains: BTreeSet<String>,
+    pub policy: Option<BlockPolicy>
+}
+
+impl Blocklist {
+    pub fn new(name: String, policy: Option<BlockPolicy>) -> Blocklist {
+        Blocklist {
+            name: name,
+            domains: BTreeSet::new(),
+            policy: policy
+        }
+    }
+
+    /// Read a list with one domain per line. Empty lines and lines starting
+    /// with `#` are ignored.
+    pub fn load<P: AsRef<Path>>(path: P, policy: Option<BlockPolicy>) -> Result<Blocklist> {
+        let name = path.as_ref().to_string_lossy().into_owned();
+        let file = try!(File::open(path));
+
+        let mut list = Blocklist::new(name, policy);
+        for line in BufReader::new(file).lines() {
+            let line = try!(line);
+            let domain = line.trim();
+            if domain.is_empty() || domain.starts_with('#') {
+                continue;
+            }
+
+            list.add_domain(domain);
+        }
+
+        Ok(list)
+    }
+
+    /// Write the list in the same format as read by `load`
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut file = try!(File::create(path));
+        for domain in &self.domains {
+            try!(writeln!(file, "{}", domain));
+        }
+
+        Ok(())
+    }
+
+    pub fn add_domain(&mut self, domain: &str) -> bool {
+        self.domains.insert(domain.trim_matches('.').to_lowercase())
+    }
+
+    pub fn remove_domain(&mut self, domain: &str) -> bool {
+        self.domains.remove(&domain.trim_matches('.').to_lowercase())
+    }
+
+    /// Check whether `qname` or any of its parent domains is on the list
+    pub fn contains(&self, qname: &str) -> bool {
+        let qname = qname.trim_matches('.').to_lowercase();
+        let labels = qname.split('.').collect::<Vec<&str>>();
+        for lbl_idx in 0..labels.len() {
+            if self.domains.contains(&labels[lbl_idx..].join(".")) {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+/// The set of active blocklists
+///
+/// Domains can be added and removed at runtime, in which case they take effect
+/// for any subsequent query. Domains added this way are kept in a separate
+/// list, which is persisted to `runtime_path` if one is configured.
+#[derive(Default)]
+pub struct Blocklists {
+    pub default_policy: BlockPolicy,
+    pub runtime_path: Option<PathBuf>,
+    lists: RwLock<Vec<Blocklist>>
+}
+
+impl Blocklists {
+    pub fn new() -> Blocklists {
+        Blocklists {
+            default_policy: BlockPolicy::Nxdomain,
+            runtime_path: None,
+            lists: RwLock::new(Vec::new())
+        }
+    }
+
+    /// Load the runtime list from `runtime_path`, if it has been configured
+    /// and the file exists
+    pub fn load_runtime(&self) -> Result<()> {
+        let path = match self.runtime_path {
+            Some(ref x) if x.exists() => x,
+            _ => return Ok(())
+        };
+
+        let mut list = try!(Blocklist::load(path, None));
+        list.name = RUNTIME_LIST_NAME.to_string();
+
+        self.add_list(list)
+    }
+
+    fn save_runtime(&self, lists: &[Blocklist]) -> Result<()> {
+        let path = match self.runtime_path {
+            Some(ref x) => x,
+            None => return Ok(())
+        };
+
+        match lists.iter().find(|x| x.name == RUNTIME_LIST_NAME) {
+            Some(list) => list.save(path),
+            None => Ok(())
+        }
+    }
+
+    /// Block a domain at runtime
+    ///
+    /// Returns false if the domain was already present in the runtime list.
+    pub fn add_domain(&self, domain: &str) -> Result<bool> {
+        let mut lists = match self.lists.write() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        if !lists.iter().any(|x| x.name == RUNTIME_LIST_NAME) {
+            lists.push(Blocklist::new(RUNTIME_LIST_NAME.to_string(), None));
+        }
+
+        let added = match lists.iter_mut().find(|x| x.name == RUNTIME_LIST_NAME) {
+            Some(list) => list.add_domain(domain),
+            None => false
+        };
+
+        try!(self.save_runtime(&lists));
+
+        Ok(added)
+    }
+
+    /// Unblock a domain at runtime by removing it from every list
+    ///
+    /// Lists loaded from files other than the runtime list are only changed in
+    /// memory. Returns false if the domain wasn't present in any list.
+    pub fn remove_domain(&self, domain: &str) -> Result<bool> {
+        let mut lists = match self.lists.write() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        let mut removed = false;
+        for list in lists.iter_mut() {
+            removed |= list.remove_domain(domain);
+        }
+
+        try!(self.save_runtime(&lists));
+
+        Ok(removed)
+    }
+
+    pub fn add_list(&self, list: Blocklist) -> Result<()> {
+        let mut lists = match self.lists.write() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        lists.push(list);
+
+        Ok(())
+    }
+
+    /// Returns the policy to apply if `qname` is blocked by any list
+    ///
+    /// When several lists match, the first one added takes precedence.
+    pub fn check(&self, qname: &str) -> Option<BlockPolicy> {
+        let lists = match self.lists.read() {
+            Ok(x) => x,
+            Err(_) => return None
+        };
+
+        for list in lists.iter() {
+            if list.contains(qname) {
+                return Some(list.policy.unwrap_or(self.default_policy));
+            }
+        }
+
+        None
+    }
+
+    pub fn read(&self) -> LockResult<RwLockReadGuard<Vec<Blocklist>>>
+    {
+        self.lists.read()
+    }
+
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<Vec<Blocklist>>>
+    {
+        self.lists.write()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use dns::protocol::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode};
+
+    use super::*;
+
+    #[test]
+    fn test_blocklist_contains() {
+        let mut list = Blocklist::new("test".to_string(), None);
+        list.add_domain("ads.example.com");
+
+        assert!(list.contains("ads.example.com"));
+        assert!(list.contains("tracker.ads.example.com"));
+        assert!(list.contains("ADS.Example.com"));
+        assert!(!list.contains("example.com"));
+        assert!(!list.contains("badads.example.com"));
+
+        list.remove_domain("ads.example.com");
+        assert!(!list.contains("ads.example.com"));
+    }
+
+    #[test]
+    fn test_block_policy_from_str() {
+        assert_eq!(BlockPolicy::Nxdomain, "nxdomain".parse::<BlockPolicy>().unwrap());
+        assert_eq!(BlockPolicy::Refused, "REFUSED".parse::<BlockPolicy>().unwrap());
+        assert_eq!(BlockPolicy::NoData, "nodata".parse::<BlockPolicy>().unwrap());
+        assert_eq!(BlockPolicy::Sinkhole { addr: "0.0.0.0".parse().unwrap() },
+                   "sinkhole:0.0.0.0".parse::<BlockPolicy>().unwrap());
+
+        assert!("sinkhole:foo".parse::<BlockPolicy>().is_err());
+        assert!("drop".parse::<BlockPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_block_policies() {
+        let question = DnsQuestion::new("ads.example.com".to_string(), QueryType::A);
+
+        {
+            let mut packet = DnsPacket::new();
+            BlockPolicy::Nxdomain.apply(&question, &mut packet);
+            assert_eq!(ResultCode::NXDOMAIN, packet.header.rescode);
+            assert_eq!(0, packet.answers.len());
+        };
+
+        {
+            let mut packet = DnsPacket::new();
+            BlockPolicy::Refused.apply(&question, &mut packet);
+            assert_eq!(ResultCode::REFUSED, packet.header.rescode);
+            assert_eq!(0, packet.answers.len());
+        };
+
+        {
+            let mut packet = DnsPacket::new();
+            BlockPolicy::NoData.apply(&question, &mut packet);
+            assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+            assert_eq!(0, packet.answers.len());
+        };
+
+        let sinkhole = BlockPolicy::Sinkhole { addr: "10.0.0.1".parse().unwrap() };
+
+        {
+            let mut packet = DnsPacket::new();
+            sinkhole.apply(&question, &mut packet);
+            assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+            assert_eq!(1, packet.answers.len());
+
+            match packet.answers[0] {
+                DnsRecord::A { ref domain, addr, .. } => {
+                    assert_eq!("ads.example.com", domain);
+                    assert_eq!("10.0.0.1".parse::<Ipv4Addr>().unwrap(), addr);
+                },
+                _ => panic!()
+            }
+        };
+
+        // A sinkhole doesn't have anything to offer for other record types
+        {
+            let aaaa_question = DnsQuestion::new("ads.example.com".to_string(), QueryType::AAAA);
+
+            let mut packet = DnsPacket::new();
+            sinkhole.apply(&aaaa_question, &mut packet);
+            assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+            assert_eq!(0, packet.answers.len());
+        };
+    }
+
+    #[test]
+    fn test_policy_override() {
+        let mut blocklists = Blocklists::new();
+        blocklists.default_policy = BlockPolicy::Refused;
+
+        let mut default_list = Blocklist::new("default".to_string(), None);
+        default_list.add_domain("ads.example.com");
+        blocklists.add_list(default_list).unwrap();
+
+        let mut override_list = Blocklist::new("override".to_string(), Some(BlockPolicy::NoData));
+        override_list.add_domain("tracker.example.com");
+        blocklists.add_list(override_list).unwrap();
+
+        assert_eq!(Some(BlockPolicy::Refused), blocklists.check("ads.example.com"));
+        assert_eq!(Some(BlockPolicy::NoData), blocklists.check("tracker.example.com"));
+        assert_eq!(None, blocklists.check("www.example.com"));
+    }
+
+    #[test]
+    fn test_runtime_changes() {
+        let blocklists = Blocklists::new();
+
+        assert_eq!(None, blocklists.check("ads.example.com"));
+
+        // Newly added domains are blocked right away
+        assert!(blocklists.add_domain("ads.example.com").unwrap());
+        assert!(!blocklists.add_domain("ads.example.com").unwrap());
+        assert_eq!(Some(BlockPolicy::Nxdomain), blocklists.check("ads.example.com"));
+
+        assert!(blocklists.remove_domain("ads.example.com").unwrap());
+        assert!(!blocklists.remove_domain("ads.example.com").unwrap());
+        assert_eq!(None, blocklists.check("ads.example.com"));
+    }
+
+    #[test]
+    fn test_runtime_persistence() {
+        let path = ::std::env::temp_dir().join("hermes_test_runtime_blocklist");
+
+        {
+            let mut blocklists = Blocklists::new();
+            blocklists.runtime_path = Some(path.clone());
+
+            blocklists.add_domain("ads.example.com").unwrap();
+            blocklists.add_domain("tracker.example.com").unwrap();
+            blocklists.remove_domain("tracker.example.com").unwrap();
+        };
+
+        let mut blocklists = Blocklists::new();
+        blocklists.runtime_path = Some(path.clone());
+        blocklists.load_runtime().unwrap();
+
+        assert_eq!(Some(BlockPolicy::Nxdomain), blocklists.check("ads.example.com"));
+        assert_eq!(None, blocklists.check("tracker.example.com"));
+
+        let _ = ::std::fs::remove_file(&path);
+    }
+}
@@ -4,10 +4,30 @@ use std::io::{Result, Read};
 use std::io::{Error, ErrorKind};
 use std::collections::BTreeMap;
 
+use dns::error::{DnsError, DnsResult};
+
+/// The largest offset a compression pointer can refer to, given its 14 bits
+pub const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+/// The most compression pointers followed when reading a single name
+///
+/// Since pointers only point backwards, reading always ends, and this is
+/// only a bound on the work done. A name of at most 127 labels can need a
+/// pointer for each of them, when every suffix was written before it.
+pub const MAX_JUMPS: usize = 127;
+
+/// The longest label of a name, since the top bits of the length byte mark
+/// compression pointers
+pub const MAX_LABEL_LEN: usize = 63;
+
+/// The longest name, counting the length bytes of its labels and the
+/// terminating empty label
+pub const MAX_NAME_LEN: usize = 255;
+
 pub trait PacketBuffer {
-    fn read(&mut self) -> Result<u8>;
-    fn get(&mut self, pos: usize) -> Result<u8>;
-    fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]>;
+    fn read(&mut self) -> DnsResult<u8>;
+    fn get(&mut self, pos: usize) -> DnsResult<u8>;
+    fn get_range(&mut self, start: usize, len: usize) -> DnsResult<&[u8]>;
     fn write(&mut self, val: u8) -> Result<()>;
     fn set(&mut self, pos: usize, val: u8) -> Result<()>;
     fn pos(&self) -> usize;
@@ -45,14 +65,36 @@ pub trait PacketBuffer {
         Ok(())
     }
 
-    fn write_qname(&mut self, qname: &str) -> Result<()> {
+    /// Write a domain name, compressed where the buffer supports it
+    ///
+    /// Each suffix of the name is looked up with `find_label`, and the first
+    /// one written before is replaced by a pointer to it. The suffixes written
+    /// out in full are saved for later names, unless they're beyond the reach
+    /// of a pointer. Labels longer than 63 bytes don't fit in the length byte,
+    /// and fail with `LabelTooLong`, while names longer than 255 bytes fail
+    /// with `NameTooLong`. Either way nothing is written.
+    fn write_qname(&mut self, qname: &str) -> DnsResult<()> {
+
+        // The root is only the terminating empty label
+        if qname.is_empty() {
+            try!(self.write_u8(0));
+            return Ok(());
+        }
 
         let split_str = qname.split('.').collect::<Vec<&str>>();
+        if let Some(label) = split_str.iter().find(|x| x.len() > MAX_LABEL_LEN) {
+            return Err(DnsError::LabelTooLong(label.len()));
+        }
+
+        let name_len = split_str.iter().map(|x| x.len() + 1).sum::<usize>() + 1;
+        if name_len > MAX_NAME_LEN {
+            return Err(DnsError::NameTooLong(name_len));
+        }
 
         let mut jump_performed = false;
         for (i, label) in split_str.iter().enumerate() {
             let search_lbl = split_str[i..split_str.len()].join(".");
-            if let Some(prev_pos) = self.find_label(&search_lbl) {
+            if let Some(prev_pos) = self.find_label(&search_lbl).filter(|x| *x <= MAX_POINTER_OFFSET) {
 
                 let jump_inst = (prev_pos as u16) | 0xC000;
                 try!(self.write_u16(jump_inst));
@@ -62,7 +104,9 @@ pub trait PacketBuffer {
             }
 
             let pos = self.pos();
-            self.save_label(&search_lbl, pos);
+            if pos <= MAX_POINTER_OFFSET {
+                self.save_label(&search_lbl, pos);
+            }
 
             let len = label.len();
             try!(self.write_u8(len as u8));
@@ -78,7 +122,7 @@ pub trait PacketBuffer {
         Ok(())
     }
 
-    fn read_u16(&mut self) -> Result<u16>
+    fn read_u16(&mut self) -> DnsResult<u16>
     {
         let res = ((try!(self.read()) as u16) << 8) |
                   (try!(self.read()) as u16);
@@ -87,7 +131,7 @@ pub trait PacketBuffer {
     }
 
     #[allow(identity_op)]
-    fn read_u32(&mut self) -> Result<u32>
+    fn read_u32(&mut self) -> DnsResult<u32>
     {
         let res = ((try!(self.read()) as u32) << 24) |
                   ((try!(self.read()) as u32) << 16) |
@@ -97,10 +141,27 @@ pub trait PacketBuffer {
         Ok(res)
     }
 
-    fn read_qname(&mut self, outstr: &mut String) -> Result<()>
+    /// Read a domain name, normalized to lower case
+    fn read_qname(&mut self, outstr: &mut String) -> DnsResult<()>
+    {
+        let mut name = String::new();
+        try!(self.read_qname_preserving_case(&mut name));
+        outstr.push_str(&name.to_lowercase());
+
+        Ok(())
+    }
+
+    /// Read a domain name, retaining the case of the labels as they appear
+    /// in the buffer
+    ///
+    /// Compression pointers have to point back to before themselves, and at
+    /// most `MAX_JUMPS` of them are followed, so that a malicious packet can't
+    /// make this loop forever.
+    fn read_qname_preserving_case(&mut self, outstr: &mut String) -> DnsResult<()>
     {
         let mut pos = self.pos();
         let mut jumped = false;
+        let mut jumps = 0;
 
         let mut delim = "";
         loop {
@@ -118,8 +179,17 @@ pub trait PacketBuffer {
                     try!(self.seek(pos+2));
                 }
 
+                jumps += 1;
+                if jumps > MAX_JUMPS {
+                    return Err(DnsError::CompressionLoop);
+                }
+
                 let b2 = try!(self.get(pos+1)) as u16;
                 let offset = (((len as u16) ^ 0xC0) << 8) | b2;
+                if offset as usize >= pos {
+                    return Err(DnsError::CompressionLoop);
+                }
+
                 pos = offset as usize;
                 jumped = true;
                 continue;
@@ -135,7 +205,7 @@ pub trait PacketBuffer {
             outstr.push_str(delim);
 
             let str_buffer = try!(self.get_range(pos, len as usize));
-            outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+            outstr.push_str(&String::from_utf8_lossy(str_buffer));
 
             delim = ".";
 
@@ -177,18 +247,27 @@ impl PacketBuffer for VectorPacketBuffer {
         self.label_lookup.insert(label.to_string(), pos);
     }
 
-    fn read(&mut self) -> Result<u8> {
+    fn read(&mut self) -> DnsResult<u8> {
+        if self.pos >= self.buffer.len() {
+            return Err(DnsError::Truncated);
+        }
         let res = self.buffer[self.pos];
         self.pos += 1;
 
         Ok(res)
     }
 
-    fn get(&mut self, pos: usize) -> Result<u8> {
+    fn get(&mut self, pos: usize) -> DnsResult<u8> {
+        if pos >= self.buffer.len() {
+            return Err(DnsError::Truncated);
+        }
         Ok(self.buffer[pos])
     }
 
-    fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
+    fn get_range(&mut self, start: usize, len: usize) -> DnsResult<&[u8]> {
+        if start + len > self.buffer.len() {
+            return Err(DnsError::Truncated);
+        }
         Ok(&self.buffer[start..start+len as usize])
     }
 
@@ -247,7 +326,7 @@ impl<'a, T> PacketBuffer for StreamPacketBuffer<'a, T> where T: Read + 'a {
         unimplemented!();
     }
 
-    fn read(&mut self) -> Result<u8> {
+    fn read(&mut self) -> DnsResult<u8> {
         while self.pos >= self.buffer.len() {
             let mut local_buffer = [0; 1];
             try!(self.stream.read(&mut local_buffer));
@@ -260,7 +339,7 @@ impl<'a, T> PacketBuffer for StreamPacketBuffer<'a, T> where T: Read + 'a {
         Ok(res)
     }
 
-    fn get(&mut self, pos: usize) -> Result<u8> {
+    fn get(&mut self, pos: usize) -> DnsResult<u8> {
         while pos >= self.buffer.len() {
             let mut local_buffer = [0; 1];
             try!(self.stream.read(&mut local_buffer));
@@ -270,7 +349,7 @@ impl<'a, T> PacketBuffer for StreamPacketBuffer<'a, T> where T: Read + 'a {
         Ok(self.buffer[pos])
     }
 
-    fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
+    fn get_range(&mut self, start: usize, len: usize) -> DnsResult<&[u8]> {
         while start+len > self.buffer.len() {
             let mut local_buffer = [0; 1];
             try!(self.stream.read(&mut local_buffer));
@@ -331,9 +410,9 @@ impl PacketBuffer for BytePacketBuffer {
     fn save_label(&mut self, _: &str, _: usize) {
     }
 
-    fn read(&mut self) -> Result<u8> {
+    fn read(&mut self) -> DnsResult<u8> {
         if self.pos >= 512 {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+            return Err(DnsError::Truncated);
         }
         let res = self.buf[self.pos];
         self.pos += 1;
@@ -341,16 +420,16 @@ impl PacketBuffer for BytePacketBuffer {
         Ok(res)
     }
 
-    fn get(&mut self, pos: usize) -> Result<u8> {
+    fn get(&mut self, pos: usize) -> DnsResult<u8> {
         if pos >= 512 {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+            return Err(DnsError::Truncated);
         }
         Ok(self.buf[pos])
     }
 
-    fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
+    fn get_range(&mut self, start: usize, len: usize) -> DnsResult<&[u8]> {
         if start + len >= 512 {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+            return Err(DnsError::Truncated);
         }
         Ok(&self.buf[start..start+len as usize])
     }
@@ -476,4 +555,182 @@ mod tests {
 
         assert_eq!("ns2.google.com", str2);
     }
+
+    #[test]
+    fn test_label_too_long() {
+        let mut buffer = VectorPacketBuffer::new();
+
+        let longest = format!("{}.google.com", "a".repeat(MAX_LABEL_LEN));
+        buffer.write_qname(&longest).unwrap();
+        let len = buffer.pos();
+
+        // Nothing is written for a name that can't be represented
+        match buffer.write_qname(&format!("a{}", longest)) {
+            Err(DnsError::LabelTooLong(64)) => {},
+            _ => panic!()
+        }
+        assert_eq!(len, buffer.pos());
+    }
+
+    #[test]
+    fn test_name_too_long() {
+        let mut buffer = VectorPacketBuffer::new();
+
+        // Four labels of 62 bytes, and one of a single byte, make 255 bytes
+        // with the length bytes and the root
+        let label = "a".repeat(62);
+        let longest = format!("{0}.{0}.{0}.{0}.b", label);
+        buffer.write_qname(&longest).unwrap();
+        assert_eq!(MAX_NAME_LEN, buffer.pos());
+
+        let mut outstr = String::new();
+        buffer.seek(0).unwrap();
+        buffer.read_qname(&mut outstr).unwrap();
+        assert_eq!(longest, outstr);
+
+        // One more byte is too much, and leaves the buffer as it was
+        let mut buffer = VectorPacketBuffer::new();
+        match buffer.write_qname(&format!("{}c", longest)) {
+            Err(DnsError::NameTooLong(256)) => {},
+            _ => panic!()
+        }
+        assert_eq!(0, buffer.pos());
+        assert!(buffer.buffer.is_empty());
+    }
+
+    #[test]
+    fn test_qname_compression() {
+        use dns::protocol::{DnsRecord, TransientTtl};
+
+        let records = vec![DnsRecord::NS {
+            domain: "google.com".to_string(),
+            host: "ns1.google.com".to_string(),
+            ttl: TransientTtl(3600)
+        }, DnsRecord::MX {
+            domain: "google.com".to_string(),
+            priority: 10,
+            host: "smtp.google.com".to_string(),
+            ttl: TransientTtl(3600)
+        }];
+
+        // Byte buffers never compress names
+        let mut uncompressed = BytePacketBuffer::new();
+        let mut compressed = VectorPacketBuffer::new();
+        for rec in &records {
+            rec.write(&mut uncompressed).unwrap();
+            rec.write(&mut compressed).unwrap();
+        }
+
+        // Only the first google.com is written out in full, and the three
+        // later ones shrink from 12 bytes to a 2 byte pointer
+        assert_eq!(79, uncompressed.pos());
+        assert_eq!(79 - 3 * 10, compressed.pos());
+
+        compressed.seek(0).unwrap();
+        for rec in &records {
+            assert_eq!(*rec, DnsRecord::read(&mut compressed).unwrap());
+        }
+        assert_eq!(compressed.buffer.len(), compressed.pos());
+    }
+
+    #[test]
+    fn test_qname_compression_range() {
+        let mut buffer = VectorPacketBuffer::new();
+        buffer.write_qname("google.com").unwrap();
+
+        buffer.buffer.resize(MAX_POINTER_OFFSET + 1, 0);
+        buffer.pos = MAX_POINTER_OFFSET + 1;
+
+        // Names beyond the reach of a pointer can't be referred to, so
+        // yahoo.com is written twice, while com and google.com from before
+        // it are still pointed to
+        buffer.write_qname("www.yahoo.com").unwrap();
+        buffer.write_qname("mail.yahoo.com").unwrap();
+        buffer.write_qname("www.google.com").unwrap();
+
+        assert_eq!(MAX_POINTER_OFFSET + 1 + 12 + 13 + 6, buffer.pos());
+
+        buffer.seek(MAX_POINTER_OFFSET + 1).unwrap();
+        for name in &["www.yahoo.com", "mail.yahoo.com", "www.google.com"] {
+            let mut outstr = String::new();
+            buffer.read_qname(&mut outstr).unwrap();
+            assert_eq!(*name, outstr);
+        }
+    }
+
+    #[test]
+    fn test_qname_pointer_loops() {
+        use dns::protocol::DnsPacket;
+
+        // A query whose name is only a pointer to itself
+        let mut buffer = VectorPacketBuffer::new();
+        buffer.buffer = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                             0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01];
+        assert!(DnsPacket::from_buffer(&mut buffer).is_err());
+
+        // Two names pointing back and forth between each other
+        let mut buffer = VectorPacketBuffer::new();
+        buffer.buffer = vec![0x01, b'a', 0xC0, 0x04, 0x01, b'b', 0xC0, 0x00];
+        let mut outstr = String::new();
+        match buffer.read_qname(&mut outstr) {
+            Err(DnsError::CompressionLoop) => {},
+            _ => panic!()
+        }
+
+        // A chain of pointers that does end is fine, up to a point
+        let chain = |jumps: usize| {
+            let mut buffer = VectorPacketBuffer::new();
+            buffer.write_qname("google.com").unwrap();
+            for i in 0..jumps {
+                let target = if i == 0 { 0 } else { 12 + 2 * (i - 1) };
+                buffer.write_u16(0xC000 | target as u16).unwrap();
+            }
+
+            buffer.seek(12 + 2 * (jumps - 1)).unwrap();
+            let mut outstr = String::new();
+            buffer.read_qname(&mut outstr).map(|_| outstr)
+        };
+
+        assert_eq!("google.com", chain(MAX_JUMPS).unwrap());
+        assert!(chain(MAX_JUMPS + 1).is_err());
+    }
+
+    #[test]
+    fn test_root_qname() {
+        let mut buffer = VectorPacketBuffer::new();
+
+        buffer.write_qname("").unwrap();
+        buffer.write_qname("").unwrap();
+
+        // The root is a single empty label, and never compressed
+        assert_eq!(vec![0, 0], buffer.buffer);
+
+        buffer.seek(0).unwrap();
+
+        let mut name = String::new();
+        buffer.read_qname(&mut name).unwrap();
+        assert_eq!("", name);
+        assert_eq!(1, buffer.pos());
+    }
+
+    #[test]
+    fn test_vector_buffer_bounds() {
+        let mut buffer = VectorPacketBuffer::new();
+        buffer.buffer = vec![0x03, b'f', b'o'];
+
+        // Reading past the end of the data should fail rather than panic
+        let mut outstr = String::new();
+        match buffer.read_qname(&mut outstr) {
+            Err(DnsError::Truncated) => {},
+            _ => panic!()
+        }
+        assert!(buffer.get(3).is_err());
+        assert!(buffer.get_range(1, 3).is_err());
+
+        buffer.pos = 3;
+        match buffer.read() {
+            Err(DnsError::Truncated) => {},
+            _ => panic!()
+        }
+    }
 }
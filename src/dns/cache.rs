@@ -4,22 +4,98 @@ use std::collections::{HashSet,HashMap,BTreeMap};
 use std::hash::{Hash,Hasher};
 use std::sync::{Arc, RwLock};
 use std::clone::Clone;
-use std::io::{Write,Result,Error,ErrorKind};
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{Read,Write,Result,Error,ErrorKind};
+use std::path::Path;
+use std::thread::{Builder, sleep};
+use std::time::Duration as SleepDuration;
 
 use chrono::*;
 
+use dns::buffer::{PacketBuffer, VectorPacketBuffer};
+use dns::context::ServerContext;
 use dns::protocol::{DnsRecord, QueryType, DnsPacket, ResultCode};
 
+/// Seconds between purges of the expired entries of the cache
+pub const CACHE_CLEANUP_INTERVAL_SECS: u64 = 60;
+
 pub enum CacheState {
     PositiveCache,
     NegativeCache,
+    FailureCache,
     NotCached
 }
 
+/// Where a cached record was learned from
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum CacheSource {
+    /// An authoritative answer from a name server for the zone
+    Authoritative,
+
+    /// Any other upstream response, such as a referral or an answer from a
+    /// forwarder
+    Upstream
+}
+
+impl CacheSource {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            CacheSource::Authoritative => "authoritative",
+            CacheSource::Upstream => "upstream"
+        }
+    }
+
+    fn to_num(&self) -> u8 {
+        match *self {
+            CacheSource::Authoritative => 1,
+            CacheSource::Upstream => 0
+        }
+    }
+
+    fn from_num(num: u8) -> CacheSource {
+        match num {
+            1 => CacheSource::Authoritative,
+            _ => CacheSource::Upstream
+        }
+    }
+}
+
 #[derive(Clone,Eq,Debug)]
 pub struct RecordEntry {
     pub record: DnsRecord,
-    pub timestamp: DateTime<Local>
+    pub timestamp: DateTime<Local>,
+    pub source: CacheSource
+}
+
+impl RecordEntry {
+    /// The number of seconds until the record expires, as of `now`
+    pub fn remaining_ttl(&self, now: DateTime<Local>) -> u32 {
+        remaining_ttl(self.record.get_ttl(), self.timestamp, now)
+    }
+
+    pub fn is_expired(&self, now: DateTime<Local>) -> bool {
+        is_expired(self.record.get_ttl(), self.timestamp, now)
+    }
+}
+
+fn is_expired(ttl: u32, timestamp: DateTime<Local>, now: DateTime<Local>) -> bool {
+    timestamp + Duration::seconds(ttl as i64) < now
+}
+
+fn remaining_ttl(ttl: u32, timestamp: DateTime<Local>, now: DateTime<Local>) -> u32 {
+    if is_expired(ttl, timestamp, now) {
+        return 0;
+    }
+
+    // Only whole seconds count, so a record keeps its full TTL for the first
+    // second in the cache
+    let elapsed = (now - timestamp).num_seconds();
+    if elapsed <= 0 {
+        ttl
+    } else {
+        ttl.saturating_sub(elapsed as u32)
+    }
 }
 
 impl PartialEq<RecordEntry> for RecordEntry {
@@ -36,14 +112,26 @@ impl Hash for RecordEntry {
 
 #[derive(Clone,Debug)]
 pub enum RecordSet {
+    /// A negative answer, either NXDOMAIN or NODATA, the latter being a
+    /// NOERROR response without any records
     NoRecords {
         qtype: QueryType,
         ttl: u32,
         timestamp: DateTime<Local>,
+        rescode: ResultCode,
+
+        /// The SOA record of the zone, returned in the authority section of
+        /// the answers
+        soa: Option<DnsRecord>
     },
     Records {
         qtype: QueryType,
         records: HashSet<RecordEntry>
+    },
+    Failure {
+        qtype: QueryType,
+        ttl: u32,
+        timestamp: DateTime<Local>,
     }
 }
 
@@ -52,7 +140,15 @@ pub struct DomainEntry {
     pub domain: String,
     pub record_types: HashMap<QueryType, RecordSet>,
     pub hits: u32,
-    pub updates: u32
+    pub updates: u32,
+
+    /// Number of lookups answered from the cache, by type, including
+    /// negative answers
+    pub type_hits: HashMap<QueryType, u32>,
+
+    /// When the domain was last stored or looked up, as a sequence number
+    /// of the cache it belongs to
+    pub last_used: u64
 }
 
 impl DomainEntry {
@@ -61,28 +157,55 @@ impl DomainEntry {
             domain: domain,
             record_types: HashMap::new(),
             hits: 0,
-            updates: 0
+            updates: 0,
+            type_hits: HashMap::new(),
+            last_used: 0
         }
     }
 
-    pub fn store_nxdomain(&mut self, qtype: QueryType, ttl: u32) {
+    pub fn store_nxdomain(&mut self, qtype: QueryType, ttl: u32, now: DateTime<Local>) {
+        self.store_negative(qtype, ResultCode::NXDOMAIN, None, ttl, now);
+    }
+
+    pub fn store_negative(&mut self,
+                          qtype: QueryType,
+                          rescode: ResultCode,
+                          soa: Option<DnsRecord>,
+                          ttl: u32,
+                          now: DateTime<Local>) {
+
         self.updates += 1;
 
         let new_set = RecordSet::NoRecords {
             qtype: qtype,
             ttl: ttl,
-            timestamp: Local::now()
+            timestamp: now,
+            rescode: rescode,
+            soa: soa
+        };
+
+        self.record_types.insert(qtype, new_set);
+    }
+
+    pub fn store_servfail(&mut self, qtype: QueryType, ttl: u32, now: DateTime<Local>) {
+        self.updates += 1;
+
+        let new_set = RecordSet::Failure {
+            qtype: qtype,
+            ttl: ttl,
+            timestamp: now
         };
 
         self.record_types.insert(qtype, new_set);
     }
 
-    pub fn store_record(&mut self, rec: &DnsRecord) {
+    pub fn store_record(&mut self, rec: &DnsRecord, source: CacheSource, now: DateTime<Local>) {
         self.updates += 1;
 
         let entry = RecordEntry {
                 record: rec.clone(),
-                timestamp: Local::now()
+                timestamp: now,
+                source: source
             };
 
         if let Some(&mut RecordSet::Records { ref mut records, .. }) =
@@ -107,23 +230,12 @@ impl DomainEntry {
         self.record_types.insert(rec.get_querytype(), new_set);
     }
 
-    pub fn get_cache_state(&self, qtype: QueryType) -> CacheState {
+    pub fn get_cache_state(&self, qtype: QueryType, now: DateTime<Local>) -> CacheState {
         match self.record_types.get(&qtype) {
             Some(&RecordSet::Records { ref records, .. }) => {
-                let now = Local::now();
-
-                let mut valid_count = 0;
-                for entry in records {
-                    let ttl_offset = Duration::seconds(entry.record.get_ttl() as i64);
-                    let expires = entry.timestamp + ttl_offset;
-                    if expires < now {
-                        continue;
-                    }
-
-                    if entry.record.get_querytype() == qtype {
-                        valid_count += 1;
-                    }
-                }
+                let valid_count = records.iter()
+                    .filter(|x| !x.is_expired(now) && x.record.get_querytype() == qtype)
+                    .count();
 
                 if valid_count > 0 {
                     CacheState::PositiveCache
@@ -132,25 +244,76 @@ impl DomainEntry {
                 }
             },
             Some(&RecordSet::NoRecords { ttl, timestamp, .. }) => {
-                let now = Local::now();
-                let ttl_offset = Duration::seconds(ttl as i64);
-                let expires = timestamp + ttl_offset;
-
-                if expires < now {
+                if is_expired(ttl, timestamp, now) {
                     CacheState::NotCached
                 } else {
                     CacheState::NegativeCache
                 }
             },
+            Some(&RecordSet::Failure { ttl, timestamp, .. }) => {
+                if is_expired(ttl, timestamp, now) {
+                    CacheState::NotCached
+                } else {
+                    CacheState::FailureCache
+                }
+            },
             None => CacheState::NotCached
         }
     }
 
+    /// The number of seconds until the records of `qtype` expire, as of
+    /// `now`
+    ///
+    /// For positive entries this is the longest remaining TTL of the records.
+    pub fn remaining_ttl(&self, qtype: QueryType, now: DateTime<Local>) -> u32 {
+        match self.record_types.get(&qtype) {
+            Some(&RecordSet::Records { ref records, .. }) => {
+                records.iter()
+                    .map(|x| x.remaining_ttl(now))
+                    .max()
+                    .unwrap_or(0)
+            },
+            Some(&RecordSet::NoRecords { ttl, timestamp, .. }) |
+            Some(&RecordSet::Failure { ttl, timestamp, .. }) => remaining_ttl(ttl, timestamp, now),
+            None => 0
+        }
+    }
+
+    /// Fill in the result code and SOA of a negative answer for `qtype`,
+    /// counting down the TTL of the SOA
+    pub fn fill_negative(&self,
+                         qtype: QueryType,
+                         now: DateTime<Local>,
+                         packet: &mut DnsPacket) {
+
+        if let Some(&RecordSet::NoRecords { ttl, timestamp, rescode, ref soa, .. }) = self.record_types.get(&qtype) {
+            packet.header.rescode = rescode;
+
+            if let Some(ref soa) = *soa {
+                let mut soa = soa.clone();
+                soa.set_ttl(remaining_ttl(ttl, timestamp, now));
+                packet.authorities.push(soa);
+            }
+        }
+    }
+
+    /// Add the unexpired records of `qtype` to `result_vec`, with their TTLs
+    /// reduced by the time they've spent in the cache
     pub fn fill_queryresult(&self,
                             qtype: QueryType,
+                            now: DateTime<Local>,
                             result_vec: &mut Vec<DnsRecord>) {
 
-        let now = Local::now();
+        self.fill_records(qtype, now, false, result_vec);
+    }
+
+    /// Add the records of `qtype` to `result_vec`, including those that have
+    /// expired if `include_expired` is set
+    fn fill_records(&self,
+                    qtype: QueryType,
+                    now: DateTime<Local>,
+                    include_expired: bool,
+                    result_vec: &mut Vec<DnsRecord>) {
 
         let current_set = match self.record_types.get(&qtype) {
             Some(x) => x,
@@ -159,38 +322,292 @@ impl DomainEntry {
 
         if let RecordSet::Records { ref records, .. } = *current_set {
             for entry in records {
-                let ttl_offset = Duration::seconds(entry.record.get_ttl() as i64);
-                let expires = entry.timestamp + ttl_offset;
-                if expires < now {
+                if entry.is_expired(now) && !include_expired {
                     continue;
                 }
 
                 if entry.record.get_querytype() == qtype {
-                    result_vec.push(entry.record.clone());
+                    let mut record = entry.record.clone();
+                    record.set_ttl(entry.remaining_ttl(now));
+                    result_vec.push(record);
                 }
             }
         }
     }
+
+    /// Drop the expired records, and negative and failure entries, except
+    /// for the types in `keep`
+    ///
+    /// Returns the number of types that were removed entirely.
+    pub fn remove_expired(&mut self, now: DateTime<Local>, keep: &[QueryType]) -> usize {
+        let before = self.record_types.len();
+
+        self.record_types.retain(|qtype, set| {
+            if keep.contains(qtype) {
+                return true;
+            }
+
+            match *set {
+                RecordSet::Records { ref mut records, .. } => {
+                    records.retain(|x| !x.is_expired(now));
+                    !records.is_empty()
+                },
+                RecordSet::NoRecords { ttl, timestamp, .. } |
+                RecordSet::Failure { ttl, timestamp, .. } => !is_expired(ttl, timestamp, now)
+            }
+        });
+
+        before - self.record_types.len()
+    }
 }
 
-#[derive(Default)]
+/// A summary of the cache at a point in time
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct CacheStats {
+    /// Number of domains in the cache
+    pub size: usize,
+
+    /// Lookups answered from the cache, in total
+    pub hits: u64,
+
+    /// Lookups that found nothing cached, in total
+    pub misses: u64,
+
+    /// Entries removed from the cache, in total
+    pub evictions: u64,
+
+    /// The domains with the most hits, and their hit counts
+    pub top_domains: Vec<(String, u32)>
+}
+
+impl CacheStats {
+    /// The ratio of hits to lookups since `previous`, or since the start if
+    /// it's not given
+    ///
+    /// Returns `None` if there were no lookups at all.
+    pub fn hit_ratio(&self, previous: Option<&CacheStats>) -> Option<f64> {
+        let (prev_hits, prev_misses) = match previous {
+            Some(x) => (x.hits, x.misses),
+            None => (0, 0)
+        };
+
+        let hits = self.hits - prev_hits;
+        let lookups = hits + self.misses - prev_misses;
+        if lookups == 0 {
+            return None;
+        }
+
+        Some(hits as f64 / lookups as f64)
+    }
+}
+
+/// The source of the current time for the cache, which tests replace to
+/// control when entries expire
+pub type Clock = Box<Fn() -> DateTime<Local> + Sync + Send>;
+
 pub struct Cache {
-    domain_entries: BTreeMap<String, Arc<DomainEntry>>
+    domain_entries: BTreeMap<String, Arc<DomainEntry>>,
+
+    /// The domains by when they were last used, oldest first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+
+    /// The maximum number of domains held, beyond which the least recently
+    /// used are evicted
+    capacity: Option<usize>,
+
+    /// Entries that are never evicted, and that are served past their
+    /// expiry when they can't be refreshed
+    pinned: HashSet<(String, QueryType)>,
+
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+
+    clock: Clock
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache::new()
+    }
 }
 
 impl Cache {
     pub fn new() -> Cache {
         Cache {
-            domain_entries: BTreeMap::new()
+            domain_entries: BTreeMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            capacity: None,
+            pinned: HashSet::new(),
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+            clock: Box::new(Local::now)
         }
     }
 
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    fn now(&self) -> DateTime<Local> {
+        (self.clock)()
+    }
+
+    /// Limit the cache to `capacity` domains, evicting the least recently
+    /// used ones once it's exceeded
+    ///
+    /// Domains with pinned entries are never evicted, and don't count
+    /// towards the capacity.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = Some(capacity);
+        self.enforce_capacity();
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// Mark `qname` as the most recently used domain
+    fn touch(&mut self, qname: &str) {
+        self.tick += 1;
+        if let Some(domain_entry) = self.domain_entries.get_mut(qname) {
+            let domain_entry = Arc::make_mut(domain_entry);
+            self.recency.remove(&domain_entry.last_used);
+            self.recency.insert(self.tick, qname.to_string());
+            domain_entry.last_used = self.tick;
+        }
+    }
+
+    /// The entry for `qname`, created if it doesn't exist yet, and marked as
+    /// the most recently used
+    fn entry_mut(&mut self, qname: &str) -> &mut DomainEntry {
+        if !self.domain_entries.contains_key(qname) {
+            self.domain_entries.insert(qname.to_string(), Arc::new(DomainEntry::new(qname.to_string())));
+        }
+
+        self.touch(qname);
+
+        Arc::make_mut(self.domain_entries.get_mut(qname).unwrap())
+    }
+
+    fn remove_domain(&mut self, qname: &str) {
+        if let Some(domain_entry) = self.domain_entries.remove(qname) {
+            self.recency.remove(&domain_entry.last_used);
+        }
+    }
+
+    /// Evict the least recently used domains until the cache is within its
+    /// capacity, skipping those with pinned entries
+    fn enforce_capacity(&mut self) {
+        let capacity = match self.capacity {
+            Some(x) => x,
+            None => return
+        };
+
+        let pinned_domains = self.pinned.iter()
+            .map(|&(ref name, _)| name)
+            .collect::<HashSet<&String>>()
+            .into_iter()
+            .filter(|name| self.domain_entries.contains_key(*name))
+            .count();
+
+        while self.domain_entries.len() > capacity + pinned_domains {
+            let victim = {
+                let pinned = &self.pinned;
+                self.recency.values()
+                    .find(|qname| !pinned.iter().any(|&(ref name, _)| name == *qname))
+                    .cloned()
+            };
+
+            match victim {
+                Some(qname) => {
+                    self.remove_domain(&qname);
+                    self.evictions += 1;
+                },
+                None => break
+            }
+        }
+    }
+
+    /// Summarize the cache, including the `top_n` domains with the most hits
+    pub fn stats(&self, top_n: usize) -> CacheStats {
+        let mut top_domains = self.domain_entries.values()
+            .filter(|x| x.hits > 0)
+            .map(|x| (x.domain.clone(), x.hits))
+            .collect::<Vec<(String, u32)>>();
+
+        top_domains.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_domains.truncate(top_n);
+
+        CacheStats {
+            size: self.domain_entries.len(),
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            top_domains: top_domains
+        }
+    }
+
+    /// Pin the records of `qtype` for `qname`
+    ///
+    /// Like any other entry, a pinned entry is looked up again once it
+    /// expires, but it's never evicted from the cache, and the expired
+    /// records are still served if the refresh fails.
+    pub fn pin(&mut self, qname: &str, qtype: QueryType) {
+        self.pinned.insert((qname.to_lowercase(), qtype));
+    }
+
+    pub fn is_pinned(&self, qname: &str, qtype: QueryType) -> bool {
+        self.pinned.contains(&(qname.to_string(), qtype))
+    }
+
+    /// The pinned entries, ordered by name and type
+    pub fn pinned(&self) -> Vec<(String, QueryType)> {
+        let mut pinned = self.pinned.iter().cloned().collect::<Vec<(String, QueryType)>>();
+        pinned.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.to_num().cmp(&b.1.to_num())));
+        pinned
+    }
+
+    /// Answer from a pinned entry, even if its records have expired
+    ///
+    /// Returns `None` if the entry isn't pinned, or if no records have ever
+    /// been stored for it.
+    pub fn lookup_pinned(&mut self,
+                         qname: &str,
+                         qtype: QueryType) -> Option<DnsPacket> {
+
+        if !self.is_pinned(qname, qtype) {
+            return None;
+        }
+
+        let now = self.now();
+
+        let mut qr = DnsPacket::new();
+        if let Some(domain_entry) = self.domain_entries.get(qname) {
+            domain_entry.fill_records(qtype, now, true, &mut qr.answers);
+        }
+
+        if qr.answers.is_empty() {
+            return None;
+        }
+
+        self.hits += 1;
+        self.record_hit(qname, qtype);
+        self.touch(qname);
+
+        Some(qr)
+    }
+
     fn get_cache_state(&mut self,
                        qname: &str,
                        qtype: QueryType) -> CacheState {
 
+        let now = self.now();
         match self.domain_entries.get(qname) {
-            Some(x) => x.get_cache_state(qtype),
+            Some(x) => x.get_cache_state(qtype, now),
             None => CacheState::NotCached
         }
     }
@@ -201,13 +618,74 @@ impl Cache {
                         result_vec: &mut Vec<DnsRecord>,
                         increment_stats: bool) {
 
+        let now = self.now();
         if let Some(domain_entry) = self.domain_entries.get_mut(qname).and_then(Arc::get_mut) {
 
             if increment_stats {
                 domain_entry.hits += 1
             }
 
-            domain_entry.fill_queryresult(qtype, result_vec);
+            domain_entry.fill_queryresult(qtype, now, result_vec);
+        }
+    }
+
+    /// The types of `qname` that are pinned
+    fn pinned_types(&self, qname: &str) -> Vec<QueryType> {
+        self.pinned.iter()
+            .filter(|&&(ref name, _)| name == qname)
+            .map(|&(_, qtype)| qtype)
+            .collect()
+    }
+
+    /// Drop the expired entries of `qname`, other than pinned ones
+    ///
+    /// The domain itself is kept, along with its statistics, until the next
+    /// `cleanup`.
+    fn remove_expired(&mut self, qname: &str) {
+        let now = self.now();
+        let keep = self.pinned_types(qname);
+
+        if let Some(domain_entry) = self.domain_entries.get_mut(qname) {
+            let removed = Arc::make_mut(domain_entry).remove_expired(now, &keep);
+            self.evictions += removed as u64;
+        }
+    }
+
+    /// Drop every expired entry, other than pinned ones, along with the
+    /// domains that have nothing left
+    ///
+    /// Returns the number of entries removed.
+    pub fn cleanup(&mut self) -> usize {
+        let now = self.now();
+
+        let mut removed = 0;
+        let mut empty = Vec::new();
+        for (qname, domain_entry) in &mut self.domain_entries {
+            let keep = self.pinned.iter()
+                .filter(|&&(ref name, _)| name == qname)
+                .map(|&(_, qtype)| qtype)
+                .collect::<Vec<QueryType>>();
+
+            let domain_entry = Arc::make_mut(domain_entry);
+            removed += domain_entry.remove_expired(now, &keep);
+
+            if domain_entry.record_types.is_empty() {
+                empty.push(qname.clone());
+            }
+        }
+
+        for qname in empty {
+            self.remove_domain(&qname);
+        }
+
+        self.evictions += removed as u64;
+
+        removed
+    }
+
+    fn record_hit(&mut self, qname: &str, qtype: QueryType) {
+        if let Some(domain_entry) = self.domain_entries.get_mut(qname).and_then(Arc::get_mut) {
+            *domain_entry.type_hits.entry(qtype).or_insert(0) += 1;
         }
     }
 
@@ -215,7 +693,18 @@ impl Cache {
                   qname: &str,
                   qtype: QueryType) -> Option<DnsPacket> {
 
-        match self.get_cache_state(qname, qtype) {
+        let state = self.get_cache_state(qname, qtype);
+        if let CacheState::NotCached = state {
+            self.misses += 1;
+            self.remove_expired(qname);
+            return None;
+        }
+
+        self.hits += 1;
+        self.record_hit(qname, qtype);
+        self.touch(qname);
+
+        match state {
             CacheState::PositiveCache => {
                 let mut qr = DnsPacket::new();
                 self.fill_queryresult(qname, qtype, &mut qr.answers, true);
@@ -224,8 +713,18 @@ impl Cache {
                 Some(qr)
             },
             CacheState::NegativeCache => {
+                let now = self.now();
+
+                let mut qr = DnsPacket::new();
+                if let Some(domain_entry) = self.domain_entries.get(qname) {
+                    domain_entry.fill_negative(qtype, now, &mut qr);
+                }
+
+                Some(qr)
+            },
+            CacheState::FailureCache => {
                 let mut qr = DnsPacket::new();
-                qr.header.rescode = ResultCode::NXDOMAIN;
+                qr.header.rescode = ResultCode::SERVFAIL;
 
                 Some(qr)
             },
@@ -234,37 +733,148 @@ impl Cache {
     }
 
     pub fn store(&mut self, records: &[DnsRecord]) {
+        self.store_from(records, CacheSource::Upstream);
+    }
+
+    pub fn store_from(&mut self, records: &[DnsRecord], source: CacheSource) {
 
+        let now = self.now();
         for rec in records {
             let domain = match rec.get_domain() {
                 Some(x) => x,
                 None => continue
             };
 
-            if let Some(ref mut rs) = self.domain_entries.get_mut(&domain)
-                .and_then(Arc::get_mut) {
+            self.entry_mut(&domain).store_record(rec, source, now);
+        }
+
+        self.enforce_capacity();
+    }
+
+    pub fn store_nxdomain(&mut self, qname: &str, qtype: QueryType, ttl: u32) {
+        let now = self.now();
+        self.entry_mut(qname).store_nxdomain(qtype, ttl, now);
+        self.enforce_capacity();
+    }
+
+    /// Remember `response` to a query for `qname` and `qtype` if it's
+    /// negative, as described in RFC 2308
+    ///
+    /// Both NXDOMAIN responses and NODATA responses, which are NOERROR
+    /// responses without any answers, are cached for the negative TTL given
+    /// by the SOA in the authority section. Responses without an SOA aren't
+    /// cached, and neither are referrals. Returns whether the response was
+    /// stored.
+    pub fn store_negative(&mut self, qname: &str, qtype: QueryType, response: &DnsPacket) -> bool {
+        let is_negative = match response.header.rescode {
+            ResultCode::NXDOMAIN => true,
+            ResultCode::NOERROR => response.answers.is_empty(),
+            _ => false
+        };
+
+        if !is_negative {
+            return false;
+        }
+
+        let (soa, ttl) = match (response.get_soa(), response.get_ttl_from_soa()) {
+            (Some(soa), Some(ttl)) => (soa.clone(), ttl),
+            _ => return false
+        };
+
+        let now = self.now();
+        let rescode = response.header.rescode;
+        self.entry_mut(qname).store_negative(qtype, rescode, Some(soa), ttl, now);
+        self.enforce_capacity();
+
+        true
+    }
+
+    pub fn store_servfail(&mut self, qname: &str, qtype: QueryType, ttl: u32) {
+        // The failure would replace the records kept for a pinned entry
+        if self.is_pinned(qname, qtype) {
+            return;
+        }
+
+        let now = self.now();
+        self.entry_mut(qname).store_servfail(qtype, ttl, now);
+        self.enforce_capacity();
+    }
+
+    /// Write the records in the cache to `buffer`, returning the number
+    /// written
+    ///
+    /// The snapshot starts with the time it was taken, followed by the number
+    /// of records and the records themselves, each with what remains of its
+    /// TTL and followed by where it was learned from. Negative and failed
+    /// lookups are left out, as are expired records and records of unknown
+    /// types.
+    pub fn write_snapshot<T: PacketBuffer>(&self, buffer: &mut T) -> Result<usize> {
+        let now = self.now();
+
+        let mut records = Vec::new();
+        for domain_entry in self.domain_entries.values() {
+            for record_set in domain_entry.record_types.values() {
+                if let RecordSet::Records { records: ref entries, .. } = *record_set {
+                    for entry in entries.iter().filter(|x| !x.is_expired(now)) {
+                        let mut record = entry.record.clone();
+                        record.set_ttl(entry.remaining_ttl(now));
+                        records.push((record, entry.source));
+                    }
+                }
+            }
+        }
+
+        try!(buffer.write_u32(now.timestamp() as u32));
+
+        // The count is filled in once it's known how many records could be
+        // written, since nothing is written for those of unknown types. The
+        // source follows each record, so that it's left out along with it.
+        let count_pos = buffer.pos();
+        try!(buffer.write_u32(0));
 
-                rs.store_record(rec);
+        let mut count = 0;
+        for &(ref record, source) in &records {
+            if try!(record.write(buffer)) == 0 {
                 continue;
             }
 
-            let mut rs = DomainEntry::new(domain.clone());
-            rs.store_record(rec);
-            self.domain_entries.insert(domain.clone(), Arc::new(rs));
+            try!(buffer.write_u8(source.to_num()));
+            count += 1;
         }
+
+        let end = buffer.pos();
+        try!(buffer.set_u16(count_pos, (count >> 16) as u16));
+        try!(buffer.set_u16(count_pos + 2, count as u16));
+        try!(buffer.seek(end));
+
+        Ok(count)
     }
 
-    pub fn store_nxdomain(&mut self, qname: &str, qtype: QueryType, ttl: u32) {
-        if let Some(ref mut rs) = self.domain_entries.get_mut(qname)
-            .and_then(Arc::get_mut) {
+    /// Store the records of a snapshot written by `write_snapshot`, returning
+    /// the number stored
+    ///
+    /// The time since the snapshot was taken is taken off the TTLs, and
+    /// records that have expired in the meantime are skipped.
+    pub fn read_snapshot<T: PacketBuffer>(&mut self, buffer: &mut T) -> Result<usize> {
+        let taken = try!(buffer.read_u32()) as i64;
+        let elapsed = cmp::max(self.now().timestamp() - taken, 0) as u64;
+
+        let mut stored = 0;
+        for _ in 0..try!(buffer.read_u32()) {
+            let mut record = try!(DnsRecord::read(buffer));
+            let source = CacheSource::from_num(try!(buffer.read()));
+
+            let ttl = record.get_ttl() as u64;
+            if ttl <= elapsed {
+                continue;
+            }
 
-            rs.store_nxdomain(qtype, ttl);
-            return
+            record.set_ttl((ttl - elapsed) as u32);
+            self.store_from(&[record], source);
+            stored += 1;
         }
 
-        let mut rs = DomainEntry::new(qname.to_string());
-        rs.store_nxdomain(qtype, ttl);
-        self.domain_entries.insert(qname.to_string(), Arc::new(rs));
+        Ok(stored)
     }
 }
 
@@ -295,6 +905,67 @@ impl SynchronizedCache {
         Ok(list)
     }
 
+    pub fn pin(&self, qname: &str, qtype: QueryType) -> Result<()> {
+        let mut cache = match self.cache.write() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        cache.pin(qname, qtype);
+
+        Ok(())
+    }
+
+    /// Limit the cache to `capacity` domains, evicting the least recently
+    /// used ones
+    pub fn set_capacity(&self, capacity: usize) -> Result<()> {
+        let mut cache = match self.cache.write() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        cache.set_capacity(capacity);
+
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> Result<Option<usize>> {
+        let cache = match self.cache.read() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        Ok(cache.capacity())
+    }
+
+    pub fn pinned(&self) -> Result<Vec<(String, QueryType)>> {
+        let cache = match self.cache.read() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        Ok(cache.pinned())
+    }
+
+    /// Drop every expired entry, returning the number removed
+    pub fn cleanup(&self) -> Result<usize> {
+        let mut cache = match self.cache.write() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        Ok(cache.cleanup())
+    }
+
+    pub fn stats(&self, top_n: usize) -> Result<CacheStats> {
+        let cache = match self.cache.read() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        Ok(cache.stats(top_n))
+    }
+
     pub fn lookup(&self,
                   qname: &str,
                   qtype: QueryType) -> Option<DnsPacket> {
@@ -307,13 +978,29 @@ impl SynchronizedCache {
         cache.lookup(qname, qtype)
     }
 
+    pub fn lookup_pinned(&self,
+                         qname: &str,
+                         qtype: QueryType) -> Option<DnsPacket> {
+
+        let mut cache = match self.cache.write() {
+            Ok(x) => x,
+            Err(_) => return None
+        };
+
+        cache.lookup_pinned(qname, qtype)
+    }
+
     pub fn store(&self, records: &[DnsRecord]) -> Result<()> {
+        self.store_from(records, CacheSource::Upstream)
+    }
+
+    pub fn store_from(&self, records: &[DnsRecord], source: CacheSource) -> Result<()> {
         let mut cache = match self.cache.write() {
             Ok(x) => x,
             Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
         };
 
-        cache.store(records);
+        cache.store_from(records, source);
 
         Ok(())
     }
@@ -332,11 +1019,109 @@ impl SynchronizedCache {
 
         Ok(())
     }
+
+    /// Remember `response` if it's negative, returning whether it was stored
+    pub fn store_negative(&self,
+                          qname: &str,
+                          qtype: QueryType,
+                          response: &DnsPacket) -> Result<bool> {
+
+        let mut cache = match self.cache.write() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        Ok(cache.store_negative(qname, qtype, response))
+    }
+
+    /// Remember that resolving `qname` failed, so that it isn't retried
+    /// upstream for `ttl` seconds
+    pub fn store_servfail(&self,
+                          qname: &str,
+                          qtype: QueryType,
+                          ttl: u32) -> Result<()> {
+
+        let mut cache = match self.cache.write() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        cache.store_servfail(qname, qtype, ttl);
+
+        Ok(())
+    }
+
+    /// Save a snapshot of the records in the cache to `path`, returning the
+    /// number saved
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<usize> {
+        let mut buffer = VectorPacketBuffer::new();
+        let count = {
+            let cache = match self.cache.read() {
+                Ok(x) => x,
+                Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+            };
+
+            try!(cache.write_snapshot(&mut buffer))
+        };
+
+        // The snapshot is written next to the file and then moved over it,
+        // so that a crash halfway through doesn't leave a truncated file
+        let mut tmp_path = path.as_ref().as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        {
+            let mut file = try!(File::create(&tmp_path));
+            try!(file.write_all(&buffer.buffer[0..buffer.pos()]));
+            try!(file.sync_all());
+        }
+
+        try!(fs::rename(&tmp_path, path));
+
+        Ok(count)
+    }
+
+    /// Load a snapshot saved by `save_to` from `path`, returning the number of
+    /// records that hadn't expired since
+    pub fn load_from<P: AsRef<Path>>(&self, path: P) -> Result<usize> {
+        let mut buffer = VectorPacketBuffer::new();
+        let _ = try!(try!(File::open(path)).read_to_end(&mut buffer.buffer));
+
+        let mut cache = match self.cache.write() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        cache.read_snapshot(&mut buffer)
+    }
+}
+
+/// Start a thread purging the expired entries of the cache every
+/// `CACHE_CLEANUP_INTERVAL_SECS`, after which the cache is saved to the
+/// configured `cache_path`, if any
+pub fn start_cache_cleaner(context: Arc<ServerContext>) -> Result<()> {
+    let interval = SleepDuration::from_secs(CACHE_CLEANUP_INTERVAL_SECS);
+
+    let _ = try!(Builder::new().name("CacheCleaner".into()).spawn(move || {
+        loop {
+            sleep(interval);
+            let _ = context.cache.cleanup();
+
+            if let Some(ref path) = context.cache_path {
+                if let Err(e) = context.cache.save_to(path) {
+                    println!("Failed to save cache to {:?}: {:?}", path, e);
+                }
+            }
+        }
+    }));
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
 
+    use std::sync::Mutex;
+
     use super::*;
 
     use dns::protocol::{DnsRecord, QueryType, ResultCode, TransientTtl};
@@ -432,5 +1217,302 @@ mod tests {
         assert_eq!(3, cache.domain_entries.get(&"www.yahoo.com".to_string()).unwrap().updates);
         assert_eq!(1, cache.domain_entries.get(&"www.microsoft.com".to_string()).unwrap().updates);
         assert_eq!(1, cache.domain_entries.get(&"www.microsoft.com".to_string()).unwrap().hits);
+
+        let stats = cache.stats(2);
+        assert_eq!(3, stats.size);
+        assert_eq!(4, stats.hits);
+        assert_eq!(4, stats.misses);
+
+        // The expired entries of www.yahoo.com were dropped when looked up
+        assert_eq!(2, stats.evictions);
+        assert_eq!(vec![("www.google.com".to_string(), 1), ("www.microsoft.com".to_string(), 1)], stats.top_domains);
+        assert_eq!(Some(0.5), stats.hit_ratio(None));
+
+        // The hit ratio can be limited to the lookups since an earlier snapshot
+        let _ = cache.lookup("www.google.com", QueryType::A);
+        let later = cache.stats(1);
+        assert_eq!(vec![("www.google.com".to_string(), 2)], later.top_domains);
+        assert_eq!(Some(1.0), later.hit_ratio(Some(&stats)));
+        assert_eq!(None, later.hit_ratio(Some(&later)));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut cache = Cache::new();
+
+        let now = Arc::new(Mutex::new(Local::now()));
+        let clock_now = now.clone();
+        cache.set_clock(Box::new(move || *clock_now.lock().unwrap()));
+
+        let advance = |seconds: i64| {
+            let mut now = now.lock().unwrap();
+            *now = *now + Duration::seconds(seconds);
+        };
+
+        cache.store(&[DnsRecord::A {
+            domain: "www.google.com".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(1)
+        }, DnsRecord::A {
+            domain: "www.yahoo.com".to_string(),
+            addr: "127.0.0.2".parse().unwrap(),
+            ttl: TransientTtl(1)
+        }, DnsRecord::AAAA {
+            domain: "www.yahoo.com".to_string(),
+            addr: "::1".parse().unwrap(),
+            ttl: TransientTtl(300)
+        }]);
+        cache.store_nxdomain("www.bing.com", QueryType::A, 1);
+
+        match cache.lookup("www.google.com", QueryType::A) {
+            Some(packet) => assert_eq!(1, packet.answers[0].get_ttl()),
+            None => panic!()
+        }
+
+        advance(2);
+
+        // Expired records aren't returned, and are dropped once looked up
+        assert!(cache.lookup("www.google.com", QueryType::A).is_none());
+        assert!(cache.domain_entries.get("www.google.com").unwrap().record_types.is_empty());
+        assert_eq!(1, cache.stats(0).evictions);
+
+        // The TTL of the remaining records counts down
+        match cache.lookup("www.yahoo.com", QueryType::AAAA) {
+            Some(packet) => assert_eq!(298, packet.answers[0].get_ttl()),
+            None => panic!()
+        }
+
+        // A cleanup purges the rest of the expired entries, along with the
+        // domains left without any
+        assert_eq!(2, cache.cleanup());
+        assert!(cache.domain_entries.get("www.google.com").is_none());
+        assert!(cache.domain_entries.get("www.bing.com").is_none());
+        assert_eq!(vec![QueryType::AAAA],
+                   cache.domain_entries.get("www.yahoo.com").unwrap().record_types.keys().cloned().collect::<Vec<QueryType>>());
+
+        let stats = cache.stats(0);
+        assert_eq!(1, stats.size);
+        assert_eq!(3, stats.evictions);
+
+        assert_eq!(0, cache.cleanup());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut cache = Cache::new();
+        cache.set_capacity(3);
+
+        let build_a = |domain: &str| DnsRecord::A {
+            domain: domain.to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        };
+
+        cache.store(&[build_a("a.com")]);
+        cache.store(&[build_a("b.com")]);
+        cache.store(&[build_a("c.com")]);
+
+        // Looking up the first domain makes the second the least recently
+        // used, which is evicted for the fourth
+        assert!(cache.lookup("a.com", QueryType::A).is_some());
+        cache.store(&[build_a("d.com")]);
+
+        let domains = cache.domain_entries.keys().cloned().collect::<Vec<String>>();
+        assert_eq!(vec!["a.com", "c.com", "d.com"], domains);
+        assert_eq!(1, cache.stats(0).evictions);
+
+        // Negative entries count towards the limit as well
+        cache.store_nxdomain("e.com", QueryType::A, 3600);
+        assert!(cache.lookup("c.com", QueryType::A).is_none());
+        assert!(cache.lookup("e.com", QueryType::A).is_some());
+        assert_eq!(3, cache.stats(0).size);
+
+        // Lowering the capacity evicts right away
+        cache.set_capacity(1);
+        let domains = cache.domain_entries.keys().cloned().collect::<Vec<String>>();
+        assert_eq!(vec!["e.com"], domains);
+        assert_eq!(4, cache.stats(0).evictions);
+    }
+
+    #[test]
+    fn test_servfail_cache() {
+        let mut cache = Cache::new();
+
+        cache.store_servfail("www.google.com", QueryType::A, 3600);
+        match cache.lookup("www.google.com", QueryType::A) {
+            Some(packet) => assert_eq!(ResultCode::SERVFAIL, packet.header.rescode),
+            None => panic!()
+        }
+
+        // Failures are tracked per type
+        assert!(cache.lookup("www.google.com", QueryType::AAAA).is_none());
+
+        // An expired failure isn't returned
+        cache.store_servfail("www.yahoo.com", QueryType::A, 0);
+        assert!(cache.lookup("www.yahoo.com", QueryType::A).is_none());
+
+        // and a successful answer replaces the failure
+        cache.store(&[DnsRecord::A {
+            domain: "www.google.com".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }]);
+        match cache.lookup("www.google.com", QueryType::A) {
+            Some(packet) => {
+                assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+                assert_eq!(1, packet.answers.len());
+            },
+            None => panic!()
+        }
+    }
+
+    #[test]
+    fn test_pinned() {
+        let mut cache = Cache::new();
+
+        cache.pin("www.google.com", QueryType::A);
+        cache.pin("WWW.Google.com", QueryType::AAAA);
+        cache.pin("www.google.com", QueryType::A);
+        assert_eq!(vec![("www.google.com".to_string(), QueryType::A),
+                        ("www.google.com".to_string(), QueryType::AAAA)],
+                   cache.pinned());
+
+        // Nothing is served for a pinned entry before it's been resolved
+        assert!(cache.lookup_pinned("www.google.com", QueryType::A).is_none());
+
+        let records = vec![DnsRecord::A {
+            domain: "www.google.com".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(0)
+        }, DnsRecord::A {
+            domain: "www.yahoo.com".to_string(),
+            addr: "127.0.0.2".parse().unwrap(),
+            ttl: TransientTtl(0)
+        }];
+        cache.store(&records);
+
+        // A failed refresh doesn't replace the expired records of a pinned
+        // entry, which are still served
+        cache.store_servfail("www.google.com", QueryType::A, 3600);
+        cache.store_servfail("www.yahoo.com", QueryType::A, 3600);
+
+        assert!(cache.lookup("www.google.com", QueryType::A).is_none());
+        match cache.lookup_pinned("www.google.com", QueryType::A) {
+            Some(packet) => assert_eq!(vec![records[0].clone()], packet.answers),
+            None => panic!()
+        }
+
+        // and which survive a cleanup
+        cache.cleanup();
+        assert!(cache.lookup_pinned("www.google.com", QueryType::A).is_some());
+
+        match cache.lookup("www.yahoo.com", QueryType::A) {
+            Some(packet) => assert_eq!(ResultCode::SERVFAIL, packet.header.rescode),
+            None => panic!()
+        }
+        assert!(cache.lookup_pinned("www.yahoo.com", QueryType::A).is_none());
+
+        // Pinned entries also survive LRU eviction, even as the least
+        // recently used domain
+        cache.set_capacity(1);
+        cache.store(&[DnsRecord::A {
+            domain: "www.bing.com".to_string(),
+            addr: "127.0.0.3".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }]);
+        cache.store(&[DnsRecord::A {
+            domain: "www.duckduckgo.com".to_string(),
+            addr: "127.0.0.4".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }]);
+
+        let domains = cache.domain_entries.keys().cloned().collect::<Vec<String>>();
+        assert_eq!(vec!["www.duckduckgo.com", "www.google.com"], domains);
+        assert!(cache.lookup_pinned("www.google.com", QueryType::A).is_some());
+    }
+
+    #[test]
+    fn test_persistence() {
+        let cache = SynchronizedCache::new();
+
+        let now = Arc::new(Mutex::new(Local::now()));
+        let clock_now = now.clone();
+        cache.cache.write().unwrap().set_clock(Box::new(move || *clock_now.lock().unwrap()));
+
+        cache.store(&[DnsRecord::A {
+            domain: "www.google.com".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }, DnsRecord::AAAA {
+            domain: "www.google.com".to_string(),
+            addr: "::1".parse().unwrap(),
+            ttl: TransientTtl(30)
+        }, DnsRecord::A {
+            domain: "www.yahoo.com".to_string(),
+            addr: "127.0.0.2".parse().unwrap(),
+            ttl: TransientTtl(5)
+        }]).unwrap();
+        cache.store_from(&[DnsRecord::MX {
+            domain: "google.com".to_string(),
+            priority: 10,
+            host: "mail.google.com".to_string(),
+            ttl: TransientTtl(600)
+        }], CacheSource::Authoritative).unwrap();
+        cache.store_nxdomain("www.bing.com", QueryType::A, 3600).unwrap();
+
+        // Records of unknown types can't be written, and are left out
+        // without upsetting the ones that follow
+        cache.store(&[DnsRecord::UNKNOWN {
+            domain: "www.google.com".to_string(),
+            qtype: 999,
+            data_len: 0,
+            ttl: TransientTtl(3600)
+        }]).unwrap();
+
+        // Only the records that are still valid are saved
+        {
+            let mut now = now.lock().unwrap();
+            *now = *now + Duration::seconds(10);
+        }
+
+        let path = ::std::env::temp_dir().join("hermes_test_cache_snapshot");
+        assert_eq!(3, cache.save_to(&path).unwrap());
+
+        // and some more time passes before they're loaded again
+        let restored = SynchronizedCache::new();
+        let later = *now.lock().unwrap() + Duration::seconds(60);
+        restored.cache.write().unwrap().set_clock(Box::new(move || later));
+
+        assert_eq!(2, restored.load_from(&path).unwrap());
+        let _ = ::std::fs::remove_file(&path);
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        assert!(!Path::new(&tmp_path).exists());
+
+        match restored.lookup("www.google.com", QueryType::A) {
+            Some(packet) => {
+                assert_eq!(1, packet.answers.len());
+                assert_eq!(3600 - 70, packet.answers[0].get_ttl());
+            },
+            None => panic!()
+        }
+
+        let domain_entry = restored.list().unwrap().into_iter()
+            .find(|x| x.domain == "google.com")
+            .unwrap();
+        match domain_entry.record_types.get(&QueryType::MX) {
+            Some(&RecordSet::Records { ref records, .. }) => {
+                let entry = records.iter().next().unwrap();
+                assert_eq!(CacheSource::Authoritative, entry.source);
+                assert_eq!(600 - 70, entry.record.get_ttl());
+            },
+            _ => panic!()
+        }
+
+        // Expired records and negative answers aren't restored
+        assert!(restored.lookup("www.google.com", QueryType::AAAA).is_none());
+        assert!(restored.lookup("www.yahoo.com", QueryType::A).is_none());
+        assert!(restored.lookup("www.bing.com", QueryType::A).is_none());
     }
 }
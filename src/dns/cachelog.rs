@@ -0,0 +1,137 @@
+//! periodic snapshots of the cache statistics, written to the log
+
+use std::io::{Result, Write};
+use std::sync::Arc;
+use std::thread::{Builder, sleep};
+use std::time::Duration;
+
+use dns::cache::CacheStats;
+use dns::context::ServerContext;
+
+/// Number of the domains with the most hits included in each snapshot
+pub const CACHE_LOG_TOP_DOMAINS: usize = 5;
+
+/// Format a single line summarizing `stats`
+///
+/// The hit ratio covers the lookups since `previous`, so that it reflects the
+/// interval between snapshots rather than the lifetime of the server.
+pub fn format_snapshot(stats: &CacheStats, previous: Option<&CacheStats>) -> String {
+    let hit_ratio = match stats.hit_ratio(previous) {
+        Some(x) => format!("{:.1}%", x * 100.0),
+        None => "n/a".to_string()
+    };
+
+    let top = stats.top_domains.iter()
+        .map(|&(ref domain, hits)| format!("{} ({})", domain, hits))
+        .collect::<Vec<String>>();
+
+    format!("Cache snapshot: {} domains, hit ratio {}, {} evictions, top domains: {}",
+            stats.size,
+            hit_ratio,
+            stats.evictions,
+            if top.is_empty() { "none".to_string() } else { top.join(", ") })
+}
+
+/// Start a thread writing a snapshot of the cache to `out` every `interval`
+pub fn spawn_cache_logger<W>(context: Arc<ServerContext>,
+                             interval: Duration,
+                             mut out: W) -> Result<()>
+    where W: Write + Send + 'static {
+
+    let _ = try!(Builder::new().name("CacheLogger".into()).spawn(move || {
+        let mut previous = None;
+        loop {
+            sleep(interval);
+
+            let stats = match context.cache.stats(CACHE_LOG_TOP_DOMAINS) {
+                Ok(x) => x,
+                Err(_) => continue
+            };
+
+            let _ = writeln!(out, "{}", format_snapshot(&stats, previous.as_ref()));
+            let _ = out.flush();
+
+            previous = Some(stats);
+        }
+    }));
+
+    Ok(())
+}
+
+/// Start logging cache snapshots to stdout, if an interval is configured in
+/// `context`
+pub fn start_cache_logger(context: Arc<ServerContext>) -> Result<()> {
+    match context.cache_log_interval {
+        Some(interval) => spawn_cache_logger(context.clone(), interval, ::std::io::stdout()),
+        None => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io;
+    use std::sync::Mutex;
+
+    use dns::context::tests::create_test_context;
+    use dns::protocol::{DnsPacket, DnsRecord, QueryType, TransientTtl};
+
+    use super::*;
+
+    /// A log sink that can be inspected while the logger is writing to it
+    #[derive(Clone)]
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_format_snapshot() {
+        let stats = CacheStats {
+            size: 3,
+            hits: 3,
+            misses: 1,
+            evictions: 0,
+            top_domains: vec![("www.google.com".to_string(), 2), ("www.yahoo.com".to_string(), 1)]
+        };
+
+        assert_eq!("Cache snapshot: 3 domains, hit ratio 75.0%, 0 evictions, top domains: www.google.com (2), www.yahoo.com (1)",
+                   format_snapshot(&stats, None));
+        assert_eq!("Cache snapshot: 0 domains, hit ratio n/a, 0 evictions, top domains: none",
+                   format_snapshot(&CacheStats::default(), None));
+    }
+
+    #[test]
+    fn test_cache_logger() {
+        let context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Ok(DnsPacket::new())
+            }));
+
+        let _ = context.cache.store(&[DnsRecord::A {
+            domain: "www.google.com".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }]);
+        let _ = context.cache.lookup("www.google.com", QueryType::A);
+
+        let sink = SharedSink(Arc::new(Mutex::new(Vec::new())));
+        spawn_cache_logger(context.clone(), Duration::from_millis(10), sink.clone()).unwrap();
+
+        sleep(Duration::from_millis(200));
+
+        let log = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        let first = log.lines().next().unwrap();
+        assert_eq!("Cache snapshot: 1 domains, hit ratio 100.0%, 0 evictions, top domains: www.google.com (1)", first);
+
+        // Later snapshots only cover the lookups since the previous one
+        assert!(log.lines().skip(1).any(|x| x.contains("hit ratio n/a")));
+    }
+}
@@ -1,24 +1,43 @@
 //! client for sending DNS queries to other servers
 
-use std::io::{Result,Error,ErrorKind,Write};
+use std::io::{Result,Error,ErrorKind,Read,Write};
 use std::marker::{Send, Sync};
-use std::net::{UdpSocket,TcpStream};
+use std::net::{UdpSocket,TcpStream,SocketAddr,ToSocketAddrs,IpAddr,Ipv4Addr,Ipv6Addr};
 use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread::{Builder,sleep};
-use std::time::Duration as SleepDuration;
+use std::time::{Duration as SleepDuration, Instant};
 use std::sync::atomic::{AtomicUsize,Ordering};
 
-use chrono::*;
+use rand::{Rng, thread_rng, random};
 
-use dns::buffer::{PacketBuffer, BytePacketBuffer, StreamPacketBuffer};
-use dns::protocol::{DnsPacket, DnsQuestion, QueryType};
+use dns::buffer::{PacketBuffer, BytePacketBuffer, VectorPacketBuffer};
+use dns::protocol::{DnsPacket, QueryType};
 use dns::netutil::{read_packet_length, write_packet_length};
 
+/// Number of random ports to try binding before leaving the choice to the OS
+const RANDOM_PORT_ATTEMPTS: usize = 10;
+
+/// Default number of seconds to wait for a response to a UDP query
+pub const DEFAULT_QUERY_TIMEOUT_SECS: u64 = 3;
+
+/// Seconds to wait on each read from or write to a TCP connection
+const TCP_QUERY_TIMEOUT_SECS: u64 = 5;
+
 pub trait DnsClient {
     fn get_sent_count(&self) -> usize;
     fn get_failed_count(&self) -> usize;
 
+    /// How long to wait for a response to a UDP query before failing with
+    /// `TimedOut`, so that another server can be tried
+    fn get_query_timeout(&self) -> SleepDuration;
+    fn set_query_timeout(&mut self, timeout: SleepDuration);
+
+    /// Whether the case of the names in UDP queries is randomized, with
+    /// responses only accepted if they echo it exactly
+    fn get_randomize_case(&self) -> bool;
+    fn set_randomize_case(&mut self, enabled: bool);
+
     fn run(&self) -> Result<()>;
     fn send_query(&self,
                   qname: &str,
@@ -39,22 +58,33 @@ pub struct DnsNetworkClient {
     total_sent: AtomicUsize,
     total_failed: AtomicUsize,
 
-    /// Counter for assigning packet ids
-    seq: AtomicUsize,
-
     /// The listener socket
     socket: UdpSocket,
 
+    /// Send each UDP query from a socket of its own, on a random port, which
+    /// makes spoofing responses harder
+    random_ports: bool,
+
+    /// Randomize the case of the name in each UDP query, and only accept
+    /// responses echoing it case for case, as described in
+    /// draft-vixie-dnsext-dns0x20
+    randomize_case: bool,
+
+    /// How long to wait for a response to a UDP query
+    query_timeout: SleepDuration,
+
     /// Queries in progress
     pending_queries: Arc<Mutex<Vec<PendingQuery>>>
 }
 
-/// A query in progress. This struct holds the `id` if the request, and a channel
-/// endpoint for returning a response back to the thread from which the query
-/// was posed.
+/// A query in progress. This struct holds the request and the server it was
+/// sent to, and a channel endpoint for returning a response back to the thread
+/// from which the query was posed.
 struct PendingQuery {
-    seq: u16,
-    timestamp: DateTime<Local>,
+    query: DnsPacket,
+    exact_case: bool,
+    server: SocketAddr,
+    deadline: Instant,
     tx: Sender<Option<DnsPacket>>
 }
 
@@ -66,16 +96,27 @@ impl DnsNetworkClient {
         DnsNetworkClient {
             total_sent: AtomicUsize::new(0),
             total_failed: AtomicUsize::new(0),
-            seq: AtomicUsize::new(0),
             socket: UdpSocket::bind(("0.0.0.0", port)).unwrap(),
+            random_ports: false,
+            randomize_case: false,
+            query_timeout: SleepDuration::from_secs(DEFAULT_QUERY_TIMEOUT_SECS),
             pending_queries: Arc::new(Mutex::new(Vec::new()))
         }
     }
 
+    /// Create a client sending UDP queries from random source ports, rather
+    /// than from a single shared port
+    pub fn with_random_ports() -> DnsNetworkClient {
+        let mut client = DnsNetworkClient::new(0);
+        client.random_ports = true;
+        client
+    }
+
     /// Send a DNS query using TCP transport
     ///
     /// This is much simpler than using UDP, since the kernel will take care of
-    /// packet ordering, connection state, timeouts etc.
+    /// packet ordering and connection state. It's used for responses that were
+    /// truncated when sent over UDP, since there's no limit on their size.
     pub fn send_tcp_query(&self,
                           qname: &str,
                           qtype: QueryType,
@@ -84,33 +125,19 @@ impl DnsNetworkClient {
 
         let _ = self.total_sent.fetch_add(1, Ordering::Release);
 
-        // Prepare request
-        let mut packet = DnsPacket::new();
-
-        packet.header.id = self.seq.fetch_add(1, Ordering::SeqCst) as u16;
-        if packet.header.id + 1 == 0xFFFF {
-            self.seq.compare_and_swap(0xFFFF, 0, Ordering::SeqCst);
-        }
-
-        packet.header.questions = 1;
-        packet.header.recursion_desired = recursive;
-
-        packet.questions.push(DnsQuestion::new(qname.into(), qtype));
+        let mut packet = DnsPacket::query(qname, qtype, recursive);
 
         // Send query
-        let mut req_buffer = BytePacketBuffer::new();
-        try!(packet.write(&mut req_buffer, 0xFFFF));
-
         let mut socket = try!(TcpStream::connect(server));
+        try!(socket.set_read_timeout(Some(SleepDuration::from_secs(TCP_QUERY_TIMEOUT_SECS))));
+        try!(socket.set_write_timeout(Some(SleepDuration::from_secs(TCP_QUERY_TIMEOUT_SECS))));
 
-        try!(write_packet_length(&mut socket, req_buffer.pos()));
-        try!(socket.write(&req_buffer.buf[0..req_buffer.pos]));
-        try!(socket.flush());
-
-        let _ = try!(read_packet_length(&mut socket));
+        let res = try!(exchange_tcp(&mut socket, &mut packet));
+        if !is_response_to(&packet, &res) {
+            return Err(Error::new(ErrorKind::InvalidData, "Response doesn't match the query"));
+        }
 
-        let mut stream_buffer = StreamPacketBuffer::new(&mut socket);
-        DnsPacket::from_buffer(&mut stream_buffer)
+        Ok(res)
     }
 
     /// Send a DNS query using UDP transport
@@ -128,27 +155,42 @@ impl DnsNetworkClient {
 
         let _ = self.total_sent.fetch_add(1, Ordering::Release);
 
-        // Prepare request
-        let mut packet = DnsPacket::new();
-
-        packet.header.id = self.seq.fetch_add(1, Ordering::SeqCst) as u16;
-        if packet.header.id + 1 == 0xFFFF {
-            self.seq.compare_and_swap(0xFFFF, 0, Ordering::SeqCst);
+        let mut packet = DnsPacket::query(qname, qtype, recursive);
+        if self.randomize_case {
+            packet.questions[0].name = randomize_case(qname);
         }
 
-        packet.header.questions = 1;
-        packet.header.recursion_desired = recursive;
+        // Responses are only accepted from the address the query is sent to
+        let server_addr = match try!(server.to_socket_addrs()).next() {
+            Some(x) => x,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "Invalid server address"))
+        };
+
+        // The shared socket is bound to an IPv4 address, so queries to IPv6
+        // servers are sent from a socket of their own as well
+        if self.random_ports || server_addr.is_ipv6() {
+            let res = self.send_from_random_port(&mut packet, server_addr);
+            if res.is_err() {
+                let _ = self.total_failed.fetch_add(1, Ordering::Release);
+            }
 
-        packet.questions.push(DnsQuestion::new(qname.to_string(), qtype));
+            return res.map(|x| restore_case(x, qname));
+        }
 
         // Create a return channel, and add a `PendingQuery` to the list of lookups
-        // in progress
+        // in progress, with an id that isn't already in use
         let (tx, rx) = channel();
         match self.pending_queries.lock() {
             Ok(mut pending_queries) => {
+                while pending_queries.iter().any(|x| x.query.header.id == packet.header.id) {
+                    packet.header.id = random();
+                }
+
                 pending_queries.push(PendingQuery {
-                    seq: packet.header.id,
-                    timestamp: Local::now(),
+                    query: packet.clone(),
+                    exact_case: self.randomize_case,
+                    server: server_addr,
+                    deadline: Instant::now() + self.query_timeout,
                     tx: tx
                 });
             },
@@ -158,12 +200,12 @@ impl DnsNetworkClient {
         // Send query
         let mut req_buffer = BytePacketBuffer::new();
         try!(packet.write(&mut req_buffer, 512));
-        try!(self.socket.send_to(&req_buffer.buf[0..req_buffer.pos], server));
+        try!(self.socket.send_to(&req_buffer.buf[0..req_buffer.pos], server_addr));
 
         // Wait for response
         if let Ok(res) = rx.recv() {
             match res {
-                Some(qr) => return Ok(qr),
+                Some(qr) => return Ok(restore_case(qr, qname)),
                 None => {
                     let _ = self.total_failed.fetch_add(1, Ordering::Release);
                     return Err(Error::new(ErrorKind::TimedOut, "Request timed out"))
@@ -175,6 +217,133 @@ impl DnsNetworkClient {
         let _ = self.total_failed.fetch_add(1, Ordering::Release);
         Err(Error::new(ErrorKind::InvalidInput, "Lookup failed"))
     }
+
+    /// Send `packet` from a socket bound to a random port, and wait for the
+    /// response on it
+    ///
+    /// The socket is connected to `server`, so that datagrams from any other
+    /// address are discarded by the OS. Datagrams which aren't a response to
+    /// the query are skipped.
+    fn send_from_random_port(&self,
+                             packet: &mut DnsPacket,
+                             server: SocketAddr) -> Result<DnsPacket> {
+
+        let socket = try!(bind_random_port(&server));
+        try!(socket.connect(server));
+
+        let mut req_buffer = BytePacketBuffer::new();
+        try!(packet.write(&mut req_buffer, 512));
+        let _ = try!(socket.send(&req_buffer.buf[0..req_buffer.pos]));
+
+        let deadline = Instant::now() + self.query_timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::new(ErrorKind::TimedOut, "Request timed out"));
+            }
+
+            try!(socket.set_read_timeout(Some(deadline - now)));
+
+            let mut res_buffer = BytePacketBuffer::new();
+            match socket.recv(&mut res_buffer.buf) {
+                Ok(_) => {},
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return Err(Error::new(ErrorKind::TimedOut, "Request timed out"));
+                },
+                Err(e) => return Err(e)
+            }
+
+            match DnsPacket::from_buffer(&mut res_buffer) {
+                Ok(ref res) if is_response_to(packet, res) &&
+                    (!self.randomize_case || has_matching_case(packet, res)) => return Ok(res.clone()),
+                _ => continue
+            }
+        }
+    }
+}
+
+/// Check that `response` answers `query`, by having the same id and, unless
+/// it has no question at all, the same question
+pub fn is_response_to(query: &DnsPacket, response: &DnsPacket) -> bool {
+    if response.header.id != query.header.id {
+        return false;
+    }
+
+    if response.questions.is_empty() {
+        return true;
+    }
+
+    response.questions.len() == query.questions.len() &&
+        query.questions.iter().zip(response.questions.iter()).all(|(a, b)| a.matches(b))
+}
+
+/// Check that the names in the questions of `response` have exactly the case
+/// of those in `query`
+///
+/// A spoofed response has to guess the case of every letter in the name, on
+/// top of the id and the port. Responses without a question have nothing to
+/// check.
+pub fn has_matching_case(query: &DnsPacket, response: &DnsPacket) -> bool {
+    query.questions.iter().zip(response.questions.iter()).all(|(a, b)| a.name == b.name)
+}
+
+/// Flip the case of each letter in `name` at random
+fn randomize_case(name: &str) -> String {
+    let mut rng = thread_rng();
+    name.chars()
+        .map(|c| if rng.gen() { c.to_ascii_uppercase() } else { c.to_ascii_lowercase() })
+        .collect()
+}
+
+/// Put the name of the original query back into the questions of a response
+/// to a query with a randomized case
+fn restore_case(mut response: DnsPacket, qname: &str) -> DnsPacket {
+    for question in &mut response.questions {
+        question.name = qname.to_string();
+    }
+
+    response
+}
+
+/// Send `packet` on `stream` and read the response, both framed with the two
+/// byte length prefix of RFC 1035 section 4.2.2
+pub fn exchange_tcp(stream: &mut TcpStream, packet: &mut DnsPacket) -> Result<DnsPacket> {
+    let mut req_buffer = VectorPacketBuffer::new();
+    try!(packet.write(&mut req_buffer, 0xFFFF));
+
+    try!(write_packet_length(stream, req_buffer.pos()));
+    try!(stream.write_all(&req_buffer.buffer));
+    try!(stream.flush());
+
+    let len = try!(read_packet_length(stream)) as usize;
+
+    let mut res_buffer = VectorPacketBuffer::new();
+    res_buffer.buffer.resize(len, 0);
+    try!(stream.read_exact(&mut res_buffer.buffer));
+
+    Ok(try!(DnsPacket::from_buffer(&mut res_buffer)))
+}
+
+/// Bind a UDP socket for sending to `server` to a random unprivileged port,
+/// falling back to any port the OS picks if the random ones are taken
+///
+/// The socket is bound to the unspecified address of the same family as
+/// `server`, which is `[::]` for IPv6 servers.
+fn bind_random_port(server: &SocketAddr) -> Result<UdpSocket> {
+    let ip = match *server {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0))
+    };
+
+    let mut rng = thread_rng();
+    for _ in 0..RANDOM_PORT_ATTEMPTS {
+        let port = rng.gen_range(1024, 65535);
+        if let Ok(socket) = UdpSocket::bind((ip, port)) {
+            return Ok(socket);
+        }
+    }
+
+    UdpSocket::bind((ip, 0))
 }
 
 impl DnsClient for DnsNetworkClient {
@@ -187,6 +356,22 @@ impl DnsClient for DnsNetworkClient {
         self.total_failed.load(Ordering::Acquire)
     }
 
+    fn get_query_timeout(&self) -> SleepDuration {
+        self.query_timeout
+    }
+
+    fn set_query_timeout(&mut self, timeout: SleepDuration) {
+        self.query_timeout = timeout;
+    }
+
+    fn get_randomize_case(&self) -> bool {
+        self.randomize_case
+    }
+
+    fn set_randomize_case(&mut self, enabled: bool) {
+        self.randomize_case = enabled;
+    }
+
     /// The run method launches a worker thread. Unless this thread is running, no
     /// responses will ever be generated, and clients will just block indefinitely.
     fn run(&self) -> Result<()> {
@@ -201,12 +386,12 @@ impl DnsClient for DnsNetworkClient {
                     loop {
                         // Read data into a buffer
                         let mut res_buffer = BytePacketBuffer::new();
-                        match socket_copy.recv_from(&mut res_buffer.buf) {
-                            Ok(_) => {},
+                        let src = match socket_copy.recv_from(&mut res_buffer.buf) {
+                            Ok((_, src)) => src,
                             Err(_) => {
                                 continue;
                             }
-                        }
+                        };
 
                         // Construct a DnsPacket from buffer, skipping the packet if parsing
                         // failed
@@ -219,13 +404,17 @@ impl DnsClient for DnsNetworkClient {
                         };
 
                         // Acquire a lock on the pending_queries list, and search for a
-                        // matching PendingQuery to which to deliver the response.
+                        // matching PendingQuery to which to deliver the response. Anything
+                        // else, such as a spoofed response with the wrong id, is dropped
+                        // and the query keeps waiting until it times out.
                         if let Ok(mut pending_queries) = pending_queries_lock.lock() {
 
                             let mut matched_query = None;
                             for (i, pending_query) in pending_queries.iter().enumerate() {
 
-                                if pending_query.seq == packet.header.id {
+                                if pending_query.server == src &&
+                                   is_response_to(&pending_query.query, &packet) &&
+                                   (!pending_query.exact_case || has_matching_case(&pending_query.query, &packet)) {
 
                                     // Matching query found, send the response
                                     let _ = pending_query.tx.send(Some(packet.clone()));
@@ -240,7 +429,7 @@ impl DnsClient for DnsNetworkClient {
                             if let Some(idx) = matched_query {
                                 pending_queries.remove(idx);
                             } else {
-                                println!("Discarding response for: {:?}", packet.questions[0]);
+                                println!("Discarding response from {} for: {:?}", src, packet.questions.first());
                             }
                         }
                     }
@@ -253,15 +442,14 @@ impl DnsClient for DnsNetworkClient {
 
             try!(Builder::new().name("DnsNetworkClient-timeout-thread".into()).spawn(
                 move || {
-                    let timeout = Duration::seconds(1);
                     loop {
                         if let Ok(mut pending_queries) = pending_queries_lock.lock() {
 
+                            let now = Instant::now();
                             let mut finished_queries = Vec::new();
                             for (i, pending_query) in pending_queries.iter().enumerate() {
 
-                                let expires = pending_query.timestamp + timeout;
-                                if expires < Local::now() {
+                                if pending_query.deadline < now {
                                     let _ = pending_query.tx.send(None);
                                     finished_queries.push(i);
                                 }
@@ -301,9 +489,13 @@ impl DnsClient for DnsNetworkClient {
 #[cfg(test)]
 pub mod tests {
 
+    use std::collections::HashSet;
     use std::io::Result;
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::sync::mpsc::Receiver;
+    use std::thread::spawn;
 
-    use dns::protocol::{DnsPacket,QueryType,DnsRecord};
+    use dns::protocol::{DnsPacket,DnsQuestion,QueryType,DnsRecord,ResultCode,TransientTtl};
     use super::*;
 
     pub type StubCallback = Fn(&str, QueryType, (&str, u16), bool) -> Result<DnsPacket>;
@@ -333,6 +525,20 @@ pub mod tests {
             0
         }
 
+        fn get_query_timeout(&self) -> SleepDuration {
+            SleepDuration::from_secs(DEFAULT_QUERY_TIMEOUT_SECS)
+        }
+
+        fn set_query_timeout(&mut self, _: SleepDuration) {
+        }
+
+        fn get_randomize_case(&self) -> bool {
+            false
+        }
+
+        fn set_randomize_case(&mut self, _: bool) {
+        }
+
         fn run(&self) -> Result<()> {
             Ok(())
         }
@@ -368,6 +574,343 @@ pub mod tests {
         }
     }
 
+    #[test]
+    pub fn test_random_source_ports() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server_port = server.local_addr().unwrap().port();
+
+        // Answers every query with NOERROR, after a spoofed NXDOMAIN from
+        // another port with the same id, and reports the source ports
+        let (tx, rx) = channel();
+        let _ = spawn(move || {
+            let spoofer = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+
+            loop {
+                let mut req_buffer = BytePacketBuffer::new();
+                let (_, src) = server.recv_from(&mut req_buffer.buf).unwrap();
+                let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+                tx.send(src.port()).unwrap();
+
+                let mut spoofed = DnsPacket::new();
+                spoofed.header.id = request.header.id;
+                spoofed.header.response = true;
+                spoofed.header.rescode = ResultCode::NXDOMAIN;
+
+                let mut res_buffer = BytePacketBuffer::new();
+                spoofed.write(&mut res_buffer, 512).unwrap();
+                spoofer.send_to(&res_buffer.buf[0..res_buffer.pos], src).unwrap();
+
+                let mut packet = DnsPacket::new();
+                packet.header.id = request.header.id;
+                packet.header.response = true;
+                packet.questions = request.questions.clone();
+
+                let mut res_buffer = BytePacketBuffer::new();
+                packet.write(&mut res_buffer, 512).unwrap();
+                server.send_to(&res_buffer.buf[0..res_buffer.pos], src).unwrap();
+            }
+        });
+
+        let client = DnsNetworkClient::with_random_ports();
+
+        for _ in 0..5 {
+            let res = client.send_udp_query("google.com",
+                                            QueryType::A,
+                                            ("127.0.0.1", server_port),
+                                            true).unwrap();
+
+            // The spoofed response from another address is never seen
+            assert_eq!(ResultCode::NOERROR, res.header.rescode);
+        }
+
+        assert_eq!(5, client.get_sent_count());
+        assert_eq!(0, client.get_failed_count());
+
+        let ports = rx.try_iter().collect::<HashSet<u16>>();
+        assert!(ports.len() > 1);
+    }
+
+    #[test]
+    pub fn test_is_response_to() {
+        let query = DnsPacket::query("google.com", QueryType::A, true);
+
+        let mut response = DnsPacket::new();
+        response.header.id = query.header.id;
+        response.header.response = true;
+        response.questions.push(DnsQuestion::new("Google.com".to_string(), QueryType::A));
+        assert!(is_response_to(&query, &response));
+
+        // Responses without a question, such as some errors, are matched by id
+        response.questions.clear();
+        assert!(is_response_to(&query, &response));
+
+        response.questions.push(DnsQuestion::new("yahoo.com".to_string(), QueryType::A));
+        assert!(!is_response_to(&query, &response));
+
+        response.questions[0] = DnsQuestion::new("google.com".to_string(), QueryType::AAAA);
+        assert!(!is_response_to(&query, &response));
+
+        response.questions[0] = DnsQuestion::new("google.com".to_string(), QueryType::A);
+        response.header.id = query.header.id.wrapping_add(1);
+        assert!(!is_response_to(&query, &response));
+    }
+
+    #[test]
+    pub fn test_has_matching_case() {
+        let mut query = DnsPacket::query("google.com", QueryType::A, true);
+        query.questions[0].name = "gOoGlE.cOm".to_string();
+
+        let mut response = DnsPacket::answer_for(&query);
+        assert!(has_matching_case(&query, &response));
+
+        response.questions[0].name = "google.com".to_string();
+        assert!(is_response_to(&query, &response));
+        assert!(!has_matching_case(&query, &response));
+    }
+
+    /// Run a server echoing the questions of the queries it receives, with
+    /// the case of every letter flipped if `swap_case` is set, and send the
+    /// names it received on the returned channel
+    fn run_echo_server(swap_case: bool) -> (u16, Receiver<String>) {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server_port = server.local_addr().unwrap().port();
+
+        let (tx, rx) = channel();
+        let _ = spawn(move || {
+            loop {
+                let mut req_buffer = BytePacketBuffer::new();
+                let (_, src) = server.recv_from(&mut req_buffer.buf).unwrap();
+                let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+                tx.send(request.questions[0].name.clone()).unwrap();
+
+                let mut packet = DnsPacket::answer_for(&request);
+                if swap_case {
+                    for question in &mut packet.questions {
+                        question.name = question.name.chars()
+                            .map(|c| if c.is_ascii_lowercase() { c.to_ascii_uppercase() } else { c.to_ascii_lowercase() })
+                            .collect();
+                    }
+                }
+
+                let mut res_buffer = BytePacketBuffer::new();
+                packet.write(&mut res_buffer, 512).unwrap();
+                server.send_to(&res_buffer.buf[0..res_buffer.pos], src).unwrap();
+            }
+        });
+
+        (server_port, rx)
+    }
+
+    #[test]
+    pub fn test_randomize_case() {
+        let (echo_port, echo_rx) = run_echo_server(false);
+        let (swap_port, _swap_rx) = run_echo_server(true);
+
+        let mut shared = DnsNetworkClient::new(0);
+        shared.run().unwrap();
+
+        let mut random = DnsNetworkClient::with_random_ports();
+
+        let qname = "www.longer-name-for-more-letters.example.com";
+        for client in &mut [&mut shared as &mut DnsClient, &mut random as &mut DnsClient] {
+            client.set_randomize_case(true);
+            client.set_query_timeout(SleepDuration::from_millis(300));
+
+            // A response echoing the case is accepted, and handed back with
+            // the name as it was asked for
+            for _ in 0..5 {
+                let res = client.send_query(qname, QueryType::A, ("127.0.0.1", echo_port), true).unwrap();
+                assert_eq!(qname, res.questions[0].name);
+            }
+
+            // A response for the right name, in the wrong case, is dropped
+            match client.send_query(qname, QueryType::A, ("127.0.0.1", swap_port), true) {
+                Ok(_) => panic!(),
+                Err(e) => assert_eq!(ErrorKind::TimedOut, e.kind())
+            }
+        }
+
+        // Queries go out with the same name in varying case
+        let names = echo_rx.try_iter().collect::<HashSet<String>>();
+        assert!(names.len() > 1);
+        assert!(names.iter().all(|x| x.eq_ignore_ascii_case(qname)));
+    }
+
+    #[test]
+    pub fn test_mismatched_id() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server_port = server.local_addr().unwrap().port();
+
+        // Answers every query with a NXDOMAIN carrying the wrong id, and one
+        // for the wrong question, before the actual response
+        let (tx, rx) = channel();
+        let _ = spawn(move || {
+            loop {
+                let mut req_buffer = BytePacketBuffer::new();
+                let (_, src) = server.recv_from(&mut req_buffer.buf).unwrap();
+                let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+                tx.send(request.header.id).unwrap();
+
+                let mut wrong_id = DnsPacket::new();
+                wrong_id.header.id = request.header.id.wrapping_add(1);
+                wrong_id.header.response = true;
+                wrong_id.header.rescode = ResultCode::NXDOMAIN;
+                wrong_id.questions = request.questions.clone();
+
+                let mut wrong_question = DnsPacket::new();
+                wrong_question.header.id = request.header.id;
+                wrong_question.header.response = true;
+                wrong_question.header.rescode = ResultCode::NXDOMAIN;
+                wrong_question.questions.push(DnsQuestion::new("evil.com".to_string(), QueryType::A));
+
+                let mut packet = DnsPacket::new();
+                packet.header.id = request.header.id;
+                packet.header.response = true;
+                packet.questions = request.questions.clone();
+
+                for res in &mut [wrong_id, wrong_question, packet] {
+                    let mut res_buffer = BytePacketBuffer::new();
+                    res.write(&mut res_buffer, 512).unwrap();
+                    server.send_to(&res_buffer.buf[0..res_buffer.pos], src).unwrap();
+                }
+            }
+        });
+
+        // Both with the shared socket, and with random ports, only the
+        // matching response is accepted
+        let client = DnsNetworkClient::new(0);
+        client.run().unwrap();
+
+        for _ in 0..5 {
+            let res = client.send_udp_query("google.com",
+                                            QueryType::A,
+                                            ("127.0.0.1", server_port),
+                                            true).unwrap();
+            assert_eq!(ResultCode::NOERROR, res.header.rescode);
+            assert_eq!("google.com", res.questions[0].name);
+        }
+
+        let client = DnsNetworkClient::with_random_ports();
+        let res = client.send_udp_query("google.com",
+                                        QueryType::A,
+                                        ("127.0.0.1", server_port),
+                                        true).unwrap();
+        assert_eq!(ResultCode::NOERROR, res.header.rescode);
+
+        // The ids aren't sequential
+        let ids = rx.try_iter().collect::<Vec<u16>>();
+        assert_eq!(6, ids.len());
+        assert!(ids.windows(2).any(|x| x[1] != x[0].wrapping_add(1)));
+    }
+
+    #[test]
+    pub fn test_ipv6_server() {
+        let server = UdpSocket::bind(("::1", 0)).unwrap();
+        let server_port = server.local_addr().unwrap().port();
+
+        let _ = spawn(move || {
+            loop {
+                let mut req_buffer = BytePacketBuffer::new();
+                let (_, src) = server.recv_from(&mut req_buffer.buf).unwrap();
+                let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+                let mut packet = DnsPacket::new();
+                packet.header.id = request.header.id;
+                packet.header.response = true;
+                packet.questions = request.questions.clone();
+                packet.answers.push(DnsRecord::A {
+                    domain: "google.com".to_string(),
+                    addr: Ipv4Addr::new(127, 0, 0, 1),
+                    ttl: TransientTtl(3600)
+                });
+
+                let mut res_buffer = BytePacketBuffer::new();
+                packet.write(&mut res_buffer, 512).unwrap();
+                server.send_to(&res_buffer.buf[0..res_buffer.pos], src).unwrap();
+            }
+        });
+
+        // The shared socket of the client is bound to an IPv4 address, which
+        // doesn't keep it from reaching the server
+        let client = DnsNetworkClient::new(0);
+        client.run().unwrap();
+
+        let res = client.send_udp_query("google.com",
+                                        QueryType::A,
+                                        ("::1", server_port),
+                                        true).unwrap();
+        assert_eq!(ResultCode::NOERROR, res.header.rescode);
+        assert_eq!(1, res.answers.len());
+
+        let client = DnsNetworkClient::with_random_ports();
+        let res = client.send_udp_query("google.com",
+                                        QueryType::A,
+                                        ("::1", server_port),
+                                        true).unwrap();
+        assert_eq!(1, res.answers.len());
+    }
+
+    #[test]
+    pub fn test_tcp_mismatched_id() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let _ = spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let len = read_packet_length(&mut stream).unwrap() as usize;
+            let mut req_buffer = VectorPacketBuffer::new();
+            req_buffer.buffer.resize(len, 0);
+            stream.read_exact(&mut req_buffer.buffer).unwrap();
+            let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+            let mut packet = DnsPacket::new();
+            packet.header.id = request.header.id.wrapping_add(1);
+            packet.header.response = true;
+            packet.questions = request.questions.clone();
+
+            let mut res_buffer = VectorPacketBuffer::new();
+            packet.write(&mut res_buffer, 0xFFFF).unwrap();
+            write_packet_length(&mut stream, res_buffer.pos()).unwrap();
+            stream.write_all(&res_buffer.buffer).unwrap();
+        });
+
+        let client = DnsNetworkClient::with_random_ports();
+        match client.send_tcp_query("google.com", QueryType::A, ("127.0.0.1", port), true) {
+            Ok(_) => panic!(),
+            Err(e) => assert_eq!(ErrorKind::InvalidData, e.kind())
+        }
+    }
+
+    #[test]
+    pub fn test_query_timeout() {
+        // A server which never answers
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server_port = server.local_addr().unwrap().port();
+
+        let mut shared = DnsNetworkClient::new(0);
+        shared.run().unwrap();
+
+        let mut random = DnsNetworkClient::with_random_ports();
+
+        assert_eq!(SleepDuration::from_secs(DEFAULT_QUERY_TIMEOUT_SECS), shared.get_query_timeout());
+
+        for client in &mut [&mut shared as &mut DnsClient, &mut random as &mut DnsClient] {
+            client.set_query_timeout(SleepDuration::from_millis(300));
+
+            let start = Instant::now();
+            match client.send_query("google.com", QueryType::A, ("127.0.0.1", server_port), true) {
+                Ok(_) => panic!(),
+                Err(e) => assert_eq!(ErrorKind::TimedOut, e.kind())
+            }
+
+            let elapsed = start.elapsed();
+            assert!(elapsed >= SleepDuration::from_millis(300));
+            assert!(elapsed < SleepDuration::from_secs(DEFAULT_QUERY_TIMEOUT_SECS));
+            assert_eq!(1, client.get_failed_count());
+        }
+    }
+
     #[test]
     pub fn test_tcp_client() {
         let client = DnsNetworkClient::new(31457);
@@ -386,4 +929,102 @@ pub mod tests {
             _ => panic!()
         }
     }
+
+    /// Serve queries on `listener` with 40 A records, too many to fit a UDP
+    /// response, and on a UDP socket on the same port with a truncated
+    /// response
+    fn run_truncating_server() -> u16 {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let udp_server = UdpSocket::bind(("127.0.0.1", port)).unwrap();
+
+        let build_response = |request: &DnsPacket| {
+            let mut packet = DnsPacket::new();
+            packet.header.id = request.header.id;
+            packet.header.response = true;
+            packet.questions = request.questions.clone();
+            for i in 0..40 {
+                packet.answers.push(DnsRecord::A {
+                    domain: request.questions[0].name.clone(),
+                    addr: Ipv4Addr::new(10, 0, 0, i),
+                    ttl: TransientTtl(3600)
+                });
+            }
+            packet
+        };
+
+        let _ = spawn(move || {
+            loop {
+                let mut req_buffer = BytePacketBuffer::new();
+                let (_, src) = udp_server.recv_from(&mut req_buffer.buf).unwrap();
+                let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+                let mut res_buffer = VectorPacketBuffer::new();
+                build_response(&request).write(&mut res_buffer, 512).unwrap();
+                udp_server.send_to(&res_buffer.buffer, src).unwrap();
+            }
+        });
+
+        let _ = spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+
+                let len = read_packet_length(&mut stream).unwrap() as usize;
+                let mut req_buffer = VectorPacketBuffer::new();
+                req_buffer.buffer.resize(len, 0);
+                stream.read_exact(&mut req_buffer.buffer).unwrap();
+                let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+                let mut res_buffer = VectorPacketBuffer::new();
+                build_response(&request).write(&mut res_buffer, 0xFFFF).unwrap();
+
+                // The response is written in two parts, to check that the
+                // client waits for all of it
+                write_packet_length(&mut stream, res_buffer.pos()).unwrap();
+                stream.write_all(&res_buffer.buffer[0..100]).unwrap();
+                stream.flush().unwrap();
+                sleep(SleepDuration::from_millis(10));
+                stream.write_all(&res_buffer.buffer[100..]).unwrap();
+            }
+        });
+
+        port
+    }
+
+    #[test]
+    pub fn test_tcp_framing() {
+        let port = run_truncating_server();
+        let client = DnsNetworkClient::with_random_ports();
+
+        let res = client.send_tcp_query("google.com",
+                                        QueryType::A,
+                                        ("127.0.0.1", port),
+                                        true).unwrap();
+
+        assert!(!res.header.truncated_message);
+        assert_eq!("google.com", res.questions[0].name);
+        assert_eq!(40, res.answers.len());
+    }
+
+    #[test]
+    pub fn test_tcp_fallback() {
+        let port = run_truncating_server();
+        let client = DnsNetworkClient::with_random_ports();
+
+        // Over UDP the response is truncated
+        let res = client.send_udp_query("google.com",
+                                        QueryType::A,
+                                        ("127.0.0.1", port),
+                                        true).unwrap();
+        assert!(res.header.truncated_message);
+        assert!(res.answers.len() < 40);
+
+        // and then resent over TCP
+        let res = client.send_query("google.com",
+                                    QueryType::A,
+                                    ("127.0.0.1", port),
+                                    true).unwrap();
+        assert!(!res.header.truncated_message);
+        assert_eq!(40, res.answers.len());
+    }
 }
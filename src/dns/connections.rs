@@ -0,0 +1,194 @@
+//! bookkeeping of open TCP connections, so that idle clients can't tie up
+//! the server
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+/// Default number of TCP connections open at once, beyond which the idlest
+/// connection is closed for a new one
+pub const DEFAULT_MAX_TCP_CONNECTIONS: usize = 256;
+
+/// Default number of TCP connections open at once from a single client
+pub const DEFAULT_MAX_TCP_CONNECTIONS_PER_CLIENT: usize = 16;
+
+struct Connection {
+    client: IpAddr,
+    last_active: Instant,
+
+    /// A handle to the stream, for closing the connection from another
+    /// thread when it's evicted
+    stream: TcpStream
+}
+
+/// The TCP connections currently open
+///
+/// Each connection is registered with `open` when accepted, and with `close`
+/// once it's done. Once `max_connections` are open, the connection that has
+/// gone the longest without a query is shut down to make room for a new one,
+/// while clients at `max_per_client` connections are refused outright.
+pub struct TcpConnections {
+    pub max_connections: usize,
+    pub max_per_client: usize,
+    connections: Mutex<HashMap<usize, Connection>>,
+    next_id: AtomicUsize,
+    evicted_count: AtomicUsize
+}
+
+impl Default for TcpConnections {
+    fn default() -> Self {
+        TcpConnections::new()
+    }
+}
+
+impl TcpConnections {
+    pub fn new() -> TcpConnections {
+        TcpConnections {
+            max_connections: DEFAULT_MAX_TCP_CONNECTIONS,
+            max_per_client: DEFAULT_MAX_TCP_CONNECTIONS_PER_CLIENT,
+            connections: Mutex::new(HashMap::new()),
+            next_id: AtomicUsize::new(0),
+            evicted_count: AtomicUsize::new(0)
+        }
+    }
+
+    /// Register a new connection from `client`, returning its id, or `None`
+    /// if the client already has as many connections as it may
+    pub fn open(&self, client: IpAddr, stream: &TcpStream) -> Option<usize> {
+        let handle = match stream.try_clone() {
+            Ok(x) => x,
+            Err(_) => return None
+        };
+
+        let mut connections = match self.connections.lock() {
+            Ok(x) => x,
+            Err(_) => return None
+        };
+
+        if connections.values().filter(|x| x.client == client).count() >= self.max_per_client {
+            return None;
+        }
+
+        if connections.len() >= self.max_connections {
+            let idlest = connections.iter()
+                .min_by_key(|&(_, x)| x.last_active)
+                .map(|(id, _)| *id);
+
+            if let Some(conn) = idlest.and_then(|id| connections.remove(&id)) {
+                let _ = conn.stream.shutdown(Shutdown::Both);
+                let _ = self.evicted_count.fetch_add(1, Ordering::Release);
+            }
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::AcqRel);
+        connections.insert(id, Connection {
+            client: client,
+            last_active: Instant::now(),
+            stream: handle
+        });
+
+        Some(id)
+    }
+
+    /// Mark connection `id` as active, having just received a query
+    pub fn touch(&self, id: usize) {
+        if let Ok(mut connections) = self.connections.lock() {
+            if let Some(conn) = connections.get_mut(&id) {
+                conn.last_active = Instant::now();
+            }
+        }
+    }
+
+    pub fn close(&self, id: usize) {
+        if let Ok(mut connections) = self.connections.lock() {
+            connections.remove(&id);
+        }
+    }
+
+    /// The number of connections currently open
+    pub fn count(&self) -> usize {
+        self.connections.lock().map(|x| x.len()).unwrap_or(0)
+    }
+
+    /// The number of connections closed to make room for new ones
+    pub fn get_evicted_count(&self) -> usize {
+        self.evicted_count.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Open a connection to `listener`, returning both ends
+    fn connect(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_evict_idlest() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let localhost = "127.0.0.1".parse().unwrap();
+
+        let mut connections = TcpConnections::new();
+        connections.max_connections = 2;
+
+        let (mut first, first_server) = connect(&listener);
+        let (_second, second_server) = connect(&listener);
+        let first_id = connections.open(localhost, &first_server).unwrap();
+        sleep(Duration::from_millis(10));
+        let second_id = connections.open(localhost, &second_server).unwrap();
+
+        // A query on the second connection leaves the first as the idlest,
+        // so it's closed to make room
+        sleep(Duration::from_millis(10));
+        connections.touch(second_id);
+
+        let (_third, third_server) = connect(&listener);
+        assert!(connections.open(localhost, &third_server).is_some());
+        assert_eq!(2, connections.count());
+        assert_eq!(1, connections.get_evicted_count());
+
+        first.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = [0; 1];
+        assert_eq!(0, first.read(&mut buf).unwrap());
+
+        // Closing an evicted connection is harmless
+        connections.close(first_id);
+        assert_eq!(2, connections.count());
+    }
+
+    #[test]
+    fn test_per_client_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = "192.0.2.1".parse().unwrap();
+        let other = "192.0.2.2".parse().unwrap();
+
+        let mut connections = TcpConnections::new();
+        connections.max_per_client = 2;
+
+        let streams = (0..4).map(|_| connect(&listener)).collect::<Vec<_>>();
+
+        let first_id = connections.open(client, &streams[0].1).unwrap();
+        assert!(connections.open(client, &streams[1].1).is_some());
+        assert!(connections.open(client, &streams[2].1).is_none());
+
+        // Other clients aren't affected
+        assert!(connections.open(other, &streams[2].1).is_some());
+
+        // and once a connection is closed, the client may open another
+        connections.close(first_id);
+        assert!(connections.open(client, &streams[3].1).is_some());
+        assert_eq!(0, connections.get_evicted_count());
+    }
+}
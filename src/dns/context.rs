@@ -1,20 +1,131 @@
 //! The `ServerContext in this thread holds the common state across the server
 
-use std::io::Result;
+use std::io::{Result,Error,ErrorKind};
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize,Ordering};
+use std::time::Duration;
 
-use dns::resolve::{DnsResolver,RecursiveDnsResolver,ForwardingDnsResolver};
+use dns::resolve::{DnsResolver,RecursiveDnsResolver,ForwardingDnsResolver,StaticDnsResolver};
 use dns::client::{DnsClient,DnsNetworkClient};
+use dns::filter::ResponseFilter;
+use dns::answermap::AnswerMap;
 use dns::cache::SynchronizedCache;
 use dns::authority::Authority;
+use dns::blocklist::Blocklists;
+use dns::responsecache::ResponseCache;
+use dns::inflight::{QueryLimiter, InflightQueries};
+use dns::tunnel::TunnelDetector;
+use dns::ratelimit::RateLimiter;
+use dns::connections::TcpConnections;
+use dns::querylog::QueryLog;
+use dns::netutil::Network;
+use dns::tsig::TsigKey;
+use dns::health::DEFAULT_HEALTH_CHECK_INTERVAL;
+use dns::protocol::{DnsPacket, DnsRecord, QueryType, ResultCode};
+
+/// Default histogram buckets for response sizes, in bytes
+pub const RESPONSE_SIZE_BUCKETS: [usize; 7] = [64, 128, 256, 512, 1024, 2048, 4096];
+
+/// Default histogram buckets for query latency, in milliseconds
+pub const QUERY_LATENCY_BUCKETS: [usize; 10] = [1, 5, 10, 25, 50, 100, 250, 500, 750, 1000];
+
+/// A lock free histogram with fixed bucket boundaries
+///
+/// Each bucket counts the observations less than or equal to its upper bound
+/// but greater than the bound of the previous bucket. Observations exceeding
+/// the largest bound are only reflected in the total count and sum.
+pub struct Histogram {
+    bounds: Vec<usize>,
+    buckets: Vec<AtomicUsize>,
+    count: AtomicUsize,
+    sum: AtomicUsize
+}
+
+impl Histogram {
+    pub fn new(bounds: &[usize]) -> Histogram {
+        Histogram {
+            bounds: bounds.to_vec(),
+            buckets: bounds.iter().map(|_| AtomicUsize::new(0)).collect(),
+            count: AtomicUsize::new(0),
+            sum: AtomicUsize::new(0)
+        }
+    }
+
+    pub fn observe(&self, value: usize) {
+        if let Some(idx) = self.bounds.iter().position(|x| value <= *x) {
+            let _ = self.buckets[idx].fetch_add(1, Ordering::Release);
+        }
+
+        let _ = self.count.fetch_add(1, Ordering::Release);
+        let _ = self.sum.fetch_add(value, Ordering::Release);
+    }
+
+    pub fn get_count(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    pub fn get_sum(&self) -> usize {
+        self.sum.load(Ordering::Acquire)
+    }
+
+    /// Returns pairs of upper bound and cumulative count for each bucket
+    pub fn get_buckets(&self) -> Vec<(usize, usize)> {
+        let mut cumulative = 0;
+        self.bounds.iter().zip(self.buckets.iter()).map(|(bound, bucket)| {
+            cumulative += bucket.load(Ordering::Acquire);
+            (*bound, cumulative)
+        }).collect()
+    }
+
+    /// Render the histogram in the Prometheus text exposition format
+    pub fn write_prometheus(&self, name: &str, help: &str, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, count) in self.get_buckets() {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.get_count());
+        let _ = writeln!(out, "{}_sum {}", name, self.get_sum());
+        let _ = writeln!(out, "{}_count {}", name, self.get_count());
+    }
+}
 
 pub struct ServerStatistics {
     pub tcp_query_count: AtomicUsize,
-    pub udp_query_count: AtomicUsize
+    pub udp_query_count: AtomicUsize,
+
+    /// Packets dropped for having the QR bit set, meaning that they're
+    /// responses rather than queries
+    pub dropped_response_count: AtomicUsize,
+
+    /// Responses sent, indexed by the result code in their header
+    pub rescode_counts: Vec<AtomicUsize>,
+
+    pub response_size: Histogram,
+    pub query_latency: Histogram
+}
+
+impl Default for ServerStatistics {
+    fn default() -> Self {
+        ServerStatistics::new()
+    }
 }
 
 impl ServerStatistics {
+    pub fn new() -> ServerStatistics {
+        ServerStatistics {
+            tcp_query_count: AtomicUsize::new(0),
+            udp_query_count: AtomicUsize::new(0),
+            dropped_response_count: AtomicUsize::new(0),
+            rescode_counts: (0..16).map(|_| AtomicUsize::new(0)).collect(),
+            response_size: Histogram::new(&RESPONSE_SIZE_BUCKETS),
+            query_latency: Histogram::new(&QUERY_LATENCY_BUCKETS)
+        }
+    }
+
     pub fn get_tcp_query_count(&self) -> usize {
         self.tcp_query_count.load(Ordering::Acquire)
     }
@@ -22,6 +133,27 @@ impl ServerStatistics {
     pub fn get_udp_query_count(&self) -> usize {
         self.udp_query_count.load(Ordering::Acquire)
     }
+
+    pub fn get_dropped_response_count(&self) -> usize {
+        self.dropped_response_count.load(Ordering::Acquire)
+    }
+
+    /// Count a response sent with `rescode`, of which only the four bits in
+    /// the header are kept apart
+    pub fn record_rescode(&self, rescode: ResultCode) {
+        let idx = (rescode.to_num() & 0x0F) as usize;
+        let _ = self.rescode_counts[idx].fetch_add(1, Ordering::Release);
+    }
+
+    /// The number of responses sent with each result code, leaving out the
+    /// codes that haven't been sent at all
+    pub fn get_rescode_counts(&self) -> Vec<(ResultCode, usize)> {
+        self.rescode_counts.iter()
+            .enumerate()
+            .map(|(i, x)| (ResultCode::from_num(i as u16), x.load(Ordering::Acquire)))
+            .filter(|&(_, count)| count > 0)
+            .collect()
+    }
 }
 
 pub enum ResolveStrategy {
@@ -29,19 +161,139 @@ pub enum ResolveStrategy {
     Forward {
         host: String,
         port: u16
+    },
+
+    /// Answer only from the `answer_map`, for testing
+    Static
+}
+
+/// How to respond to ANY queries received over UDP
+///
+/// ANY queries are a popular vector for amplification attacks, since a small
+/// query can produce a large response. Queries received over TCP are always
+/// answered in full.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum AnyPolicy {
+    /// Answer with every record available
+    Full,
+
+    /// Answer with a single record set
+    Minimal,
+
+    /// Answer with an empty response with the TC flag set, forcing the client
+    /// to retry over TCP
+    TruncateUdp
+}
+
+impl FromStr for AnyPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<AnyPolicy> {
+        match s {
+            "full" => Ok(AnyPolicy::Full),
+            "minimal" => Ok(AnyPolicy::Minimal),
+            "tc-on-udp" => Ok(AnyPolicy::TruncateUdp),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "Unknown ANY policy"))
+        }
     }
 }
 
+/// Default number of seconds to remember failed upstream lookups
+pub const DEFAULT_SERVFAIL_TTL: u32 = 5;
+
+/// Default number of UDP queries waiting for a worker thread, beyond which
+/// queries are answered with SERVFAIL
+pub const DEFAULT_MAX_UDP_QUEUE: usize = 1024;
+
+/// Default number of seconds before an idle TCP connection is closed
+pub const DEFAULT_TCP_IDLE_TIMEOUT: u64 = 10;
+
+/// Default number of seconds before an idle TCP connection is closed, for
+/// clients using EDNS TCP keepalive
+pub const DEFAULT_TCP_KEEPALIVE_TIMEOUT: u64 = 30;
+
+/// Default number of seconds a TCP connection is kept open, however active
+pub const DEFAULT_TCP_MAX_LIFETIME: u64 = 120;
+
+/// Default TTL for records added without one, in zones without their own
+/// default
+pub const DEFAULT_TTL: u32 = 3600;
+
 pub struct ServerContext {
     pub authority: Authority,
+    pub default_ttl: u32,
+    pub allow_transfer: Vec<Network>,
+
+    /// Names of the TSIG keys allowed to transfer zones without a list of
+    /// their own
+    pub allow_transfer_keys: Vec<String>,
+
+    /// Networks of clients that get the addresses on their own network first
+    pub sortlist: Vec<Network>,
+
+    /// Custom logic applied to every response, in order, before it's sent
+    pub response_filters: Vec<Box<ResponseFilter + Sync + Send>>,
+
+    pub tsig_keys: Vec<TsigKey>,
+    pub blocklists: Blocklists,
     pub cache: SynchronizedCache,
+
+    /// NS records of the root servers, and their addresses, which answer
+    /// queries for the root when there's no zone for it
+    pub root_hints: Vec<DnsRecord>,
+
     pub client: Box<DnsClient + Sync + Send>,
+    pub query_limiter: QueryLimiter,
+    pub inflight: InflightQueries,
+    pub tunnel_detector: TunnelDetector,
+
+    /// Limits the rate of queries over UDP from each client
+    pub rate_limiter: RateLimiter,
+
+    pub servfail_ttl: u32,
     pub dns_port: u16,
     pub api_port: u16,
     pub resolve_strategy: ResolveStrategy,
+    pub answer_map: AnswerMap,
     pub allow_recursive: bool,
+    pub any_policy: AnyPolicy,
+
+    /// Response code for requests with an opcode other than QUERY
+    pub unsupported_opcode_rescode: ResultCode,
+
+    pub whoami_name: Option<String>,
+    pub response_cache: ResponseCache,
+    pub enable_response_cache: bool,
+    pub strip_additional: bool,
+
+    /// Rotate the addresses of authoritative answers between queries
+    pub round_robin: bool,
+
+    /// Log each packet dropped for having the QR bit set, on top of counting
+    /// them
+    pub log_dropped_responses: bool,
+
+    /// Let the /resolve endpoint of the web server query upstreams on any
+    /// port, rather than only port 53 and the forwarder
+    pub resolve_any_upstream: bool,
+
+    pub query_log: QueryLog,
     pub enable_udp: bool,
     pub enable_tcp: bool,
+    pub max_udp_queue: usize,
+    pub tcp_idle_timeout: Duration,
+    pub tcp_keepalive_timeout: Duration,
+    pub tcp_max_lifetime: Duration,
+    pub tcp_connections: TcpConnections,
+    pub health_check_interval: Duration,
+
+    /// Interval between snapshots of the cache statistics written to the log,
+    /// which are disabled while unset
+    pub cache_log_interval: Option<Duration>,
+
+    /// File the cache is saved to periodically, and loaded from on startup
+    pub cache_path: Option<PathBuf>,
+
     pub enable_api: bool,
     pub statistics: ServerStatistics
 }
@@ -56,19 +308,48 @@ impl ServerContext {
     pub fn new() -> ServerContext {
         ServerContext {
             authority: Authority::new(),
+            default_ttl: DEFAULT_TTL,
+            allow_transfer: Vec::new(),
+            allow_transfer_keys: Vec::new(),
+            sortlist: Vec::new(),
+            response_filters: Vec::new(),
+            tsig_keys: Vec::new(),
+            blocklists: Blocklists::new(),
             cache: SynchronizedCache::new(),
+            root_hints: Vec::new(),
             client: Box::new(DnsNetworkClient::new(34255)),
+            query_limiter: QueryLimiter::new(),
+            inflight: InflightQueries::new(),
+            tunnel_detector: TunnelDetector::new(),
+            rate_limiter: RateLimiter::new(),
+            servfail_ttl: DEFAULT_SERVFAIL_TTL,
             dns_port: 53,
             api_port: 5380,
             resolve_strategy: ResolveStrategy::Recursive,
+            answer_map: AnswerMap::new(),
             allow_recursive: true,
+            any_policy: AnyPolicy::Full,
+            unsupported_opcode_rescode: ResultCode::NOTIMP,
+            whoami_name: None,
+            response_cache: ResponseCache::new(),
+            enable_response_cache: false,
+            strip_additional: false,
+            round_robin: false,
+            log_dropped_responses: false,
+            resolve_any_upstream: false,
+            query_log: QueryLog::new(),
             enable_udp: true,
             enable_tcp: true,
+            max_udp_queue: DEFAULT_MAX_UDP_QUEUE,
+            tcp_idle_timeout: Duration::from_secs(DEFAULT_TCP_IDLE_TIMEOUT),
+            tcp_keepalive_timeout: Duration::from_secs(DEFAULT_TCP_KEEPALIVE_TIMEOUT),
+            tcp_max_lifetime: Duration::from_secs(DEFAULT_TCP_MAX_LIFETIME),
+            tcp_connections: TcpConnections::new(),
+            health_check_interval: Duration::from_secs(DEFAULT_HEALTH_CHECK_INTERVAL),
+            cache_log_interval: None,
+            cache_path: None,
             enable_api: true,
-            statistics: ServerStatistics {
-                tcp_query_count: AtomicUsize::new(0),
-                udp_query_count: AtomicUsize::new(0)
-            }
+            statistics: ServerStatistics::new()
         }
     }
 
@@ -82,12 +363,24 @@ impl ServerContext {
         Ok(())
     }
 
+    /// Send a query to an upstream server, subject to the `query_limiter`
+    pub fn send_upstream(&self,
+                         qname: &str,
+                         qtype: QueryType,
+                         server: (&str, u16),
+                         recursive: bool) -> Result<DnsPacket> {
+
+        let _permit = try!(self.query_limiter.acquire());
+        self.client.send_query(qname, qtype, server, recursive)
+    }
+
     pub fn create_resolver(&self, ptr: Arc<ServerContext>) -> Box<DnsResolver> {
         match self.resolve_strategy {
             ResolveStrategy::Recursive => Box::new(RecursiveDnsResolver::new(ptr)),
             ResolveStrategy::Forward { ref host, port } => {
                 Box::new(ForwardingDnsResolver::new(ptr, (host.clone(), port)))
-            }
+            },
+            ResolveStrategy::Static => Box::new(StaticDnsResolver::new(ptr))
         }
     }
 }
@@ -96,9 +389,9 @@ impl ServerContext {
 pub mod tests {
 
     use std::sync::Arc;
-    use std::sync::atomic::AtomicUsize;
 
     use dns::authority::Authority;
+    use dns::blocklist::Blocklists;
     use dns::cache::SynchronizedCache;
 
     use dns::client::tests::{StubCallback,DnsStubClient};
@@ -109,21 +402,73 @@ pub mod tests {
 
         Arc::new(ServerContext {
             authority: Authority::new(),
+            default_ttl: DEFAULT_TTL,
+            allow_transfer: Vec::new(),
+            allow_transfer_keys: Vec::new(),
+            sortlist: Vec::new(),
+            response_filters: Vec::new(),
+            tsig_keys: Vec::new(),
+            blocklists: Blocklists::new(),
             cache: SynchronizedCache::new(),
+            root_hints: Vec::new(),
             client: Box::new(DnsStubClient::new(callback)),
+            query_limiter: QueryLimiter::new(),
+            inflight: InflightQueries::new(),
+            tunnel_detector: TunnelDetector::new(),
+            rate_limiter: RateLimiter::new(),
+            servfail_ttl: DEFAULT_SERVFAIL_TTL,
             dns_port: 53,
             api_port: 5380,
             resolve_strategy: ResolveStrategy::Recursive,
+            answer_map: AnswerMap::new(),
             allow_recursive: true,
+            any_policy: AnyPolicy::Full,
+            unsupported_opcode_rescode: ResultCode::NOTIMP,
+            whoami_name: None,
+            response_cache: ResponseCache::new(),
+            enable_response_cache: false,
+            strip_additional: false,
+            round_robin: false,
+            log_dropped_responses: false,
+            resolve_any_upstream: false,
+            query_log: QueryLog::new(),
             enable_udp: true,
             enable_tcp: true,
+            max_udp_queue: DEFAULT_MAX_UDP_QUEUE,
+            tcp_idle_timeout: Duration::from_secs(DEFAULT_TCP_IDLE_TIMEOUT),
+            tcp_keepalive_timeout: Duration::from_secs(DEFAULT_TCP_KEEPALIVE_TIMEOUT),
+            tcp_max_lifetime: Duration::from_secs(DEFAULT_TCP_MAX_LIFETIME),
+            tcp_connections: TcpConnections::new(),
+            health_check_interval: Duration::from_secs(DEFAULT_HEALTH_CHECK_INTERVAL),
+            cache_log_interval: None,
+            cache_path: None,
             enable_api: true,
-            statistics: ServerStatistics {
-                tcp_query_count: AtomicUsize::new(0),
-                udp_query_count: AtomicUsize::new(0)
-            }
+            statistics: ServerStatistics::new()
         })
 
     }
 
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::new(&[10, 100]);
+        histogram.observe(5);
+        histogram.observe(10);
+        histogram.observe(50);
+        histogram.observe(500);
+
+        assert_eq!(4, histogram.get_count());
+        assert_eq!(565, histogram.get_sum());
+        assert_eq!(vec![(10, 2), (100, 3)], histogram.get_buckets());
+
+        let mut out = String::new();
+        histogram.write_prometheus("test", "A test histogram", &mut out);
+
+        assert_eq!("# HELP test A test histogram\n\
+                    # TYPE test histogram\n\
+                    test_bucket{le=\"10\"} 2\n\
+                    test_bucket{le=\"100\"} 3\n\
+                    test_bucket{le=\"+Inf\"} 4\n\
+                    test_sum 565\n\
+                    test_count 4\n", out);
+    }
 }
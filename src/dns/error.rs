@@ -0,0 +1,114 @@
+//! errors that occur when reading and writing dns packets
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::result;
+
+pub type DnsResult<T> = result::Result<T, DnsError>;
+
+/// What went wrong while handling the wire format of a packet
+///
+/// It converts to and from `std::io::Error`, so the two can be mixed using
+/// `try!`. Errors other than `Io` become errors of kind `InvalidData`, apart
+/// from `Truncated` which becomes `InvalidInput`, as the buffers reported it
+/// before.
+#[derive(Debug)]
+pub enum DnsError {
+    /// The packet ended before what was being read
+    Truncated,
+
+    /// A label of a name was longer than the 63 bytes allowed
+    LabelTooLong(usize),
+
+    /// A name was longer than the 255 bytes allowed
+    NameTooLong(usize),
+
+    /// A name had compression pointers pointing forward, or more of them
+    /// than `MAX_JUMPS`
+    CompressionLoop,
+
+    /// Part of a record overran the length of its data
+    Malformed(&'static str),
+
+    /// An error from the underlying stream
+    Io(io::Error)
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DnsError::LabelTooLong(len) => write!(f, "Label of {} bytes is too long", len),
+            DnsError::NameTooLong(len) => write!(f, "Name of {} bytes is too long", len),
+            DnsError::Malformed(msg) => write!(f, "{}", msg),
+            DnsError::Io(ref e) => write!(f, "{}", e),
+            _ => write!(f, "{}", self.description())
+        }
+    }
+}
+
+impl Error for DnsError {
+    fn description(&self) -> &str {
+        match *self {
+            DnsError::Truncated => "End of buffer",
+            DnsError::LabelTooLong(_) => "Label too long",
+            DnsError::NameTooLong(_) => "Name too long",
+            DnsError::CompressionLoop => "Too many compression pointers",
+            DnsError::Malformed(msg) => msg,
+            DnsError::Io(ref e) => e.description()
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            DnsError::Io(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for DnsError {
+    fn from(err: io::Error) -> DnsError {
+        DnsError::Io(err)
+    }
+}
+
+impl From<DnsError> for io::Error {
+    fn from(err: DnsError) -> io::Error {
+        match err {
+            DnsError::Io(e) => e,
+            DnsError::Truncated => io::Error::new(io::ErrorKind::InvalidInput, err),
+            _ => io::Error::new(io::ErrorKind::InvalidData, err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{Error, ErrorKind};
+
+    use super::*;
+
+    #[test]
+    fn test_io_conversion() {
+        let err: io::Error = DnsError::Truncated.into();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+
+        let err: io::Error = DnsError::CompressionLoop.into();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+
+        // The original error can still be told apart after the conversion
+        match err.into_inner().and_then(|x| x.downcast::<DnsError>().ok()) {
+            Some(x) => match *x {
+                DnsError::CompressionLoop => {},
+                _ => panic!()
+            },
+            None => panic!()
+        }
+
+        // while io errors are passed through as they are
+        let err: io::Error = DnsError::from(Error::new(ErrorKind::TimedOut, "Timeout")).into();
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+    }
+}
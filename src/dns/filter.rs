@@ -0,0 +1,25 @@
+//! hooks for modifying responses right before they're sent
+
+use std::net::SocketAddr;
+
+use dns::protocol::DnsPacket;
+
+/// Custom logic applied to every response
+///
+/// Filters run after all of the built in processing, such as pool selection
+/// and the sortlist, just before the response is serialized. They're free
+/// to change anything about the response, including the header.
+pub trait ResponseFilter {
+    fn apply(&self, query: &DnsPacket, response: &mut DnsPacket, client: SocketAddr);
+}
+
+/// Apply each of `filters` to `response`, in order
+pub fn apply_filters(filters: &[Box<ResponseFilter + Sync + Send>],
+                     query: &DnsPacket,
+                     response: &mut DnsPacket,
+                     client: SocketAddr) {
+
+    for filter in filters {
+        filter.apply(query, response, client);
+    }
+}
@@ -0,0 +1,231 @@
+//! health checks for the addresses of A and AAAA records, which are left out
+//! of answers while their checks are failing
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{Result, Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread::{Builder, sleep};
+use std::time::Duration;
+
+use dns::authority::Authority;
+use dns::context::ServerContext;
+use dns::protocol::DnsRecord;
+
+/// Default number of seconds between rounds of health checks
+pub const DEFAULT_HEALTH_CHECK_INTERVAL: u64 = 10;
+
+/// Seconds to wait for a target before considering it down
+const HEALTH_CHECK_TIMEOUT_SECS: u64 = 2;
+
+/// How the address of a record is checked
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum HealthCheck {
+    /// Connect to the port over TCP
+    Tcp(u16),
+
+    /// Request the path from the port over HTTP, expecting a 2xx or 3xx
+    /// status
+    Http(u16, String)
+}
+
+impl HealthCheck {
+    /// Check the target at `addr`, returning whether it's up
+    pub fn run(&self, addr: IpAddr, timeout: Duration) -> bool {
+        match *self {
+            HealthCheck::Tcp(port) => TcpStream::connect_timeout(&SocketAddr::new(addr, port), timeout).is_ok(),
+            HealthCheck::Http(port, ref path) => check_http(addr, port, path, timeout).unwrap_or(false)
+        }
+    }
+}
+
+fn check_http(addr: IpAddr, port: u16, path: &str, timeout: Duration) -> Result<bool> {
+    let mut stream = try!(TcpStream::connect_timeout(&SocketAddr::new(addr, port), timeout));
+    try!(stream.set_read_timeout(Some(timeout)));
+    try!(stream.set_write_timeout(Some(timeout)));
+
+    let host = match addr {
+        IpAddr::V4(x) => x.to_string(),
+        IpAddr::V6(x) => format!("[{}]", x)
+    };
+
+    try!(write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host));
+
+    // Only the status line is of interest
+    let mut status_line = [0; 12];
+    try!(stream.read_exact(&mut status_line));
+
+    let status_line = String::from_utf8_lossy(&status_line).to_string();
+    Ok(status_line.starts_with("HTTP/") && (status_line.ends_with(" 2") || status_line.ends_with(" 3")))
+}
+
+impl FromStr for HealthCheck {
+    type Err = Error;
+
+    /// Parse a check in the format `tcp:PORT` or `http:PORT[/PATH]`
+    fn from_str(s: &str) -> Result<HealthCheck> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid health check {}", s));
+
+        let parts = s.trim().splitn(2, ':').collect::<Vec<&str>>();
+        if parts.len() != 2 {
+            return Err(invalid());
+        }
+
+        let (port, path) = match parts[1].find('/') {
+            Some(idx) => (&parts[1][..idx], &parts[1][idx..]),
+            None => (parts[1], "/")
+        };
+
+        let port = match port.parse::<u16>() {
+            Ok(x) if x > 0 => x,
+            _ => return Err(invalid())
+        };
+
+        match parts[0] {
+            "tcp" if !parts[1].contains('/') => Ok(HealthCheck::Tcp(port)),
+            "http" => Ok(HealthCheck::Http(port, path.to_string())),
+            _ => Err(invalid())
+        }
+    }
+}
+
+impl fmt::Display for HealthCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HealthCheck::Tcp(port) => write!(f, "tcp:{}", port),
+            HealthCheck::Http(port, ref path) => write!(f, "http:{}{}", port, path)
+        }
+    }
+}
+
+/// Run the health checks of all zones once, and record which records are
+/// down
+///
+/// Returns the number of records that are down.
+pub fn check_all(authority: &Authority, timeout: Duration) -> usize {
+    let checks = match authority.read() {
+        Ok(zones) => {
+            zones.zones().iter()
+                .flat_map(|zone| zone.health_checks.iter())
+                .map(|(rec, check)| (rec.clone(), check.clone()))
+                .collect::<Vec<(DnsRecord, HealthCheck)>>()
+        },
+        Err(_) => return 0
+    };
+
+    // The lock isn't held while checking
+    let mut down = BTreeSet::new();
+    for (rec, check) in checks {
+        let addr = match rec {
+            DnsRecord::A { addr, .. } => IpAddr::V4(addr),
+            DnsRecord::AAAA { addr, .. } => IpAddr::V6(addr),
+            _ => continue
+        };
+
+        if !check.run(addr, timeout) {
+            down.insert(rec);
+        }
+    }
+
+    let count = down.len();
+    authority.set_down(down);
+
+    count
+}
+
+/// Start a thread running the health checks at the interval configured in
+/// `context`
+pub fn start_health_checker(context: Arc<ServerContext>) -> Result<()> {
+    let timeout = Duration::from_secs(HEALTH_CHECK_TIMEOUT_SECS);
+
+    let _ = try!(Builder::new().name("HealthChecker".into()).spawn(move || {
+        loop {
+            check_all(&context.authority, timeout);
+            sleep(context.health_check_interval);
+        }
+    }));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::net::TcpListener;
+
+    use dns::authority::Zone;
+    use dns::protocol::{QueryType, TransientTtl};
+
+    use super::*;
+
+    fn build_a(domain: &str, addr: &str) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            addr: addr.parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }
+    }
+
+    fn query_addrs(authority: &Authority) -> Vec<String> {
+        authority.query("www.example.com", QueryType::A).unwrap().answers.iter()
+            .filter_map(|x| match *x {
+                DnsRecord::A { addr, .. } => Some(addr.to_string()),
+                _ => None
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_health_check() {
+        assert_eq!(HealthCheck::Tcp(80), "tcp:80".parse::<HealthCheck>().unwrap());
+        assert_eq!(HealthCheck::Http(8080, "/".to_string()), "http:8080".parse::<HealthCheck>().unwrap());
+        assert_eq!(HealthCheck::Http(80, "/health".to_string()), "http:80/health".parse::<HealthCheck>().unwrap());
+        assert_eq!("http:80/health", HealthCheck::Http(80, "/health".to_string()).to_string());
+
+        assert!("tcp".parse::<HealthCheck>().is_err());
+        assert!("tcp:0".parse::<HealthCheck>().is_err());
+        assert!("tcp:80/health".parse::<HealthCheck>().is_err());
+        assert!("icmp:80".parse::<HealthCheck>().is_err());
+    }
+
+    #[test]
+    fn test_failover() {
+        // Only 127.0.0.1 is listening on the port
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let up = build_a("www.example.com", "127.0.0.1");
+        let down = build_a("www.example.com", "127.0.0.2");
+
+        let authority = Authority::new();
+        {
+            let mut zones = authority.write().unwrap();
+
+            let mut zone = Zone::new("example.com".to_string(), String::new(), String::new());
+            zone.add_record(&up);
+            zone.add_record(&down);
+            zone.health_checks.insert(up.clone(), HealthCheck::Tcp(port));
+            zone.health_checks.insert(down.clone(), HealthCheck::Tcp(port));
+            zones.add_zone(zone).unwrap();
+        }
+
+        // Until checked, every address is assumed to be up
+        assert_eq!(2, query_addrs(&authority).len());
+
+        let generation = authority.generation();
+        assert_eq!(1, check_all(&authority, Duration::from_secs(1)));
+        assert!(authority.generation() > generation);
+
+        // The down target is excluded, while the up target is returned
+        assert_eq!(vec!["127.0.0.1".to_string()], query_addrs(&authority));
+        assert!(authority.is_down(&down));
+        assert!(!authority.is_down(&up));
+
+        // With every target down, all of them are returned
+        drop(listener);
+        assert_eq!(2, check_all(&authority, Duration::from_secs(1)));
+        assert_eq!(2, query_addrs(&authority).len());
+    }
+}
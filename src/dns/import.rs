@@ -0,0 +1,296 @@
+//! bulk import of records into the authority
+
+use std::io::{Result, Error, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use dns::authority::{Zone, Zones};
+use dns::protocol::{DnsRecord, TransientTtl, split_character_strings};
+
+/// The outcome of an import
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct ImportReport {
+    pub added: usize,
+    pub zones_created: usize,
+
+    /// Rows that couldn't be imported, as line numbers and reasons
+    pub errors: Vec<(usize, String)>
+}
+
+/// Split a line of CSV into its fields
+///
+/// Fields may be quoted, in which case they can contain commas, and quotes
+/// are escaped by doubling them.
+fn split_csv_line(line: &str) -> Result<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes => {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    let _ = chars.next();
+                } else {
+                    in_quotes = false;
+                }
+            },
+            '"' if field.trim().is_empty() => {
+                field.clear();
+                in_quotes = true;
+            },
+            ',' if !in_quotes => {
+                fields.push(field.trim().to_string());
+                field.clear();
+            },
+            _ => field.push(c)
+        }
+    }
+
+    if in_quotes {
+        return Err(Error::new(ErrorKind::InvalidData, "Unterminated quote"));
+    }
+
+    fields.push(field.trim().to_string());
+
+    Ok(fields)
+}
+
+/// Expand `name` relative to `zone`
+///
+/// `@` or an empty name refers to the zone itself, and names not already
+/// within the zone have it appended.
+fn expand_name(name: &str, zone: &str) -> String {
+    let name = name.trim_right_matches('.').to_lowercase();
+
+    if name.is_empty() || name == "@" {
+        zone.to_string()
+    } else if name == zone || name.ends_with(&format!(".{}", zone)) {
+        name
+    } else {
+        format!("{}.{}", name, zone)
+    }
+}
+
+/// Build a record from the fields of a row
+pub fn build_record(domain: &str,
+                    rtype: &str,
+                    ttl: u32,
+                    value: &str,
+                    priority: Option<u16>) -> Result<DnsRecord> {
+
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidInput, format!("{} for {} record", msg, rtype));
+
+    let domain = domain.to_string();
+    let ttl = TransientTtl(ttl);
+
+    match rtype.to_uppercase().as_str() {
+        "A" => match value.parse::<Ipv4Addr>() {
+            Ok(addr) => Ok(DnsRecord::A { domain: domain, addr: addr, ttl: ttl }),
+            Err(_) => Err(invalid("Invalid address"))
+        },
+        "AAAA" => match value.parse::<Ipv6Addr>() {
+            Ok(addr) => Ok(DnsRecord::AAAA { domain: domain, addr: addr, ttl: ttl }),
+            Err(_) => Err(invalid("Invalid address"))
+        },
+        "NS" | "CNAME" | "PTR" | "DNAME" | "ANAME" | "ALIAS" | "MX" => {
+            let host = value.trim_right_matches('.').to_lowercase();
+            if host.is_empty() {
+                return Err(invalid("Missing host"));
+            }
+
+            match rtype.to_uppercase().as_str() {
+                "NS" => Ok(DnsRecord::NS { domain: domain, host: host, ttl: ttl }),
+                "CNAME" => Ok(DnsRecord::CNAME { domain: domain, host: host, ttl: ttl }),
+                "PTR" => Ok(DnsRecord::PTR { domain: domain, host: host, ttl: ttl }),
+                "DNAME" => Ok(DnsRecord::DNAME { domain: domain, host: host, ttl: ttl }),
+                "ANAME" | "ALIAS" => Ok(DnsRecord::ANAME { domain: domain, host: host, ttl: ttl }),
+                _ => match priority {
+                    Some(priority) => Ok(DnsRecord::MX {
+                        domain: domain,
+                        priority: priority,
+                        host: host,
+                        ttl: ttl
+                    }),
+                    None => Err(invalid("Missing priority"))
+                }
+            }
+        },
+        "TXT" => Ok(DnsRecord::TXT { domain: domain, data: split_character_strings(value), ttl: ttl }),
+        "SPF" => Ok(DnsRecord::SPF { domain: domain, data: split_character_strings(value), ttl: ttl }),
+        _ => Err(Error::new(ErrorKind::InvalidInput, format!("Unsupported record type {}", rtype)))
+    }
+}
+
+/// Import records from CSV data into `zones`
+///
+/// Each row has the columns `zone,name,type,ttl,value[,priority]`, where the
+/// priority is only used for MX records. Rows with an empty TTL use the zone
+/// default, or `default_ttl` if the zone has none. A header row, blank lines and lines
+/// starting with `#` are skipped. Zones that don't exist are created. Rows
+/// that fail are reported by line number, without stopping the import.
+///
+/// Nothing is saved, so the caller should save the zones once done.
+pub fn import_csv(zones: &mut Zones, data: &str, default_ttl: u32) -> ImportReport {
+    let mut report = ImportReport::default();
+
+    for (idx, line) in data.lines().enumerate() {
+        let line_no = idx + 1;
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields = match split_csv_line(line) {
+            Ok(x) => x,
+            Err(e) => {
+                report.errors.push((line_no, e.to_string()));
+                continue;
+            }
+        };
+
+        if idx == 0 && fields[0].to_lowercase() == "zone" {
+            continue;
+        }
+
+        if fields.len() < 5 || fields.len() > 6 {
+            report.errors.push((line_no, format!("Expected 5 or 6 columns, found {}", fields.len())));
+            continue;
+        }
+
+        let zone_name = fields[0].trim_right_matches('.').to_lowercase();
+        if zone_name.is_empty() {
+            report.errors.push((line_no, "Missing zone".to_string()));
+            continue;
+        }
+
+        let ttl = if fields[3].is_empty() {
+            zones.get_zone(&zone_name)
+                .and_then(|x| x.default_ttl)
+                .unwrap_or(default_ttl)
+        } else {
+            match fields[3].parse::<u32>() {
+                Ok(x) => x,
+                Err(_) => {
+                    report.errors.push((line_no, format!("Invalid TTL {}", fields[3])));
+                    continue;
+                }
+            }
+        };
+
+        let priority = match fields.get(5).map(|x| x.as_str()) {
+            None | Some("") => None,
+            Some(x) => match x.parse::<u16>() {
+                Ok(x) => Some(x),
+                Err(_) => {
+                    report.errors.push((line_no, format!("Invalid priority {}", x)));
+                    continue;
+                }
+            }
+        };
+
+        let domain = expand_name(&fields[1], &zone_name);
+        let rec = match build_record(&domain, &fields[2], ttl, &fields[4], priority) {
+            Ok(x) => x,
+            Err(e) => {
+                report.errors.push((line_no, e.to_string()));
+                continue;
+            }
+        };
+
+        if zones.get_zone(&zone_name).is_none() {
+            let zone = Zone::new(zone_name.clone(), String::new(), String::new());
+            if let Err(e) = zones.add_zone(zone) {
+                report.errors.push((line_no, e.to_string()));
+                continue;
+            }
+
+            report.zones_created += 1;
+        }
+
+        match zones.add_record(&zone_name, &rec) {
+            Ok(true) => report.added += 1,
+            Ok(false) => {},
+            Err(e) => report.errors.push((line_no, e.to_string()))
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_split_csv_line() {
+        assert_eq!(vec!["a", "b", "", "c"], split_csv_line("a, b,,c").unwrap());
+        assert_eq!(vec!["example.com", "@", "TXT", "300", "v=spf1, \"quoted\""],
+                   split_csv_line("example.com,@,TXT,300,\"v=spf1, \"\"quoted\"\"\"").unwrap());
+        assert!(split_csv_line("a,\"b").is_err());
+    }
+
+    #[test]
+    fn test_import_csv() {
+        let csv = "zone,name,type,ttl,value,priority
+example.com,www,A,3600,127.0.0.1
+example.com,@,MX,3600,mail.example.com.,10
+example.com,ftp.example.com,CNAME,300,www.example.com
+example.com,www,A,3600,127.0.0.1
+example.com,bad,A,3600,not-an-address
+example.com,mail,MX,3600,mail.example.com
+example.com,www,A,soon,127.0.0.2
+example.com,www,A,3600
+";
+
+        let mut zones = Zones::new();
+        let report = import_csv(&mut zones, csv, 3600);
+
+        assert_eq!(3, report.added);
+        assert_eq!(1, report.zones_created);
+        assert_eq!(vec![6, 7, 8, 9], report.errors.iter().map(|x| x.0).collect::<Vec<usize>>());
+
+        let zone = zones.get_zone("example.com").unwrap();
+        assert_eq!(3, zone.records.len());
+        assert!(zone.records.contains(&DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }));
+        assert!(zone.records.contains(&DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: TransientTtl(3600)
+        }));
+        assert!(zone.records.contains(&DnsRecord::CNAME {
+            domain: "ftp.example.com".to_string(),
+            host: "www.example.com".to_string(),
+            ttl: TransientTtl(300)
+        }));
+    }
+
+    #[test]
+    fn test_import_csv_default_ttl() {
+        let csv = "example.com,www,A,,127.0.0.1
+example.org,www,A,,127.0.0.1
+";
+
+        let mut zones = Zones::new();
+        let mut zone = Zone::new("example.com".to_string(), String::new(), String::new());
+        zone.default_ttl = Some(600);
+        zones.add_zone(zone).unwrap();
+
+        let report = import_csv(&mut zones, csv, 3600);
+        assert_eq!(2, report.added);
+
+        let zone = zones.get_zone("example.com").unwrap();
+        assert_eq!(Some(600), zone.records.iter().next().map(|x| x.get_ttl()));
+
+        let zone = zones.get_zone("example.org").unwrap();
+        assert_eq!(Some(3600), zone.records.iter().next().map(|x| x.get_ttl()));
+    }
+}
@@ -0,0 +1,251 @@
+//! bookkeeping for queries in progress towards upstream servers
+
+use std::collections::HashMap;
+use std::io::{Result, Error, ErrorKind};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use dns::protocol::{DnsPacket, QueryType};
+
+/// Default upper bound on the number of concurrent upstream queries
+pub const DEFAULT_MAX_CONCURRENT: usize = 64;
+
+/// Default upper bound on the number of upstream queries waiting for a slot
+pub const DEFAULT_MAX_QUEUED: usize = 256;
+
+/// How long to wait for a duplicate query in progress before giving up
+const COALESCE_TIMEOUT_MS: u64 = 10_000;
+
+struct LimiterState {
+    active: usize,
+    queued: usize
+}
+
+/// A counting semaphore for upstream queries
+///
+/// At most `max_concurrent` queries are allowed at any time. Beyond that, up
+/// to `max_queued` callers will block until a slot frees up, while anything
+/// more is rejected outright.
+pub struct QueryLimiter {
+    state: Mutex<LimiterState>,
+    cond: Condvar,
+    pub max_concurrent: usize,
+    pub max_queued: usize
+}
+
+/// A slot acquired from a `QueryLimiter`, which is released when dropped
+pub struct QueryPermit<'a> {
+    limiter: &'a QueryLimiter
+}
+
+impl<'a> Drop for QueryPermit<'a> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.limiter.state.lock() {
+            state.active -= 1;
+            self.limiter.cond.notify_one();
+        }
+    }
+}
+
+impl Default for QueryLimiter {
+    fn default() -> Self {
+        QueryLimiter::new()
+    }
+}
+
+impl QueryLimiter {
+    pub fn new() -> QueryLimiter {
+        QueryLimiter {
+            state: Mutex::new(LimiterState {
+                active: 0,
+                queued: 0
+            }),
+            cond: Condvar::new(),
+            max_concurrent: DEFAULT_MAX_CONCURRENT,
+            max_queued: DEFAULT_MAX_QUEUED
+        }
+    }
+
+    /// Acquire a slot, waiting for one to become available if needed
+    ///
+    /// Fails if the queue of waiting callers is already full.
+    pub fn acquire(&self) -> Result<QueryPermit> {
+        let mut state = match self.state.lock() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        if state.active >= self.max_concurrent {
+            if state.queued >= self.max_queued {
+                return Err(Error::new(ErrorKind::Other, "Too many upstream queries in progress"));
+            }
+
+            state.queued += 1;
+            while state.active >= self.max_concurrent {
+                state = match self.cond.wait(state) {
+                    Ok(x) => x,
+                    Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+                };
+            }
+            state.queued -= 1;
+        }
+
+        state.active += 1;
+
+        Ok(QueryPermit {
+            limiter: self
+        })
+    }
+
+    pub fn get_active_count(&self) -> usize {
+        match self.state.lock() {
+            Ok(x) => x.active,
+            Err(_) => 0
+        }
+    }
+}
+
+/// A query in progress, along with its result once available
+struct InflightQuery {
+    result: Mutex<Option<Option<DnsPacket>>>,
+    cond: Condvar
+}
+
+/// Coalescing of identical queries in progress
+///
+/// The first caller for a given name and type performs the query, while any
+/// others arriving before it completes wait for the same result.
+#[derive(Default)]
+pub struct InflightQueries {
+    queries: Mutex<HashMap<(String, QueryType), Arc<InflightQuery>>>,
+
+    /// Number of queries answered with the result of another query
+    coalesced: AtomicUsize
+}
+
+impl InflightQueries {
+    pub fn new() -> InflightQueries {
+        InflightQueries {
+            queries: Mutex::new(HashMap::new()),
+            coalesced: AtomicUsize::new(0)
+        }
+    }
+
+    pub fn get_coalesced_count(&self) -> usize {
+        self.coalesced.load(Ordering::Acquire)
+    }
+
+    /// Run `perform` for `(qname, qtype)`, unless an identical query is
+    /// already in progress, in which case its result is shared
+    pub fn coalesce<F>(&self, qname: &str, qtype: QueryType, perform: F) -> Result<DnsPacket>
+        where F: FnOnce() -> Result<DnsPacket>
+    {
+        let key = (qname.to_lowercase(), qtype);
+
+        let (query, is_leader) = {
+            let mut queries = match self.queries.lock() {
+                Ok(x) => x,
+                Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+            };
+
+            match queries.get(&key) {
+                Some(x) => (x.clone(), false),
+                None => {
+                    let query = Arc::new(InflightQuery {
+                        result: Mutex::new(None),
+                        cond: Condvar::new()
+                    });
+                    queries.insert(key.clone(), query.clone());
+
+                    (query, true)
+                }
+            }
+        };
+
+        if is_leader {
+            let result = perform();
+
+            if let Ok(mut queries) = self.queries.lock() {
+                queries.remove(&key);
+            }
+
+            if let Ok(mut shared) = query.result.lock() {
+                *shared = Some(result.as_ref().ok().cloned());
+                query.cond.notify_all();
+            }
+
+            return result;
+        }
+
+        let _ = self.coalesced.fetch_add(1, Ordering::Release);
+
+        let mut shared = match query.result.lock() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        // Don't wait forever, in case the query being waited for somehow
+        // depends on this one
+        let timeout = Duration::from_millis(COALESCE_TIMEOUT_MS);
+        while shared.is_none() {
+            shared = match query.cond.wait_timeout(shared, timeout) {
+                Ok((x, wait_result)) => {
+                    if wait_result.timed_out() && x.is_none() {
+                        return Err(Error::new(ErrorKind::TimedOut, "Timed out waiting for query in progress"));
+                    }
+                    x
+                },
+                Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+            };
+        }
+
+        match *shared {
+            Some(Some(ref packet)) => Ok(packet.clone()),
+            _ => Err(Error::new(ErrorKind::Other, "Query in progress failed"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_query_limiter() {
+        let mut limiter = QueryLimiter::new();
+        limiter.max_concurrent = 2;
+        limiter.max_queued = 0;
+
+        {
+            let _a = limiter.acquire().unwrap();
+            let _b = limiter.acquire().unwrap();
+            assert_eq!(2, limiter.get_active_count());
+
+            // Both slots are taken, and there's no room to queue
+            assert!(limiter.acquire().is_err());
+        }
+
+        // The slots are released once the permits go out of scope
+        assert_eq!(0, limiter.get_active_count());
+        assert!(limiter.acquire().is_ok());
+    }
+
+    #[test]
+    fn test_coalesce_sequential() {
+        let inflight = InflightQueries::new();
+
+        // Nothing is shared once a query has completed
+        let mut count = 0;
+        for _ in 0..2 {
+            let res = inflight.coalesce("google.com", QueryType::A, || {
+                count += 1;
+                Ok(DnsPacket::new())
+            });
+            assert!(res.is_ok());
+        }
+
+        assert_eq!(2, count);
+    }
+}
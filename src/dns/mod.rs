@@ -1,12 +1,27 @@
 //! The dns module implements the DNS protocol and the related functions
 
+pub mod answermap;
 pub mod authority;
+pub mod blocklist;
 pub mod buffer;
 pub mod cache;
+pub mod cachelog;
 pub mod client;
+pub mod connections;
+pub mod error;
+pub mod filter;
+pub mod health;
+pub mod import;
+pub mod inflight;
+pub mod netutil;
 pub mod protocol;
+pub mod querylog;
+pub mod ratelimit;
 pub mod resolve;
+pub mod responsecache;
 pub mod server;
+pub mod transfer;
+pub mod tsig;
+pub mod tunnel;
+pub mod zonefile;
 pub mod context;
-
-mod netutil;
@@ -1,9 +1,11 @@
-use std::io::{Result,Write,Read};
-use std::net::TcpStream;
+use std::fmt;
+use std::io::{Result,Error,ErrorKind,Write,Read};
+use std::net::{IpAddr,TcpStream};
+use std::str::FromStr;
 
 pub fn read_packet_length(stream: &mut TcpStream) -> Result<u16> {
     let mut len_buffer = [0; 2];
-    try!(stream.read(&mut len_buffer));
+    try!(stream.read_exact(&mut len_buffer));
 
     Ok(((len_buffer[0] as u16) << 8) | (len_buffer[1] as u16))
 }
@@ -17,3 +19,146 @@ pub fn write_packet_length(stream: &mut TcpStream, len: usize) -> Result<()> {
 
     Ok(())
 }
+
+/// A network in CIDR notation, such as `192.0.2.0/24`
+///
+/// A plain address is taken to be a network of just that address.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct Network {
+    pub addr: IpAddr,
+    pub prefix_len: u8
+}
+
+fn ip_octets(addr: &IpAddr) -> Vec<u8> {
+    match *addr {
+        IpAddr::V4(x) => x.octets().to_vec(),
+        IpAddr::V6(x) => x.octets().to_vec()
+    }
+}
+
+impl Network {
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let network = ip_octets(&self.addr);
+        let addr = ip_octets(addr);
+
+        if network.len() != addr.len() {
+            return false;
+        }
+
+        let mut remaining = self.prefix_len as usize;
+        for (a, b) in network.iter().zip(addr.iter()) {
+            if remaining == 0 {
+                break;
+            }
+
+            let bits = if remaining > 8 { 8 } else { remaining };
+            let mask = (0xFF00 >> bits) as u8;
+            if a & mask != b & mask {
+                return false;
+            }
+
+            remaining -= bits;
+        }
+
+        true
+    }
+}
+
+/// The name to look up the PTR records of `addr` by
+///
+/// IPv4 addresses are written as their octets in reverse order under
+/// `in-addr.arpa`, and IPv6 addresses as their nibbles in reverse order
+/// under `ip6.arpa`, such as `4.3.2.1.in-addr.arpa` for `1.2.3.4`.
+pub fn reverse_name(addr: IpAddr) -> String {
+    let octets = ip_octets(&addr);
+
+    let mut labels = match addr {
+        IpAddr::V4(_) => octets.iter().map(|x| x.to_string()).collect::<Vec<String>>(),
+        IpAddr::V6(_) => octets.iter()
+            .flat_map(|x| vec![format!("{:x}", x >> 4), format!("{:x}", x & 0x0F)])
+            .collect::<Vec<String>>()
+    };
+    labels.reverse();
+
+    labels.push(match addr {
+        IpAddr::V4(_) => "in-addr.arpa".to_string(),
+        IpAddr::V6(_) => "ip6.arpa".to_string()
+    });
+
+    labels.join(".")
+}
+
+impl FromStr for Network {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Network> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid network {}", s));
+
+        let parts = s.trim().splitn(2, '/').collect::<Vec<&str>>();
+        let addr = match parts[0].parse::<IpAddr>() {
+            Ok(x) => x,
+            Err(_) => return Err(invalid())
+        };
+
+        let max_prefix_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+
+        let prefix_len = match parts.get(1) {
+            Some(x) => match x.parse::<u8>() {
+                Ok(x) if x <= max_prefix_len => x,
+                _ => return Err(invalid())
+            },
+            None => max_prefix_len
+        };
+
+        Ok(Network {
+            addr: addr,
+            prefix_len: prefix_len
+        })
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_network() {
+        let network = "192.0.2.0/24".parse::<Network>().unwrap();
+        assert!(network.contains(&"192.0.2.77".parse().unwrap()));
+        assert!(!network.contains(&"192.0.3.1".parse().unwrap()));
+        assert!(!network.contains(&"::1".parse().unwrap()));
+        assert_eq!("192.0.2.0/24", network.to_string());
+
+        let network = "2001:db8::1".parse::<Network>().unwrap();
+        assert_eq!(128, network.prefix_len);
+        assert!(network.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!network.contains(&"2001:db8::2".parse().unwrap()));
+
+        assert!("0.0.0.0/0".parse::<Network>().unwrap().contains(&"203.0.113.1".parse().unwrap()));
+
+        assert!("192.0.2.0/33".parse::<Network>().is_err());
+        assert!("example.com".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn test_reverse_name() {
+        assert_eq!("4.3.2.1.in-addr.arpa", reverse_name("1.2.3.4".parse().unwrap()));
+        assert_eq!("1.0.0.127.in-addr.arpa", reverse_name("127.0.0.1".parse().unwrap()));
+
+        // The example from RFC 3596 section 2.5
+        assert_eq!("b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.0.0.0.0.1.2.3.4.ip6.arpa",
+                   reverse_name("4321:0:1:2:3:4:567:89ab".parse().unwrap()));
+        assert_eq!("1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa",
+                   reverse_name("::1".parse().unwrap()));
+    }
+}
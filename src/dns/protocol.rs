@@ -1,15 +1,18 @@
 //! implements the DNS protocol in a transport agnostic fashion
 
-//use std::io::{Error, ErrorKind};
-use std::cmp::Ordering;
+use std::io::{Error, ErrorKind};
+use std::cmp::{self, Ordering};
 use std::fmt;
 use std::hash::{Hash,Hasher};
 use std::io::{Result, Read};
 use std::net::{Ipv4Addr,Ipv6Addr};
+use std::str::FromStr;
 
-use rand::random;
+use rand::{Rng, random};
+use rustc_serialize::hex::{FromHex, ToHex};
 
 use dns::buffer::{PacketBuffer, VectorPacketBuffer};
+use dns::error::{DnsError, DnsResult};
 
 /// `QueryType` represents the requested Record Type of a query
 ///
@@ -24,11 +27,24 @@ pub enum QueryType {
     NS, // 2
     CNAME, // 5
     SOA, // 6
+    PTR, // 12
     MX, // 15
     TXT, // 16
     AAAA, // 28
     SRV, // 33
-    OPT // 41
+    NAPTR, // 35
+    DNAME, // 39
+    OPT, // 41
+    SSHFP, // 44
+    DHCID, // 49
+    TLSA, // 52
+    SPF, // 99
+    TSIG, // 250
+    IXFR, // 251
+    AXFR, // 252
+    ANY, // 255
+    CAA, // 257
+    ANAME // 65305
 }
 
 impl QueryType {
@@ -39,11 +55,24 @@ impl QueryType {
             QueryType::NS => 2,
             QueryType::CNAME => 5,
             QueryType::SOA => 6,
+            QueryType::PTR => 12,
             QueryType::MX => 15,
             QueryType::TXT => 16,
             QueryType::AAAA => 28,
             QueryType::SRV => 33,
-            QueryType::OPT => 41
+            QueryType::NAPTR => 35,
+            QueryType::DNAME => 39,
+            QueryType::OPT => 41,
+            QueryType::SSHFP => 44,
+            QueryType::DHCID => 49,
+            QueryType::TLSA => 52,
+            QueryType::SPF => 99,
+            QueryType::TSIG => 250,
+            QueryType::IXFR => 251,
+            QueryType::AXFR => 252,
+            QueryType::ANY => 255,
+            QueryType::CAA => 257,
+            QueryType::ANAME => 65305
         }
     }
 
@@ -53,16 +82,116 @@ impl QueryType {
             2 => QueryType::NS,
             5 => QueryType::CNAME,
             6 => QueryType::SOA,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
             16 => QueryType::TXT,
             28 => QueryType::AAAA,
             33 => QueryType::SRV,
+            35 => QueryType::NAPTR,
+            39 => QueryType::DNAME,
             41 => QueryType::OPT,
+            44 => QueryType::SSHFP,
+            49 => QueryType::DHCID,
+            52 => QueryType::TLSA,
+            99 => QueryType::SPF,
+            250 => QueryType::TSIG,
+            251 => QueryType::IXFR,
+            252 => QueryType::AXFR,
+            255 => QueryType::ANY,
+            257 => QueryType::CAA,
+            65305 => QueryType::ANAME,
             _ => QueryType::UNKNOWN(num)
         }
     }
 }
 
+impl FromStr for QueryType {
+    type Err = Error;
+
+    /// Parse the name of a type, such as `AAAA`, or `TYPE` followed by its
+    /// number as in RFC 3597
+    fn from_str(s: &str) -> Result<QueryType> {
+        let upper = s.to_uppercase();
+        let qtype = match upper.as_str() {
+            "A" => QueryType::A,
+            "NS" => QueryType::NS,
+            "CNAME" => QueryType::CNAME,
+            "SOA" => QueryType::SOA,
+            "PTR" => QueryType::PTR,
+            "MX" => QueryType::MX,
+            "TXT" => QueryType::TXT,
+            "AAAA" => QueryType::AAAA,
+            "SRV" => QueryType::SRV,
+            "NAPTR" => QueryType::NAPTR,
+            "DNAME" => QueryType::DNAME,
+            "OPT" => QueryType::OPT,
+            "SSHFP" => QueryType::SSHFP,
+            "DHCID" => QueryType::DHCID,
+            "TLSA" => QueryType::TLSA,
+            "SPF" => QueryType::SPF,
+            "TSIG" => QueryType::TSIG,
+            "IXFR" => QueryType::IXFR,
+            "AXFR" => QueryType::AXFR,
+            "ANY" => QueryType::ANY,
+            "CAA" => QueryType::CAA,
+            "ANAME" => QueryType::ANAME,
+            _ if upper.starts_with("TYPE") => {
+                match upper[4..].parse::<u16>() {
+                    Ok(num) => QueryType::from_num(num),
+                    Err(_) => return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid type {}", s)))
+                }
+            },
+            _ => return Err(Error::new(ErrorKind::InvalidInput, format!("Unknown type {}", s)))
+        };
+
+        Ok(qtype)
+    }
+}
+
+/// The kind of request a packet holds, as given by the opcode of the header
+///
+/// Opcodes without a variant of their own are kept as `UNKNOWN`, and can be
+/// converted to and from their number with `to_num` and `from_num`.
+#[derive(PartialEq,Eq,Debug,Clone,Hash,Copy)]
+pub enum OpCode {
+    UNKNOWN(u8),
+    QUERY, // 0
+    IQUERY, // 1
+    STATUS, // 2
+    NOTIFY, // 4
+    UPDATE // 5
+}
+
+impl OpCode {
+    pub fn to_num(&self) -> u8 {
+        match *self {
+            OpCode::UNKNOWN(x) => x,
+            OpCode::QUERY => 0,
+            OpCode::IQUERY => 1,
+            OpCode::STATUS => 2,
+            OpCode::NOTIFY => 4,
+            OpCode::UPDATE => 5
+        }
+    }
+
+    pub fn from_num(num: u8) -> OpCode {
+        match num {
+            0 => OpCode::QUERY,
+            1 => OpCode::IQUERY,
+            2 => OpCode::STATUS,
+            4 => OpCode::NOTIFY,
+            5 => OpCode::UPDATE,
+            _ => OpCode::UNKNOWN(num)
+        }
+    }
+}
+
+impl Default for OpCode {
+    fn default() -> Self {
+        OpCode::QUERY
+    }
+}
+
 #[derive(Copy,Clone,Debug,Eq,Ord)]
 pub struct TransientTtl(pub u32);
 
@@ -84,6 +213,22 @@ impl Hash for TransientTtl {
     }
 }
 
+/// The class used by pseudo-records such as TSIG, matching any class
+pub const CLASS_ANY: u16 = 255;
+
+/// EDNS option code for TCP keepalive, as specified in RFC 7828
+pub const EDNS_TCP_KEEPALIVE: u16 = 11;
+
+/// EDNS option code for padding, as specified in RFC 7830
+pub const EDNS_PADDING: u16 = 12;
+
+/// An option carried in the data of an OPT record
+#[derive(Debug,Clone,PartialEq,Eq,Hash,PartialOrd,Ord)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>
+}
+
 /// `DnsRecord` is the primary representation of a DNS record
 ///
 /// This enumeration is used for reading as well as writing records, from network
@@ -112,6 +257,11 @@ pub enum DnsRecord {
         host: String,
         ttl: TransientTtl
     }, // 5
+    PTR {
+        domain: String,
+        host: String,
+        ttl: TransientTtl
+    }, // 12
     SOA {
         domain: String,
         m_name: String,
@@ -131,7 +281,10 @@ pub enum DnsRecord {
     }, // 15
     TXT {
         domain: String,
-        data: String,
+        /// The character-strings of the record, in order. Any longer than
+        /// `MAX_CHARACTER_STRING_LEN` bytes are split up when written, so
+        /// they're read back as several.
+        data: Vec<String>,
         ttl: TransientTtl
     }, // 16
     AAAA {
@@ -147,17 +300,115 @@ pub enum DnsRecord {
         host: String,
         ttl: TransientTtl
     }, // 33
+
+    /// A rule for rewriting a string, such as a telephone number for ENUM,
+    /// into a URI or the next name to look up, as specified in RFC 3403
+    NAPTR {
+        domain: String,
+        order: u16,
+        preference: u16,
+        flags: String,
+        services: String,
+        regexp: String,
+
+        /// The next name to look up, or the root, given as an empty name,
+        /// when the regexp applies instead
+        replacement: String,
+        ttl: TransientTtl
+    }, // 35
+
+    /// Redirection of the names below `domain` to the same names below
+    /// `host`, as specified in RFC 6672
+    DNAME {
+        domain: String,
+        host: String,
+        ttl: TransientTtl
+    }, // 39
     OPT {
         packet_len: u16,
         flags: u32,
-        data: String
-    } // 41
+        options: Vec<EdnsOption>
+    }, // 41
+
+    /// The fingerprint of an SSH host key, as specified in RFC 4255, with
+    /// the fingerprint given in hex
+    SSHFP {
+        domain: String,
+        algorithm: u8,
+        fp_type: u8,
+        fingerprint: String,
+        ttl: TransientTtl
+    }, // 44
+
+    /// An opaque identifier of a DHCP client, as specified in RFC 4701
+    DHCID {
+        domain: String,
+        data: Vec<u8>,
+        ttl: TransientTtl
+    }, // 49
+
+    /// The certificate or public key of a TLS server, or a digest of it, as
+    /// specified for DANE in RFC 6698, with the data given in hex
+    TLSA {
+        domain: String,
+        usage: u8,
+        selector: u8,
+        matching_type: u8,
+        cert_data: String,
+        ttl: TransientTtl
+    }, // 52
+    SPF {
+        domain: String,
+        data: Vec<String>,
+        ttl: TransientTtl
+    }, // 99
+
+    /// A transaction signature, as specified in RFC 8945, which is named
+    /// after the key used and always has a TTL of zero
+    TSIG {
+        domain: String,
+        algorithm: String,
+        time_signed: u64,
+        fudge: u16,
+        mac: Vec<u8>,
+        original_id: u16,
+        error: u16,
+        other: Vec<u8>
+    }, // 250
+
+    /// The certificate authorities allowed to issue certificates for the
+    /// domain, as specified in RFC 8659
+    CAA {
+        domain: String,
+        flags: u8,
+        tag: String,
+        value: String,
+        ttl: TransientTtl
+    }, // 257
+
+    /// A pseudo-record for the apex of a zone, which is answered with the
+    /// addresses of `host`, resolved at query time
+    ANAME {
+        domain: String,
+        host: String,
+        ttl: TransientTtl
+    } // 65305
 }
 
 impl DnsRecord {
 
+    /// Build a TXT record holding `data`, which is split up into as many
+    /// character-strings as needed
+    pub fn txt(domain: &str, data: &str, ttl: u32) -> DnsRecord {
+        DnsRecord::TXT {
+            domain: domain.to_string(),
+            data: split_character_strings(data),
+            ttl: TransientTtl(ttl)
+        }
+    }
+
     #[allow(identity_op,cyclomatic_complexity)]
-    pub fn read<T: PacketBuffer>(buffer: &mut T) -> Result<DnsRecord> {
+    pub fn read<T: PacketBuffer>(buffer: &mut T) -> DnsResult<DnsRecord> {
         let mut domain = String::new();
         try!(buffer.read_qname(&mut domain));
 
@@ -211,10 +462,38 @@ impl DnsRecord {
                     ttl: TransientTtl(ttl)
                 })
             },
-            QueryType::CNAME => {
+            QueryType::PTR => {
+                let mut host = String::new();
+                try!(buffer.read_qname(&mut host));
+
+                Ok(DnsRecord::PTR {
+                    domain: domain,
+                    host: host,
+                    ttl: TransientTtl(ttl)
+                })
+            },
+            QueryType::DNAME => {
+                let mut host = String::new();
+                try!(buffer.read_qname(&mut host));
+
+                Ok(DnsRecord::DNAME {
+                    domain: domain,
+                    host: host,
+                    ttl: TransientTtl(ttl)
+                })
+            },
+            QueryType::CNAME | QueryType::ANAME => {
                 let mut cname = String::new();
                 try!(buffer.read_qname(&mut cname));
 
+                if qtype == QueryType::ANAME {
+                    return Ok(DnsRecord::ANAME {
+                        domain: domain,
+                        host: cname,
+                        ttl: TransientTtl(ttl)
+                    });
+                }
+
                 Ok(DnsRecord::CNAME {
                     domain: domain,
                     host: cname,
@@ -238,6 +517,29 @@ impl DnsRecord {
                     ttl: TransientTtl(ttl)
                 })
             },
+            QueryType::NAPTR => {
+                let end = buffer.pos() + data_len as usize;
+
+                let order = try!(buffer.read_u16());
+                let preference = try!(buffer.read_u16());
+                let flags = try!(read_character_string(buffer, end));
+                let services = try!(read_character_string(buffer, end));
+                let regexp = try!(read_character_string(buffer, end));
+
+                let mut replacement = String::new();
+                try!(buffer.read_qname(&mut replacement));
+
+                Ok(DnsRecord::NAPTR {
+                    domain: domain,
+                    order: order,
+                    preference: preference,
+                    flags: flags,
+                    services: services,
+                    regexp: regexp,
+                    replacement: replacement,
+                    ttl: TransientTtl(ttl)
+                })
+            },
             QueryType::MX => {
                 let priority = try!(buffer.read_u16());
                 let mut mx = String::new();
@@ -275,13 +577,31 @@ impl DnsRecord {
                     ttl: TransientTtl(ttl)
                 })
             },
-            QueryType::TXT => {
-                let mut txt = String::new();
+            QueryType::TXT | QueryType::SPF => {
+                let mut txt = Vec::new();
+
+                // The data is a sequence of character-strings, each prefixed
+                // with its length
+                let end = buffer.pos() + data_len as usize;
+                while buffer.pos() < end {
+                    let len = try!(buffer.read()) as usize;
+                    if buffer.pos() + len > end {
+                        return Err(DnsError::Malformed("Character-string exceeds record"));
+                    }
 
-                let cur_pos = buffer.pos();
-                txt.push_str(&String::from_utf8_lossy(try!(buffer.get_range(cur_pos, data_len as usize))));
+                    let cur_pos = buffer.pos();
+                    txt.push(String::from_utf8_lossy(try!(buffer.get_range(cur_pos, len))).to_string());
+                    try!(buffer.step(len));
+                }
 
-                try!(buffer.step(data_len as usize));
+                // SPF records share the format of TXT records
+                if qtype == QueryType::SPF {
+                    return Ok(DnsRecord::SPF {
+                        domain: domain,
+                        data: txt,
+                        ttl: TransientTtl(ttl)
+                    });
+                }
 
                 Ok(DnsRecord::TXT {
                     domain: domain,
@@ -289,20 +609,159 @@ impl DnsRecord {
                     ttl: TransientTtl(ttl)
                 })
             },
+            // OPT records always belong to the root, so any other owner
+            // makes for an unknown record that fails EDNS validation
+            QueryType::OPT if !domain.is_empty() => {
+                try!(buffer.step(data_len as usize));
+
+                Ok(DnsRecord::UNKNOWN {
+                    domain: domain,
+                    qtype: qtype_num,
+                    data_len: data_len,
+                    ttl: TransientTtl(ttl)
+                })
+            },
             QueryType::OPT => {
-                let mut data = String::new();
+                let mut options = Vec::new();
+
+                let end = buffer.pos() + data_len as usize;
+                while buffer.pos() + 4 <= end {
+                    let code = try!(buffer.read_u16());
+                    let len = try!(buffer.read_u16()) as usize;
+                    if buffer.pos() + len > end {
+                        return Err(DnsError::Malformed("EDNS option exceeds record"));
+                    }
 
-                let cur_pos = buffer.pos();
-                data.push_str(&String::from_utf8_lossy(try!(buffer.get_range(cur_pos, data_len as usize))));
-                try!(buffer.step(data_len as usize));
+                    let cur_pos = buffer.pos();
+                    let data = try!(buffer.get_range(cur_pos, len)).to_vec();
+                    try!(buffer.step(len));
+
+                    options.push(EdnsOption {
+                        code: code,
+                        data: data
+                    });
+                }
+                try!(buffer.seek(end));
 
                 Ok(DnsRecord::OPT {
                     packet_len: class,
                     flags: ttl,
-                    data: data
+                    options: options
+                })
+            },
+            QueryType::DHCID => {
+                let cur_pos = buffer.pos();
+                let data = try!(buffer.get_range(cur_pos, data_len as usize)).to_vec();
+                try!(buffer.step(data_len as usize));
+
+                Ok(DnsRecord::DHCID {
+                    domain: domain,
+                    data: data,
+                    ttl: TransientTtl(ttl)
+                })
+            },
+            QueryType::SSHFP => {
+                if data_len < 2 {
+                    return Err(DnsError::Malformed("SSHFP fingerprint exceeds record"));
+                }
+
+                let algorithm = try!(buffer.read());
+                let fp_type = try!(buffer.read());
+
+                let fp_len = data_len as usize - 2;
+                let cur_pos = buffer.pos();
+                let fingerprint = try!(buffer.get_range(cur_pos, fp_len)).to_hex();
+                try!(buffer.step(fp_len));
+
+                Ok(DnsRecord::SSHFP {
+                    domain: domain,
+                    algorithm: algorithm,
+                    fp_type: fp_type,
+                    fingerprint: fingerprint,
+                    ttl: TransientTtl(ttl)
+                })
+            },
+            QueryType::TLSA => {
+                if data_len < 3 {
+                    return Err(DnsError::Malformed("TLSA data exceeds record"));
+                }
+
+                let usage = try!(buffer.read());
+                let selector = try!(buffer.read());
+                let matching_type = try!(buffer.read());
+
+                let cert_len = data_len as usize - 3;
+                let cur_pos = buffer.pos();
+                let cert_data = try!(buffer.get_range(cur_pos, cert_len)).to_hex();
+                try!(buffer.step(cert_len));
+
+                Ok(DnsRecord::TLSA {
+                    domain: domain,
+                    usage: usage,
+                    selector: selector,
+                    matching_type: matching_type,
+                    cert_data: cert_data,
+                    ttl: TransientTtl(ttl)
+                })
+            },
+            QueryType::CAA => {
+                let flags = try!(buffer.read());
+                let tag_len = try!(buffer.read()) as usize;
+                if tag_len + 2 > data_len as usize {
+                    return Err(DnsError::Malformed("CAA tag exceeds record"));
+                }
+
+                let cur_pos = buffer.pos();
+                let tag = String::from_utf8_lossy(try!(buffer.get_range(cur_pos, tag_len))).to_string();
+                try!(buffer.step(tag_len));
+
+                // The value takes up the rest of the record
+                let value_len = data_len as usize - tag_len - 2;
+                let cur_pos = buffer.pos();
+                let value = String::from_utf8_lossy(try!(buffer.get_range(cur_pos, value_len))).to_string();
+                try!(buffer.step(value_len));
+
+                Ok(DnsRecord::CAA {
+                    domain: domain,
+                    flags: flags,
+                    tag: tag,
+                    value: value,
+                    ttl: TransientTtl(ttl)
+                })
+            },
+            QueryType::TSIG => {
+                let mut algorithm = String::new();
+                try!(buffer.read_qname(&mut algorithm));
+
+                let time_signed = ((try!(buffer.read_u16()) as u64) << 32) |
+                                  (try!(buffer.read_u32()) as u64);
+                let fudge = try!(buffer.read_u16());
+
+                let mac_len = try!(buffer.read_u16()) as usize;
+                let cur_pos = buffer.pos();
+                let mac = try!(buffer.get_range(cur_pos, mac_len)).to_vec();
+                try!(buffer.step(mac_len));
+
+                let original_id = try!(buffer.read_u16());
+                let error = try!(buffer.read_u16());
+
+                let other_len = try!(buffer.read_u16()) as usize;
+                let cur_pos = buffer.pos();
+                let other = try!(buffer.get_range(cur_pos, other_len)).to_vec();
+                try!(buffer.step(other_len));
+
+                Ok(DnsRecord::TSIG {
+                    domain: domain,
+                    algorithm: algorithm,
+                    time_signed: time_signed,
+                    fudge: fudge,
+                    mac: mac,
+                    original_id: original_id,
+                    error: error,
+                    other: other
                 })
             },
-            QueryType::UNKNOWN(_) => {
+            QueryType::UNKNOWN(_) | QueryType::IXFR | QueryType::AXFR | QueryType::ANY => {
                 try!(buffer.step(data_len as usize));
 
                 Ok(DnsRecord::UNKNOWN {
@@ -359,9 +818,38 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 try!(buffer.set_u16(pos, size as u16));
             },
-            DnsRecord::CNAME { ref domain, ref host, ttl: TransientTtl(ttl) } => {
+            DnsRecord::PTR { ref domain, ref host, ttl: TransientTtl(ttl) } => {
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(QueryType::PTR.to_num()));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+
+                let pos = buffer.pos();
+                try!(buffer.write_u16(0));
+
+                try!(buffer.write_qname(host));
+
+                let size = buffer.pos() - (pos + 2);
+                try!(buffer.set_u16(pos, size as u16));
+            },
+            DnsRecord::DNAME { ref domain, ref host, ttl: TransientTtl(ttl) } => {
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(QueryType::DNAME.to_num()));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+
+                let pos = buffer.pos();
+                try!(buffer.write_u16(0));
+
+                try!(buffer.write_qname(host));
+
+                let size = buffer.pos() - (pos + 2);
+                try!(buffer.set_u16(pos, size as u16));
+            },
+            DnsRecord::CNAME { ref domain, ref host, ttl: TransientTtl(ttl) } |
+            DnsRecord::ANAME { ref domain, ref host, ttl: TransientTtl(ttl) } => {
                 try!(buffer.write_qname(domain));
-                try!(buffer.write_u16(QueryType::CNAME.to_num()));
+                try!(buffer.write_u16(self.get_querytype().to_num()));
                 try!(buffer.write_u16(1));
                 try!(buffer.write_u32(ttl));
 
@@ -390,6 +878,50 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 try!(buffer.set_u16(pos, size as u16));
             },
+            DnsRecord::NAPTR {
+                ref domain,
+                order,
+                preference,
+                ref flags,
+                ref services,
+                ref regexp,
+                ref replacement,
+                ttl: TransientTtl(ttl)
+            } => {
+
+                if [flags, services, regexp].iter().any(|x| x.len() > MAX_CHARACTER_STRING_LEN) {
+                    return Err(Error::new(ErrorKind::InvalidInput, "NAPTR string too long"));
+                }
+
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(QueryType::NAPTR.to_num()));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+
+                let pos = buffer.pos();
+                try!(buffer.write_u16(0));
+
+                try!(buffer.write_u16(order));
+                try!(buffer.write_u16(preference));
+                for string in &[flags, services, regexp] {
+                    try!(buffer.write_u8(string.len() as u8));
+                    for b in string.as_bytes() {
+                        try!(buffer.write_u8(*b));
+                    }
+                }
+
+                // The replacement is never compressed, as required by RFC 3403
+                for label in replacement.split('.').filter(|x| !x.is_empty()) {
+                    try!(buffer.write_u8(label.len() as u8));
+                    for b in label.as_bytes() {
+                        try!(buffer.write_u8(*b));
+                    }
+                }
+                try!(buffer.write_u8(0));
+
+                let size = buffer.pos() - (pos + 2);
+                try!(buffer.set_u16(pos, size as u16));
+            },
             DnsRecord::MX { ref domain, priority, ref host, ttl: TransientTtl(ttl) } => {
                 try!(buffer.write_qname(domain));
                 try!(buffer.write_u16(QueryType::MX.to_num()));
@@ -436,18 +968,150 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 try!(buffer.set_u16(pos, size as u16));
             },
-            DnsRecord::TXT { ref domain, ref data, ttl: TransientTtl(ttl) } => {
+            DnsRecord::TXT { ref domain, ref data, ttl: TransientTtl(ttl) } |
+            DnsRecord::SPF { ref domain, ref data, ttl: TransientTtl(ttl) } => {
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(self.get_querytype().to_num()));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+
+                // Strings too long for a single character-string are split
+                // up, and a record without any has an empty one
+                let mut strings = data.iter()
+                    .flat_map(|x| split_character_strings(x))
+                    .collect::<Vec<String>>();
+                if strings.is_empty() {
+                    strings.push(String::new());
+                }
+
+                let data_len = strings.iter().map(|x| 1 + x.len()).sum::<usize>();
+                try!(buffer.write_u16(data_len as u16));
+
+                for string in &strings {
+                    try!(buffer.write_u8(string.len() as u8));
+                    for b in string.as_bytes() {
+                        try!(buffer.write_u8(*b));
+                    }
+                }
+            },
+            DnsRecord::OPT { packet_len, flags, ref options } => {
+                try!(buffer.write_u8(0));
+                try!(buffer.write_u16(QueryType::OPT.to_num()));
+                try!(buffer.write_u16(packet_len));
+                try!(buffer.write_u32(flags));
+
+                let pos = buffer.pos();
+                try!(buffer.write_u16(0));
+
+                for option in options {
+                    try!(buffer.write_u16(option.code));
+                    try!(buffer.write_u16(option.data.len() as u16));
+                    for b in &option.data {
+                        try!(buffer.write_u8(*b));
+                    }
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                try!(buffer.set_u16(pos, size as u16));
+            },
+            DnsRecord::DHCID { ref domain, ref data, ttl: TransientTtl(ttl) } => {
                 try!(buffer.write_qname(domain));
-                try!(buffer.write_u16(QueryType::TXT.to_num()));
+                try!(buffer.write_u16(QueryType::DHCID.to_num()));
                 try!(buffer.write_u16(1));
                 try!(buffer.write_u32(ttl));
                 try!(buffer.write_u16(data.len() as u16));
 
-                for b in data.as_bytes() {
+                for b in data {
+                    try!(buffer.write_u8(*b));
+                }
+            },
+            DnsRecord::SSHFP { ref domain, algorithm, fp_type, ref fingerprint, ttl: TransientTtl(ttl) } => {
+                let fingerprint = match fingerprint.from_hex() {
+                    Ok(x) => x,
+                    Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "Invalid SSHFP fingerprint"))
+                };
+
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(QueryType::SSHFP.to_num()));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+                try!(buffer.write_u16((2 + fingerprint.len()) as u16));
+
+                try!(buffer.write_u8(algorithm));
+                try!(buffer.write_u8(fp_type));
+                for b in &fingerprint {
+                    try!(buffer.write_u8(*b));
+                }
+            },
+            DnsRecord::TLSA { ref domain, usage, selector, matching_type, ref cert_data, ttl: TransientTtl(ttl) } => {
+                let cert_data = match cert_data.from_hex() {
+                    Ok(x) => x,
+                    Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "Invalid TLSA data"))
+                };
+
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(QueryType::TLSA.to_num()));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+                try!(buffer.write_u16((3 + cert_data.len()) as u16));
+
+                try!(buffer.write_u8(usage));
+                try!(buffer.write_u8(selector));
+                try!(buffer.write_u8(matching_type));
+                for b in &cert_data {
+                    try!(buffer.write_u8(*b));
+                }
+            },
+            DnsRecord::CAA { ref domain, flags, ref tag, ref value, ttl: TransientTtl(ttl) } => {
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(QueryType::CAA.to_num()));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+                try!(buffer.write_u16((2 + tag.len() + value.len()) as u16));
+
+                try!(buffer.write_u8(flags));
+                try!(buffer.write_u8(tag.len() as u8));
+                for b in tag.as_bytes().iter().chain(value.as_bytes()) {
                     try!(buffer.write_u8(*b));
                 }
             },
-            DnsRecord::OPT { .. } => {
+            DnsRecord::TSIG { ref domain, ref algorithm, time_signed, fudge, ref mac, original_id, error, ref other } => {
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(QueryType::TSIG.to_num()));
+                try!(buffer.write_u16(CLASS_ANY));
+                try!(buffer.write_u32(0));
+
+                let pos = buffer.pos();
+                try!(buffer.write_u16(0));
+
+                // The algorithm name is never compressed
+                for label in algorithm.split('.').filter(|x| !x.is_empty()) {
+                    try!(buffer.write_u8(label.len() as u8));
+                    for b in label.as_bytes() {
+                        try!(buffer.write_u8(*b));
+                    }
+                }
+                try!(buffer.write_u8(0));
+
+                try!(buffer.write_u16((time_signed >> 32) as u16));
+                try!(buffer.write_u32(time_signed as u32));
+                try!(buffer.write_u16(fudge));
+
+                try!(buffer.write_u16(mac.len() as u16));
+                for b in mac {
+                    try!(buffer.write_u8(*b));
+                }
+
+                try!(buffer.write_u16(original_id));
+                try!(buffer.write_u16(error));
+
+                try!(buffer.write_u16(other.len() as u16));
+                for b in other {
+                    try!(buffer.write_u8(*b));
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                try!(buffer.set_u16(pos, size as u16));
             },
             DnsRecord::UNKNOWN { .. } => {
                 println!("Skipping record: {:?}", self);
@@ -463,11 +1127,21 @@ impl DnsRecord {
             DnsRecord::AAAA { .. } => QueryType::AAAA,
             DnsRecord::NS { .. } => QueryType::NS,
             DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::PTR { .. } => QueryType::PTR,
             DnsRecord::SRV { .. } => QueryType::SRV,
+            DnsRecord::NAPTR { .. } => QueryType::NAPTR,
+            DnsRecord::DNAME { .. } => QueryType::DNAME,
             DnsRecord::MX { .. } => QueryType::MX,
             DnsRecord::UNKNOWN { qtype, .. } => QueryType::UNKNOWN(qtype),
             DnsRecord::SOA { .. } => QueryType::SOA,
             DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::SPF { .. } => QueryType::SPF,
+            DnsRecord::SSHFP { .. } => QueryType::SSHFP,
+            DnsRecord::DHCID { .. } => QueryType::DHCID,
+            DnsRecord::TLSA { .. } => QueryType::TLSA,
+            DnsRecord::CAA { .. } => QueryType::CAA,
+            DnsRecord::TSIG { .. } => QueryType::TSIG,
+            DnsRecord::ANAME { .. } => QueryType::ANAME,
             DnsRecord::OPT { .. } => QueryType::OPT
         }
     }
@@ -478,40 +1152,199 @@ impl DnsRecord {
             DnsRecord::AAAA { ref domain, .. } |
             DnsRecord::NS { ref domain, .. } |
             DnsRecord::CNAME { ref domain, .. } |
+            DnsRecord::PTR { ref domain, .. } |
             DnsRecord::SRV { ref domain, .. } |
+            DnsRecord::NAPTR { ref domain, .. } |
+            DnsRecord::DNAME { ref domain, .. } |
             DnsRecord::MX { ref domain, .. } |
             DnsRecord::UNKNOWN { ref domain, .. } |
             DnsRecord::SOA { ref domain, .. } |
-            DnsRecord::TXT { ref domain, .. } => Some(domain.clone()),
+            DnsRecord::TXT { ref domain, .. } |
+            DnsRecord::SPF { ref domain, .. } |
+            DnsRecord::SSHFP { ref domain, .. } |
+            DnsRecord::DHCID { ref domain, .. } |
+            DnsRecord::TLSA { ref domain, .. } |
+            DnsRecord::CAA { ref domain, .. } |
+            DnsRecord::TSIG { ref domain, .. } |
+            DnsRecord::ANAME { ref domain, .. } => Some(domain.clone()),
             DnsRecord::OPT { .. } => None
         }
     }
 
-    pub fn get_ttl(&self) -> u32 {
+    /// Replace the owner name of the record, for records that have one
+    pub fn set_domain(&mut self, name: &str) {
         match *self {
-            DnsRecord::A { ttl: TransientTtl(ttl), .. } |
-            DnsRecord::AAAA { ttl: TransientTtl(ttl), .. } |
-            DnsRecord::NS { ttl: TransientTtl(ttl), .. } |
-            DnsRecord::CNAME { ttl: TransientTtl(ttl), .. } |
+            DnsRecord::A { ref mut domain, .. } |
+            DnsRecord::AAAA { ref mut domain, .. } |
+            DnsRecord::NS { ref mut domain, .. } |
+            DnsRecord::CNAME { ref mut domain, .. } |
+            DnsRecord::PTR { ref mut domain, .. } |
+            DnsRecord::SRV { ref mut domain, .. } |
+            DnsRecord::NAPTR { ref mut domain, .. } |
+            DnsRecord::DNAME { ref mut domain, .. } |
+            DnsRecord::MX { ref mut domain, .. } |
+            DnsRecord::UNKNOWN { ref mut domain, .. } |
+            DnsRecord::SOA { ref mut domain, .. } |
+            DnsRecord::TXT { ref mut domain, .. } |
+            DnsRecord::SPF { ref mut domain, .. } |
+            DnsRecord::SSHFP { ref mut domain, .. } |
+            DnsRecord::DHCID { ref mut domain, .. } |
+            DnsRecord::TLSA { ref mut domain, .. } |
+            DnsRecord::CAA { ref mut domain, .. } |
+            DnsRecord::TSIG { ref mut domain, .. } |
+            DnsRecord::ANAME { ref mut domain, .. } => *domain = name.to_string(),
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    /// Compare two records according to the DNSSEC canonical ordering
+    ///
+    /// Records are ordered by owner name as specified in RFC 4034 section 6.1,
+    /// then by numeric record type. Records of the same name and type fall
+    /// back on the derived ordering of their data.
+    pub fn cmp_canonical(&self, other: &DnsRecord) -> Ordering {
+        let domain = self.get_domain().unwrap_or_else(String::new);
+        let other_domain = other.get_domain().unwrap_or_else(String::new);
+
+        match cmp_canonical_names(&domain, &other_domain) {
+            Ordering::Equal => {},
+            x => return x
+        }
+
+        match self.get_querytype().to_num().cmp(&other.get_querytype().to_num()) {
+            Ordering::Equal => {},
+            x => return x
+        }
+
+        self.cmp(other)
+    }
+
+    pub fn get_ttl(&self) -> u32 {
+        match *self {
+            DnsRecord::A { ttl: TransientTtl(ttl), .. } |
+            DnsRecord::AAAA { ttl: TransientTtl(ttl), .. } |
+            DnsRecord::NS { ttl: TransientTtl(ttl), .. } |
+            DnsRecord::CNAME { ttl: TransientTtl(ttl), .. } |
+            DnsRecord::PTR { ttl: TransientTtl(ttl), .. } |
             DnsRecord::SRV { ttl: TransientTtl(ttl), .. } |
+            DnsRecord::NAPTR { ttl: TransientTtl(ttl), .. } |
+            DnsRecord::DNAME { ttl: TransientTtl(ttl), .. } |
             DnsRecord::MX { ttl: TransientTtl(ttl), .. } |
             DnsRecord::UNKNOWN { ttl: TransientTtl(ttl), .. } |
             DnsRecord::SOA { ttl: TransientTtl(ttl), .. } |
-            DnsRecord::TXT { ttl: TransientTtl(ttl), .. } => ttl,
-            DnsRecord::OPT { .. } => 0
+            DnsRecord::TXT { ttl: TransientTtl(ttl), .. } |
+            DnsRecord::SPF { ttl: TransientTtl(ttl), .. } |
+            DnsRecord::SSHFP { ttl: TransientTtl(ttl), .. } |
+            DnsRecord::DHCID { ttl: TransientTtl(ttl), .. } |
+            DnsRecord::TLSA { ttl: TransientTtl(ttl), .. } |
+            DnsRecord::CAA { ttl: TransientTtl(ttl), .. } |
+            DnsRecord::ANAME { ttl: TransientTtl(ttl), .. } => ttl,
+            DnsRecord::OPT { .. } |
+            DnsRecord::TSIG { .. } => 0
+        }
+    }
+
+    /// Change the TTL of the record, which has no effect on OPT and TSIG
+    /// records
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match *self {
+            DnsRecord::A { ref mut ttl, .. } |
+            DnsRecord::AAAA { ref mut ttl, .. } |
+            DnsRecord::NS { ref mut ttl, .. } |
+            DnsRecord::CNAME { ref mut ttl, .. } |
+            DnsRecord::PTR { ref mut ttl, .. } |
+            DnsRecord::SRV { ref mut ttl, .. } |
+            DnsRecord::NAPTR { ref mut ttl, .. } |
+            DnsRecord::DNAME { ref mut ttl, .. } |
+            DnsRecord::MX { ref mut ttl, .. } |
+            DnsRecord::UNKNOWN { ref mut ttl, .. } |
+            DnsRecord::SOA { ref mut ttl, .. } |
+            DnsRecord::TXT { ref mut ttl, .. } |
+            DnsRecord::SPF { ref mut ttl, .. } |
+            DnsRecord::SSHFP { ref mut ttl, .. } |
+            DnsRecord::DHCID { ref mut ttl, .. } |
+            DnsRecord::TLSA { ref mut ttl, .. } |
+            DnsRecord::CAA { ref mut ttl, .. } |
+            DnsRecord::ANAME { ref mut ttl, .. } => *ttl = TransientTtl(new_ttl),
+            DnsRecord::OPT { .. } |
+            DnsRecord::TSIG { .. } => {}
+        }
+    }
+}
+
+/// Maximum length in bytes of a character-string, such as those making up
+/// TXT records
+pub const MAX_CHARACTER_STRING_LEN: usize = 255;
+
+/// Read a character-string, which has to end before `end`
+fn read_character_string<T: PacketBuffer>(buffer: &mut T, end: usize) -> DnsResult<String> {
+    let len = try!(buffer.read()) as usize;
+    if buffer.pos() + len > end {
+        return Err(DnsError::Malformed("Character-string exceeds record"));
+    }
+
+    let cur_pos = buffer.pos();
+    let string = String::from_utf8_lossy(try!(buffer.get_range(cur_pos, len))).to_string();
+    try!(buffer.step(len));
+
+    Ok(string)
+}
+
+/// Split `data` into character-strings of at most `MAX_CHARACTER_STRING_LEN`
+/// bytes each, without breaking up multi byte characters
+pub fn split_character_strings(data: &str) -> Vec<String> {
+    let mut result = Vec::new();
+
+    let mut current = String::new();
+    for c in data.chars() {
+        if current.len() + c.len_utf8() > MAX_CHARACTER_STRING_LEN {
+            result.push(current);
+            current = String::new();
         }
+        current.push(c);
+    }
+
+    if !current.is_empty() || result.is_empty() {
+        result.push(current);
     }
+
+    result
+}
+
+/// Compare two domain names in canonical order
+///
+/// Names are compared label by label starting from the rightmost label, with
+/// each label compared case insensitively as a string of octets. A name sorts
+/// before any of its subdomains.
+pub fn cmp_canonical_names(a: &str, b: &str) -> Ordering {
+    let a_labels = a.split('.').filter(|x| !x.is_empty()).rev();
+    let b_labels = b.split('.').filter(|x| !x.is_empty()).rev();
+
+    let a_lower = a_labels.map(|x| x.to_lowercase().into_bytes());
+    let b_lower = b_labels.map(|x| x.to_lowercase().into_bytes());
+
+    a_lower.cmp(b_lower)
 }
 
 /// The result code for a DNS query, as described in the specification
+///
+/// Codes above 15 are extended result codes (RFC 6891), which only fit in
+/// the header together with the upper bits stored in the OPT record.
 #[derive(Copy,Clone,Debug,PartialEq,Eq)]
 pub enum ResultCode {
-    NOERROR = 0,
-    FORMERR = 1,
-    SERVFAIL = 2,
-    NXDOMAIN = 3,
-    NOTIMP = 4,
-    REFUSED = 5
+    UNKNOWN(u16),
+    NOERROR, // 0
+    FORMERR, // 1
+    SERVFAIL, // 2
+    NXDOMAIN, // 3
+    NOTIMP, // 4
+    REFUSED, // 5
+    YXDOMAIN, // 6
+    YXRRSET, // 7
+    NXRRSET, // 8
+    NOTAUTH, // 9
+    NOTZONE, // 10
+    BADVERS // 16
 }
 
 impl Default for ResultCode {
@@ -521,14 +1354,39 @@ impl Default for ResultCode {
 }
 
 impl ResultCode {
-    pub fn from_num(num: u8) -> ResultCode {
+    pub fn to_num(&self) -> u16 {
+        match *self {
+            ResultCode::UNKNOWN(x) => x,
+            ResultCode::NOERROR => 0,
+            ResultCode::FORMERR => 1,
+            ResultCode::SERVFAIL => 2,
+            ResultCode::NXDOMAIN => 3,
+            ResultCode::NOTIMP => 4,
+            ResultCode::REFUSED => 5,
+            ResultCode::YXDOMAIN => 6,
+            ResultCode::YXRRSET => 7,
+            ResultCode::NXRRSET => 8,
+            ResultCode::NOTAUTH => 9,
+            ResultCode::NOTZONE => 10,
+            ResultCode::BADVERS => 16
+        }
+    }
+
+    pub fn from_num(num: u16) -> ResultCode {
         match num {
+            0 => ResultCode::NOERROR,
             1 => ResultCode::FORMERR,
             2 => ResultCode::SERVFAIL,
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
-            0 | _ => ResultCode::NOERROR
+            6 => ResultCode::YXDOMAIN,
+            7 => ResultCode::YXRRSET,
+            8 => ResultCode::NXRRSET,
+            9 => ResultCode::NOTAUTH,
+            10 => ResultCode::NOTZONE,
+            16 => ResultCode::BADVERS,
+            _ => ResultCode::UNKNOWN(num)
         }
     }
 }
@@ -541,7 +1399,7 @@ pub struct DnsHeader {
     pub recursion_desired: bool, // 1 bit
     pub truncated_message: bool, // 1 bit
     pub authoritative_answer: bool, // 1 bit
-    pub opcode: u8, // 4 bits
+    pub opcode: OpCode, // 4 bits
     pub response: bool, // 1 bit
 
     pub rescode: ResultCode, // 4 bits
@@ -563,7 +1421,7 @@ impl DnsHeader {
                     recursion_desired: false,
                     truncated_message: false,
                     authoritative_answer: false,
-                    opcode: 0,
+                    opcode: OpCode::QUERY,
                     response: false,
 
                     rescode: ResultCode::NOERROR,
@@ -584,10 +1442,10 @@ impl DnsHeader {
         try!(buffer.write_u8( ((self.recursion_desired as u8)) |
                               ((self.truncated_message as u8) << 1) |
                               ((self.authoritative_answer as u8) << 2) |
-                              (self.opcode << 3) |
+                              ((self.opcode.to_num() & 0x0F) << 3) |
                               ((self.response as u8) << 7) as u8) );
 
-        try!(buffer.write_u8( (self.rescode.clone() as u8) |
+        try!(buffer.write_u8( ((self.rescode.to_num() & 0x0F) as u8) |
                               ((self.checking_disabled as u8) << 4) |
                               ((self.authed_data as u8) << 5) |
                               ((self.z as u8) << 6) |
@@ -606,7 +1464,7 @@ impl DnsHeader {
     }
 
     #[allow(identity_op)]
-    pub fn read<T: PacketBuffer>(&mut self, buffer: &mut T) -> Result<()> {
+    pub fn read<T: PacketBuffer>(&mut self, buffer: &mut T) -> DnsResult<()> {
         self.id = try!(buffer.read_u16());
 
         let flags = try!(buffer.read_u16());
@@ -615,10 +1473,10 @@ impl DnsHeader {
         self.recursion_desired = (a & (1 << 0)) > 0;
         self.truncated_message = (a & (1 << 1)) > 0;
         self.authoritative_answer = (a & (1 << 2)) > 0;
-        self.opcode = (a >> 3) & 0x0F;
+        self.opcode = OpCode::from_num((a >> 3) & 0x0F);
         self.response = (a & (1 << 7)) > 0;
 
-        self.rescode = ResultCode::from_num(b & 0x0F);
+        self.rescode = ResultCode::from_num((b & 0x0F) as u16);
         self.checking_disabled = (b & (1 << 4)) > 0;
         self.authed_data = (b & (1 << 5)) > 0;
         self.z = (b & (1 << 6)) > 0;
@@ -642,7 +1500,7 @@ impl fmt::Display for DnsHeader {
         try!(write!(f, "\trecursion_desired: {0}\n", self.recursion_desired));
         try!(write!(f, "\ttruncated_message: {0}\n", self.truncated_message));
         try!(write!(f, "\tauthoritative_answer: {0}\n", self.authoritative_answer));
-        try!(write!(f, "\topcode: {0}\n", self.opcode));
+        try!(write!(f, "\topcode: {:?}\n", self.opcode));
         try!(write!(f, "\tresponse: {0}\n", self.response));
 
         try!(write!(f, "\trescode: {:?}\n", self.rescode));
@@ -675,8 +1533,15 @@ impl DnsQuestion {
         }
     }
 
+    /// Check whether `other` asks the same question, ignoring the case of the
+    /// names, which DNS doesn't distinguish
+    pub fn matches(&self, other: &DnsQuestion) -> bool {
+        self.qtype == other.qtype && self.name.eq_ignore_ascii_case(&other.name)
+    }
+
     pub fn binary_len(&self) -> usize {
-        self.name.split('.').map(|x| x.len() + 1).fold(1, |x, y| x+y)
+        // The name, followed by the type and class
+        self.name.split('.').map(|x| x.len() + 1).fold(1, |x, y| x+y) + 4
     }
 
     pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<()> {
@@ -690,8 +1555,12 @@ impl DnsQuestion {
         Ok(())
     }
 
-    pub fn read<T: PacketBuffer>(&mut self, buffer: &mut T) -> Result<()> {
-        try!(buffer.read_qname(&mut self.name));
+    /// Read a question from `buffer`
+    ///
+    /// Unlike the names of records, the name is kept in its original case, so
+    /// that it can be echoed exactly in the response.
+    pub fn read<T: PacketBuffer>(&mut self, buffer: &mut T) -> DnsResult<()> {
+        try!(buffer.read_qname_preserving_case(&mut self.name));
         self.qtype = QueryType::from_num(try!(buffer.read_u16())); // qtype
         let _ = try!(buffer.read_u16()); // class
 
@@ -734,7 +1603,39 @@ impl DnsPacket {
         }
     }
 
-    pub fn from_buffer<T: PacketBuffer>(buffer: &mut T) -> Result<DnsPacket> {
+    /// Build a query for `qname` with a random id, which makes responses
+    /// harder to spoof than with predictable ids
+    pub fn query(qname: &str, qtype: QueryType, recursion: bool) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+
+        packet.header.id = random();
+        packet.header.questions = 1;
+        packet.header.recursion_desired = recursion;
+
+        packet.questions.push(DnsQuestion::new(qname.to_string(), qtype));
+
+        packet
+    }
+
+    /// Build the skeleton of a response to `query`, echoing its id, opcode,
+    /// questions and the RD flag as required by RFC 1035
+    ///
+    /// Whether recursion is available depends on the configuration of the
+    /// server, so the RA flag is left unset for the caller to fill in.
+    pub fn answer_for(query: &DnsPacket) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+
+        packet.header.id = query.header.id;
+        packet.header.opcode = query.header.opcode;
+        packet.header.recursion_desired = query.header.recursion_desired;
+        packet.header.response = true;
+
+        packet.questions = query.questions.clone();
+
+        packet
+    }
+
+    pub fn from_buffer<T: PacketBuffer>(buffer: &mut T) -> DnsResult<DnsPacket> {
         let mut result = DnsPacket::new();
         try!(result.header.read(buffer));
 
@@ -758,6 +1659,18 @@ impl DnsPacket {
             result.resources.push(rec);
         }
 
+        // The upper 8 bits of an extended result code are kept in the OPT
+        // record, while the header only has room for the lower 4 bits
+        let ext_rescode = result.resources.iter().filter_map(|x| match *x {
+            DnsRecord::OPT { flags, .. } => Some((flags >> 24) as u16),
+            _ => None
+        }).next();
+
+        if let Some(ext_rescode) = ext_rescode {
+            let rescode = (ext_rescode << 4) | result.header.rescode.to_num();
+            result.header.rescode = ResultCode::from_num(rescode);
+        }
+
         Ok(result)
     }
 
@@ -786,28 +1699,163 @@ impl DnsPacket {
         }
     }
 
+    /// The SOA record in the authority section, if any
+    pub fn get_soa(&self) -> Option<&DnsRecord> {
+        self.authorities.iter().find(|x| x.get_querytype() == QueryType::SOA)
+    }
+
+    /// The TTL of a negative answer, per RFC 2308 the lesser of the TTL of
+    /// the SOA record in the authority section and its minimum field
     pub fn get_ttl_from_soa(&self) -> Option<u32> {
-        for answer in &self.authorities {
-            if let DnsRecord::SOA { minimum, .. } = *answer {
-                return Some(minimum);
+        match self.get_soa() {
+            Some(&DnsRecord::SOA { minimum, ttl: TransientTtl(ttl), .. }) => Some(cmp::min(minimum, ttl)),
+            _ => None
+        }
+    }
+
+    /// Pick a random address from the A records in the answer section, using
+    /// `rng` to make the choice
+    ///
+    /// Records of other types, such as a CNAME preceding the address, are
+    /// skipped rather than causing the lookup to fail.
+    pub fn get_random_a<R: Rng>(&self, rng: &mut R) -> Option<String> {
+        let addrs = self.answers.iter().filter_map(|x| match *x {
+            DnsRecord::A { ref addr, .. } => Some(addr.to_string()),
+            _ => None
+        }).collect::<Vec<String>>();
+
+        if !addrs.is_empty() {
+            let idx = rng.gen::<usize>() % addrs.len();
+            return Some(addrs[idx].clone());
+        }
+
+        None
+    }
+
+    /// Rotate the A records, and the AAAA records, of the answer section by
+    /// `turn` places, leaving the positions of other records as they are
+    ///
+    /// Answering with increasing values of `turn` makes consecutive answers
+    /// start with the next address, for round robin load balancing.
+    pub fn rotate_answers(&mut self, turn: usize) {
+        for qtype in &[QueryType::A, QueryType::AAAA] {
+            let positions = self.answers.iter()
+                .enumerate()
+                .filter(|&(_, x)| x.get_querytype() == *qtype)
+                .map(|(i, _)| i)
+                .collect::<Vec<usize>>();
+
+            if positions.len() < 2 {
+                continue;
+            }
+
+            let mut records = positions.iter()
+                .map(|&i| self.answers[i].clone())
+                .collect::<Vec<DnsRecord>>();
+            let shift = turn % records.len();
+            records.rotate_left(shift);
+
+            for (i, rec) in positions.into_iter().zip(records.into_iter()) {
+                self.answers[i] = rec;
+            }
+        }
+    }
+
+    /// Append the records of `other` to this packet, skipping any that are
+    /// already present
+    ///
+    /// Records are compared without regard to their TTL. The header of this
+    /// packet is kept as is, apart from the record counts.
+    pub fn merge(&mut self, other: &DnsPacket) {
+        fn merge_section(section: &mut Vec<DnsRecord>, records: &[DnsRecord]) {
+            for rec in records {
+                if !section.contains(rec) {
+                    section.push(rec.clone());
+                }
+            }
+        }
+
+        merge_section(&mut self.answers, &other.answers);
+        merge_section(&mut self.authorities, &other.authorities);
+        merge_section(&mut self.resources, &other.resources);
+
+        self.header.questions = self.questions.len() as u16;
+        self.header.answers = self.answers.len() as u16;
+        self.header.authoritative_entries = self.authorities.len() as u16;
+        self.header.resource_entries = self.resources.len() as u16;
+    }
+
+    /// Find the EDNS option with the given code in the OPT record, if any
+    pub fn get_edns_option(&self, code: u16) -> Option<&EdnsOption> {
+        for rec in &self.resources {
+            if let DnsRecord::OPT { ref options, .. } = *rec {
+                return options.iter().find(|x| x.code == code);
             }
         }
 
         None
     }
 
-    pub fn get_random_a(&self) -> Option<String> {
-        if !self.answers.is_empty() {
-            let idx = random::<usize>() % self.answers.len();
-            let a_record = &self.answers[idx];
-            if let DnsRecord::A{ ref addr, .. } = *a_record {
-                return Some(addr.to_string());
+    /// The UDP payload size advertised in the OPT record, if the packet has
+    /// one
+    pub fn edns_udp_size(&self) -> Option<u16> {
+        for rec in &self.resources {
+            if let DnsRecord::OPT { packet_len, .. } = *rec {
+                return Some(packet_len);
             }
         }
 
         None
     }
 
+    /// Advertise support for EDNS0 with a UDP payload size of `udp_size`
+    ///
+    /// An OPT record without any options is added to the additional section,
+    /// unless there already is one, in which case only its payload size is
+    /// changed.
+    pub fn add_edns0(&mut self, udp_size: u16) {
+        for rec in &mut self.resources {
+            if let DnsRecord::OPT { ref mut packet_len, .. } = *rec {
+                *packet_len = udp_size;
+                return;
+            }
+        }
+
+        self.resources.push(DnsRecord::OPT {
+            packet_len: udp_size,
+            flags: 0,
+            options: Vec::new()
+        });
+    }
+
+    /// Whether the packet follows the rules of EDNS (RFC 6891 section 6.1.1)
+    ///
+    /// There may be at most one OPT record, which has to be in the additional
+    /// section and owned by the root.
+    pub fn has_valid_edns(&self) -> bool {
+        let is_opt = |x: &DnsRecord| x.get_querytype().to_num() == QueryType::OPT.to_num();
+
+        if self.answers.iter().any(&is_opt) || self.authorities.iter().any(&is_opt) {
+            return false;
+        }
+
+        let opts = self.resources.iter().filter(|x| is_opt(x)).collect::<Vec<&DnsRecord>>();
+        match opts.len() {
+            0 => true,
+            1 => opts[0].get_querytype() == QueryType::OPT,
+            _ => false
+        }
+    }
+
+    /// Remove everything but OPT records from the additional section
+    ///
+    /// Glue is of little use to stub clients, while the OPT record is needed
+    /// for EDNS to keep working.
+    pub fn strip_additional(&mut self) {
+        self.resources.retain(|x| x.get_querytype() == QueryType::OPT);
+        self.header.resource_entries = self.resources.len() as u16;
+    }
+
     pub fn get_unresolved_cnames(&self) -> Vec<DnsRecord> {
 
         let mut unresolved = Vec::new();
@@ -832,9 +1880,31 @@ impl DnsPacket {
         unresolved
     }
 
-    pub fn get_resolved_ns(&self, qname: &str) -> Option<String> {
+    /// Pick a random address of a name server for `qname` with glue, using
+    /// `rng` to make the choice
+    ///
+    /// IPv4 and IPv6 glue are picked from as one pool. The address is given
+    /// in its textual form, which is parsed again when sending the query, and
+    /// decides whether it's sent over IPv4 or IPv6.
+    pub fn get_resolved_ns<R: Rng>(&self, qname: &str, rng: &mut R) -> Option<String> {
+        let addrs = self.get_resolved_ns_addrs(qname);
+
+        if !addrs.is_empty() {
+            let idx = rng.gen::<usize>() % addrs.len();
+            return Some(addrs[idx].clone());
+        }
 
-        let mut new_authorities = Vec::new();
+        None
+    }
+
+    /// The addresses of all name servers for `qname` in the authority
+    /// section, which have glue in the additional section
+    ///
+    /// Both A and AAAA glue is used, so that servers which can only be
+    /// reached over IPv6 are included.
+    pub fn get_resolved_ns_addrs(&self, qname: &str) -> Vec<String> {
+
+        let mut addrs = Vec::new();
         for auth in &self.authorities {
             if let DnsRecord::NS { ref domain, ref host, .. } = *auth {
                 if !qname.ends_with(domain) {
@@ -842,34 +1912,23 @@ impl DnsPacket {
                 }
 
                 for rsrc in &self.resources {
-                    if let DnsRecord::A{ ref domain, ref addr, ttl: TransientTtl(ttl) } = *rsrc {
-                        if domain != host {
-                            continue;
-                        }
-
-                        let rec = DnsRecord::A {
-                            domain: host.clone(),
-                            addr: *addr,
-                            ttl: TransientTtl(ttl)
-                        };
-
-                        new_authorities.push(rec);
+                    match *rsrc {
+                        DnsRecord::A { ref domain, ref addr, .. } if domain == host => {
+                            addrs.push(addr.to_string());
+                        },
+                        DnsRecord::AAAA { ref domain, ref addr, .. } if domain == host => {
+                            addrs.push(addr.to_string());
+                        },
+                        _ => {}
                     }
                 }
             }
         }
 
-        if !new_authorities.is_empty() {
-            let idx = random::<usize>() % new_authorities.len();
-            if let DnsRecord::A { addr, .. } = new_authorities[idx] {
-                return Some(addr.to_string());
-            }
-        }
-
-        None
+        addrs
     }
 
-    pub fn get_unresolved_ns(&self, qname: &str) -> Option<String> {
+    pub fn get_unresolved_ns<R: Rng>(&self, qname: &str, rng: &mut R) -> Option<String> {
 
         let mut new_authorities = Vec::new();
         for auth in &self.authorities {
@@ -883,7 +1942,7 @@ impl DnsPacket {
         }
 
         if !new_authorities.is_empty() {
-            let idx = random::<usize>() % new_authorities.len();
+            let idx = rng.gen::<usize>() % new_authorities.len();
             return Some(new_authorities[idx].clone());
         }
 
@@ -896,15 +1955,39 @@ impl DnsPacket {
     {
         let mut test_buffer = VectorPacketBuffer::new();
 
+        // Extended result codes have their upper bits stored in the OPT
+        // record. Without one, only the lower 4 bits are sent.
+        let ext_rescode = ((self.header.rescode.to_num() >> 4) & 0xFF) as u32;
+        for rec in &mut self.resources {
+            if let DnsRecord::OPT { ref mut flags, .. } = *rec {
+                *flags = (*flags & 0x00FF_FFFF) | (ext_rescode << 24);
+            }
+        }
+
         let mut size = self.header.binary_len();
         for ref question in &self.questions {
             size += question.binary_len();
             try!(question.write(&mut test_buffer));
         }
 
-        let mut record_count = self.answers.len() + self.authorities.len() + self.resources.len();
+        // The OPT record is always included, so its space is reserved before
+        // fitting the other records. It's written last.
+        let (opts, resources): (Vec<&DnsRecord>, Vec<&DnsRecord>) = self.resources.iter()
+            .partition(|x| x.get_querytype() == QueryType::OPT);
+
+        let mut opt_buffer = VectorPacketBuffer::new();
+        for rec in &opts {
+            size += try!(rec.write(&mut opt_buffer));
+        }
+
+        let mut record_count = self.answers.len() + self.authorities.len() + resources.len();
 
-        for (i, rec) in self.answers.iter().chain(self.authorities.iter()).chain(self.resources.iter()).enumerate() {
+        // The counts are determined by what fits in the buffer
+        self.header.answers = 0;
+        self.header.authoritative_entries = 0;
+        self.header.resource_entries = opts.len() as u16;
+
+        for (i, rec) in self.answers.iter().chain(self.authorities.iter()).chain(resources.iter().cloned()).enumerate() {
             size += try!(rec.write(&mut test_buffer));
             if size > max_size {
                 record_count = i;
@@ -927,7 +2010,13 @@ impl DnsPacket {
             try!(question.write(buffer));
         }
 
-        for rec in self.answers.iter().chain(self.authorities.iter()).chain(self.resources.iter()).take(record_count) {
+        let records = self.answers.iter()
+            .chain(self.authorities.iter())
+            .chain(resources.iter().cloned())
+            .take(record_count)
+            .chain(opts.iter().cloned());
+
+        for rec in records {
             try!(rec.write(buffer));
         }
 
@@ -941,6 +2030,349 @@ mod tests {
     use super::*;
     use dns::buffer::{PacketBuffer, VectorPacketBuffer};
 
+    use proptest::prelude::*;
+    use rand::{thread_rng, SeedableRng, StdRng};
+    use rustc_serialize::base64::FromBase64;
+
+    fn arb_domain() -> BoxedStrategy<String> {
+        prop::collection::vec("[a-z0-9]{1,10}", 1..5)
+            .prop_map(|labels| labels.join("."))
+            .boxed()
+    }
+
+    fn arb_ttl() -> BoxedStrategy<TransientTtl> {
+        any::<u32>().prop_map(TransientTtl).boxed()
+    }
+
+    impl Arbitrary for DnsRecord {
+        type Parameters = ();
+        type Strategy = BoxedStrategy<DnsRecord>;
+
+        fn arbitrary_with(_: ()) -> Self::Strategy {
+            // UNKNOWN and OPT records are excluded, since their data isn't
+            // retained and they can't be written back out
+            prop_oneof![
+                (arb_domain(), any::<[u8; 4]>(), arb_ttl())
+                    .prop_map(|(domain, addr, ttl)| DnsRecord::A {
+                        domain: domain,
+                        addr: Ipv4Addr::from(addr),
+                        ttl: ttl
+                    }),
+                (arb_domain(), any::<[u8; 16]>(), arb_ttl())
+                    .prop_map(|(domain, addr, ttl)| DnsRecord::AAAA {
+                        domain: domain,
+                        addr: Ipv6Addr::from(addr),
+                        ttl: ttl
+                    }),
+                (arb_domain(), arb_domain(), arb_ttl())
+                    .prop_map(|(domain, host, ttl)| DnsRecord::NS {
+                        domain: domain,
+                        host: host,
+                        ttl: ttl
+                    }),
+                (arb_domain(), arb_domain(), arb_ttl())
+                    .prop_map(|(domain, host, ttl)| DnsRecord::CNAME {
+                        domain: domain,
+                        host: host,
+                        ttl: ttl
+                    }),
+                (arb_domain(), arb_domain(), arb_ttl())
+                    .prop_map(|(domain, host, ttl)| DnsRecord::PTR {
+                        domain: domain,
+                        host: host,
+                        ttl: ttl
+                    }),
+                (arb_domain(), arb_domain(), arb_ttl())
+                    .prop_map(|(domain, host, ttl)| DnsRecord::DNAME {
+                        domain: domain,
+                        host: host,
+                        ttl: ttl
+                    }),
+                (arb_domain(), arb_domain(), arb_ttl())
+                    .prop_map(|(domain, host, ttl)| DnsRecord::ANAME {
+                        domain: domain,
+                        host: host,
+                        ttl: ttl
+                    }),
+                (arb_domain(), arb_domain(), arb_domain(), any::<[u32; 5]>(), arb_ttl())
+                    .prop_map(|(domain, m_name, r_name, values, ttl)| DnsRecord::SOA {
+                        domain: domain,
+                        m_name: m_name,
+                        r_name: r_name,
+                        serial: values[0],
+                        refresh: values[1],
+                        retry: values[2],
+                        expire: values[3],
+                        minimum: values[4],
+                        ttl: ttl
+                    }),
+                (arb_domain(), any::<u16>(), arb_domain(), arb_ttl())
+                    .prop_map(|(domain, priority, host, ttl)| DnsRecord::MX {
+                        domain: domain,
+                        priority: priority,
+                        host: host,
+                        ttl: ttl
+                    }),
+                (arb_domain(), "[ -~]{0,100}", arb_ttl())
+                    .prop_map(|(domain, data, ttl)| DnsRecord::TXT {
+                        domain: domain,
+                        data: vec![data],
+                        ttl: ttl
+                    }),
+                (arb_domain(), "[ -~]{0,100}", arb_ttl())
+                    .prop_map(|(domain, data, ttl)| DnsRecord::SPF {
+                        domain: domain,
+                        data: vec![data],
+                        ttl: ttl
+                    }),
+                (arb_domain(), any::<[u16; 3]>(), arb_domain(), arb_ttl())
+                    .prop_map(|(domain, values, host, ttl)| DnsRecord::SRV {
+                        domain: domain,
+                        priority: values[0],
+                        weight: values[1],
+                        port: values[2],
+                        host: host,
+                        ttl: ttl
+                    }),
+                (arb_domain(), any::<[u16; 2]>(), ("[A-Z]{0,2}", "[ -~]{0,40}", "[ -~]{0,100}"), arb_domain(), arb_ttl())
+                    .prop_map(|(domain, values, strings, replacement, ttl)| DnsRecord::NAPTR {
+                        domain: domain,
+                        order: values[0],
+                        preference: values[1],
+                        flags: strings.0,
+                        services: strings.1,
+                        regexp: strings.2,
+                        replacement: replacement,
+                        ttl: ttl
+                    }),
+                (arb_domain(), any::<[u8; 2]>(), prop::collection::vec(any::<u8>(), 0..64), arb_ttl())
+                    .prop_map(|(domain, values, fingerprint, ttl)| DnsRecord::SSHFP {
+                        domain: domain,
+                        algorithm: values[0],
+                        fp_type: values[1],
+                        fingerprint: fingerprint.to_hex(),
+                        ttl: ttl
+                    }),
+                (arb_domain(), prop::collection::vec(any::<u8>(), 0..64), arb_ttl())
+                    .prop_map(|(domain, data, ttl)| DnsRecord::DHCID {
+                        domain: domain,
+                        data: data,
+                        ttl: ttl
+                    }),
+                (arb_domain(), any::<[u8; 3]>(), prop::collection::vec(any::<u8>(), 0..64), arb_ttl())
+                    .prop_map(|(domain, values, cert_data, ttl)| DnsRecord::TLSA {
+                        domain: domain,
+                        usage: values[0],
+                        selector: values[1],
+                        matching_type: values[2],
+                        cert_data: cert_data.to_hex(),
+                        ttl: ttl
+                    }),
+                (arb_domain(), any::<u8>(), "[a-z0-9]{1,15}", "[ -~]{0,100}", arb_ttl())
+                    .prop_map(|(domain, flags, tag, value, ttl)| DnsRecord::CAA {
+                        domain: domain,
+                        flags: flags,
+                        tag: tag,
+                        value: value,
+                        ttl: ttl
+                    }),
+                (arb_domain(), arb_domain(), 0..(1u64 << 48), any::<[u16; 3]>(),
+                 prop::collection::vec(any::<u8>(), 0..64), prop::collection::vec(any::<u8>(), 0..8))
+                    .prop_map(|(domain, algorithm, time_signed, values, mac, other)| DnsRecord::TSIG {
+                        domain: domain,
+                        algorithm: algorithm,
+                        time_signed: time_signed,
+                        fudge: values[0],
+                        mac: mac,
+                        original_id: values[1],
+                        error: values[2],
+                        other: other
+                    })
+            ].boxed()
+        }
+    }
+
+    impl Arbitrary for DnsQuestion {
+        type Parameters = ();
+        type Strategy = BoxedStrategy<DnsQuestion>;
+
+        fn arbitrary_with(_: ()) -> Self::Strategy {
+            (arb_domain(), any::<u16>())
+                .prop_map(|(name, qtype)| DnsQuestion::new(name, QueryType::from_num(qtype)))
+                .boxed()
+        }
+    }
+
+    impl Arbitrary for DnsPacket {
+        type Parameters = ();
+        type Strategy = BoxedStrategy<DnsPacket>;
+
+        fn arbitrary_with(_: ()) -> Self::Strategy {
+            let flags = (any::<u16>(), any::<[bool; 7]>(), 0..16u8, 0..16u16);
+            let sections = (prop::collection::vec(any::<DnsQuestion>(), 0..3),
+                            prop::collection::vec(any::<DnsRecord>(), 0..6),
+                            prop::collection::vec(any::<DnsRecord>(), 0..6),
+                            prop::collection::vec(any::<DnsRecord>(), 0..6));
+
+            (flags, sections).prop_map(|((id, bits, opcode, rescode),
+                                         (questions, answers, authorities, resources))| {
+                let mut packet = DnsPacket::new();
+                packet.header.id = id;
+                packet.header.recursion_desired = bits[0];
+                packet.header.truncated_message = bits[1];
+                packet.header.authoritative_answer = bits[2];
+                packet.header.response = bits[3];
+                packet.header.checking_disabled = bits[4];
+                packet.header.authed_data = bits[5];
+                packet.header.recursion_available = bits[6];
+                packet.header.opcode = OpCode::from_num(opcode);
+                packet.header.rescode = ResultCode::from_num(rescode);
+                packet.questions = questions;
+                packet.answers = answers;
+                packet.authorities = authorities;
+                packet.resources = resources;
+
+                packet
+            }).boxed()
+        }
+    }
+
+    fn assert_records_eq(expected: &[DnsRecord], actual: &[DnsRecord]) {
+        assert_eq!(expected, actual);
+
+        // TTL's are ignored when comparing records, so check them separately
+        for (a, b) in expected.iter().zip(actual.iter()) {
+            assert_eq!(a.get_ttl(), b.get_ttl());
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(500))]
+
+        #[test]
+        fn test_packet_roundtrip(mut packet in any::<DnsPacket>()) {
+            let mut buffer = VectorPacketBuffer::new();
+            packet.write(&mut buffer, 0xFFFF).unwrap();
+
+            buffer.seek(0).unwrap();
+            let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+            // Everything written should have been consumed when reading
+            assert_eq!(buffer.buffer.len(), buffer.pos());
+
+            assert_eq!(packet.header.id, parsed.header.id);
+            assert_eq!(packet.header.recursion_desired, parsed.header.recursion_desired);
+            assert_eq!(packet.header.truncated_message, parsed.header.truncated_message);
+            assert_eq!(packet.header.authoritative_answer, parsed.header.authoritative_answer);
+            assert_eq!(packet.header.opcode, parsed.header.opcode);
+            assert_eq!(packet.header.response, parsed.header.response);
+            assert_eq!(packet.header.rescode, parsed.header.rescode);
+            assert_eq!(packet.header.checking_disabled, parsed.header.checking_disabled);
+            assert_eq!(packet.header.authed_data, parsed.header.authed_data);
+            assert_eq!(packet.header.recursion_available, parsed.header.recursion_available);
+
+            assert_eq!(packet.questions, parsed.questions);
+            assert_records_eq(&packet.answers, &parsed.answers);
+            assert_records_eq(&packet.authorities, &parsed.authorities);
+            assert_records_eq(&packet.resources, &parsed.resources);
+        }
+    }
+
+    #[test]
+    fn test_opcode() {
+        for num in 0..16 {
+            assert_eq!(num, OpCode::from_num(num).to_num());
+        }
+        assert_eq!(OpCode::UNKNOWN(3), OpCode::from_num(3));
+        assert_eq!(OpCode::QUERY, DnsHeader::default().opcode);
+
+        let mut packet = DnsPacket::new();
+        packet.header.id = 1234;
+        packet.header.opcode = OpCode::NOTIFY;
+        packet.header.authoritative_answer = true;
+        packet.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::SOA));
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        // The opcode sits between the QR bit and the AA bit
+        assert_eq!(0x24, buffer.buffer[2]);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(OpCode::NOTIFY, parsed.header.opcode);
+        assert!(parsed.header.authoritative_answer);
+        assert!(!parsed.header.response);
+        assert_eq!(packet.questions, parsed.questions);
+    }
+
+    #[test]
+    fn test_query() {
+        let packet = DnsPacket::query("google.com", QueryType::MX, true);
+
+        assert_eq!(1, packet.header.questions);
+        assert!(packet.header.recursion_desired);
+        assert!(!packet.header.response);
+        assert_eq!(OpCode::QUERY, packet.header.opcode);
+        assert_eq!(vec![DnsQuestion::new("google.com".to_string(), QueryType::MX)],
+                   packet.questions);
+        assert!(packet.answers.is_empty());
+
+        let packet = DnsPacket::query("google.com", QueryType::A, false);
+        assert!(!packet.header.recursion_desired);
+
+        // The ids are random, so a handful of queries won't all share one
+        let ids = (0..10).map(|_| DnsPacket::query("google.com", QueryType::A, true).header.id)
+            .collect::<Vec<u16>>();
+        assert!(ids.iter().any(|x| *x != ids[0]));
+    }
+
+    #[test]
+    fn test_extended_rescode() {
+        for num in 0..4096 {
+            assert_eq!(num, ResultCode::from_num(num).to_num());
+        }
+        assert_eq!(ResultCode::UNKNOWN(11), ResultCode::from_num(11));
+        assert_eq!(ResultCode::BADVERS, ResultCode::from_num(16));
+
+        let mut packet = DnsPacket::new();
+        packet.header.id = 1234;
+        packet.header.response = true;
+        packet.header.rescode = ResultCode::BADVERS;
+        packet.questions.push(DnsQuestion::new("google.com".to_string(), QueryType::A));
+        packet.add_edns0(4096);
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        // BADVERS is 16, which leaves zero in the header and 1 in the upper
+        // bits kept in the TTL field of the OPT record
+        assert_eq!(0, buffer.buffer[3] & 0x0F);
+        match packet.resources[0] {
+            DnsRecord::OPT { flags, .. } => assert_eq!(1, flags >> 24),
+            _ => panic!()
+        }
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(ResultCode::BADVERS, parsed.header.rescode);
+
+        // Without an OPT record only the header bits are available
+        buffer.seek(0).unwrap();
+        let mut header = DnsHeader::new();
+        header.read(&mut buffer).unwrap();
+        assert_eq!(ResultCode::NOERROR, header.rescode);
+
+        // Codes without a name are preserved as well
+        packet.header.rescode = ResultCode::UNKNOWN(0x123);
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(ResultCode::UNKNOWN(0x123), parsed.header.rescode);
+    }
+
     #[test]
     fn test_packet() {
         let mut packet = DnsPacket::new();
@@ -983,4 +2415,910 @@ mod tests {
         assert_eq!(packet.answers[2], parsed_packet.answers[2]);
         assert_eq!(packet.answers[3], parsed_packet.answers[3]);
     }
+
+    #[test]
+    fn test_deeply_compressed_names() {
+        // Every name ends in a pointer to the one before it, giving a chain
+        // of pointers as long as the names are deep
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+
+        let mut name = "example.com".to_string();
+        for label in &["a", "b", "c", "d", "e", "f", "g"] {
+            name = format!("{}.{}", label, name);
+            packet.answers.push(DnsRecord::A {
+                domain: name.clone(),
+                addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
+                ttl: TransientTtl(3600)
+            });
+        }
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        buffer.seek(0).unwrap();
+        let parsed_packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(packet.answers, parsed_packet.answers);
+        assert_eq!(Some("g.f.e.d.c.b.a.example.com".to_string()), parsed_packet.answers[6].get_domain());
+    }
+
+    #[test]
+    fn test_get_random_a() {
+        let mut rng = thread_rng();
+
+        let mut packet = DnsPacket::new();
+        assert_eq!(None, packet.get_random_a(&mut rng));
+
+        packet.answers.push(DnsRecord::CNAME {
+            domain: "www.google.com".to_string(),
+            host: "google.com".to_string(),
+            ttl: TransientTtl(3600)
+        });
+        assert_eq!(None, packet.get_random_a(&mut rng));
+
+        packet.answers.push(DnsRecord::A {
+            domain: "google.com".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        });
+
+        // The CNAME should never be picked
+        for _ in 0..10 {
+            assert_eq!(Some("127.0.0.1".to_string()), packet.get_random_a(&mut rng));
+        }
+    }
+
+    /// An `Rng` which always produces the same value
+    struct ConstantRng(u64);
+
+    impl Rng for ConstantRng {
+        fn next_u32(&mut self) -> u32 {
+            self.0 as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0
+        }
+    }
+
+    fn build_referral() -> DnsPacket {
+        let mut packet = DnsPacket::new();
+
+        for i in 0..4 {
+            packet.answers.push(DnsRecord::A {
+                domain: "google.com".to_string(),
+                addr: format!("10.0.0.{}", i).parse().unwrap(),
+                ttl: TransientTtl(3600)
+            });
+            packet.authorities.push(DnsRecord::NS {
+                domain: "com".to_string(),
+                host: format!("ns{}.com", i),
+                ttl: TransientTtl(3600)
+            });
+            packet.resources.push(DnsRecord::A {
+                domain: format!("ns{}.com", i),
+                addr: format!("192.168.0.{}", i).parse().unwrap(),
+                ttl: TransientTtl(3600)
+            });
+        }
+
+        packet
+    }
+
+    #[test]
+    fn test_deterministic_picks() {
+        let packet = build_referral();
+
+        // With a fixed value from the rng, the pick is fixed as well
+        let mut rng = ConstantRng(2);
+        assert_eq!(Some("10.0.0.2".to_string()), packet.get_random_a(&mut rng));
+        assert_eq!(Some("192.168.0.2".to_string()), packet.get_resolved_ns("google.com", &mut rng));
+        assert_eq!(Some("ns2.com".to_string()), packet.get_unresolved_ns("google.com", &mut rng));
+
+        // And identically seeded rngs produce the same sequence of picks
+        let seed: &[_] = &[1, 2, 3, 4];
+        let mut rng1 = StdRng::from_seed(seed);
+        let mut rng2 = StdRng::from_seed(seed);
+        for _ in 0..20 {
+            assert_eq!(packet.get_random_a(&mut rng1), packet.get_random_a(&mut rng2));
+            assert_eq!(packet.get_resolved_ns("google.com", &mut rng1),
+                       packet.get_resolved_ns("google.com", &mut rng2));
+            assert_eq!(packet.get_unresolved_ns("google.com", &mut rng1),
+                       packet.get_unresolved_ns("google.com", &mut rng2));
+        }
+    }
+
+    #[test]
+    fn test_answer_for() {
+        let query = DnsPacket::query("google.com", QueryType::MX, true);
+
+        let mut packet = DnsPacket::answer_for(&query);
+        assert_eq!(query.header.id, packet.header.id);
+        assert_eq!(query.questions, packet.questions);
+        assert!(packet.header.response);
+        assert!(packet.header.recursion_desired);
+        assert!(!packet.header.recursion_available);
+        assert!(packet.answers.is_empty());
+
+        // The skeleton reads back as a response to the query
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+        buffer.seek(0).unwrap();
+
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(query.header.id, parsed.header.id);
+        assert_eq!(1, parsed.questions.len());
+        assert_eq!("google.com", parsed.questions[0].name);
+        assert_eq!(QueryType::MX, parsed.questions[0].qtype);
+    }
+
+    #[test]
+    fn test_question_matches() {
+        let question = DnsQuestion::new("www.Google.com".to_string(), QueryType::A);
+
+        assert!(question.matches(&DnsQuestion::new("www.google.com".to_string(), QueryType::A)));
+        assert!(question.matches(&DnsQuestion::new("WWW.GOOGLE.COM".to_string(), QueryType::A)));
+        assert!(question.matches(&DnsQuestion::new("wWw.gOoGlE.CoM".to_string(), QueryType::A)));
+
+        assert!(!question.matches(&DnsQuestion::new("www.google.com".to_string(), QueryType::AAAA)));
+        assert!(!question.matches(&DnsQuestion::new("www.google.co".to_string(), QueryType::A)));
+        assert!(!question.matches(&DnsQuestion::new("ww.google.com".to_string(), QueryType::A)));
+
+        // Case aside, the derived equality is as strict as ever
+        assert!(question != DnsQuestion::new("www.google.com".to_string(), QueryType::A));
+    }
+
+    #[test]
+    fn test_resolved_ns_aaaa_glue() {
+        let mut packet = build_referral();
+        packet.authorities.push(DnsRecord::NS {
+            domain: "com".to_string(),
+            host: "ns6.com".to_string(),
+            ttl: TransientTtl(3600)
+        });
+        packet.resources.push(DnsRecord::AAAA {
+            domain: "ns6.com".to_string(),
+            addr: "2001:db8::6".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        });
+
+        let addrs = packet.get_resolved_ns_addrs("google.com");
+        assert_eq!(5, addrs.len());
+        assert!(addrs.contains(&"2001:db8::6".to_string()));
+
+        // A delegation with only IPv6 glue still gives an address
+        packet.resources.retain(|x| x.get_querytype() == QueryType::AAAA);
+
+        let mut rng = ConstantRng(2);
+        assert_eq!(Some("2001:db8::6".to_string()), packet.get_resolved_ns("google.com", &mut rng));
+    }
+
+    #[test]
+    fn test_txt_constructor() {
+        let key = format!("v=DKIM1; k=rsa; p={}", "A".repeat(400));
+
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::txt("mail._domainkey.example.com", &key, 300));
+        packet.answers.push(DnsRecord::txt("example.com", "v=spf1 -all", 300));
+
+        match packet.answers[1] {
+            DnsRecord::TXT { ref data, .. } => assert_eq!(vec!["v=spf1 -all".to_string()], *data),
+            _ => panic!()
+        }
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        // The long value is chunked at 255 bytes, and comes back the same way
+        assert_eq!(packet.answers, parsed.answers);
+        assert_eq!(300, parsed.answers[0].get_ttl());
+        match parsed.answers[0] {
+            DnsRecord::TXT { ref domain, ref data, .. } => {
+                assert_eq!("mail._domainkey.example.com", domain);
+                assert_eq!(vec![255, key.len() - 255], data.iter().map(|x| x.len()).collect::<Vec<usize>>());
+                assert_eq!(key, data.concat());
+            },
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn test_txt_roundtrip() {
+        let long = "a".repeat(300);
+
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::TXT {
+            domain: "google.com".to_string(),
+            data: vec!["v=spf1 -all".to_string(), String::new(), "\u{e5}".to_string()],
+            ttl: TransientTtl(3600)
+        });
+        packet.answers.push(DnsRecord::TXT {
+            domain: "google.com".to_string(),
+            data: vec![long.clone()],
+            ttl: TransientTtl(3600)
+        });
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(2, parsed.answers.len());
+
+        // The boundaries between strings are kept
+        assert_eq!(packet.answers[0], parsed.answers[0]);
+
+        // and long strings are split up on the way
+        match parsed.answers[1] {
+            DnsRecord::TXT { ref data, .. } => {
+                assert_eq!(vec![255, 45], data.iter().map(|x| x.len()).collect::<Vec<usize>>());
+                assert_eq!(long, data.concat());
+            },
+            _ => panic!()
+        }
+
+        // A string running past the end of the record is rejected
+        let mut buffer = VectorPacketBuffer::new();
+        packet.answers[0].write(&mut buffer).unwrap();
+
+        // the data length follows the name, type, class and TTL
+        buffer.set_u16(12 + 8, 5).unwrap();
+
+        buffer.seek(0).unwrap();
+        match DnsRecord::read(&mut buffer) {
+            Err(DnsError::Malformed(_)) => {},
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn test_spf_roundtrip() {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::SPF {
+            domain: "google.com".to_string(),
+            data: vec!["v=spf1 include:_spf.google.com ~all".to_string()],
+            ttl: TransientTtl(3600)
+        });
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(1, parsed.answers.len());
+        assert_eq!(QueryType::SPF, parsed.answers[0].get_querytype());
+        assert_eq!(packet.answers[0], parsed.answers[0]);
+        assert_eq!(3600, parsed.answers[0].get_ttl());
+    }
+
+    #[test]
+    fn test_ptr_roundtrip() {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new("1.0.0.127.in-addr.arpa".to_string(), QueryType::PTR));
+        packet.answers.push(DnsRecord::PTR {
+            domain: "1.0.0.127.in-addr.arpa".to_string(),
+            host: "localhost.example.com".to_string(),
+            ttl: TransientTtl(3600)
+        });
+        packet.answers.push(DnsRecord::PTR {
+            domain: "1.0.0.127.in-addr.arpa".to_string(),
+            host: "www.example.com".to_string(),
+            ttl: TransientTtl(3600)
+        });
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        // The owner names are written as pointers to the question, and the
+        // second target shares the suffix of the first
+        let uncompressed = 12 + (24 + 4) + 2 * (24 + 10) + 23 + 17;
+        assert!(buffer.pos() < uncompressed);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(QueryType::PTR, parsed.questions[0].qtype);
+        assert_eq!(2, parsed.answers.len());
+        assert_eq!(QueryType::PTR, parsed.answers[0].get_querytype());
+        assert_eq!(packet.answers, parsed.answers);
+        assert_eq!(Some("1.0.0.127.in-addr.arpa".to_string()), parsed.answers[0].get_domain());
+        assert_eq!(3600, parsed.answers[1].get_ttl());
+
+        match parsed.answers[1] {
+            DnsRecord::PTR { ref host, .. } => assert_eq!("www.example.com", host),
+            _ => panic!()
+        }
+
+        assert_eq!(QueryType::PTR, "ptr".parse::<QueryType>().unwrap());
+        assert_eq!(QueryType::PTR, QueryType::from_num(12));
+    }
+
+    #[test]
+    fn test_dname_roundtrip() {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new("a.b.example.com".to_string(), QueryType::A));
+        packet.answers.push(DnsRecord::DNAME {
+            domain: "b.example.com".to_string(),
+            host: "example.net".to_string(),
+            ttl: TransientTtl(3600)
+        });
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        // The rdata length is filled in once the target has been written
+        let end = buffer.pos();
+        assert_eq!([0, 13], buffer.buffer[end-15..end-13]);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(1, parsed.answers.len());
+        assert_eq!(QueryType::DNAME, parsed.answers[0].get_querytype());
+        assert_eq!(packet.answers, parsed.answers);
+        assert_eq!(Some("b.example.com".to_string()), parsed.answers[0].get_domain());
+        assert_eq!(3600, parsed.answers[0].get_ttl());
+
+        match parsed.answers[0] {
+            DnsRecord::DNAME { ref host, .. } => assert_eq!("example.net", host),
+            _ => panic!()
+        }
+
+        assert_eq!(QueryType::DNAME, "dname".parse::<QueryType>().unwrap());
+        assert_eq!(QueryType::DNAME, QueryType::from_num(39));
+    }
+
+    #[test]
+    fn test_dhcid_roundtrip() {
+        // The identifier from the example in RFC 4701 section 3.6.1
+        let data = "AAIBY2/AuCccgoJbsaxcQc9TUapptP69lOjxfNuVAA2kjEA="
+            .from_base64().unwrap();
+
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::DHCID {
+            domain: "chi.example.com".to_string(),
+            data: data.clone(),
+            ttl: TransientTtl(3600)
+        });
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(1, parsed.answers.len());
+        assert_eq!(QueryType::DHCID, parsed.answers[0].get_querytype());
+        assert_eq!(packet.answers[0], parsed.answers[0]);
+
+        match parsed.answers[0] {
+            DnsRecord::DHCID { ref data, .. } => assert_eq!(35, data.len()),
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn test_naptr_roundtrip() {
+        // An ENUM entry for +44 1632 960083, rewriting the number into a SIP URI
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::NAPTR {
+            domain: "3.8.0.0.6.9.2.3.6.1.4.4.e164.arpa".to_string(),
+            order: 100,
+            preference: 10,
+            flags: "u".to_string(),
+            services: "E2U+sip".to_string(),
+            regexp: "!^\\+441632960083$!sip:info@example.com!".to_string(),
+            replacement: String::new(),
+            ttl: TransientTtl(3600)
+        });
+        // and a non-terminal rule, handing over to another name
+        packet.answers.push(DnsRecord::NAPTR {
+            domain: "3.8.0.0.6.9.2.3.6.1.4.4.e164.arpa".to_string(),
+            order: 200,
+            preference: 10,
+            flags: String::new(),
+            services: "E2U+sip".to_string(),
+            regexp: String::new(),
+            replacement: "sip.e164.arpa".to_string(),
+            ttl: TransientTtl(3600)
+        });
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        // Both rules share the owner name, but the replacement is written out
+        // in full even though its suffix came before
+        let end = buffer.pos();
+        assert_eq!([0, 0xC8, 0, 0x0A, 0, 7], buffer.buffer[end-29..end-23]);
+        assert_eq!([0, 3, b's', b'i', b'p', 4, b'e', b'1', b'6', b'4', 4, b'a', b'r', b'p', b'a', 0],
+                   buffer.buffer[end-16..end]);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(2, parsed.answers.len());
+        assert_eq!(QueryType::NAPTR, parsed.answers[0].get_querytype());
+        assert_eq!(packet.answers, parsed.answers);
+
+        match parsed.answers[0] {
+            DnsRecord::NAPTR { ref flags, ref services, ref regexp, ref replacement, .. } => {
+                assert_eq!("u", flags);
+                assert_eq!("E2U+sip", services);
+                assert_eq!("!^\\+441632960083$!sip:info@example.com!", regexp);
+                assert_eq!("", replacement);
+            },
+            _ => panic!()
+        }
+
+        // Strings longer than a character-string can't be written
+        let rec = DnsRecord::NAPTR {
+            domain: "example.com".to_string(),
+            order: 1,
+            preference: 1,
+            flags: String::new(),
+            services: String::new(),
+            regexp: "x".repeat(256),
+            replacement: String::new(),
+            ttl: TransientTtl(3600)
+        };
+        assert!(rec.write(&mut VectorPacketBuffer::new()).is_err());
+    }
+
+    #[test]
+    fn test_sshfp_roundtrip() {
+        // A SHA-256 fingerprint of an Ed25519 key
+        let fingerprint = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::SSHFP {
+            domain: "host.example.com".to_string(),
+            algorithm: 4,
+            fp_type: 2,
+            fingerprint: fingerprint.to_string(),
+            ttl: TransientTtl(3600)
+        });
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        // The fingerprint is sent as raw bytes, after the algorithm and type
+        let end = buffer.pos();
+        assert_eq!([0, 34, 4, 2, 0xe3, 0xb0], buffer.buffer[end-36..end-30]);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(1, parsed.answers.len());
+        assert_eq!(QueryType::SSHFP, parsed.answers[0].get_querytype());
+        assert_eq!(packet.answers, parsed.answers);
+        assert_eq!(QueryType::SSHFP, "SSHFP".parse::<QueryType>().unwrap());
+
+        // Fingerprints that aren't hex can't be written
+        let rec = DnsRecord::SSHFP {
+            domain: "host.example.com".to_string(),
+            algorithm: 4,
+            fp_type: 2,
+            fingerprint: "not hex".to_string(),
+            ttl: TransientTtl(3600)
+        };
+        assert!(rec.write(&mut VectorPacketBuffer::new()).is_err());
+    }
+
+    #[test]
+    fn test_tlsa_roundtrip() {
+        // A SHA-256 digest of the public key of the server certificate
+        let cert_data = "d2abde240d7cd3ee6b4b28c54df034b97983a1d16e8a410e4561cb106618e971";
+
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::TLSA {
+            domain: "_443._tcp.example.com".to_string(),
+            usage: 3,
+            selector: 1,
+            matching_type: 1,
+            cert_data: cert_data.to_string(),
+            ttl: TransientTtl(3600)
+        });
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        let end = buffer.pos();
+        assert_eq!([0, 35, 3, 1, 1, 0xd2], buffer.buffer[end-37..end-31]);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(1, parsed.answers.len());
+        assert_eq!(QueryType::TLSA, parsed.answers[0].get_querytype());
+        assert_eq!(packet.answers, parsed.answers);
+        assert_eq!(Some("_443._tcp.example.com".to_string()), parsed.answers[0].get_domain());
+
+        match parsed.answers[0] {
+            DnsRecord::TLSA { usage, selector, matching_type, ref cert_data, .. } => {
+                assert_eq!((3, 1, 1), (usage, selector, matching_type));
+                assert_eq!(32, cert_data.len() / 2);
+            },
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn test_caa_roundtrip() {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::CAA {
+            domain: "example.com".to_string(),
+            flags: 0,
+            tag: "issue".to_string(),
+            value: "letsencrypt.org".to_string(),
+            ttl: TransientTtl(3600)
+        });
+        packet.answers.push(DnsRecord::CAA {
+            domain: "example.com".to_string(),
+            flags: 128,
+            tag: "iodef".to_string(),
+            value: "mailto:security@example.com".to_string(),
+            ttl: TransientTtl(3600)
+        });
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(2, parsed.answers.len());
+        assert_eq!(QueryType::CAA, parsed.answers[0].get_querytype());
+        assert_eq!(packet.answers, parsed.answers);
+
+        match parsed.answers[1] {
+            DnsRecord::CAA { flags, ref tag, ref value, .. } => {
+                assert_eq!(128, flags);
+                assert_eq!("iodef", tag);
+                assert_eq!("mailto:security@example.com", value);
+            },
+            _ => panic!()
+        }
+
+        // A tag running past the end of the record is rejected
+        let mut buffer = VectorPacketBuffer::new();
+        packet.answers[0].write(&mut buffer).unwrap();
+        // the data length follows the name, type, class and TTL
+        buffer.set_u16(13 + 8, 3).unwrap();
+
+        buffer.seek(0).unwrap();
+        match DnsRecord::read(&mut buffer) {
+            Err(DnsError::Malformed(_)) => {},
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn test_tsig_roundtrip() {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 4321;
+        packet.resources.push(DnsRecord::TSIG {
+            domain: "transfer.example.com".to_string(),
+            algorithm: "hmac-sha256".to_string(),
+            time_signed: 0x0123_4567_89AB,
+            fudge: 300,
+            mac: vec![0xAB; 32],
+            original_id: 1234,
+            error: 18,
+            other: vec![0, 0, 0x12, 0x34, 0x56, 0x78]
+        });
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(1, parsed.resources.len());
+        assert_eq!(QueryType::TSIG, parsed.resources[0].get_querytype());
+        assert_eq!(packet.resources[0], parsed.resources[0]);
+        assert_eq!(0, parsed.resources[0].get_ttl());
+    }
+
+    #[test]
+    fn test_split_character_strings() {
+        assert_eq!(vec![""], split_character_strings(""));
+        assert_eq!(vec!["v=spf1 -all"], split_character_strings("v=spf1 -all"));
+
+        let long = "a".repeat(300);
+        let split = split_character_strings(&long);
+        assert_eq!(vec![255, 45], split.iter().map(|x| x.len()).collect::<Vec<usize>>());
+        assert_eq!(long, split.concat());
+
+        // Multi byte characters are kept whole
+        let long = "\u{e5}".repeat(200);
+        let split = split_character_strings(&long);
+        assert_eq!(vec![254, 146], split.iter().map(|x| x.len()).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn test_rotate_answers() {
+        let build_a = |addr: &str| DnsRecord::A {
+            domain: "google.com".to_string(),
+            addr: addr.parse().unwrap(),
+            ttl: TransientTtl(3600)
+        };
+
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::CNAME {
+            domain: "www.google.com".to_string(),
+            host: "google.com".to_string(),
+            ttl: TransientTtl(3600)
+        });
+        packet.answers.push(build_a("127.0.0.1"));
+        packet.answers.push(build_a("127.0.0.2"));
+        packet.answers.push(DnsRecord::AAAA {
+            domain: "google.com".to_string(),
+            addr: "::1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        });
+        packet.answers.push(build_a("127.0.0.3"));
+
+        let original = packet.answers.clone();
+
+        // The addresses take turns at the head, and other records stay put
+        packet.rotate_answers(1);
+        assert_eq!(vec![original[0].clone(),
+                        build_a("127.0.0.2"),
+                        build_a("127.0.0.3"),
+                        original[3].clone(),
+                        build_a("127.0.0.1")], packet.answers);
+
+        let mut packet2 = DnsPacket::new();
+        packet2.answers = original.clone();
+        packet2.rotate_answers(5);
+        assert_eq!(build_a("127.0.0.3"), packet2.answers[1]);
+
+        let mut packet3 = DnsPacket::new();
+        packet3.answers = original.clone();
+        packet3.rotate_answers(3);
+        assert_eq!(original, packet3.answers);
+    }
+
+    #[test]
+    fn test_merge() {
+        let cname = DnsRecord::CNAME {
+            domain: "www.google.com".to_string(),
+            host: "google.com".to_string(),
+            ttl: TransientTtl(3600)
+        };
+        let a1 = DnsRecord::A {
+            domain: "google.com".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        };
+        let a2 = DnsRecord::A {
+            domain: "google.com".to_string(),
+            addr: "127.0.0.2".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        };
+
+        let mut packet = DnsPacket::new();
+        packet.header.id = 1337;
+        packet.header.response = true;
+        packet.questions.push(DnsQuestion::new("www.google.com".to_string(), QueryType::A));
+        packet.answers.push(cname.clone());
+        packet.answers.push(a1.clone());
+
+        // The overlapping record differs only by TTL
+        let mut other = DnsPacket::new();
+        other.header.id = 42;
+        other.header.rescode = ResultCode::NXDOMAIN;
+        other.answers.push(DnsRecord::A {
+            domain: "google.com".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(60)
+        });
+        other.answers.push(a2.clone());
+
+        packet.merge(&other);
+
+        assert_eq!(vec![cname, a1, a2], packet.answers);
+        assert_eq!(3600, packet.answers[1].get_ttl());
+        assert_eq!(3, packet.header.answers);
+        assert_eq!(1, packet.header.questions);
+
+        // The header of the packet merged into is kept
+        assert_eq!(1337, packet.header.id);
+        assert!(packet.header.response);
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+
+        // Counts written are those of the records, not doubled up
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(3, parsed.header.answers);
+        assert_eq!(3, parsed.answers.len());
+    }
+
+    #[test]
+    fn test_strip_additional() {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::NS {
+            domain: "google.com".to_string(),
+            host: "ns1.google.com".to_string(),
+            ttl: TransientTtl(3600)
+        });
+        packet.resources.push(DnsRecord::A {
+            domain: "ns1.google.com".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        });
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: 4096,
+            flags: 0,
+            options: Vec::new()
+        });
+        packet.resources.push(DnsRecord::AAAA {
+            domain: "ns1.google.com".to_string(),
+            addr: "::1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        });
+
+        packet.strip_additional();
+
+        assert_eq!(1, packet.answers.len());
+        assert_eq!(1, packet.resources.len());
+        assert_eq!(QueryType::OPT, packet.resources[0].get_querytype());
+        assert_eq!(1, packet.header.resource_entries);
+    }
+
+    #[test]
+    fn test_add_edns0() {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new("google.com".to_string(), QueryType::A));
+        for i in 0..40 {
+            packet.answers.push(DnsRecord::A {
+                domain: "google.com".to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, i),
+                ttl: TransientTtl(3600)
+            });
+        }
+
+        assert_eq!(None, packet.edns_udp_size());
+
+        packet.add_edns0(1232);
+        packet.add_edns0(4096);
+        assert_eq!(1, packet.resources.len());
+        assert_eq!(Some(4096), packet.edns_udp_size());
+
+        // The answers exceed 512 bytes, but fit the advertised size
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 4096).unwrap();
+        assert!(buffer.pos() > 512);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert!(!parsed.header.truncated_message);
+        assert_eq!(40, parsed.answers.len());
+        assert_eq!(Some(4096), parsed.edns_udp_size());
+        assert!(parsed.has_valid_edns());
+
+        // With the limit of plain DNS, the response is truncated but keeps
+        // the OPT record
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 512).unwrap();
+        assert!(buffer.pos() <= 512);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert!(parsed.header.truncated_message);
+        assert!(parsed.answers.len() < 40);
+        assert_eq!(Some(4096), parsed.edns_udp_size());
+    }
+
+    #[test]
+    fn test_edns_options() {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new("google.com".to_string(), QueryType::A));
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: 4096,
+            flags: 0,
+            options: vec![EdnsOption { code: 10, data: vec![0xFF, 0x00, 0x80] },
+                          EdnsOption { code: EDNS_TCP_KEEPALIVE, data: Vec::new() }]
+        });
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(packet.resources, parsed.resources);
+        assert_eq!(Some(&EdnsOption { code: EDNS_TCP_KEEPALIVE, data: Vec::new() }),
+                   parsed.get_edns_option(EDNS_TCP_KEEPALIVE));
+        assert!(parsed.get_edns_option(EDNS_PADDING).is_none());
+    }
+
+    #[test]
+    fn test_write_reserves_opt() {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.questions.push(DnsQuestion::new("google.com".to_string(), QueryType::A));
+        for i in 0..50 {
+            packet.answers.push(DnsRecord::A {
+                domain: "google.com".to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, i),
+                ttl: TransientTtl(3600)
+            });
+        }
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: 512,
+            flags: 0,
+            options: vec![EdnsOption { code: EDNS_PADDING, data: vec![0; 200] }]
+        });
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 512).unwrap();
+
+        // The answers are trimmed to leave room for the OPT record
+        assert!(buffer.pos() <= 512);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert!(parsed.header.truncated_message);
+        assert!(!parsed.answers.is_empty());
+        assert!(parsed.answers.len() < 50);
+        assert_eq!(1, parsed.resources.len());
+        assert_eq!(Some(&EdnsOption { code: EDNS_PADDING, data: vec![0; 200] }),
+                   parsed.get_edns_option(EDNS_PADDING));
+    }
+    #[test]
+    fn test_write_opt_at_size_boundary() {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.questions.push(DnsQuestion::new("google.com".to_string(), QueryType::A));
+        for i in 0..20 {
+            packet.answers.push(DnsRecord::A {
+                domain: "google.com".to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, i),
+                ttl: TransientTtl(3600)
+            });
+        }
+        packet.add_edns0(4096);
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+        let full_size = buffer.pos();
+
+        // A limit of exactly the full size fits everything
+        let mut buffer = VectorPacketBuffer::new();
+        packet.clone().write(&mut buffer, full_size).unwrap();
+        assert_eq!(full_size, buffer.pos());
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert!(!parsed.header.truncated_message);
+        assert_eq!(20, parsed.answers.len());
+        assert_eq!(1, parsed.header.resource_entries);
+        assert_eq!(1, parsed.resources.len());
+
+        // while a byte less drops the last answer, and nothing more, keeping
+        // the OPT record and its count
+        let mut buffer = VectorPacketBuffer::new();
+        packet.clone().write(&mut buffer, full_size - 1).unwrap();
+        assert!(buffer.pos() < full_size);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert!(parsed.header.truncated_message);
+        assert_eq!(19, parsed.answers.len());
+        assert_eq!(1, parsed.header.resource_entries);
+        assert_eq!(QueryType::OPT, parsed.resources[0].get_querytype());
+    }
 }
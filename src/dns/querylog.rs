@@ -0,0 +1,343 @@
+//! structured logging of queries, optionally enriched with client information
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Result, Error, ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Sender};
+use std::thread::Builder;
+
+use chrono::*;
+use rustc_serialize::json::{self, ToJson, Json};
+
+use dns::netutil::Network;
+use dns::protocol::{QueryType, ResultCode};
+use dns::server::Transport;
+
+/// Information about the network a client belongs to
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ClientInfo {
+    pub asn: u32,
+    pub country: String
+}
+
+/// A source of information about clients, used to enrich the query log
+pub trait ClientInfoLookup {
+    fn lookup(&self, addr: &IpAddr) -> Option<ClientInfo>;
+}
+
+/// A network along with the information for addresses within it
+struct NetworkEntry {
+    network: Network,
+    info: ClientInfo
+}
+
+/// A database mapping networks to ASN and country
+///
+/// The database is read from a CSV file in the style of the MaxMind CSV
+/// exports, with one network per line as `network,asn,country`, for instance
+/// `192.0.2.0/24,64496,NO`. A header line and comments starting with `#` are
+/// ignored. When networks overlap, the most specific one wins.
+#[derive(Default)]
+pub struct IpDatabase {
+    entries: Vec<NetworkEntry>
+}
+
+impl IpDatabase {
+    pub fn new() -> IpDatabase {
+        IpDatabase {
+            entries: Vec::new()
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<IpDatabase> {
+        let file = try!(File::open(path));
+
+        let mut lines = Vec::new();
+        for line in BufReader::new(file).lines() {
+            lines.push(try!(line));
+        }
+
+        IpDatabase::parse(&lines.join("\n"))
+    }
+
+    pub fn parse(data: &str) -> Result<IpDatabase> {
+        let mut db = IpDatabase::new();
+
+        for (idx, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("network,") {
+                continue;
+            }
+
+            let fields = line.split(',').map(|x| x.trim()).collect::<Vec<&str>>();
+            if fields.len() < 3 {
+                return Err(Error::new(ErrorKind::InvalidData,
+                                      format!("Invalid entry on line {}", idx + 1)));
+            }
+
+            let network = fields[0].parse::<Network>().ok();
+            let asn = fields[1].trim_left_matches("AS").parse::<u32>().ok();
+
+            match (network, asn) {
+                (Some(network), Some(asn)) => {
+                    db.entries.push(NetworkEntry {
+                        network: network,
+                        info: ClientInfo {
+                            asn: asn,
+                            country: fields[2].to_string()
+                        }
+                    });
+                },
+                _ => {
+                    return Err(Error::new(ErrorKind::InvalidData,
+                                          format!("Invalid entry on line {}", idx + 1)));
+                }
+            }
+        }
+
+        // Check the most specific networks first
+        db.entries.sort_by(|a, b| b.network.prefix_len.cmp(&a.network.prefix_len));
+
+        Ok(db)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl ClientInfoLookup for IpDatabase {
+    fn lookup(&self, addr: &IpAddr) -> Option<ClientInfo> {
+        self.entries.iter()
+            .find(|x| x.network.contains(addr))
+            .map(|x| x.info.clone())
+    }
+}
+
+/// A single entry of the query log
+#[derive(Clone,Debug)]
+pub struct QueryLogEntry {
+    pub timestamp: DateTime<Local>,
+    pub client: SocketAddr,
+    pub transport: Transport,
+    pub qname: String,
+    pub qtype: QueryType,
+    pub rescode: ResultCode,
+    pub elapsed_ms: i64
+}
+
+impl QueryLogEntry {
+    pub fn to_json(&self, info: Option<&ClientInfo>) -> Json {
+        let mut d = BTreeMap::new();
+        d.insert("timestamp".to_string(), self.timestamp.to_rfc3339().to_json());
+        d.insert("client".to_string(), self.client.ip().to_string().to_json());
+        d.insert("port".to_string(), self.client.port().to_json());
+        d.insert("transport".to_string(), format!("{:?}", self.transport).to_lowercase().to_json());
+        d.insert("qname".to_string(), self.qname.to_json());
+        d.insert("qtype".to_string(), format!("{:?}", self.qtype).to_json());
+        d.insert("rescode".to_string(), format!("{:?}", self.rescode).to_json());
+        d.insert("elapsed_ms".to_string(), self.elapsed_ms.to_json());
+
+        if let Some(info) = info {
+            d.insert("asn".to_string(), info.asn.to_json());
+            d.insert("country".to_string(), info.country.to_json());
+        }
+
+        Json::Object(d)
+    }
+}
+
+/// Writes log entries as JSON lines, enriching them with client information
+/// if a lookup is available
+///
+/// Lookups are cached per address, since the same clients tend to show up
+/// over and over.
+pub struct QueryLogWriter<W: Write> {
+    output: W,
+    lookup: Option<Box<ClientInfoLookup + Send>>,
+    cache: HashMap<IpAddr, Option<ClientInfo>>
+}
+
+/// Upper bound on the number of cached client lookups
+const MAX_CACHED_LOOKUPS: usize = 100_000;
+
+impl<W: Write> QueryLogWriter<W> {
+    pub fn new(output: W, lookup: Option<Box<ClientInfoLookup + Send>>) -> QueryLogWriter<W> {
+        QueryLogWriter {
+            output: output,
+            lookup: lookup,
+            cache: HashMap::new()
+        }
+    }
+
+    pub fn write_entry(&mut self, entry: &QueryLogEntry) -> Result<()> {
+        let addr = entry.client.ip();
+
+        let info = match self.lookup {
+            Some(ref lookup) => {
+                if !self.cache.contains_key(&addr) {
+                    if self.cache.len() >= MAX_CACHED_LOOKUPS {
+                        self.cache.clear();
+                    }
+                    self.cache.insert(addr, lookup.lookup(&addr));
+                }
+
+                self.cache.get(&addr).and_then(|x| x.clone())
+            },
+            None => None
+        };
+
+        let line = match json::encode(&entry.to_json(info.as_ref())) {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to encode log entry"))
+        };
+
+        try!(writeln!(self.output, "{}", line));
+        self.output.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+/// The query log
+///
+/// Logging is disabled until `start` is called. Entries are handed off to a
+/// separate thread, which takes care of enrichment and output, so that
+/// logging doesn't hold up responses.
+#[derive(Default)]
+pub struct QueryLog {
+    sender: Mutex<Option<Sender<QueryLogEntry>>>
+}
+
+impl QueryLog {
+    pub fn new() -> QueryLog {
+        QueryLog {
+            sender: Mutex::new(None)
+        }
+    }
+
+    pub fn start<W>(&self, output: W, lookup: Option<Box<ClientInfoLookup + Send>>) -> Result<()>
+        where W: Write + Send + 'static
+    {
+        let (tx, rx) = channel::<QueryLogEntry>();
+
+        let mut writer = QueryLogWriter::new(output, lookup);
+        let _ = try!(Builder::new().name("QueryLog-writer".into()).spawn(move || {
+            for entry in rx.iter() {
+                if let Err(e) = writer.write_entry(&entry) {
+                    println!("Failed to write query log entry: {:?}", e);
+                }
+            }
+        }));
+
+        match self.sender.lock() {
+            Ok(mut sender) => *sender = Some(tx),
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        }
+
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        match self.sender.lock() {
+            Ok(x) => x.is_some(),
+            Err(_) => false
+        }
+    }
+
+    pub fn log(&self, entry: QueryLogEntry) {
+        if let Ok(sender) = self.sender.lock() {
+            if let Some(ref sender) = *sender {
+                let _ = sender.send(entry);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const TEST_DB: &'static str = "network,asn,country
+# Documentation networks
+192.0.2.0/24,64496,NO
+198.51.100.0/24,AS64497,SE
+198.51.100.128/25,64498,DK
+2001:db8::/32,64499,FI";
+
+    fn build_entry(client: &str) -> QueryLogEntry {
+        QueryLogEntry {
+            timestamp: Local::now(),
+            client: client.parse().unwrap(),
+            transport: Transport::Udp,
+            qname: "google.com".to_string(),
+            qtype: QueryType::A,
+            rescode: ResultCode::NOERROR,
+            elapsed_ms: 3
+        }
+    }
+
+    #[test]
+    fn test_ip_database() {
+        let db = IpDatabase::parse(TEST_DB).unwrap();
+        assert_eq!(4, db.len());
+
+        let lookup = |x: &str| db.lookup(&x.parse::<IpAddr>().unwrap()).map(|x| x.asn);
+
+        assert_eq!(Some(64496), lookup("192.0.2.1"));
+        assert_eq!(Some(64497), lookup("198.51.100.1"));
+        assert_eq!(Some(64498), lookup("198.51.100.200"));
+        assert_eq!(Some(64499), lookup("2001:db8::1"));
+        assert_eq!(None, lookup("203.0.113.1"));
+        assert_eq!(None, lookup("::ffff:192.0.2.1"));
+
+        assert!(IpDatabase::parse("192.0.2.0/33,64496,NO").is_err());
+        assert!(IpDatabase::parse("192.0.2.0/24,NO").is_err());
+    }
+
+    #[test]
+    fn test_log_enrichment() {
+        let db = IpDatabase::parse(TEST_DB).unwrap();
+        let mut writer = QueryLogWriter::new(Vec::new(), Some(Box::new(db)));
+
+        writer.write_entry(&build_entry("192.0.2.10:5353")).unwrap();
+        writer.write_entry(&build_entry("203.0.113.1:5353")).unwrap();
+
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        let lines = output.lines()
+            .map(|x| Json::from_str(x).unwrap())
+            .collect::<Vec<Json>>();
+
+        assert_eq!(2, lines.len());
+        assert_eq!(Some(64496), lines[0].find("asn").and_then(|x| x.as_u64()));
+        assert_eq!(Some("NO"), lines[0].find("country").and_then(|x| x.as_string()));
+        assert_eq!(Some("192.0.2.10"), lines[0].find("client").and_then(|x| x.as_string()));
+        assert_eq!(Some("google.com"), lines[0].find("qname").and_then(|x| x.as_string()));
+
+        // Addresses outside the database are logged without enrichment
+        assert!(lines[1].find("asn").is_none());
+    }
+
+    #[test]
+    fn test_log_without_database() {
+        let mut writer = QueryLogWriter::new(Vec::new(), None);
+        writer.write_entry(&build_entry("192.0.2.10:5353")).unwrap();
+
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        let entry = Json::from_str(output.trim()).unwrap();
+
+        assert!(entry.find("asn").is_none());
+        assert!(entry.find("country").is_none());
+        assert_eq!(Some("A"), entry.find("qtype").and_then(|x| x.as_string()));
+    }
+}
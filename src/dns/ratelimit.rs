@@ -0,0 +1,228 @@
+//! per client rate limiting of queries over UDP, which keeps the server from
+//! being of much use in amplification attacks
+
+use std::collections::HashMap;
+use std::io::{Result, Error, ErrorKind};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Default number of queries a client may send at once
+pub const DEFAULT_BURST: u32 = 20;
+
+/// How often buckets of clients that have gone quiet are removed
+const PRUNE_INTERVAL_SECS: u64 = 10;
+
+/// What to do with queries from clients over their limit
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum RateLimitAction {
+    /// Don't answer at all
+    Drop,
+
+    /// Answer with an empty response with the TC bit set, so that legitimate
+    /// clients retry over TCP, where the source address can't be spoofed
+    Truncate
+}
+
+impl RateLimitAction {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            RateLimitAction::Drop => "drop",
+            RateLimitAction::Truncate => "truncate"
+        }
+    }
+}
+
+impl FromStr for RateLimitAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<RateLimitAction> {
+        match s {
+            "drop" => Ok(RateLimitAction::Drop),
+            "truncate" => Ok(RateLimitAction::Truncate),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "Unknown rate limit action"))
+        }
+    }
+}
+
+/// The queries a single client has left, refilled at a steady rate
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant
+}
+
+struct LimiterState {
+    buckets: HashMap<IpAddr, TokenBucket>,
+    pruned: Instant
+}
+
+/// A token bucket rate limiter, keyed by client address
+///
+/// Each client may send `queries_per_second` queries a second on average, and
+/// up to `burst` queries at once. Limiting is disabled while
+/// `queries_per_second` is unset.
+pub struct RateLimiter {
+    pub queries_per_second: Option<u32>,
+    pub burst: u32,
+    pub action: RateLimitAction,
+    state: Mutex<LimiterState>,
+    limited_count: AtomicUsize
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new()
+    }
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter {
+            queries_per_second: None,
+            burst: DEFAULT_BURST,
+            action: RateLimitAction::Drop,
+            state: Mutex::new(LimiterState {
+                buckets: HashMap::new(),
+                pruned: Instant::now()
+            }),
+            limited_count: AtomicUsize::new(0)
+        }
+    }
+
+    /// Check whether a query from `client` is within its limit, taking a
+    /// token from its bucket if so
+    pub fn check(&self, client: IpAddr) -> bool {
+        self.check_at(client, Instant::now())
+    }
+
+    /// As `check`, for a query received at `now`
+    pub fn check_at(&self, client: IpAddr, now: Instant) -> bool {
+        let rate = match self.queries_per_second {
+            Some(x) => x as f64,
+            None => return true
+        };
+
+        let capacity = self.burst as f64;
+
+        let mut state = match self.state.lock() {
+            Ok(x) => x,
+            Err(_) => return true
+        };
+
+        // A bucket that has been idle long enough to fill up again is no
+        // different from a new one, so there's no need to keep it around
+        if elapsed_secs(state.pruned, now) >= PRUNE_INTERVAL_SECS as f64 {
+            state.buckets.retain(|_, bucket| {
+                elapsed_secs(bucket.updated, now) * rate < capacity - bucket.tokens
+            });
+            state.pruned = now;
+        }
+
+        let bucket = state.buckets.entry(client).or_insert(TokenBucket {
+            tokens: capacity,
+            updated: now
+        });
+
+        let refill = elapsed_secs(bucket.updated, now) * rate;
+        bucket.tokens = (bucket.tokens + refill).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+
+        let _ = self.limited_count.fetch_add(1, Ordering::Release);
+
+        false
+    }
+
+    /// The number of clients currently being tracked
+    pub fn client_count(&self) -> usize {
+        match self.state.lock() {
+            Ok(state) => state.buckets.len(),
+            Err(_) => 0
+        }
+    }
+
+    pub fn get_limited_count(&self) -> usize {
+        self.limited_count.load(Ordering::Acquire)
+    }
+}
+
+/// Seconds from `earlier` to `later`, or zero if `later` comes first
+fn elapsed_secs(earlier: Instant, later: Instant) -> f64 {
+    if later <= earlier {
+        return 0.0;
+    }
+
+    let elapsed = later.duration_since(earlier);
+    elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_flood_from_single_client() {
+        let mut limiter = RateLimiter::new();
+        limiter.queries_per_second = Some(10);
+        limiter.burst = 5;
+
+        let flooder = "192.0.2.1".parse().unwrap();
+        let other = "192.0.2.2".parse().unwrap();
+        let start = Instant::now();
+
+        // The burst is let through at once, and nothing more
+        let allowed = (0..100).filter(|_| limiter.check_at(flooder, start)).count();
+        assert_eq!(5, allowed);
+        assert_eq!(95, limiter.get_limited_count());
+
+        // Another client has a bucket of its own
+        assert!(limiter.check_at(other, start));
+
+        // Tokens come back at the configured rate
+        let later = start + Duration::from_millis(300);
+        let allowed = (0..100).filter(|_| limiter.check_at(flooder, later)).count();
+        assert_eq!(3, allowed);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let limiter = RateLimiter::new();
+
+        let client = "192.0.2.1".parse().unwrap();
+        assert!((0..1000).all(|_| limiter.check(client)));
+        assert_eq!(0, limiter.client_count());
+    }
+
+    #[test]
+    fn test_prune() {
+        let mut limiter = RateLimiter::new();
+        limiter.queries_per_second = Some(10);
+        limiter.burst = 5;
+
+        let start = Instant::now();
+        for i in 0..10 {
+            assert!(limiter.check_at(format!("192.0.2.{}", i).parse().unwrap(), start));
+        }
+        assert_eq!(10, limiter.client_count());
+
+        // Once the buckets have filled up again they're removed, leaving
+        // only the client querying right now
+        let later = start + Duration::from_secs(PRUNE_INTERVAL_SECS);
+        assert!(limiter.check_at("198.51.100.1".parse().unwrap(), later));
+        assert_eq!(1, limiter.client_count());
+    }
+
+    #[test]
+    fn test_parse_action() {
+        assert_eq!(RateLimitAction::Drop, "drop".parse::<RateLimitAction>().unwrap());
+        assert_eq!(RateLimitAction::Truncate, "truncate".parse::<RateLimitAction>().unwrap());
+        assert!("refuse".parse::<RateLimitAction>().is_err());
+    }
+}
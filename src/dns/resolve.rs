@@ -1,15 +1,26 @@
 //! resolver implementations implementing different strategies for answering
 //! incoming queries
 
+use std::cmp;
 use std::io::Result;
 use std::vec::Vec;
 use std::io::{Error, ErrorKind};
 use std::sync::Arc;
 
-use dns::protocol::{QueryType, DnsPacket, ResultCode};
+use rand::{Rng, thread_rng};
+
+use dns::protocol::{QueryType, DnsPacket, DnsRecord, ResultCode, TransientTtl};
+use dns::cache::CacheSource;
 use dns::client::DnsClient;
 use dns::context::ServerContext;
 
+/// Upper bound on the length of the chain followed when flattening an ANAME
+const MAX_FLATTEN_DEPTH: usize = 10;
+
+/// Upper bound on the number of referrals followed by the recursive resolver
+/// for a single lookup, which stops delegations that lead in circles
+pub const MAX_REFERRALS: usize = 16;
+
 pub trait DnsResolver {
 
     fn get_context(&self) -> Arc<ServerContext>;
@@ -28,10 +39,23 @@ pub trait DnsResolver {
         let context = self.get_context();
 
         if let Some(qr) = context.authority.query(qname, qtype) {
+            if qtype == QueryType::A || qtype == QueryType::AAAA {
+                let aname = qr.answers.iter().find(|x| x.get_querytype() == QueryType::ANAME).cloned();
+                if let Some(aname) = aname {
+                    return Ok(self.flatten_aname(qname, qtype, &aname));
+                }
+            }
+
             return Ok(qr);
         }
 
-        if !recursive || !context.allow_recursive {
+        // Without a zone for the root, queries for it are answered from the
+        // root hints rather than resolved
+        if qname.is_empty() {
+            return Ok(answer_root(&context.root_hints, qtype));
+        }
+
+        if !context.allow_recursive {
             let mut packet = DnsPacket::new();
             packet.header.rescode = ResultCode::REFUSED;
             return Ok(packet);
@@ -47,12 +71,224 @@ pub trait DnsResolver {
             }
         }
 
-        self.perform(qname, qtype)
+        // A client not asking for recursion is referred to the closest name
+        // servers we know of, rather than having us resolve the name
+        if !recursive {
+            return Ok(build_referral(&context, qname));
+        }
+
+        // Identical queries in progress share a single upstream lookup
+        let result = context.inflight.coalesce(qname, qtype, || self.perform(qname, qtype));
+
+        // Remember upstream failures for a little while, rather than retrying
+        // them for every client asking
+        let failed = match result {
+            Ok(ref qr) => qr.header.rescode == ResultCode::SERVFAIL,
+            Err(ref e) => e.kind() == ErrorKind::TimedOut
+        };
+
+        // Pinned entries fall back to their expired records instead
+        if failed {
+            if let Some(qr) = context.cache.lookup_pinned(qname, qtype) {
+                return Ok(qr);
+            }
+        }
+
+        if failed && context.servfail_ttl > 0 {
+            let _ = context.cache.store_servfail(qname, qtype, context.servfail_ttl);
+        }
+
+        result
+    }
+
+    /// Answer an address query for `qname` with the addresses of the target
+    /// of `aname`, as if they were records of `qname`
+    ///
+    /// CNAMEs and further ANAMEs from the target are followed. The TTL of
+    /// the resulting records is clamped to that of the ANAME, and they're
+    /// cached for that long.
+    fn flatten_aname(&mut self,
+                     qname: &str,
+                     qtype: QueryType,
+                     aname: &DnsRecord) -> DnsPacket {
+
+        let context = self.get_context();
+
+        // Flattened answers are cached under the name of the ANAME
+        if let Some(mut qr) = context.cache.lookup(qname, qtype) {
+            if !qr.answers.is_empty() {
+                qr.header.authoritative_answer = true;
+                qr.authorities.clear();
+                return qr;
+            }
+        }
+
+        let mut packet = DnsPacket::new();
+        packet.header.authoritative_answer = true;
+
+        let (mut target, max_ttl) = match *aname {
+            DnsRecord::ANAME { ref host, ttl: TransientTtl(ttl), .. } => (host.clone(), ttl),
+            _ => return packet
+        };
+
+        for _ in 0..MAX_FLATTEN_DEPTH {
+            // Going through the authority directly, rather than `resolve`,
+            // avoids flattening recursively
+            let result = match context.authority.query(&target, qtype) {
+                Some(x) => x,
+                None => match self.resolve(&target, qtype, true) {
+                    Ok(x) => x,
+                    Err(_) => break
+                }
+            };
+
+            let mut next_target = None;
+            for rec in result.answers {
+                match rec {
+                    DnsRecord::A { addr, ttl: TransientTtl(ttl), .. } => {
+                        packet.answers.push(DnsRecord::A {
+                            domain: qname.to_string(),
+                            addr: addr,
+                            ttl: TransientTtl(cmp::min(ttl, max_ttl))
+                        });
+                    },
+                    DnsRecord::AAAA { addr, ttl: TransientTtl(ttl), .. } => {
+                        packet.answers.push(DnsRecord::AAAA {
+                            domain: qname.to_string(),
+                            addr: addr,
+                            ttl: TransientTtl(cmp::min(ttl, max_ttl))
+                        });
+                    },
+                    DnsRecord::CNAME { ref domain, ref host, .. } |
+                    DnsRecord::ANAME { ref domain, ref host, .. } if *domain == target => {
+                        next_target = Some(host.clone());
+                    },
+                    _ => {}
+                }
+            }
+
+            if !packet.answers.is_empty() {
+                let _ = context.cache.store(&packet.answers);
+                return packet;
+            }
+
+            if result.header.rescode != ResultCode::NOERROR {
+                break;
+            }
+
+            target = match next_target {
+                Some(x) => x,
+                None => return packet
+            };
+        }
+
+        packet.header.rescode = ResultCode::SERVFAIL;
+        packet
     }
 
     fn perform(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket>;
 }
 
+/// Build a referral to the closest known name servers for `qname`
+///
+/// The NS records of the closest enclosing domain found in the cache are
+/// returned in the authority section, along with any cached addresses of the
+/// name servers in the additional section. If no name servers are known at
+/// all, the query is refused.
+pub fn build_referral(context: &ServerContext, qname: &str) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+
+    let labels = qname.split('.').collect::<Vec<&str>>();
+    for lbl_idx in 0..labels.len()+1 {
+        let domain = labels[lbl_idx..].join(".");
+
+        let nameservers = match context.cache.lookup(&domain, QueryType::NS) {
+            Some(qr) => qr.answers,
+            None => continue
+        };
+
+        for ns in nameservers {
+            if let DnsRecord::NS { ref host, .. } = ns {
+                for qtype in &[QueryType::A, QueryType::AAAA] {
+                    if let Some(qr) = context.cache.lookup(host, *qtype) {
+                        packet.resources.extend(qr.answers);
+                    }
+                }
+            }
+
+            packet.authorities.push(ns);
+        }
+
+        if !packet.authorities.is_empty() {
+            return packet;
+        }
+    }
+
+    packet.header.rescode = ResultCode::REFUSED;
+    packet
+}
+
+/// Answer a query for the root from `hints`
+///
+/// Only NS queries are answered, with the addresses of the root servers in the
+/// additional section. Any other type, or any query while there are no hints,
+/// is refused.
+pub fn answer_root(hints: &[DnsRecord], qtype: QueryType) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+
+    if qtype != QueryType::NS || hints.is_empty() {
+        packet.header.rescode = ResultCode::REFUSED;
+        return packet;
+    }
+
+    for rec in hints {
+        match rec.get_querytype() {
+            QueryType::NS => packet.answers.push(rec.clone()),
+            QueryType::A | QueryType::AAAA => packet.resources.push(rec.clone()),
+            _ => {}
+        }
+    }
+
+    packet
+}
+
+/// A Static DNS Resolver
+///
+/// This resolver answers solely from the answer map of the context, without
+/// consulting zones, the cache or any other server
+pub struct StaticDnsResolver {
+    context: Arc<ServerContext>
+}
+
+impl StaticDnsResolver {
+    pub fn new(context: Arc<ServerContext>) -> StaticDnsResolver {
+        StaticDnsResolver {
+            context: context
+        }
+    }
+}
+
+impl DnsResolver for StaticDnsResolver {
+    fn get_context(&self) -> Arc<ServerContext> {
+        self.context.clone()
+    }
+
+    fn resolve(&mut self,
+               qname: &str,
+               qtype: QueryType,
+               _: bool) -> Result<DnsPacket> {
+
+        self.perform(qname, qtype)
+    }
+
+    fn perform(&mut self,
+               qname: &str,
+               qtype: QueryType) -> Result<DnsPacket> {
+
+        Ok(self.context.answer_map.lookup(qname, qtype))
+    }
+}
+
 /// A Forwarding DNS Resolver
 ///
 /// This resolver uses an external DNS server to service a query
@@ -80,13 +316,14 @@ impl DnsResolver for ForwardingDnsResolver {
                qtype: QueryType) -> Result<DnsPacket> {
 
         let &(ref host, port) = &self.server;
-        let result = self.context.client.send_query(qname,
-                                                    qtype,
-                                                    (host.as_str(), port),
-                                                    true);
+        let result = self.context.send_upstream(qname,
+                                                qtype,
+                                                (host.as_str(), port),
+                                                true);
 
         if let Ok(ref qr) = result {
             let _ = self.context.cache.store(&qr.answers);
+            let _ = self.context.cache.store_negative(qname, qtype, qr);
         }
 
         result
@@ -97,14 +334,34 @@ impl DnsResolver for ForwardingDnsResolver {
 ///
 /// This resolver can answer any request using the root servers of the internet
 pub struct RecursiveDnsResolver {
-    context: Arc<ServerContext>
+    context: Arc<ServerContext>,
+
+    /// Source of randomness when picking between name servers
+    rng: Box<Rng>
 }
 
 impl RecursiveDnsResolver {
     pub fn new(context: Arc<ServerContext>) -> RecursiveDnsResolver {
+        RecursiveDnsResolver::with_rng(context, Box::new(thread_rng()))
+    }
+
+    /// Create a resolver using `rng` to pick name servers, which allows for
+    /// reproducible lookups with a seeded rng
+    pub fn with_rng(context: Arc<ServerContext>, rng: Box<Rng>) -> RecursiveDnsResolver {
         RecursiveDnsResolver {
-            context: context
+            context: context,
+            rng: rng
+        }
+    }
+
+    /// Remove a random server from `servers` and return it
+    fn take_server(&mut self, servers: &mut Vec<String>) -> Option<String> {
+        if servers.is_empty() {
+            return None;
         }
+
+        let idx = self.rng.gen::<usize>() % servers.len();
+        Some(servers.swap_remove(idx))
     }
 }
 
@@ -128,9 +385,9 @@ impl DnsResolver for RecursiveDnsResolver {
 
             match self.context.cache
                 .lookup(&domain, QueryType::NS)
-                .and_then(|qr| qr.get_unresolved_ns(&domain))
+                .and_then(|qr| qr.get_unresolved_ns(&domain, &mut self.rng))
                 .and_then(|ns| self.context.cache.lookup(&ns, QueryType::A))
-                .and_then(|qr| qr.get_random_a()) {
+                .and_then(|qr| qr.get_random_a(&mut self.rng)) {
 
                 Some(addr) => {
                     tentative_ns = Some(addr);
@@ -140,45 +397,87 @@ impl DnsResolver for RecursiveDnsResolver {
             }
         }
 
-        let mut ns = match tentative_ns {
+        // Without anything cached, start from the root hints
+        let mut servers = match tentative_ns {
+            Some(x) => vec![x],
+            None => self.context.root_hints.iter().filter_map(|x| match *x {
+                DnsRecord::A { ref addr, .. } => Some(addr.to_string()),
+                _ => None
+            }).collect::<Vec<String>>()
+        };
+
+        let mut ns = match self.take_server(&mut servers) {
             Some(x) => x,
             None => return Err(Error::new(ErrorKind::NotFound, "No DNS server found"))
         };
 
-        // Start querying name servers
+        // Start querying name servers. The servers that are left are the
+        // alternatives for the same zone, which are tried in turn when one
+        // fails to respond.
+        let mut referrals = 0;
         loop {
             println!("attempting lookup of {:?} {} with ns {}", qtype, qname, ns);
 
             let ns_copy = ns.clone();
 
             let server = (ns_copy.as_str(), 53);
-            let response = try!(self.context.client.send_query(qname,
-                                                               qtype.clone(),
-                                                               server,
-                                                               false));
+            let response = match self.context.send_upstream(qname,
+                                                            qtype.clone(),
+                                                            server,
+                                                            false) {
+                Ok(x) => x,
+                Err(e) => match self.take_server(&mut servers) {
+                    Some(x) => {
+                        ns = x;
+                        continue;
+                    },
+                    None => return Err(e)
+                }
+            };
 
             // If we've got an actual answer, we're done!
             if !response.answers.is_empty() &&
                response.header.rescode == ResultCode::NOERROR {
 
-                let _ = self.context.cache.store(&response.answers);
+                let source = if response.header.authoritative_answer {
+                    CacheSource::Authoritative
+                } else {
+                    CacheSource::Upstream
+                };
+
+                let _ = self.context.cache.store_from(&response.answers, source);
                 let _ = self.context.cache.store(&response.authorities);
                 let _ = self.context.cache.store(&response.resources);
                 return Ok(response.clone());
             }
 
             if response.header.rescode == ResultCode::NXDOMAIN {
-                if let Some(ttl) = response.get_ttl_from_soa() {
-                    let _ = self.context.cache.store_nxdomain(qname, qtype, ttl);
-                }
+                let _ = self.context.cache.store_negative(qname, qtype, &response);
+                return Ok(response.clone());
+            }
+
+            // An empty answer with the SOA of the zone means the name exists,
+            // but has no records of the type, as opposed to a referral
+            if response.header.rescode == ResultCode::NOERROR &&
+               response.get_soa().is_some() {
+
+                let _ = self.context.cache.store_negative(qname, qtype, &response);
                 return Ok(response.clone());
             }
 
-            // Otherwise, try to find a new nameserver based on NS and a
-            // corresponding A record in the additional section
-            if let Some(new_ns) = response.get_resolved_ns(qname) {
+            // Otherwise we've been referred elsewhere, which only goes on for
+            // so long
+            referrals += 1;
+            if referrals > MAX_REFERRALS {
+                return Err(Error::new(ErrorKind::Other, "Too many referrals"));
+            }
+
+            // Try to find a new nameserver based on NS and a corresponding A
+            // record in the additional section
+            servers = response.get_resolved_ns_addrs(qname);
+            if let Some(new_ns) = self.take_server(&mut servers) {
                 // If there is such a record, we can retry the loop with that NS
-                ns = new_ns.clone();
+                ns = new_ns;
                 let _ = self.context.cache.store(&response.answers);
                 let _ = self.context.cache.store(&response.authorities);
                 let _ = self.context.cache.store(&response.resources);
@@ -186,9 +485,14 @@ impl DnsResolver for RecursiveDnsResolver {
                 continue;
             }
 
-            // If not, we'll have to resolve the ip of a NS record
-            let new_ns_name = match response.get_unresolved_ns(qname) {
+            // If not, we'll have to resolve the ip of a NS record. A response
+            // without any name servers is an answer of its own, while one
+            // referring to servers that don't apply to the name is lame.
+            let new_ns_name = match response.get_unresolved_ns(qname, &mut self.rng) {
                 Some(x) => x,
+                None if response.authorities.iter().any(|x| x.get_querytype() == QueryType::NS) => {
+                    return Err(Error::new(ErrorKind::NotFound, "Lame referral"));
+                },
                 None => return Ok(response.clone())
             };
 
@@ -197,11 +501,18 @@ impl DnsResolver for RecursiveDnsResolver {
                                                        QueryType::A,
                                                        true));
 
-            // Pick a random IP and restart
-            if let Some(new_ns) = recursive_response.get_random_a() {
-                ns = new_ns.clone();
-            } else {
-                return Ok(response.clone())
+            // Pick a random IP and restart, with the others to fall back on
+            servers = recursive_response.answers.iter().filter_map(|x| match *x {
+                DnsRecord::A { ref addr, .. } => Some(addr.to_string()),
+                _ => None
+            }).collect::<Vec<String>>();
+
+            // Having no way to reach the name servers we were referred to
+            // means that the lookup has failed
+            match self.take_server(&mut servers) {
+                Some(new_ns) => ns = new_ns,
+                None => return Err(Error::new(ErrorKind::NotFound,
+                                              format!("No address found for name server {}", new_ns_name)))
             }
         }
     }
@@ -210,12 +521,18 @@ impl DnsResolver for RecursiveDnsResolver {
 #[cfg(test)]
 mod tests {
 
-    use std::sync::Arc;
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread::{sleep, spawn};
+    use std::time::Duration;
+
+    use chrono::Local;
 
     use dns::protocol::{DnsPacket, QueryType, DnsRecord, ResultCode, TransientTtl};
 
     use super::*;
 
+    use dns::authority::Zone;
     use dns::context::ResolveStrategy;
     use dns::context::tests::create_test_context;
 
@@ -303,6 +620,77 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_forwarding_resolver_caches_all_types() {
+        let query_count = Arc::new(AtomicUsize::new(0));
+        let query_count_clone = query_count.clone();
+
+        let mut context = create_test_context(
+            Box::new(move |qname, qtype, _, _| {
+                let _ = query_count_clone.fetch_add(1, Ordering::Release);
+
+                let mut packet = DnsPacket::new();
+
+                if qname == "google.com" && qtype == QueryType::AAAA {
+                    packet.answers.push(DnsRecord::AAAA {
+                        domain: "google.com".to_string(),
+                        addr: "::1".parse().unwrap(),
+                        ttl: TransientTtl(3600)
+                    });
+                } else if qname == "google.com" && qtype == QueryType::MX {
+                    packet.answers.push(DnsRecord::MX {
+                        domain: "google.com".to_string(),
+                        priority: 10,
+                        host: "mail.google.com".to_string(),
+                        ttl: TransientTtl(3600)
+                    });
+                } else {
+                    packet.header.rescode = ResultCode::NXDOMAIN;
+                }
+
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        host: "127.0.0.1".to_string(),
+                        port: 53
+                    };
+            },
+            None => panic!()
+        }
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        for qtype in &[QueryType::AAAA, QueryType::MX] {
+            let before = query_count.load(Ordering::Acquire);
+
+            // The first lookup goes upstream, while the second one should be
+            // answered from the cache
+            for _ in 0..2 {
+                let res = match resolver.resolve("google.com", *qtype, true) {
+                    Ok(x) => x,
+                    Err(_) => panic!()
+                };
+
+                assert_eq!(1, res.answers.len());
+                assert_eq!(*qtype, res.answers[0].get_querytype());
+            }
+
+            assert_eq!(before + 1, query_count.load(Ordering::Acquire));
+        }
+
+        let list = match context.cache.list() {
+            Ok(x) => x,
+            Err(_) => panic!()
+        };
+
+        assert_eq!(1, list.len());
+        assert_eq!(2, list[0].record_types.len());
+        assert_eq!(2, list[0].hits);
+    }
+
     #[test]
     fn test_recursive_resolver_with_no_nameserver() {
         let context = create_test_context(
@@ -476,6 +864,125 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_recursive_resolver_failover() {
+        let servers = Arc::new(Mutex::new(Vec::new()));
+        let servers_clone = servers.clone();
+
+        // Of each pair of servers, the first one never responds
+        let mut context = create_test_context(
+            Box::new(move |qname, _, (server, _), _| {
+                servers_clone.lock().unwrap().push(server.to_string());
+
+                let mut packet = DnsPacket::new();
+                match server {
+                    "127.0.0.1" | "127.0.0.3" => {
+                        return Err(Error::new(ErrorKind::TimedOut, "Request timed out"));
+                    },
+                    "127.0.0.2" => {
+                        for (host, addr) in vec![("a.mytld.net", "127.0.0.3"), ("b.mytld.net", "127.0.0.4")] {
+                            packet.authorities.push(DnsRecord::NS {
+                                domain: "com".to_string(),
+                                host: host.to_string(),
+                                ttl: TransientTtl(3600)
+                            });
+                            packet.resources.push(DnsRecord::A {
+                                domain: host.to_string(),
+                                addr: addr.parse().unwrap(),
+                                ttl: TransientTtl(3600)
+                            });
+                        }
+                    },
+                    _ => {
+                        packet.answers.push(DnsRecord::A {
+                            domain: qname.to_string(),
+                            addr: "10.0.0.1".parse().unwrap(),
+                            ttl: TransientTtl(3600)
+                        });
+                    }
+                }
+
+                Ok(packet)
+            }));
+
+        // With nothing cached, the lookup starts from the root hints
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                for (host, addr) in vec![("a.myroot.net", "127.0.0.1"), ("b.myroot.net", "127.0.0.2")] {
+                    ctx.root_hints.push(DnsRecord::NS {
+                        domain: "".to_string(),
+                        host: host.to_string(),
+                        ttl: TransientTtl(3600)
+                    });
+                    ctx.root_hints.push(DnsRecord::A {
+                        domain: host.to_string(),
+                        addr: addr.parse().unwrap(),
+                        ttl: TransientTtl(3600)
+                    });
+                }
+            },
+            None => panic!()
+        }
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        match resolver.resolve("google.com", QueryType::A, true) {
+            Ok(packet) => {
+                assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+                assert_eq!(1, packet.answers.len());
+            },
+            Err(_) => panic!()
+        }
+
+        // Whichever server of a pair was asked first, the lookup ends up at
+        // the one that responds
+        let servers = servers.lock().unwrap();
+        assert!(servers.len() >= 2 && servers.len() <= 4);
+        assert!(servers.contains(&"127.0.0.2".to_string()));
+        assert_eq!("127.0.0.4", servers[servers.len() - 1]);
+    }
+
+    #[test]
+    fn test_recursive_resolver_referral_loop() {
+        let upstream_count = Arc::new(AtomicUsize::new(0));
+        let upstream_count_clone = upstream_count.clone();
+
+        // The server keeps referring to itself
+        let context = create_test_context(
+            Box::new(move |_, _, _, _| {
+                upstream_count_clone.fetch_add(1, Ordering::SeqCst);
+
+                let mut packet = DnsPacket::new();
+                packet.authorities.push(DnsRecord::NS {
+                    domain: "com".to_string(),
+                    host: "a.mytld.net".to_string(),
+                    ttl: TransientTtl(3600)
+                });
+                packet.resources.push(DnsRecord::A {
+                    domain: "a.mytld.net".to_string(),
+                    addr: "127.0.0.1".parse().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+
+                Ok(packet)
+            }));
+
+        let _ = context.cache.store(&[DnsRecord::NS {
+            domain: "".to_string(),
+            host: "a.myroot.net".to_string(),
+            ttl: TransientTtl(3600)
+        }, DnsRecord::A {
+            domain: "a.myroot.net".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }]);
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        assert!(resolver.resolve("google.com", QueryType::A, true).is_err());
+        assert_eq!(MAX_REFERRALS + 1, upstream_count.load(Ordering::SeqCst));
+    }
+
     #[test]
     fn test_recursive_resolver_successfully() {
         let context = create_test_context(
@@ -592,5 +1099,643 @@ mod tests {
             assert_eq!(2, list[2].hits);
         };
     }
-}
 
+    #[test]
+    fn test_resolve_authoritative_rescodes() {
+        let context = create_test_context(
+            Box::new(|_, _, _, _| {
+                panic!("No upstream queries should be performed");
+            }));
+
+        {
+            let mut zones = context.authority.write().unwrap();
+            zones.add_zone(Zone::new("example.com".to_string(),
+                                     "ns1.example.com".to_string(),
+                                     "admin.example.com".to_string())).unwrap();
+            zones.add_record("example.com", &DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr: "127.0.0.1".parse().unwrap(),
+                ttl: TransientTtl(3600)
+            }).unwrap();
+        }
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        // A name in a zone we host
+        let res = resolver.resolve("www.example.com", QueryType::A, false).unwrap();
+        assert_eq!(ResultCode::NOERROR, res.header.rescode);
+        assert_eq!(1, res.answers.len());
+
+        // A missing name in a zone we host
+        let res = resolver.resolve("mail.example.com", QueryType::A, false).unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, res.header.rescode);
+        assert!(res.header.authoritative_answer);
+
+        // A name in a zone we don't host at all
+        let res = resolver.resolve("www.google.com", QueryType::A, false).unwrap();
+        assert_eq!(ResultCode::REFUSED, res.header.rescode);
+        assert!(!res.header.authoritative_answer);
+    }
+
+    #[test]
+    fn test_flatten_aname() {
+        let mut context = create_test_context(
+            Box::new(|qname, qtype, _, _| {
+                let mut packet = DnsPacket::new();
+
+                // The target is itself an alias
+                if qname == "example.cdn.net" {
+                    packet.answers.push(DnsRecord::CNAME {
+                        domain: "example.cdn.net".to_string(),
+                        host: "edge.cdn.net".to_string(),
+                        ttl: TransientTtl(3600)
+                    });
+                    packet.answers.push(DnsRecord::A {
+                        domain: "edge.cdn.net".to_string(),
+                        addr: "127.0.0.1".parse().unwrap(),
+                        ttl: TransientTtl(60)
+                    });
+                    packet.answers.push(DnsRecord::A {
+                        domain: "edge.cdn.net".to_string(),
+                        addr: "127.0.0.2".parse().unwrap(),
+                        ttl: TransientTtl(600)
+                    });
+                } else if qtype == QueryType::A {
+                    packet.header.rescode = ResultCode::NXDOMAIN;
+                }
+
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        host: "127.0.0.1".to_string(),
+                        port: 53
+                    };
+            },
+            None => panic!()
+        }
+
+        {
+            let mut zones = context.authority.write().unwrap();
+            zones.add_zone(Zone::new("example.com".to_string(),
+                                     "ns1.example.com".to_string(),
+                                     "admin.example.com".to_string())).unwrap();
+            zones.add_record("example.com", &DnsRecord::ANAME {
+                domain: "example.com".to_string(),
+                host: "example.cdn.net".to_string(),
+                ttl: TransientTtl(300)
+            }).unwrap();
+            zones.add_record("example.com", &DnsRecord::ANAME {
+                domain: "broken.example.com".to_string(),
+                host: "missing.cdn.net".to_string(),
+                ttl: TransientTtl(300)
+            }).unwrap();
+        }
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        // The apex is answered with the addresses of the target, with the
+        // TTL clamped to that of the ANAME
+        let res = resolver.resolve("example.com", QueryType::A, true).unwrap();
+        assert_eq!(ResultCode::NOERROR, res.header.rescode);
+        assert!(res.header.authoritative_answer);
+        assert_eq!(vec![
+            DnsRecord::A {
+                domain: "example.com".to_string(),
+                addr: "127.0.0.1".parse().unwrap(),
+                ttl: TransientTtl(60)
+            },
+            DnsRecord::A {
+                domain: "example.com".to_string(),
+                addr: "127.0.0.2".parse().unwrap(),
+                ttl: TransientTtl(300)
+            }
+        ], res.answers);
+        assert_eq!(vec![60, 300], res.answers.iter().map(|x| x.get_ttl()).collect::<Vec<u32>>());
+
+        // The ANAME itself is never handed out
+        let res = resolver.resolve("example.com", QueryType::ANAME, true).unwrap();
+        assert!(res.answers.is_empty());
+
+        // A target that can't be resolved fails the query
+        let res = resolver.resolve("broken.example.com", QueryType::A, true).unwrap();
+        assert_eq!(ResultCode::SERVFAIL, res.header.rescode);
+        assert!(res.answers.is_empty());
+    }
+
+    #[test]
+    fn test_apex_alias() {
+        let upstream_count = Arc::new(AtomicUsize::new(0));
+        let upstream_count_clone = upstream_count.clone();
+
+        let mut context = create_test_context(
+            Box::new(move |qname, _, _, _| {
+                upstream_count_clone.fetch_add(1, Ordering::SeqCst);
+
+                let mut packet = DnsPacket::new();
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: "127.0.0.1".parse().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        host: "127.0.0.1".to_string(),
+                        port: 53
+                    };
+            },
+            None => panic!()
+        }
+
+        {
+            let mut zones = context.authority.write().unwrap();
+            zones.add_zone(Zone::new("example.com".to_string(),
+                                     "ns1.example.com".to_string(),
+                                     "admin.example.com".to_string())).unwrap();
+            zones.add_record("example.com", &DnsRecord::ANAME {
+                domain: "example.com".to_string(),
+                host: "example.cdn.net".to_string(),
+                ttl: TransientTtl(300)
+            }).unwrap();
+        }
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        for _ in 0..2 {
+            let res = resolver.resolve("example.com", QueryType::A, true).unwrap();
+            assert!(res.header.authoritative_answer);
+            assert_eq!(vec![DnsRecord::A {
+                domain: "example.com".to_string(),
+                addr: "127.0.0.1".parse().unwrap(),
+                ttl: TransientTtl(300)
+            }], res.answers);
+            assert_eq!(300, res.answers[0].get_ttl());
+        }
+
+        // The second answer came from the cache
+        assert_eq!(1, upstream_count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_coalesce_identical_queries() {
+        let query_count = Arc::new(AtomicUsize::new(0));
+        let query_count_clone = query_count.clone();
+
+        let mut context = create_test_context(
+            Box::new(move |qname, _, _, _| {
+                let _ = query_count_clone.fetch_add(1, Ordering::Release);
+
+                // Keep the query in progress long enough for the others to
+                // arrive
+                sleep(Duration::from_millis(200));
+
+                let mut packet = DnsPacket::new();
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: "127.0.0.1".parse().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        host: "127.0.0.1".to_string(),
+                        port: 53
+                    };
+            },
+            None => panic!()
+        }
+
+        let thread_count = 10;
+        let barrier = Arc::new(Barrier::new(thread_count));
+
+        let handles = (0..thread_count).map(|_| {
+            let context = context.clone();
+            let barrier = barrier.clone();
+
+            spawn(move || {
+                let mut resolver = context.create_resolver(context.clone());
+
+                barrier.wait();
+                resolver.resolve("google.com", QueryType::A, true)
+            })
+        }).collect::<Vec<_>>();
+
+        for handle in handles {
+            let res = handle.join().unwrap().unwrap();
+            assert_eq!(1, res.answers.len());
+        }
+
+        assert_eq!(1, query_count.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn test_single_flight() {
+        let query_count = Arc::new(AtomicUsize::new(0));
+        let query_count_clone = query_count.clone();
+
+        let mut context = create_test_context(
+            Box::new(move |qname, _, _, _| {
+                let seq = query_count_clone.fetch_add(1, Ordering::Release);
+
+                sleep(Duration::from_millis(200));
+
+                // Make each upstream answer unique, so that any answer not
+                // shared from the single query would stand out
+                let mut packet = DnsPacket::new();
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: format!("10.0.0.{}", seq + 1).parse().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        host: "127.0.0.1".to_string(),
+                        port: 53
+                    };
+            },
+            None => panic!()
+        }
+
+        let thread_count = 50;
+        let barrier = Arc::new(Barrier::new(thread_count));
+
+        let handles = (0..thread_count).map(|_| {
+            let context = context.clone();
+            let barrier = barrier.clone();
+
+            spawn(move || {
+                let mut resolver = context.create_resolver(context.clone());
+
+                barrier.wait();
+                resolver.resolve("yahoo.com", QueryType::A, true)
+            })
+        }).collect::<Vec<_>>();
+
+        let answers = handles.into_iter()
+            .map(|x| x.join().unwrap().unwrap().answers)
+            .collect::<Vec<_>>();
+
+        assert_eq!(1, query_count.load(Ordering::Acquire));
+        assert_eq!(thread_count, answers.len());
+
+        let expected = vec![DnsRecord::A {
+            domain: "yahoo.com".to_string(),
+            addr: "10.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }];
+        for answer in answers {
+            assert_eq!(expected, answer);
+        }
+    }
+
+    #[test]
+    fn test_upstream_limit() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Ok(DnsPacket::new())
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        host: "127.0.0.1".to_string(),
+                        port: 53
+                    };
+                ctx.query_limiter.max_concurrent = 1;
+                ctx.query_limiter.max_queued = 0;
+            },
+            None => panic!()
+        }
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        // With the only slot taken, further upstream queries are rejected
+        {
+            let _permit = context.query_limiter.acquire().unwrap();
+            assert!(resolver.resolve("google.com", QueryType::A, true).is_err());
+        };
+
+        assert!(resolver.resolve("google.com", QueryType::A, true).is_ok());
+    }
+
+    #[test]
+    fn test_referral_without_recursion() {
+        let upstream_count = Arc::new(AtomicUsize::new(0));
+        let upstream_count_clone = upstream_count.clone();
+
+        let mut context = create_test_context(
+            Box::new(move |qname, _, _, _| {
+                upstream_count_clone.fetch_add(1, Ordering::SeqCst);
+
+                let mut packet = DnsPacket::new();
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: "127.0.0.1".parse().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        host: "127.0.0.1".to_string(),
+                        port: 53
+                    };
+            },
+            None => panic!()
+        }
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        // Nothing is known about the name, so there's no one to refer to
+        match resolver.resolve("www.google.com", QueryType::A, false) {
+            Ok(packet) => assert_eq!(ResultCode::REFUSED, packet.header.rescode),
+            Err(_) => panic!()
+        }
+
+        let ns = DnsRecord::NS {
+            domain: "google.com".to_string(),
+            host: "ns1.google.com".to_string(),
+            ttl: TransientTtl(3600)
+        };
+        let glue = DnsRecord::A {
+            domain: "ns1.google.com".to_string(),
+            addr: "127.0.0.2".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        };
+        let _ = context.cache.store(&[ns.clone(), glue.clone()]);
+
+        // With RD=0, the delegation is returned rather than an answer
+        match resolver.resolve("www.google.com", QueryType::A, false) {
+            Ok(packet) => {
+                assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+                assert!(packet.answers.is_empty());
+                assert_eq!(vec![ns], packet.authorities);
+                assert_eq!(vec![glue], packet.resources);
+            },
+            Err(_) => panic!()
+        }
+        assert_eq!(0, upstream_count.load(Ordering::SeqCst));
+
+        // while RD=1 resolves the name
+        match resolver.resolve("www.google.com", QueryType::A, true) {
+            Ok(packet) => assert_eq!(1, packet.answers.len()),
+            Err(_) => panic!()
+        }
+        assert_eq!(1, upstream_count.load(Ordering::SeqCst));
+
+        // and once cached, the answer is returned with RD=0 as well
+        match resolver.resolve("www.google.com", QueryType::A, false) {
+            Ok(packet) => {
+                assert_eq!(1, packet.answers.len());
+                assert!(packet.authorities.is_empty());
+            },
+            Err(_) => panic!()
+        }
+        assert_eq!(1, upstream_count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_negative_cache() {
+        let upstream_count = Arc::new(AtomicUsize::new(0));
+        let upstream_count_clone = upstream_count.clone();
+
+        let mut context = create_test_context(
+            Box::new(move |qname, qtype, _, _| {
+                upstream_count_clone.fetch_add(1, Ordering::SeqCst);
+
+                let mut packet = DnsPacket::new();
+                if qname == "google.com" && qtype == QueryType::A {
+                    packet.answers.push(DnsRecord::A {
+                        domain: "google.com".to_string(),
+                        addr: "127.0.0.1".parse().unwrap(),
+                        ttl: TransientTtl(3600)
+                    });
+                    return Ok(packet);
+                }
+
+                if qname != "google.com" {
+                    packet.header.rescode = ResultCode::NXDOMAIN;
+                }
+
+                // Without an SOA the negative TTL is unknown
+                if qname != "nosoa.google.com" {
+                    packet.authorities.push(DnsRecord::SOA {
+                        domain: "google.com".to_string(),
+                        m_name: "ns1.google.com".to_string(),
+                        r_name: "admin.google.com".to_string(),
+                        serial: 1,
+                        refresh: 3600,
+                        retry: 3600,
+                        expire: 3600,
+                        minimum: 300,
+                        ttl: TransientTtl(3600)
+                    });
+                }
+
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        host: "127.0.0.1".to_string(),
+                        port: 53
+                    };
+            },
+            None => panic!()
+        }
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        // An NXDOMAIN is answered from the cache the second time, along with
+        // the SOA of the zone
+        for _ in 0..2 {
+            let res = resolver.resolve("missing.google.com", QueryType::A, true).unwrap();
+            assert_eq!(ResultCode::NXDOMAIN, res.header.rescode);
+            assert_eq!(1, res.authorities.len());
+        }
+        assert_eq!(1, upstream_count.load(Ordering::SeqCst));
+
+        // The TTL is the lesser of the SOA minimum and the TTL of the SOA
+        let remaining = context.cache.list().unwrap().iter()
+            .find(|x| x.domain == "missing.google.com")
+            .map(|x| x.remaining_ttl(QueryType::A, Local::now()))
+            .unwrap();
+        assert!(remaining <= 300 && remaining > 290);
+
+        // and so is a NODATA answer, without affecting other types
+        for _ in 0..2 {
+            let res = resolver.resolve("google.com", QueryType::AAAA, true).unwrap();
+            assert_eq!(ResultCode::NOERROR, res.header.rescode);
+            assert!(res.answers.is_empty());
+            assert_eq!(1, res.authorities.len());
+        }
+        assert_eq!(2, upstream_count.load(Ordering::SeqCst));
+
+        assert_eq!(1, resolver.resolve("google.com", QueryType::A, true).unwrap().answers.len());
+        assert_eq!(3, upstream_count.load(Ordering::SeqCst));
+
+        // An NXDOMAIN without an SOA isn't cached
+        for _ in 0..2 {
+            let res = resolver.resolve("nosoa.google.com", QueryType::A, true).unwrap();
+            assert_eq!(ResultCode::NXDOMAIN, res.header.rescode);
+        }
+        assert_eq!(5, upstream_count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_servfail_cache() {
+        let upstream_count = Arc::new(AtomicUsize::new(0));
+        let upstream_count_clone = upstream_count.clone();
+
+        let mut context = create_test_context(
+            Box::new(move |qname, _, _, _| {
+                upstream_count_clone.fetch_add(1, Ordering::SeqCst);
+
+                if qname == "google.com" {
+                    let mut packet = DnsPacket::new();
+                    packet.header.rescode = ResultCode::SERVFAIL;
+                    Ok(packet)
+                } else {
+                    Err(Error::new(ErrorKind::TimedOut, "Request timed out"))
+                }
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        host: "127.0.0.1".to_string(),
+                        port: 53
+                    };
+            },
+            None => panic!()
+        }
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        // A SERVFAIL from upstream is served from the cache the second time
+        for _ in 0..2 {
+            match resolver.resolve("google.com", QueryType::A, true) {
+                Ok(packet) => assert_eq!(ResultCode::SERVFAIL, packet.header.rescode),
+                Err(_) => panic!()
+            }
+        }
+        assert_eq!(1, upstream_count.load(Ordering::SeqCst));
+
+        // and so is a timeout
+        assert!(resolver.resolve("yahoo.com", QueryType::A, true).is_err());
+        match resolver.resolve("yahoo.com", QueryType::A, true) {
+            Ok(packet) => assert_eq!(ResultCode::SERVFAIL, packet.header.rescode),
+            Err(_) => panic!()
+        }
+        assert_eq!(2, upstream_count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_servfail_cache_disabled() {
+        let upstream_count = Arc::new(AtomicUsize::new(0));
+        let upstream_count_clone = upstream_count.clone();
+
+        let mut context = create_test_context(
+            Box::new(move |_, _, _, _| {
+                upstream_count_clone.fetch_add(1, Ordering::SeqCst);
+
+                let mut packet = DnsPacket::new();
+                packet.header.rescode = ResultCode::SERVFAIL;
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        host: "127.0.0.1".to_string(),
+                        port: 53
+                    };
+                ctx.servfail_ttl = 0;
+            },
+            None => panic!()
+        }
+
+        let mut resolver = context.create_resolver(context.clone());
+        for _ in 0..2 {
+            assert!(resolver.resolve("google.com", QueryType::A, true).is_ok());
+        }
+
+        assert_eq!(2, upstream_count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_pinned_entry() {
+        let upstream_count = Arc::new(AtomicUsize::new(0));
+        let upstream_count_clone = upstream_count.clone();
+
+        // The first lookup succeeds with records that expire immediately, and
+        // every refresh after it times out
+        let mut context = create_test_context(
+            Box::new(move |qname, _, _, _| {
+                if upstream_count_clone.fetch_add(1, Ordering::SeqCst) > 0 {
+                    return Err(Error::new(ErrorKind::TimedOut, "Request timed out"));
+                }
+
+                let mut packet = DnsPacket::new();
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: "127.0.0.1".parse().unwrap(),
+                    ttl: TransientTtl(0)
+                });
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        host: "127.0.0.1".to_string(),
+                        port: 53
+                    };
+            },
+            None => panic!()
+        }
+
+        let _ = context.cache.pin("google.com", QueryType::A);
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        // The expired records are refreshed on every lookup, and served when
+        // the refresh fails
+        for i in 0..3 {
+            match resolver.resolve("google.com", QueryType::A, true) {
+                Ok(packet) => {
+                    assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+                    assert_eq!(1, packet.answers.len());
+                    assert_eq!("google.com", packet.answers[0].get_domain().unwrap());
+                },
+                Err(_) => panic!()
+            }
+            assert_eq!(i + 1, upstream_count.load(Ordering::SeqCst));
+        }
+
+        // Entries that aren't pinned get the usual failure
+        assert!(resolver.resolve("yahoo.com", QueryType::A, true).is_err());
+        match resolver.resolve("yahoo.com", QueryType::A, true) {
+            Ok(packet) => assert_eq!(ResultCode::SERVFAIL, packet.header.rescode),
+            Err(_) => panic!()
+        }
+    }
+}
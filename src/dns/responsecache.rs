@@ -0,0 +1,157 @@
+//! a cache of serialized responses to authoritative queries
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use dns::protocol::QueryType;
+use dns::server::Transport;
+
+/// Default upper bound on the number of cached responses
+pub const DEFAULT_MAX_ENTRIES: usize = 10_000;
+
+/// Everything in a query that can affect the bytes of the response, apart
+/// from the transaction id
+#[derive(Clone,Debug,PartialEq,Eq,Hash)]
+pub struct ResponseKey {
+    pub qname: String,
+    pub qtype: QueryType,
+    pub transport: Transport,
+    pub recursion_desired: bool,
+    pub edns: bool,
+    pub tcp_keepalive: bool,
+    pub size_limit: usize
+}
+
+struct ResponseEntry {
+    generation: usize,
+    data: Vec<u8>
+}
+
+/// Serialized responses for authoritative answers
+///
+/// Entries are tagged with the generation of the authority at the time the
+/// answer was produced, and any change to the zones makes them stale. Stale
+/// entries are never returned, and are replaced on the next store.
+pub struct ResponseCache {
+    entries: RwLock<HashMap<ResponseKey, ResponseEntry>>,
+    pub max_entries: usize
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        ResponseCache::new()
+    }
+}
+
+impl ResponseCache {
+    pub fn new() -> ResponseCache {
+        ResponseCache {
+            entries: RwLock::new(HashMap::new()),
+            max_entries: DEFAULT_MAX_ENTRIES
+        }
+    }
+
+    /// Look up the response for `key`, with the transaction id set to `id`
+    ///
+    /// Returns `None` if there's no entry, or if it was stored for another
+    /// generation of the authority than `generation`.
+    pub fn lookup(&self, key: &ResponseKey, generation: usize, id: u16) -> Option<Vec<u8>> {
+        let entries = match self.entries.read() {
+            Ok(x) => x,
+            Err(_) => return None
+        };
+
+        let entry = match entries.get(key) {
+            Some(x) => x,
+            None => return None
+        };
+
+        if entry.generation != generation || entry.data.len() < 2 {
+            return None;
+        }
+
+        let mut data = entry.data.clone();
+        data[0] = (id >> 8) as u8;
+        data[1] = (id & 0xFF) as u8;
+
+        Some(data)
+    }
+
+    /// Store the serialized response for `key`
+    pub fn store(&self, key: ResponseKey, generation: usize, data: &[u8]) {
+        let mut entries = match self.entries.write() {
+            Ok(x) => x,
+            Err(_) => return
+        };
+
+        // Rather than tracking usage, just start over when the cache is full.
+        // Stale entries are dropped at the same time.
+        if !entries.contains_key(&key) && entries.len() >= self.max_entries {
+            entries.clear();
+        }
+
+        entries.insert(key, ResponseEntry {
+            generation: generation,
+            data: data.to_vec()
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        match self.entries.read() {
+            Ok(x) => x.len(),
+            Err(_) => 0
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn build_key(qname: &str) -> ResponseKey {
+        ResponseKey {
+            qname: qname.to_string(),
+            qtype: QueryType::A,
+            transport: Transport::Udp,
+            recursion_desired: false,
+            edns: false,
+            tcp_keepalive: false,
+            size_limit: 512
+        }
+    }
+
+    #[test]
+    fn test_response_cache() {
+        let cache = ResponseCache::new();
+        assert!(cache.lookup(&build_key("example.com"), 0, 1).is_none());
+
+        cache.store(build_key("example.com"), 0, &[0, 0, 1, 2, 3]);
+
+        // The id is patched in, and the rest is left as is
+        assert_eq!(Some(vec![0x12, 0x34, 1, 2, 3]),
+                   cache.lookup(&build_key("example.com"), 0, 0x1234));
+
+        // Entries from an earlier generation are never returned
+        assert!(cache.lookup(&build_key("example.com"), 1, 0x1234).is_none());
+        assert!(cache.lookup(&build_key("www.example.com"), 0, 0x1234).is_none());
+    }
+
+    #[test]
+    fn test_response_cache_limit() {
+        let mut cache = ResponseCache::new();
+        cache.max_entries = 2;
+
+        cache.store(build_key("a.com"), 0, &[0, 0]);
+        cache.store(build_key("b.com"), 0, &[0, 0]);
+        assert_eq!(2, cache.len());
+
+        cache.store(build_key("c.com"), 0, &[0, 0]);
+        assert_eq!(1, cache.len());
+        assert!(cache.lookup(&build_key("c.com"), 0, 0).is_some());
+    }
+}
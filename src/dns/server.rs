@@ -1,21 +1,27 @@
 //! UDP and TCP server implementations for DNS
 
-use std::io::{Result,Write};
+use std::cmp;
+use std::io::{Result,Read,Write};
 use std::net::{UdpSocket, TcpListener, TcpStream, Shutdown};
 use std::sync::{Arc,Mutex,Condvar};
-use std::sync::mpsc::{channel, Sender};
 use std::thread::Builder;
 use std::sync::atomic::Ordering;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 
-use rand::random;
+use chrono::*;
 
 use dns::resolve::DnsResolver;
-use dns::protocol::{DnsPacket, QueryType, DnsRecord, ResultCode};
-use dns::buffer::{PacketBuffer, BytePacketBuffer, VectorPacketBuffer, StreamPacketBuffer};
-use dns::context::ServerContext;
-use dns::netutil::{read_packet_length, write_packet_length};
+use dns::protocol::{DnsPacket, QueryType, DnsRecord, ResultCode, TransientTtl, EdnsOption, EDNS_TCP_KEEPALIVE, OpCode};
+use dns::buffer::{PacketBuffer, VectorPacketBuffer};
+use dns::context::{ServerContext, AnyPolicy};
+use dns::responsecache::ResponseKey;
+use dns::querylog::QueryLogEntry;
+use dns::netutil::{Network, read_packet_length, write_packet_length};
+use dns::transfer::encode_transfer;
+use dns::filter::apply_filters;
+use dns::ratelimit::RateLimitAction;
 
 macro_rules! return_or_report {
     ( $x:expr, $message:expr ) => {
@@ -98,20 +104,51 @@ pub fn execute_query(context: Arc<ServerContext>, request: &DnsPacket) -> DnsPac
     packet.header.recursion_available = context.allow_recursive;
     packet.header.response = true;
 
-    if request.header.recursion_desired && !context.allow_recursive {
+    // Anything but standard queries is rejected up front, echoing the opcode
+    if request.header.opcode != OpCode::QUERY {
+        packet.header.opcode = request.header.opcode;
+        packet.header.rescode = context.unsupported_opcode_rescode;
+        packet.questions.extend(request.questions.iter().cloned());
+
+        return packet;
+    }
+
+    let refuse_tunnel = request.questions.first()
+        .map(|x| context.tunnel_detector.inspect(&x.name))
+        .unwrap_or(false);
+
+    if !request.has_valid_edns() {
+        packet.header.rescode = ResultCode::FORMERR;
+        packet.add_edns0(MAX_UDP_QUERY_SIZE as u16);
+    }
+    else if request.header.recursion_desired && !context.allow_recursive {
         packet.header.rescode = ResultCode::REFUSED;
     }
     else if request.questions.is_empty() {
         packet.header.rescode = ResultCode::FORMERR;
     }
+    else if refuse_tunnel {
+        packet.questions.push(request.questions[0].clone());
+        packet.header.rescode = ResultCode::REFUSED;
+    }
+    else if let Some(policy) = context.blocklists.check(&request.questions[0].name) {
+        let question = &request.questions[0];
+        packet.questions.push(question.clone());
+
+        policy.apply(question, &mut packet);
+    }
     else {
         let mut results = Vec::new();
 
         let question = &request.questions[0];
         packet.questions.push(question.clone());
 
+        // Names are handled in lower case internally, while the question in
+        // the request retains the original case
+        let qname = question.name.to_lowercase();
+
         let mut resolver = context.create_resolver(context.clone());
-        let rescode = match resolver.resolve(&question.name,
+        let rescode = match resolver.resolve(&qname,
                                              question.qtype,
                                              request.header.recursion_desired) {
 
@@ -133,15 +170,18 @@ pub fn execute_query(context: Arc<ServerContext>, request: &DnsPacket) -> DnsPac
 
         packet.header.rescode = rescode;
 
-        for result in results {
-            for rec in result.answers {
-                packet.answers.push(rec);
-            }
-            for rec in result.authorities {
-                packet.authorities.push(rec);
-            }
-            for rec in result.resources {
-                packet.resources.push(rec);
+        // Only claim to be authoritative if all of the data is
+        packet.header.authoritative_answer = !results.is_empty() &&
+            results.iter().all(|x| x.header.authoritative_answer);
+
+        for result in &results {
+            packet.merge(result);
+        }
+
+        // Echo the case of the question in matching owner names
+        for rec in &mut packet.answers {
+            if rec.get_domain().map_or(false, |x| x.to_lowercase() == qname) {
+                rec.set_domain(&question.name);
             }
         }
     }
@@ -149,11 +189,299 @@ pub fn execute_query(context: Arc<ServerContext>, request: &DnsPacket) -> DnsPac
     packet
 }
 
+/// The transport on which a query was received
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Https
+}
+
+/// Execute a query, applying the `AnyPolicy` of the server to ANY queries
+/// received over UDP
+pub fn execute_query_with_policy(context: Arc<ServerContext>,
+                                 request: &DnsPacket,
+                                 transport: Transport) -> DnsPacket
+{
+    let is_udp_any = transport == Transport::Udp &&
+                     request.questions.len() == 1 &&
+                     request.questions[0].qtype == QueryType::ANY;
+
+    if !is_udp_any {
+        return execute_query(context, request);
+    }
+
+    match context.any_policy {
+        AnyPolicy::Full => execute_query(context, request),
+        AnyPolicy::Minimal => {
+            let mut packet = execute_query(context, request);
+
+            // Keep only the first record set of the answer
+            let first_type = packet.answers.first().map(|x| x.get_querytype());
+            packet.answers.retain(|x| Some(x.get_querytype()) == first_type);
+            packet.authorities.clear();
+            packet.resources.clear();
+
+            packet
+        },
+        AnyPolicy::TruncateUdp => {
+            let mut packet = DnsPacket::answer_for(request);
+            packet.header.recursion_available = context.allow_recursive;
+            packet.header.truncated_message = true;
+
+            packet
+        }
+    }
+}
+
+/// Answer a query for the configured whoami name, if that's what this is
+///
+/// The response describes the client as seen by the server, which is useful
+/// for diagnosing NAT and other middleboxes. The source address and port are
+/// always returned as TXT records, and queries over TCP also get a record
+/// stating the transport. Queries for other types than TXT or ANY get an
+/// empty answer.
+pub fn execute_whoami(context: &ServerContext,
+                      request: &DnsPacket,
+                      transport: Transport,
+                      src: SocketAddr) -> Option<DnsPacket>
+{
+    let whoami_name = match context.whoami_name {
+        Some(ref x) => x,
+        None => return None
+    };
+
+    if request.questions.len() != 1 {
+        return None;
+    }
+
+    let question = &request.questions[0];
+    if question.name.to_lowercase() != whoami_name.to_lowercase() {
+        return None;
+    }
+
+    let mut packet = DnsPacket::answer_for(request);
+    packet.header.recursion_available = context.allow_recursive;
+    packet.header.authoritative_answer = true;
+
+    if question.qtype != QueryType::TXT && question.qtype != QueryType::ANY {
+        return Some(packet);
+    }
+
+    let mut data = vec![src.ip().to_string(), format!("port={}", src.port())];
+    if transport == Transport::Tcp {
+        data.push("transport=tcp".to_string());
+    }
+
+    for txt in data {
+        packet.answers.push(DnsRecord::TXT {
+            domain: question.name.clone(),
+            data: vec![txt],
+            ttl: TransientTtl(0)
+        });
+    }
+
+    Some(packet)
+}
+
+/// Order the address records of `packet` so that the ones on the same network
+/// as `client` come first
+///
+/// The network is the first one of `sortlist` that contains the client, as
+/// with the RFC 1035 sortlist. Other records keep their positions, and the
+/// order is otherwise preserved. Returns whether the client was on any of the
+/// networks.
+pub fn apply_sortlist(packet: &mut DnsPacket, sortlist: &[Network], client: &IpAddr) -> bool
+{
+    let network = match sortlist.iter().find(|x| x.contains(client)) {
+        Some(x) => x,
+        None => return false
+    };
+
+    let address = |rec: &DnsRecord| match *rec {
+        DnsRecord::A { addr, .. } => Some(IpAddr::V4(addr)),
+        DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(addr)),
+        _ => None
+    };
+
+    let positions = packet.answers.iter()
+        .enumerate()
+        .filter(|&(_, x)| address(x).is_some())
+        .map(|(idx, _)| idx)
+        .collect::<Vec<usize>>();
+
+    let mut records = positions.iter()
+        .map(|&idx| packet.answers[idx].clone())
+        .collect::<Vec<DnsRecord>>();
+
+    records.sort_by_key(|x| !address(x).map_or(false, |addr| network.contains(&addr)));
+
+    for (idx, rec) in positions.into_iter().zip(records.into_iter()) {
+        packet.answers[idx] = rec;
+    }
+
+    true
+}
+
+/// Determine the response cache key for a request, if it's eligible
+///
+/// Only plain queries are considered, and only while there are no response
+/// filters or sortlist, which may depend on the client. Names that are
+/// blocked, or that match the whoami name, are always handled without the
+/// cache.
+fn response_cache_key(context: &ServerContext,
+                      request: &DnsPacket,
+                      transport: Transport,
+                      size_limit: usize) -> Option<ResponseKey>
+{
+    if !context.enable_response_cache ||
+       !context.response_filters.is_empty() ||
+       !context.sortlist.is_empty() ||
+       !request.has_valid_edns() ||
+       request.header.opcode != OpCode::QUERY ||
+       request.questions.len() != 1 {
+        return None;
+    }
+
+    let question = &request.questions[0];
+
+    if let Some(ref whoami_name) = context.whoami_name {
+        if question.name.to_lowercase() == whoami_name.to_lowercase() {
+            return None;
+        }
+    }
+
+    if context.blocklists.check(&question.name).is_some() {
+        return None;
+    }
+
+    Some(ResponseKey {
+        qname: question.name.clone(),
+        qtype: question.qtype,
+        transport: transport,
+        recursion_desired: request.header.recursion_desired,
+        edns: request.edns_udp_size().is_some(),
+        tcp_keepalive: request.get_edns_option(EDNS_TCP_KEEPALIVE).is_some(),
+        size_limit: size_limit
+    })
+}
+
+/// Execute a query from `src` and write the response to `res_buffer`
+///
+/// This wraps `execute_query_with_policy`, and records the end to end latency
+/// of the query as well as the size of the resulting response in the server
+/// statistics. If the response cache is enabled, authoritative responses are
+/// stored in serialized form and reused until the zones change. The additional
+/// section is stripped right before writing, if so configured, and an OPT record
+/// is added for clients using EDNS. The response filters are applied last.
+pub fn execute_and_write(context: Arc<ServerContext>,
+                         request: &DnsPacket,
+                         transport: Transport,
+                         src: SocketAddr,
+                         res_buffer: &mut VectorPacketBuffer,
+                         size_limit: usize) -> Result<()>
+{
+    // The latency is measured with a monotonic clock, so that changes to the
+    // system time don't skew it
+    let timestamp = Local::now();
+    let start = Instant::now();
+
+    // The generation has to be read before resolving, so that a concurrent
+    // zone change makes the response stale rather than getting lost
+    let generation = context.authority.generation();
+    let cache_key = response_cache_key(&context, request, transport, size_limit);
+
+    let cached = cache_key.as_ref()
+        .and_then(|key| context.response_cache.lookup(key, generation, request.header.id));
+
+    if let Some(data) = cached {
+        for b in data {
+            try!(res_buffer.write_u8(b));
+        }
+    } else {
+        let mut packet = match execute_whoami(&context, request, transport, src) {
+            Some(x) => x,
+            None => execute_query_with_policy(context.clone(), request, transport)
+        };
+
+        // Names with records in several pools are answered from one of
+        // them, which depends on the client and varies between queries
+        let pooled = match request.questions.first() {
+            Some(question) if packet.header.authoritative_answer => {
+                context.authority.select_pool(&mut packet, &question.name.to_lowercase(), &src.ip())
+            },
+            _ => false
+        };
+
+        // Addresses take turns at the head of the answer, if enabled
+        let rotated = context.round_robin && packet.header.authoritative_answer &&
+                      context.authority.rotate_answers(&mut packet);
+
+        // The order of addresses depends on the client as well
+        apply_sortlist(&mut packet, &context.sortlist, &src.ip());
+
+        if context.strip_additional {
+            packet.strip_additional();
+        }
+
+        // Clients using EDNS are told how large a query we accept
+        if request.edns_udp_size().is_some() {
+            packet.add_edns0(MAX_UDP_QUERY_SIZE as u16);
+        }
+
+        if transport == Transport::Tcp && request.get_edns_option(EDNS_TCP_KEEPALIVE).is_some() {
+            add_tcp_keepalive(&mut packet, context.tcp_keepalive_timeout);
+        }
+
+        apply_filters(&context.response_filters, request, &mut packet, src);
+
+        try!(packet.write(res_buffer, size_limit));
+
+        // Flattened ANAME answers depend on the target, and not only on the
+        // zones, so they can't be reused until the zones change. Neither can
+        // answers from pools, or rotated answers.
+        if let Some(key) = cache_key {
+            let is_flattened = context.authority.query(&key.qname.to_lowercase(), QueryType::A)
+                .map_or(false, |x| x.answers.iter().any(|x| x.get_querytype() == QueryType::ANAME));
+
+            if packet.header.authoritative_answer && !is_flattened && !pooled && !rotated {
+                let len = res_buffer.pos();
+                let data = try!(res_buffer.get_range(0, len));
+                context.response_cache.store(key, generation, data);
+            }
+        }
+    }
+
+    let duration = start.elapsed();
+    let elapsed = duration.as_secs() as i64 * 1000 + (duration.subsec_nanos() / 1_000_000) as i64;
+    context.statistics.query_latency.observe(elapsed as usize);
+    context.statistics.response_size.observe(res_buffer.pos());
+
+    let rescode = ResultCode::from_num((try!(res_buffer.get(3)) & 0x0F) as u16);
+    context.statistics.record_rescode(rescode);
+
+    if let Some(question) = request.questions.first() {
+        context.query_log.log(QueryLogEntry {
+            timestamp: timestamp,
+            client: src,
+            transport: transport,
+            qname: question.name.clone(),
+            qtype: question.qtype,
+            rescode: rescode,
+            elapsed_ms: elapsed
+        });
+    }
+
+    Ok(())
+}
+
 /// The UDP server
 ///
 /// Accepts DNS queries through UDP, and uses the `ServerContext` to determine
-/// how to service the request. Packets are read on a single thread, after which
-/// a new thread is spawned to service the request asynchronously.
+/// how to service the request. Packets are read on a single thread and queued
+/// for a fixed pool of worker threads, so that a slow lookup only holds up the
+/// worker servicing it. Once `max_udp_queue` queries are waiting, further
+/// queries are answered with SERVFAIL straight away rather than queued.
 pub struct DnsUdpServer {
     context: Arc<ServerContext>,
     request_queue: Arc<Mutex<VecDeque<(SocketAddr, DnsPacket)>>>,
@@ -170,18 +498,37 @@ impl DnsUdpServer {
             thread_count: thread_count
         }
     }
-}
 
-impl DnsServer for DnsUdpServer {
+    /// Queue a query for the worker threads, or return the SERVFAIL response
+    /// to send in its place if the queue is full
+    fn enqueue(&self, src: SocketAddr, request: DnsPacket) -> Option<DnsPacket> {
+
+        // Acquire lock, add request to queue, and notify waiting threads
+        // using the condition.
+        let mut queue = match self.request_queue.lock() {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Failed to send UDP request for processing: {}", e);
+                return None;
+            }
+        };
 
-    /// Launch the server
-    ///
-    /// This method takes ownership of the server, preventing the method from
-    /// being called multiple times.
-    fn run_server(self) -> Result<()> {
+        if queue.len() >= self.context.max_udp_queue {
+            let mut packet = DnsPacket::answer_for(&request);
+            packet.header.recursion_available = self.context.allow_recursive;
+            packet.header.rescode = ResultCode::SERVFAIL;
 
-        // Bind the socket
-        let socket = try!(UdpSocket::bind(("0.0.0.0", self.context.dns_port)));
+            return Some(packet);
+        }
+
+        queue.push_back((src, request));
+        self.request_cond.notify_one();
+
+        None
+    }
+
+    /// Start the worker threads and the thread reading queries from `socket`
+    fn serve(self, socket: UdpSocket) -> Result<()> {
 
         // Spawn threads for handling requests
         for thread_id in 0..self.thread_count {
@@ -203,32 +550,28 @@ impl DnsServer for DnsUdpServer {
 
                     // Acquire lock, and wait on the condition until data is
                     // available. Then proceed with popping an entry of the queue.
-                    let (src, request) = match request_queue.lock().ok()
-                        .and_then(|x| request_cond.wait(x).ok())
-                        .and_then(|mut x| x.pop_front()) {
-
-                        Some(x) => x,
-                        None => {
-                            println!("Not expected to happen!");
-                            continue;
+                    let (src, request) = {
+                        let mut queue = return_or_report!(request_queue.lock(), "Failed to lock UDP request queue");
+                        while queue.is_empty() {
+                            queue = return_or_report!(request_cond.wait(queue), "Failed to wait for UDP requests");
                         }
-                    };
-
-                    let mut size_limit = 512;
 
-                    // Check for EDNS
-                    if request.resources.len() == 1 {
-                        if let DnsRecord::OPT { packet_len, .. } = request.resources[0] {
-                            size_limit = packet_len as usize;
+                        match queue.pop_front() {
+                            Some(x) => x,
+                            None => continue
                         }
-                    }
+                    };
+
+                    // Clients using EDNS may accept larger responses, but
+                    // never less than the size every client does
+                    let size_limit = request.edns_udp_size()
+                        .map_or(MIN_UDP_PAYLOAD_SIZE, |x| cmp::max(x as usize, MIN_UDP_PAYLOAD_SIZE));
 
                     // Create a response buffer, and ask the context for an appropriate
                     // resolver
                     let mut res_buffer = VectorPacketBuffer::new();
 
-                    let mut packet = execute_query(context.clone(), &request);
-                    let _ = packet.write(&mut res_buffer, size_limit);
+                    let _ = execute_and_write(context.clone(), &request, Transport::Udp, src, &mut res_buffer, size_limit);
 
                     // Fire off the response
                     let len = res_buffer.pos();
@@ -243,9 +586,10 @@ impl DnsServer for DnsUdpServer {
             loop {
                 let _ = self.context.statistics.udp_query_count.fetch_add(1, Ordering::Release);
 
-                // Read a query packet
-                let mut req_buffer = BytePacketBuffer::new();
-                let (_, src) = match socket.recv_from(&mut req_buffer.buf) {
+                // Read a query packet, with room for a byte more than we
+                // accept so that oversized datagrams can be told apart
+                let mut req_buffer = [0; MAX_UDP_QUERY_SIZE + 1];
+                let (len, src) = match socket.recv_from(&mut req_buffer) {
                     Ok(x) => x,
                     Err(e) => {
                         println!("Failed to read from UDP socket: {:?}", e);
@@ -254,23 +598,42 @@ impl DnsServer for DnsUdpServer {
                 };
 
                 // Parse it
-                let request = match DnsPacket::from_buffer(&mut req_buffer) {
-                    Ok(x) => x,
-                    Err(e) => {
-                        println!("Failed to parse UDP query packet: {:?}", e);
+                let request = match parse_udp_query(&req_buffer, len) {
+                    UdpQuery::Query(x) => x,
+                    UdpQuery::Malformed(mut response) => {
+                        println!("Failed to parse UDP query packet of {} bytes from {}", len, src);
+
+                        let mut res_buffer = VectorPacketBuffer::new();
+                        if response.write(&mut res_buffer, 512).is_ok() {
+                            let _ = socket.send_to(&res_buffer.buffer[0..res_buffer.pos], src);
+                        }
                         continue;
-                    }
+                    },
+                    UdpQuery::Response => {
+                        drop_response(&self.context, src);
+                        continue;
+                    },
+                    UdpQuery::Dropped => continue
                 };
 
-                // Acquire lock, add request to queue, and notify waiting threads
-                // using the condition.
-                match self.request_queue.lock() {
-                    Ok(mut queue) => {
-                        queue.push_back((src, request));
-                        self.request_cond.notify_one();
-                    },
-                    Err(e) => {
-                        println!("Failed to send UDP request for processing: {}", e);
+                // Clients over their limit are answered here, if at all,
+                // without the query ever being executed
+                if !self.context.rate_limiter.check(src.ip()) {
+                    if let Some(mut response) = rate_limited_response(&self.context, &request) {
+                        let mut res_buffer = VectorPacketBuffer::new();
+                        if response.write(&mut res_buffer, 512).is_ok() {
+                            let _ = socket.send_to(&res_buffer.buffer[0..res_buffer.pos], src);
+                        }
+                    }
+                    continue;
+                }
+
+                // With every worker busy and the queue full, fail fast rather
+                // than leave the client waiting on a reply that may never come
+                if let Some(mut response) = self.enqueue(src, request) {
+                    let mut res_buffer = VectorPacketBuffer::new();
+                    if response.write(&mut res_buffer, 512).is_ok() {
+                        let _ = socket.send_to(&res_buffer.buffer[0..res_buffer.pos], src);
                     }
                 }
             }
@@ -280,73 +643,250 @@ impl DnsServer for DnsUdpServer {
     }
 }
 
-/// TCP DNS server
-pub struct DnsTcpServer {
-    context: Arc<ServerContext>,
-    senders: Vec<Sender<TcpStream>>,
-    thread_count: usize
+impl DnsServer for DnsUdpServer {
+
+    /// Launch the server
+    ///
+    /// This method takes ownership of the server, preventing the method from
+    /// being called multiple times.
+    fn run_server(self) -> Result<()> {
+
+        // Bind the socket
+        let socket = try!(UdpSocket::bind(("0.0.0.0", self.context.dns_port)));
+
+        self.serve(socket)
+    }
 }
 
-impl DnsTcpServer {
-    pub fn new(context: Arc<ServerContext>, thread_count: usize) -> DnsTcpServer {
-        DnsTcpServer {
-            context: context,
-            senders: Vec::new(),
-            thread_count: thread_count
+/// Largest query accepted over UDP, matching the largest payload size
+/// commonly negotiated through EDNS
+pub const MAX_UDP_QUERY_SIZE: usize = 4096;
+
+/// Size of the UDP responses every client accepts, and the smallest payload
+/// size an EDNS client can advertise
+pub const MIN_UDP_PAYLOAD_SIZE: usize = 512;
+
+/// The outcome of parsing a datagram received on the UDP socket
+pub enum UdpQuery {
+    /// A well formed query, ready to be executed
+    Query(DnsPacket),
+
+    /// A FORMERR response to send back, since the query was truncated or
+    /// malformed
+    Malformed(DnsPacket),
+
+    /// A response rather than a query, as seen in reflection attacks, which
+    /// is dropped
+    Response,
+
+    /// Not worth answering, since there's not even a query id
+    Dropped
+}
+
+/// Parse a query received over UDP, where `len` is the number of bytes the
+/// read placed in `buf`
+///
+/// A datagram that fills the whole buffer may have been cut short by the
+/// read, so it's rejected rather than parsed.
+pub fn parse_udp_query(buf: &[u8], len: usize) -> UdpQuery {
+    // Without a full header, there's no telling what we've received
+    if len < 12 {
+        return UdpQuery::Dropped;
+    }
+
+    if buf[2] & 0x80 != 0 {
+        return UdpQuery::Response;
+    }
+
+    if len < buf.len() {
+        let mut req_buffer = VectorPacketBuffer::new();
+        req_buffer.buffer.extend_from_slice(&buf[0..len]);
+
+        if let Ok(request) = DnsPacket::from_buffer(&mut req_buffer) {
+            return UdpQuery::Query(request);
+        }
+    }
+
+    let mut packet = DnsPacket::new();
+    packet.header.id = ((buf[0] as u16) << 8) | (buf[1] as u16);
+    packet.header.opcode = OpCode::from_num((buf[2] >> 3) & 0x0F);
+    packet.header.response = true;
+    packet.header.rescode = ResultCode::FORMERR;
+
+    UdpQuery::Malformed(packet)
+}
+
+/// The response to a query over UDP from a client over its rate limit, if
+/// it gets one at all
+///
+/// Truncated responses carry nothing but the question, so they're no larger
+/// than the query and useless for amplification.
+pub fn rate_limited_response(context: &ServerContext, request: &DnsPacket) -> Option<DnsPacket> {
+    match context.rate_limiter.action {
+        RateLimitAction::Drop => None,
+        RateLimitAction::Truncate => {
+            let mut packet = DnsPacket::answer_for(request);
+            packet.header.recursion_available = context.allow_recursive;
+            packet.header.truncated_message = true;
+
+            Some(packet)
         }
     }
 }
 
-impl DnsServer for DnsTcpServer {
-    fn run_server(mut self) -> Result<()> {
-        let socket = try!(TcpListener::bind(("0.0.0.0", self.context.dns_port)));
+/// Count, and optionally log, a packet from `src` dropped for having the QR
+/// bit set
+fn drop_response(context: &ServerContext, src: SocketAddr) {
+    let _ = context.statistics.dropped_response_count.fetch_add(1, Ordering::Release);
 
-        // Spawn threads for handling requests, and create the channels
-        for thread_id in 0..self.thread_count {
-            let (tx, rx) = channel();
-            self.senders.push(tx);
+    if context.log_dropped_responses {
+        println!("Dropped packet with the QR bit set from {}", src);
+    }
+}
 
-            let context = self.context.clone();
+/// Add an EDNS TCP keepalive option announcing `timeout` to a response
+fn add_tcp_keepalive(packet: &mut DnsPacket, timeout: Duration) {
+    // The timeout is given in units of 100 milliseconds
+    let millis = timeout.as_secs() * 1000 + (timeout.subsec_nanos() / 1_000_000) as u64;
+    let units = if millis / 100 > 0xFFFF { 0xFFFF } else { millis / 100 } as u16;
 
-            let name = "DnsTcpServer-request-".to_string() + &thread_id.to_string();
-            let _ = try!(Builder::new().name(name).spawn(move || {
-                loop {
-                    let mut stream = match rx.recv() {
-                        Ok(x) => x,
-                        Err(_) => continue
-                    };
+    let option = EdnsOption {
+        code: EDNS_TCP_KEEPALIVE,
+        data: vec![(units >> 8) as u8, (units & 0xFF) as u8]
+    };
 
-                    let _ = context.statistics.tcp_query_count.fetch_add(1, Ordering::Release);
+    packet.add_edns0(MAX_UDP_QUERY_SIZE as u16);
 
-                    // When DNS packets are sent over TCP, they're prefixed with a two byte
-                    // length. We don't really need to know the length in advance, so we
-                    // just move past it and continue reading as usual
-                    ignore_or_report!(read_packet_length(&mut stream), "Failed to read query packet length");
+    for rec in &mut packet.resources {
+        if let DnsRecord::OPT { ref mut options, .. } = *rec {
+            options.retain(|x| x.code != EDNS_TCP_KEEPALIVE);
+            options.push(option);
+            return;
+        }
+    }
+}
 
-                    let request = {
-                        let mut stream_buffer = StreamPacketBuffer::new(&mut stream);
-                        return_or_report!(DnsPacket::from_buffer(&mut stream_buffer), "Failed to read query packet")
-                    };
+/// Serve queries on a TCP connection until the client closes it, or it has
+/// been idle for too long
+///
+/// Connections are closed after `tcp_idle_timeout` without a query, unless
+/// the client has signaled support for EDNS TCP keepalive (RFC 7828), in which
+/// case the longer `tcp_keepalive_timeout` applies and is announced to the
+/// client in the responses. However active, no connection is kept open for
+/// longer than `tcp_max_lifetime`, and connections beyond the limits of
+/// `tcp_connections` are refused or evict the idlest one.
+pub fn handle_tcp_connection(context: Arc<ServerContext>, mut stream: TcpStream) {
+    let src = return_or_report!(stream.peer_addr(), "Failed to get peer address");
+
+    match context.tcp_connections.open(src.ip(), &stream) {
+        Some(id) => {
+            serve_tcp_connection(&context, &mut stream, src, id);
+            context.tcp_connections.close(id);
+        },
+        None => println!("Refused TCP connection from {}, which has too many open", src)
+    }
 
-                    let mut res_buffer = VectorPacketBuffer::new();
+    let _ = stream.shutdown(Shutdown::Both);
+}
 
-                    let mut packet = execute_query(context.clone(), &request);
-                    ignore_or_report!(packet.write(&mut res_buffer, 0xFFFF), "Failed to write packet to buffer");
+/// Answer queries on the TCP connection `id` until it's closed or times out
+fn serve_tcp_connection(context: &Arc<ServerContext>, stream: &mut TcpStream, src: SocketAddr, id: usize) {
+    let deadline = Instant::now() + context.tcp_max_lifetime;
 
-                    // As is the case for incoming queries, we need to send a 2 byte length
-                    // value before handing of the actual packet.
-                    let len = res_buffer.pos();
-                    ignore_or_report!(write_packet_length(&mut stream, len), "Failed to write packet size");
+    let mut idle_timeout = context.tcp_idle_timeout;
 
-                    // Now we can go ahead and write the actual packet
-                    let data = return_or_report!(res_buffer.get_range(0, len), "Failed to get packet data");
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
 
-                    ignore_or_report!(stream.write(data), "Failed to write response packet");
+        let timeout = cmp::min(idle_timeout, deadline - now);
+        ignore_or_report!(stream.set_read_timeout(Some(timeout)), "Failed to set read timeout");
 
-                    ignore_or_report!(stream.shutdown(Shutdown::Both), "Failed to shutdown socket");
-                }
-            }));
+        // When DNS packets are sent over TCP, they're prefixed with a two byte
+        // length. Failing to read it means that the client has gone away, or
+        // that the connection has been idle for too long.
+        let len = match read_packet_length(stream) {
+            Ok(x) => x as usize,
+            Err(_) => break
+        };
+
+        context.tcp_connections.touch(id);
+        let _ = context.statistics.tcp_query_count.fetch_add(1, Ordering::Release);
+
+        // A connection cut short, or stalling, in the middle of a query is
+        // given up on rather than the partial query parsed
+        let mut req_buffer = VectorPacketBuffer::new();
+        req_buffer.buffer.resize(len, 0);
+        if stream.read_exact(&mut req_buffer.buffer).is_err() {
+            println!("Failed to read query packet from {}", src);
+            break;
+        }
+
+        let request = return_or_report!(DnsPacket::from_buffer(&mut req_buffer), "Failed to parse query packet");
+
+        if request.header.response {
+            drop_response(context, src);
+            continue;
+        }
+
+        if request.get_edns_option(EDNS_TCP_KEEPALIVE).is_some() {
+            idle_timeout = context.tcp_keepalive_timeout;
+        }
+
+        // Zone transfers span any number of messages, and are handled
+        // separately from regular queries
+        let qtype = request.questions.first().map(|x| x.qtype);
+        if qtype == Some(QueryType::AXFR) || qtype == Some(QueryType::IXFR) {
+            let now = UTC::now().timestamp() as u64;
+            let messages = return_or_report!(encode_transfer(context, &request, &req_buffer.buffer, &src.ip(), now),
+                                             "Failed to write packet to buffer");
+
+            for data in messages {
+                ignore_or_report!(write_packet_length(stream, data.len()), "Failed to write packet size");
+                ignore_or_report!(stream.write_all(&data), "Failed to write response packet");
+            }
+
+            continue;
+        }
+
+        let mut res_buffer = VectorPacketBuffer::new();
+
+        ignore_or_report!(execute_and_write(context.clone(), &request, Transport::Tcp, src, &mut res_buffer, 0xFFFF), "Failed to write packet to buffer");
+
+        // As is the case for incoming queries, we need to send a 2 byte length
+        // value before handing of the actual packet.
+        let len = res_buffer.pos();
+        ignore_or_report!(write_packet_length(stream, len), "Failed to write packet size");
+
+        // Now we can go ahead and write the actual packet
+        let data = return_or_report!(res_buffer.get_range(0, len), "Failed to get packet data");
+
+        ignore_or_report!(stream.write_all(data), "Failed to write response packet");
+    }
+}
+
+/// TCP DNS server
+///
+/// Each connection is handled on a thread of its own, so that a client
+/// keeping a connection open doesn't hold up any other. The number of
+/// connections is bounded by the `tcp_connections` of the context instead.
+pub struct DnsTcpServer {
+    context: Arc<ServerContext>
+}
+
+impl DnsTcpServer {
+    pub fn new(context: Arc<ServerContext>) -> DnsTcpServer {
+        DnsTcpServer {
+            context: context
         }
+    }
+}
+
+impl DnsServer for DnsTcpServer {
+    fn run_server(self) -> Result<()> {
+        let socket = try!(TcpListener::bind(("0.0.0.0", self.context.dns_port)));
 
         let _ = try!(Builder::new().name("DnsTcpServer-incoming".into()).spawn(move || {
             for wrap_stream in socket.incoming() {
@@ -358,13 +898,13 @@ impl DnsServer for DnsTcpServer {
                     }
                 };
 
-                // Hand it off to a worker thread
-                let thread_no = random::<usize>() % self.thread_count;
-                match self.senders[thread_no].send(stream) {
-                    Ok(_) => {},
-                    Err(e) => {
-                        println!("Failed to send TCP request for processing on thread {}: {}", thread_no, e);
-                    }
+                let context = self.context.clone();
+                let spawned = Builder::new().name("DnsTcpServer-connection".into()).spawn(move || {
+                    handle_tcp_connection(context, stream);
+                });
+
+                if let Err(e) = spawned {
+                    println!("Failed to spawn thread for TCP connection: {:?}", e);
                 }
             }
         }));
@@ -377,15 +917,23 @@ impl DnsServer for DnsTcpServer {
 mod tests {
 
     use std::sync::Arc;
-    use std::net::Ipv4Addr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
     use std::io::{Error, ErrorKind};
+    use std::thread::{sleep, spawn};
+    use std::time::Instant;
 
     use dns::protocol::{DnsPacket, DnsQuestion, QueryType, DnsRecord, ResultCode, TransientTtl};
 
     use super::*;
 
+    use dns::answermap::AnswerMap;
+    use dns::authority::{Zone, DEFAULT_MAX_RECORDS_PER_ZONE};
+    use dns::blocklist::{Blocklist, BlockPolicy};
     use dns::context::ResolveStrategy;
     use dns::context::tests::create_test_context;
+    use dns::filter::ResponseFilter;
+    use dns::transfer::fetch_zone;
+    use dns::tunnel::TunnelAction;
 
     fn build_query(qname: &str, qtype: QueryType) -> DnsPacket {
         let mut query_packet = DnsPacket::new();
@@ -565,5 +1113,1587 @@ mod tests {
         };
 
     }
-}
 
+    #[test]
+    fn test_execute_query_servfail() {
+        // Referrals to a name server without glue, and whose name doesn't
+        // resolve, leave nowhere to go
+        let context = create_test_context(
+            Box::new(|qname, _, _, _| {
+                let mut packet = DnsPacket::new();
+                packet.header.response = true;
+
+                if qname == "ns1.broken.net" {
+                    packet.header.rescode = ResultCode::NXDOMAIN;
+                } else {
+                    packet.authorities.push(DnsRecord::NS {
+                        domain: "com".to_string(),
+                        host: "ns1.broken.net".to_string(),
+                        ttl: TransientTtl(3600)
+                    });
+                }
+
+                Ok(packet)
+            }));
+
+        let _ = context.cache.store(&[DnsRecord::NS {
+            domain: "".to_string(),
+            host: "a.myroot.net".to_string(),
+            ttl: TransientTtl(3600)
+        }, DnsRecord::A {
+            domain: "a.myroot.net".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }]);
+
+        let mut request = build_query("google.com", QueryType::A);
+        request.header.id = 4321;
+
+        let res = execute_query(context.clone(), &request);
+        assert_eq!(ResultCode::SERVFAIL, res.header.rescode);
+        assert!(res.header.response);
+        assert_eq!(4321, res.header.id);
+        assert_eq!(request.questions, res.questions);
+        assert!(res.answers.is_empty());
+        assert!(res.authorities.is_empty());
+
+        // The same goes for when every name server fails to respond
+        let context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Err(Error::new(ErrorKind::TimedOut, "Timeout"))
+            }));
+
+        let _ = context.cache.store(&[DnsRecord::NS {
+            domain: "".to_string(),
+            host: "a.myroot.net".to_string(),
+            ttl: TransientTtl(3600)
+        }, DnsRecord::A {
+            domain: "a.myroot.net".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }]);
+
+        let res = execute_query(context.clone(), &request);
+        assert_eq!(ResultCode::SERVFAIL, res.header.rescode);
+        assert!(res.header.response);
+        assert!(res.answers.is_empty());
+    }
+
+    #[test]
+    fn test_execute_query_blocked() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Err(Error::new(ErrorKind::NotFound, "Blocked names shouldn't be resolved"))
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.blocklists.default_policy = BlockPolicy::Sinkhole {
+                    addr: "0.0.0.0".parse().unwrap()
+                };
+
+                let mut list = Blocklist::new("test".to_string(), None);
+                list.add_domain("ads.example.com");
+                ctx.blocklists.add_list(list).unwrap();
+
+                let mut refused_list = Blocklist::new("refused".to_string(), Some(BlockPolicy::Refused));
+                refused_list.add_domain("malware.example.com");
+                ctx.blocklists.add_list(refused_list).unwrap();
+            },
+            None => panic!()
+        }
+
+        {
+            let res = execute_query(context.clone(),
+                                    &build_query("tracker.ads.example.com", QueryType::A));
+            assert_eq!(ResultCode::NOERROR, res.header.rescode);
+            assert_eq!(1, res.questions.len());
+            assert_eq!(1, res.answers.len());
+
+            match res.answers[0] {
+                DnsRecord::A { ref domain, addr, .. } => {
+                    assert_eq!("tracker.ads.example.com", domain);
+                    assert_eq!("0.0.0.0".parse::<Ipv4Addr>().unwrap(), addr);
+                },
+                _ => panic!()
+            }
+        };
+
+        {
+            let res = execute_query(context.clone(),
+                                    &build_query("malware.example.com", QueryType::A));
+            assert_eq!(ResultCode::REFUSED, res.header.rescode);
+            assert_eq!(0, res.answers.len());
+        };
+
+        // Names that aren't blocked are resolved as usual
+        {
+            let res = execute_query(context.clone(),
+                                    &build_query("www.example.com", QueryType::A));
+            assert_eq!(ResultCode::SERVFAIL, res.header.rescode);
+        };
+    }
+
+    #[test]
+    fn test_execute_query_malformed_edns() {
+        let context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Err(Error::new(ErrorKind::NotFound, "Malformed queries shouldn't be resolved"))
+            }));
+
+        let opt = DnsRecord::OPT {
+            packet_len: 4096,
+            flags: 0,
+            options: Vec::new()
+        };
+
+        let check_formerr = |request: &DnsPacket| {
+            let res = execute_query(context.clone(), request);
+            assert_eq!(ResultCode::FORMERR, res.header.rescode);
+            assert_eq!(1, res.resources.len());
+
+            match res.resources[0] {
+                DnsRecord::OPT { ref options, .. } => assert!(options.is_empty()),
+                _ => panic!()
+            }
+        };
+
+        // Multiple OPT records
+        let mut query = build_query("www.google.com", QueryType::A);
+        query.resources.push(opt.clone());
+        query.resources.push(opt.clone());
+
+        let mut req_buffer = VectorPacketBuffer::new();
+        query.write(&mut req_buffer, 512).unwrap();
+        req_buffer.seek(0).unwrap();
+        let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+        assert_eq!(2, request.resources.len());
+        check_formerr(&request);
+
+        // An OPT record with a name other than the root, which has to be
+        // built by hand since OPT records are always written for the root
+        let mut req_buffer = VectorPacketBuffer::new();
+        build_query("www.google.com", QueryType::A).write(&mut req_buffer, 512).unwrap();
+        req_buffer.write_qname("example.com").unwrap();
+        req_buffer.write_u16(QueryType::OPT.to_num()).unwrap();
+        req_buffer.write_u16(4096).unwrap();
+        req_buffer.write_u32(0).unwrap();
+        req_buffer.write_u16(0).unwrap();
+        req_buffer.set_u16(10, 1).unwrap();
+
+        req_buffer.seek(0).unwrap();
+        let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+        assert_eq!(1, request.resources.len());
+        check_formerr(&request);
+
+        // An OPT record outside of the additional section
+        let mut request = build_query("www.google.com", QueryType::A);
+        request.answers.push(opt.clone());
+        check_formerr(&request);
+
+        // While a single OPT record is fine
+        let mut request = build_query("www.google.com", QueryType::A);
+        request.resources.push(opt.clone());
+        assert!(request.has_valid_edns());
+        assert_eq!(ResultCode::SERVFAIL, execute_query(context.clone(), &request).header.rescode);
+    }
+
+    #[test]
+    fn test_unsupported_opcode() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Err(Error::new(ErrorKind::NotFound, "Only standard queries should be resolved"))
+            }));
+
+        // An inverse query, with opcode 1
+        let mut request = build_query("www.google.com", QueryType::A);
+        request.header.id = 1234;
+        request.header.opcode = OpCode::IQUERY;
+
+        let mut req_buffer = VectorPacketBuffer::new();
+        request.write(&mut req_buffer, 512).unwrap();
+        req_buffer.seek(0).unwrap();
+        let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+        let mut res_buffer = VectorPacketBuffer::new();
+        execute_and_write(context.clone(),
+                          &request,
+                          Transport::Udp,
+                          "127.0.0.1:1234".parse().unwrap(),
+                          &mut res_buffer,
+                          512).unwrap();
+
+        res_buffer.seek(0).unwrap();
+        let res = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+        assert_eq!(1234, res.header.id);
+        assert!(res.header.response);
+        assert_eq!(OpCode::IQUERY, res.header.opcode);
+        assert_eq!(ResultCode::NOTIMP, res.header.rescode);
+        assert_eq!(1, res.questions.len());
+        assert!(res.answers.is_empty());
+
+        // The response code is configurable
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.unsupported_opcode_rescode = ResultCode::REFUSED;
+            },
+            None => panic!()
+        }
+
+        let res = execute_query(context.clone(), &request);
+        assert_eq!(OpCode::IQUERY, res.header.opcode);
+        assert_eq!(ResultCode::REFUSED, res.header.rescode);
+    }
+
+    #[test]
+    fn test_execute_query_root() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Err(Error::new(ErrorKind::NotFound, "The root shouldn't be resolved"))
+            }));
+
+        // A query for the root NS records, with recursion desired
+        let raw = [0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                   0x00, 0x00, 0x02, 0x00, 0x01];
+
+        let mut req_buffer = VectorPacketBuffer::new();
+        for b in raw.iter() {
+            req_buffer.write_u8(*b).unwrap();
+        }
+        req_buffer.seek(0).unwrap();
+
+        let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+        assert_eq!(1, request.questions.len());
+        assert_eq!("", request.questions[0].name);
+        assert_eq!(QueryType::NS, request.questions[0].qtype);
+
+        // Without root hints, there's nothing to answer with
+        assert_eq!(ResultCode::REFUSED, execute_query(context.clone(), &request).header.rescode);
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.root_hints.push(DnsRecord::NS {
+                    domain: "".to_string(),
+                    host: "a.root-servers.net".to_string(),
+                    ttl: TransientTtl(3600000)
+                });
+                ctx.root_hints.push(DnsRecord::A {
+                    domain: "a.root-servers.net".to_string(),
+                    addr: "198.41.0.4".parse::<Ipv4Addr>().unwrap(),
+                    ttl: TransientTtl(3600000)
+                });
+            },
+            None => panic!()
+        }
+
+        let mut res = execute_query(context.clone(), &request);
+        assert_eq!(ResultCode::NOERROR, res.header.rescode);
+        assert_eq!(1, res.answers.len());
+        assert_eq!(1, res.resources.len());
+
+        // The response survives a round trip, with the root written as a
+        // single empty label
+        let mut res_buffer = VectorPacketBuffer::new();
+        res.write(&mut res_buffer, 512).unwrap();
+        assert_eq!(&raw[12..], &res_buffer.buffer[12..17]);
+
+        res_buffer.seek(0).unwrap();
+        let res = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+        assert_eq!("", res.questions[0].name);
+        match res.answers[0] {
+            DnsRecord::NS { ref domain, ref host, .. } => {
+                assert_eq!("", domain);
+                assert_eq!("a.root-servers.net", host);
+            },
+            _ => panic!()
+        }
+
+        // Other types for the root are refused
+        let res = execute_query(context.clone(), &build_query("", QueryType::SOA));
+        assert_eq!(ResultCode::REFUSED, res.header.rescode);
+
+        // unless there's a zone for the root
+        {
+            let mut zones = context.authority.write().unwrap();
+            zones.add_zone(Zone::new("".to_string(),
+                                     "a.root-servers.net".to_string(),
+                                     "nstld.verisign-grs.com".to_string())).unwrap();
+        }
+
+        let res = execute_query(context.clone(), &build_query("", QueryType::SOA));
+        assert!(res.header.authoritative_answer);
+        match res.authorities[0] {
+            DnsRecord::SOA { ref domain, .. } => assert_eq!("", domain),
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn test_execute_query_static() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Err(Error::new(ErrorKind::NotFound, "Static answers shouldn't be resolved"))
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.answer_map = AnswerMap::parse(r#"[
+                    {"qname": "test.example.com", "qtype": "A", "answers": [
+                        {"type": "A", "domain": "test.example.com", "host": "192.0.2.7", "ttl": 42}
+                    ]}
+                ]"#).unwrap();
+                ctx.resolve_strategy = ResolveStrategy::Static;
+            },
+            None => panic!()
+        }
+
+        let res = execute_query(context.clone(), &build_query("test.example.com", QueryType::A));
+        assert_eq!(ResultCode::NOERROR, res.header.rescode);
+        assert_eq!(1, res.answers.len());
+
+        match res.answers[0] {
+            DnsRecord::A { ref domain, addr, ttl: TransientTtl(ttl) } => {
+                assert_eq!("test.example.com", domain);
+                assert_eq!("192.0.2.7".parse::<Ipv4Addr>().unwrap(), addr);
+                assert_eq!(42, ttl);
+            },
+            _ => panic!()
+        }
+
+        let res = execute_query(context.clone(), &build_query("www.example.com", QueryType::A));
+        assert_eq!(ResultCode::NXDOMAIN, res.header.rescode);
+    }
+
+    #[test]
+    fn test_execute_query_tunnel() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Ok(DnsPacket::new())
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.tunnel_detector.action = Some(TunnelAction::Refuse);
+            },
+            None => panic!()
+        }
+
+        let res = execute_query(context.clone(),
+                                &build_query("mzxw6ytboi4dqnzyhe2tsmrvgi3dknjqgu4tmnrs.t.example.com", QueryType::TXT));
+        assert_eq!(ResultCode::REFUSED, res.header.rescode);
+        assert_eq!(1, res.questions.len());
+
+        // Ordinary names are resolved as usual
+        let res = execute_query(context.clone(), &build_query("www.example.com", QueryType::A));
+        assert!(res.header.rescode != ResultCode::REFUSED);
+
+        assert_eq!(1, context.tunnel_detector.get_flagged_count());
+    }
+
+    #[test]
+    fn test_execute_and_write() {
+        let context = create_test_context(
+            Box::new(|_, _, _, _| {
+                let mut packet = DnsPacket::new();
+                packet.answers.push(DnsRecord::A {
+                    domain: "google.com".to_string(),
+                    addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+
+                Ok(packet)
+            }));
+
+        assert_eq!(0, context.statistics.response_size.get_count());
+        assert_eq!(0, context.statistics.query_latency.get_count());
+
+        let mut res_buffer = VectorPacketBuffer::new();
+        execute_and_write(context.clone(),
+                          &build_query("google.com", QueryType::A),
+                          Transport::Udp,
+                          "127.0.0.1:1234".parse().unwrap(),
+                          &mut res_buffer,
+                          512).unwrap();
+
+        // Both histograms should have registered the query, with the size
+        // matching the written response
+        assert_eq!(1, context.statistics.response_size.get_count());
+        assert_eq!(res_buffer.pos(), context.statistics.response_size.get_sum());
+        assert_eq!(1, context.statistics.query_latency.get_count());
+    }
+
+    #[test]
+    fn test_edns_response() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Ok(DnsPacket::new())
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.enable_response_cache = true;
+            },
+            None => panic!()
+        }
+
+        {
+            let mut zones = context.authority.write().unwrap();
+            zones.add_zone(Zone::new("example.com".to_string(),
+                                     "ns1.example.com".to_string(),
+                                     "admin.example.com".to_string())).unwrap();
+            zones.add_record("example.com", &DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr: "10.0.0.1".parse::<Ipv4Addr>().unwrap(),
+                ttl: TransientTtl(3600)
+            }).unwrap();
+        }
+
+        let query = |udp_size: Option<u16>| {
+            let mut request = build_query("www.example.com", QueryType::A);
+            if let Some(udp_size) = udp_size {
+                request.add_edns0(udp_size);
+            }
+
+            let mut res_buffer = VectorPacketBuffer::new();
+            execute_and_write(context.clone(),
+                              &request,
+                              Transport::Udp,
+                              "127.0.0.1:1234".parse().unwrap(),
+                              &mut res_buffer,
+                              512).unwrap();
+
+            res_buffer.seek(0).unwrap();
+            DnsPacket::from_buffer(&mut res_buffer).unwrap()
+        };
+
+        // Clients using EDNS get an OPT record advertising our own size, and
+        // the others don't, even once the response is cached
+        for _ in 0..2 {
+            let res = query(Some(1232));
+            assert_eq!(1, res.answers.len());
+            assert_eq!(Some(MAX_UDP_QUERY_SIZE as u16), res.edns_udp_size());
+
+            let res = query(None);
+            assert_eq!(1, res.answers.len());
+            assert_eq!(None, res.edns_udp_size());
+        }
+        assert_eq!(2, context.response_cache.len());
+    }
+
+    #[test]
+    fn test_sortlist() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Ok(DnsPacket::new())
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.enable_response_cache = true;
+                ctx.sortlist.push("10.0.0.0/24".parse().unwrap());
+            },
+            None => panic!()
+        }
+
+        {
+            let mut zones = context.authority.write().unwrap();
+            zones.add_zone(Zone::new("example.com".to_string(),
+                                     "ns1.example.com".to_string(),
+                                     "admin.example.com".to_string())).unwrap();
+
+            // The zone keeps these in order, with the address on the client
+            // network last
+            for addr in &["1.1.1.1", "8.8.8.8", "10.0.0.5", "9.9.9.9"] {
+                zones.add_record("example.com", &DnsRecord::A {
+                    domain: "www.example.com".to_string(),
+                    addr: addr.parse::<Ipv4Addr>().unwrap(),
+                    ttl: TransientTtl(3600)
+                }).unwrap();
+            }
+        }
+
+        let query = |src: &str| {
+            let mut res_buffer = VectorPacketBuffer::new();
+            execute_and_write(context.clone(),
+                              &build_query("www.example.com", QueryType::A),
+                              Transport::Udp,
+                              src.parse().unwrap(),
+                              &mut res_buffer,
+                              512).unwrap();
+
+            res_buffer.seek(0).unwrap();
+            DnsPacket::from_buffer(&mut res_buffer).unwrap().answers.iter()
+                .filter_map(|x| match *x {
+                    DnsRecord::A { addr, .. } => Some(addr.to_string()),
+                    _ => None
+                })
+                .collect::<Vec<String>>()
+        };
+
+        // A client on the network gets the address on the same network first
+        let addrs = query("10.0.0.7:1234");
+        assert_eq!(4, addrs.len());
+        assert_eq!("10.0.0.5", addrs[0]);
+
+        // Other clients get the answer as is
+        let addrs = query("127.0.0.1:1234");
+        assert_eq!(4, addrs.len());
+        assert_eq!("10.0.0.5", addrs[3]);
+
+        // and since the order depends on the client, neither response is
+        // reused for the next one
+        assert_eq!(0, context.response_cache.len());
+
+        let addrs = query("10.0.0.7:1234");
+        assert_eq!("10.0.0.5", addrs[0]);
+    }
+
+    #[test]
+    fn test_pools_mixed_case_query() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Ok(DnsPacket::new())
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.enable_response_cache = true;
+            },
+            None => panic!()
+        }
+
+        {
+            let mut zones = context.authority.write().unwrap();
+
+            let mut zone = Zone::new("example.com".to_string(),
+                                     "ns1.example.com".to_string(),
+                                     "admin.example.com".to_string());
+            for &(pool, addr) in &[("east", "10.0.0.1"), ("west", "10.0.1.1")] {
+                let rec = DnsRecord::A {
+                    domain: "www.example.com".to_string(),
+                    addr: addr.parse::<Ipv4Addr>().unwrap(),
+                    ttl: TransientTtl(3600)
+                };
+                zone.add_record(&rec);
+                zone.assign_pool(pool, &rec);
+            }
+            zones.add_zone(zone).unwrap();
+        }
+
+        // The answer is given the case of the query, and is still narrowed
+        // down to a single pool, which isn't reused for the next query
+        let mut res_buffer = VectorPacketBuffer::new();
+        execute_and_write(context.clone(),
+                          &build_query("WWW.Example.COM", QueryType::A),
+                          Transport::Udp,
+                          "127.0.0.1:1234".parse().unwrap(),
+                          &mut res_buffer,
+                          512).unwrap();
+
+        res_buffer.seek(0).unwrap();
+        let res = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+        assert_eq!(1, res.answers.len());
+        assert_eq!(0, context.response_cache.len());
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Ok(DnsPacket::new())
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.enable_response_cache = true;
+                ctx.round_robin = true;
+            },
+            None => panic!()
+        }
+
+        {
+            let mut zones = context.authority.write().unwrap();
+            zones.add_zone(Zone::new("example.com".to_string(),
+                                     "ns1.example.com".to_string(),
+                                     "admin.example.com".to_string())).unwrap();
+
+            for addr in &["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+                zones.add_record("example.com", &DnsRecord::A {
+                    domain: "www.example.com".to_string(),
+                    addr: addr.parse::<Ipv4Addr>().unwrap(),
+                    ttl: TransientTtl(3600)
+                }).unwrap();
+            }
+        }
+
+        let query = || {
+            let mut res_buffer = VectorPacketBuffer::new();
+            execute_and_write(context.clone(),
+                              &build_query("www.example.com", QueryType::A),
+                              Transport::Udp,
+                              "127.0.0.1:1234".parse().unwrap(),
+                              &mut res_buffer,
+                              512).unwrap();
+
+            res_buffer.seek(0).unwrap();
+            DnsPacket::from_buffer(&mut res_buffer).unwrap().answers.iter()
+                .filter_map(|x| match *x {
+                    DnsRecord::A { addr, .. } => Some(addr.to_string()),
+                    _ => None
+                })
+                .collect::<Vec<String>>()
+        };
+
+        // Every answer holds all of the addresses, with the next one first
+        let answers = vec![query(), query(), query()];
+        for addrs in &answers {
+            assert_eq!(3, addrs.len());
+        }
+        assert_eq!(answers[0][1], answers[1][0]);
+        assert_eq!(answers[1][1], answers[2][0]);
+        assert_eq!(answers[0], query());
+
+        // Rotated answers aren't reused
+        assert_eq!(0, context.response_cache.len());
+    }
+
+    #[test]
+    fn test_apply_sortlist() {
+        let build_a = |addr: &str| DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: addr.parse::<Ipv4Addr>().unwrap(),
+            ttl: TransientTtl(3600)
+        };
+
+        let cname = DnsRecord::CNAME {
+            domain: "example.com".to_string(),
+            host: "www.example.com".to_string(),
+            ttl: TransientTtl(3600)
+        };
+
+        let mut packet = DnsPacket::new();
+        packet.answers.push(cname.clone());
+        packet.answers.push(build_a("192.0.2.1"));
+        packet.answers.push(build_a("10.0.0.5"));
+        packet.answers.push(build_a("198.51.100.1"));
+        packet.answers.push(build_a("10.0.0.6"));
+
+        let sortlist = vec!["192.168.0.0/16".parse::<Network>().unwrap(),
+                            "10.0.0.0/24".parse::<Network>().unwrap()];
+
+        // Clients on none of the networks are left alone
+        let original = packet.answers.clone();
+        assert!(!apply_sortlist(&mut packet, &sortlist, &"127.0.0.1".parse().unwrap()));
+        assert_eq!(original, packet.answers);
+
+        assert!(apply_sortlist(&mut packet, &sortlist, &"10.0.0.7".parse().unwrap()));
+        assert_eq!(vec![cname.clone(),
+                        build_a("10.0.0.5"),
+                        build_a("10.0.0.6"),
+                        build_a("192.0.2.1"),
+                        build_a("198.51.100.1")], packet.answers);
+    }
+
+    /// Answers every address query with the same address
+    struct FixedAddressFilter(Ipv4Addr);
+
+    impl ResponseFilter for FixedAddressFilter {
+        fn apply(&self, query: &DnsPacket, response: &mut DnsPacket, client: SocketAddr) {
+            assert_eq!(1, query.questions.len());
+            assert_eq!("127.0.0.1:1234".parse::<SocketAddr>().unwrap(), client);
+
+            for rec in &mut response.answers {
+                if let DnsRecord::A { ref mut addr, .. } = *rec {
+                    *addr = self.0;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_response_filters() {
+        let mut context = create_test_context(
+            Box::new(|qname, _, _, _| {
+                let mut packet = DnsPacket::new();
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+                packet.answers.push(DnsRecord::AAAA {
+                    domain: qname.to_string(),
+                    addr: "::1".parse::<Ipv6Addr>().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                    host: "127.0.0.1".to_string(),
+                    port: 53
+                };
+                ctx.response_filters.push(Box::new(FixedAddressFilter("10.0.0.1".parse().unwrap())));
+            },
+            None => panic!()
+        }
+
+        let mut res_buffer = VectorPacketBuffer::new();
+        execute_and_write(context.clone(),
+                          &build_query("google.com", QueryType::A),
+                          Transport::Udp,
+                          "127.0.0.1:1234".parse().unwrap(),
+                          &mut res_buffer,
+                          512).unwrap();
+
+        res_buffer.seek(0).unwrap();
+        let res = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+
+        // Only the A record is rewritten
+        assert_eq!(2, res.answers.len());
+        match res.answers[0] {
+            DnsRecord::A { ref domain, addr, .. } => {
+                assert_eq!("google.com", domain);
+                assert_eq!("10.0.0.1".parse::<Ipv4Addr>().unwrap(), addr);
+            },
+            _ => panic!()
+        }
+        match res.answers[1] {
+            DnsRecord::AAAA { addr, .. } => assert_eq!("::1".parse::<Ipv6Addr>().unwrap(), addr),
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn test_any_policy() {
+        let mut context = create_test_context(
+            Box::new(|qname, qtype, _, _| {
+                let mut packet = DnsPacket::new();
+
+                if qname == "google.com" && qtype == QueryType::ANY {
+                    packet.answers.push(DnsRecord::A {
+                        domain: "google.com".to_string(),
+                        addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
+                        ttl: TransientTtl(3600)
+                    });
+                    packet.answers.push(DnsRecord::A {
+                        domain: "google.com".to_string(),
+                        addr: "127.0.0.2".parse::<Ipv4Addr>().unwrap(),
+                        ttl: TransientTtl(3600)
+                    });
+                    packet.answers.push(DnsRecord::MX {
+                        domain: "google.com".to_string(),
+                        priority: 10,
+                        host: "mail.google.com".to_string(),
+                        ttl: TransientTtl(3600)
+                    });
+                } else {
+                    packet.header.rescode = ResultCode::NXDOMAIN;
+                }
+
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        host: "127.0.0.1".to_string(),
+                        port: 53
+                    };
+                ctx.any_policy = AnyPolicy::TruncateUdp;
+            },
+            None => panic!()
+        }
+
+        let query = build_query("google.com", QueryType::ANY);
+
+        // Over UDP, the client should be told to retry over TCP
+        {
+            let mut res_buffer = VectorPacketBuffer::new();
+            execute_and_write(context.clone(), &query, Transport::Udp, "127.0.0.1:1234".parse().unwrap(), &mut res_buffer, 512).unwrap();
+
+            res_buffer.seek(0).unwrap();
+            let res = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+
+            assert!(res.header.truncated_message);
+            assert_eq!(1, res.questions.len());
+            assert_eq!(0, res.answers.len());
+        };
+
+        // While TCP gets the full answer
+        {
+            let mut res_buffer = VectorPacketBuffer::new();
+            execute_and_write(context.clone(), &query, Transport::Tcp, "127.0.0.1:1234".parse().unwrap(), &mut res_buffer, 0xFFFF).unwrap();
+
+            res_buffer.seek(0).unwrap();
+            let res = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+
+            assert!(!res.header.truncated_message);
+            assert_eq!(3, res.answers.len());
+        };
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.any_policy = AnyPolicy::Minimal;
+            },
+            None => panic!()
+        }
+
+        // The minimal policy answers UDP queries with a single record set
+        {
+            let res = execute_query_with_policy(context.clone(), &query, Transport::Udp);
+            assert!(!res.header.truncated_message);
+            assert_eq!(2, res.answers.len());
+            assert_eq!(QueryType::A, res.answers[0].get_querytype());
+            assert_eq!(QueryType::A, res.answers[1].get_querytype());
+
+            let res = execute_query_with_policy(context.clone(), &query, Transport::Tcp);
+            assert_eq!(3, res.answers.len());
+        };
+    }
+
+    #[test]
+    fn test_whoami() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                let mut packet = DnsPacket::new();
+                packet.header.rescode = ResultCode::NXDOMAIN;
+                Ok(packet)
+            }));
+
+        let src = "192.168.1.10:4321".parse::<SocketAddr>().unwrap();
+        let query = build_query("whoami.hermes", QueryType::TXT);
+
+        // Disabled by default, so the query is resolved as usual
+        assert!(execute_whoami(&context, &query, Transport::Udp, src).is_none());
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.whoami_name = Some("whoami.hermes".to_string());
+            },
+            None => panic!()
+        }
+
+        // The client address is returned over UDP
+        {
+            let mut res_buffer = VectorPacketBuffer::new();
+            execute_and_write(context.clone(), &query, Transport::Udp, src, &mut res_buffer, 512).unwrap();
+
+            res_buffer.seek(0).unwrap();
+            let res = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+
+            assert_eq!(ResultCode::NOERROR, res.header.rescode);
+            assert_eq!(2, res.answers.len());
+            match res.answers[0] {
+                DnsRecord::TXT { ref domain, ref data, .. } => {
+                    assert_eq!("whoami.hermes", domain);
+                    assert_eq!(&vec!["192.168.1.10".to_string()], data);
+                },
+                _ => panic!()
+            }
+            match res.answers[1] {
+                DnsRecord::TXT { ref data, .. } => assert_eq!(&vec!["port=4321".to_string()], data),
+                _ => panic!()
+            }
+        };
+
+        // TCP includes the transport as well
+        {
+            let res = execute_whoami(&context, &query, Transport::Tcp, src).unwrap();
+            assert_eq!(3, res.answers.len());
+            match res.answers[2] {
+                DnsRecord::TXT { ref data, .. } => assert_eq!(&vec!["transport=tcp".to_string()], data),
+                _ => panic!()
+            }
+        };
+
+        // The name is matched case insensitively
+        {
+            let res = execute_whoami(&context,
+                                     &build_query("WHOAMI.hermes", QueryType::TXT),
+                                     Transport::Udp,
+                                     src).unwrap();
+            assert_eq!(2, res.answers.len());
+        };
+
+        // Other types get an empty answer
+        {
+            let res = execute_whoami(&context,
+                                     &build_query("whoami.hermes", QueryType::A),
+                                     Transport::Udp,
+                                     src).unwrap();
+            assert_eq!(ResultCode::NOERROR, res.header.rescode);
+            assert_eq!(0, res.answers.len());
+        };
+
+        // And other names are unaffected
+        assert!(execute_whoami(&context,
+                               &build_query("google.com", QueryType::TXT),
+                               Transport::Udp,
+                               src).is_none());
+    }
+
+    #[test]
+    fn test_response_cache() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                let mut packet = DnsPacket::new();
+                packet.header.rescode = ResultCode::NXDOMAIN;
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.enable_response_cache = true;
+            },
+            None => panic!()
+        }
+
+        {
+            let mut zones = context.authority.write().unwrap();
+            zones.add_zone(Zone::new("example.com".to_string(),
+                                     "ns1.example.com".to_string(),
+                                     "admin.example.com".to_string())).unwrap();
+            zones.add_record("example.com", &DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
+                ttl: TransientTtl(3600)
+            }).unwrap();
+        }
+
+        let src = "127.0.0.1:1234".parse::<SocketAddr>().unwrap();
+
+        let query = |id: u16| {
+            let mut query = build_query("www.example.com", QueryType::A);
+            query.header.id = id;
+
+            let mut res_buffer = VectorPacketBuffer::new();
+            execute_and_write(context.clone(), &query, Transport::Udp, src, &mut res_buffer, 512).unwrap();
+
+            res_buffer.seek(0).unwrap();
+            DnsPacket::from_buffer(&mut res_buffer).unwrap()
+        };
+
+        let res = query(1);
+        assert!(res.header.authoritative_answer);
+        assert_eq!(1, context.response_cache.len());
+
+        // A hit has the id of the new query, but is otherwise the same
+        let res = query(2);
+        assert_eq!(2, res.header.id);
+        assert_eq!(1, res.answers.len());
+        match res.answers[0] {
+            DnsRecord::A { addr, .. } => assert_eq!("127.0.0.1".parse::<Ipv4Addr>().unwrap(), addr),
+            _ => panic!()
+        }
+
+        // Editing the zone invalidates the cached response
+        {
+            let mut zones = context.authority.write().unwrap();
+            let zone = zones.get_zone_mut("example.com").unwrap();
+            zone.delete_record(&DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
+                ttl: TransientTtl(3600)
+            });
+            zone.add_record(&DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr: "127.0.0.2".parse::<Ipv4Addr>().unwrap(),
+                ttl: TransientTtl(3600)
+            });
+            zone.serial += 1;
+        }
+
+        let res = query(3);
+        assert_eq!(3, res.header.id);
+        assert_eq!(1, res.answers.len());
+        match res.answers[0] {
+            DnsRecord::A { addr, .. } => assert_eq!("127.0.0.2".parse::<Ipv4Addr>().unwrap(), addr),
+            _ => panic!()
+        }
+
+        // Queries with malformed EDNS are still refused once a query with
+        // EDNS is cached
+        let opt = DnsRecord::OPT {
+            packet_len: 4096,
+            flags: 0,
+            options: Vec::new()
+        };
+
+        let edns_query = |extra_opt: bool| {
+            let mut query = build_query("www.example.com", QueryType::A);
+            query.resources.push(opt.clone());
+            if extra_opt {
+                query.resources.push(opt.clone());
+            }
+
+            let mut res_buffer = VectorPacketBuffer::new();
+            execute_and_write(context.clone(), &query, Transport::Udp, src, &mut res_buffer, 512).unwrap();
+
+            res_buffer.seek(0).unwrap();
+            DnsPacket::from_buffer(&mut res_buffer).unwrap()
+        };
+
+        assert_eq!(ResultCode::NOERROR, edns_query(false).header.rescode);
+        assert_eq!(2, context.response_cache.len());
+
+        let res = edns_query(true);
+        assert_eq!(ResultCode::FORMERR, res.header.rescode);
+        assert!(res.answers.is_empty());
+        assert_eq!(2, context.response_cache.len());
+
+        // Non-authoritative responses are never cached
+        {
+            let mut res_buffer = VectorPacketBuffer::new();
+            execute_and_write(context.clone(),
+                              &build_query("www.google.com", QueryType::A),
+                              Transport::Udp,
+                              src,
+                              &mut res_buffer,
+                              512).unwrap();
+        };
+        assert_eq!(2, context.response_cache.len());
+    }
+
+    #[test]
+    fn test_parse_udp_query() {
+        let mut query = build_query("www.google.com", QueryType::A);
+        query.header.id = 0x1234;
+
+        let mut req_buffer = VectorPacketBuffer::new();
+        query.write(&mut req_buffer, 512).unwrap();
+        let len = req_buffer.pos;
+
+        let mut buf = [0; MAX_UDP_QUERY_SIZE + 1];
+        buf[0..len].copy_from_slice(&req_buffer.buffer[0..len]);
+
+        match parse_udp_query(&buf, len) {
+            UdpQuery::Query(request) => assert_eq!("www.google.com", request.questions[0].name),
+            _ => panic!()
+        }
+
+        // A datagram cut short in the middle of the question is FORMERR
+        match parse_udp_query(&buf, len - 3) {
+            UdpQuery::Malformed(response) => {
+                assert_eq!(0x1234, response.header.id);
+                assert_eq!(ResultCode::FORMERR, response.header.rescode);
+            },
+            _ => panic!()
+        }
+
+        // As is an oversized datagram filling the whole buffer, even though
+        // what was read of it would parse
+        match parse_udp_query(&buf, buf.len()) {
+            UdpQuery::Malformed(response) => {
+                assert_eq!(0x1234, response.header.id);
+                assert!(response.header.response);
+                assert_eq!(ResultCode::FORMERR, response.header.rescode);
+                assert!(response.questions.is_empty());
+            },
+            _ => panic!()
+        }
+
+        // Datagrams without a full header are dropped
+        match parse_udp_query(&buf, 4) {
+            UdpQuery::Dropped => {},
+            _ => panic!()
+        }
+
+        // and responses are told apart, to be counted
+        buf[2] |= 0x80;
+        match parse_udp_query(&buf, len) {
+            UdpQuery::Response => {},
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn test_preserve_query_case() {
+        let mut context = create_test_context(
+            Box::new(|qname, _, _, _| {
+                let mut packet = DnsPacket::new();
+
+                if qname == "www.google.com" {
+                    packet.answers.push(DnsRecord::CNAME {
+                        domain: "www.google.com".to_string(),
+                        host: "google.com".to_string(),
+                        ttl: TransientTtl(3600)
+                    });
+                    packet.answers.push(DnsRecord::A {
+                        domain: "google.com".to_string(),
+                        addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
+                        ttl: TransientTtl(3600)
+                    });
+                } else {
+                    packet.header.rescode = ResultCode::NXDOMAIN;
+                }
+
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        host: "127.0.0.1".to_string(),
+                        port: 53
+                    };
+            },
+            None => panic!()
+        }
+
+        // Serialize and parse the query, as it would arrive over the network
+        let mut req_buffer = VectorPacketBuffer::new();
+        build_query("wWw.GooGle.cOm", QueryType::A).write(&mut req_buffer, 512).unwrap();
+        req_buffer.seek(0).unwrap();
+        let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+        assert_eq!("wWw.GooGle.cOm", request.questions[0].name);
+
+        let res = execute_query(context.clone(), &request);
+
+        assert_eq!(ResultCode::NOERROR, res.header.rescode);
+        assert_eq!("wWw.GooGle.cOm", res.questions[0].name);
+        assert_eq!(2, res.answers.len());
+
+        // The owner name matching the question has the case of the query,
+        // while other names are left alone
+        assert_eq!(Some("wWw.GooGle.cOm".to_string()), res.answers[0].get_domain());
+        assert_eq!(Some("google.com".to_string()), res.answers[1].get_domain());
+    }
+
+    fn run_tcp_handler(context: Arc<ServerContext>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_tcp_connection(context, stream);
+        });
+
+        addr
+    }
+
+    fn tcp_query(stream: &mut TcpStream, query: &mut DnsPacket) -> DnsPacket {
+        let mut req_buffer = VectorPacketBuffer::new();
+        query.write(&mut req_buffer, 0xFFFF).unwrap();
+
+        let len = req_buffer.pos();
+        write_packet_length(stream, len).unwrap();
+        stream.write(req_buffer.get_range(0, len).unwrap()).unwrap();
+
+        let len = read_packet_length(stream).unwrap() as usize;
+        let mut res_buffer = VectorPacketBuffer::new();
+        res_buffer.buffer.resize(len, 0);
+        stream.read_exact(&mut res_buffer.buffer).unwrap();
+
+        DnsPacket::from_buffer(&mut res_buffer).unwrap()
+    }
+
+    #[test]
+    fn test_zone_transfer_acl() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Err(Error::new(ErrorKind::NotFound, "Fail"))
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                let mut zones = ctx.authority.write().unwrap();
+
+                let mut zone = Zone::new("example.com".to_string(), "ns1.example.com".to_string(), "admin.example.com".to_string());
+                zone.serial = 7;
+                zone.allow_transfer = Some(vec!["127.0.0.0/8".parse().unwrap()]);
+                zone.add_record(&DnsRecord::A {
+                    domain: "www.example.com".to_string(),
+                    addr: "127.0.0.1".parse().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+                zones.add_zone(zone).unwrap();
+
+                let mut zone = Zone::new("internal.com".to_string(), "ns1.internal.com".to_string(), "admin.internal.com".to_string());
+                zone.allow_transfer = Some(vec!["192.0.2.0/24".parse().unwrap()]);
+                zones.add_zone(zone).unwrap();
+
+                // Allowed by the server wide list, but overridden by the zone
+                ctx.allow_transfer = vec!["127.0.0.1".parse().unwrap()];
+            },
+            None => panic!()
+        }
+
+        // A client on the zone's list gets the transfer
+        let addr = run_tcp_handler(context.clone());
+        let records = fetch_zone("example.com", &addr, None, DEFAULT_MAX_RECORDS_PER_ZONE).unwrap();
+        assert_eq!(2, records.len());
+        match records[0] {
+            DnsRecord::SOA { ref domain, serial, .. } => {
+                assert_eq!("example.com", domain);
+                assert_eq!(7, serial);
+            },
+            _ => panic!()
+        }
+        assert_eq!(QueryType::A, records[1].get_querytype());
+
+        // Anyone else is refused
+        let addr = run_tcp_handler(context.clone());
+        match fetch_zone("internal.com", &addr, None, DEFAULT_MAX_RECORDS_PER_ZONE) {
+            Ok(_) => panic!(),
+            Err(e) => assert!(e.to_string().contains("REFUSED"))
+        }
+    }
+
+    #[test]
+    fn test_zone_transfer_loopback() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Err(Error::new(ErrorKind::NotFound, "Fail"))
+            }));
+
+        let mut zone = Zone::new("example.com".to_string(), "ns1.example.com".to_string(), "admin.example.com".to_string());
+        zone.serial = 42;
+        for i in 0..500 {
+            zone.add_record(&DnsRecord::A {
+                domain: format!("host{}.example.com", i),
+                addr: Ipv4Addr::new(10, 0, (i / 256) as u8, (i % 256) as u8),
+                ttl: TransientTtl(3600)
+            });
+        }
+        zone.add_record(&DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: TransientTtl(3600)
+        });
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.authority.write().unwrap().add_zone(zone.clone()).unwrap();
+                ctx.allow_transfer = vec!["127.0.0.1".parse().unwrap()];
+            },
+            None => panic!()
+        }
+
+        // The records span several messages, which are read back and used to
+        // rebuild the zone
+        let addr = run_tcp_handler(context);
+        let mut records = fetch_zone("example.com", &addr, None, DEFAULT_MAX_RECORDS_PER_ZONE).unwrap();
+        let mut expected = zone.transfer_records();
+        expected.pop();
+        assert_eq!(expected, records);
+
+        let mut copy = match records.remove(0) {
+            DnsRecord::SOA { ref domain, ref m_name, ref r_name, serial, .. } => {
+                let mut copy = Zone::new(domain.clone(), m_name.clone(), r_name.clone());
+                copy.serial = serial;
+                copy
+            },
+            _ => panic!()
+        };
+        for rec in &records {
+            copy.add_record(rec);
+        }
+
+        assert_eq!(42, copy.serial);
+        assert_eq!(zone.sorted_records(), copy.sorted_records());
+    }
+
+    #[test]
+    fn test_drop_responses() {
+        let context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Ok(DnsPacket::new())
+            }));
+
+        let addr = run_tcp_handler(context.clone());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // A packet with the QR bit set gets no response, so the first
+        // response read is the one for the following query
+        let mut response = build_query("www.google.com", QueryType::A);
+        response.header.id = 1;
+        response.header.response = true;
+
+        let mut req_buffer = VectorPacketBuffer::new();
+        response.write(&mut req_buffer, 0xFFFF).unwrap();
+        let len = req_buffer.pos();
+        write_packet_length(&mut stream, len).unwrap();
+        stream.write(req_buffer.get_range(0, len).unwrap()).unwrap();
+
+        let mut query = build_query("www.google.com", QueryType::A);
+        query.header.id = 2;
+        let res = tcp_query(&mut stream, &mut query);
+
+        assert_eq!(2, res.header.id);
+        assert_eq!(1, context.statistics.get_dropped_response_count());
+    }
+
+    #[test]
+    fn test_rate_limited_response() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Ok(DnsPacket::new())
+            }));
+
+        let query = build_query("www.google.com", QueryType::A);
+
+        // By default, clients over their limit get nothing at all
+        assert!(rate_limited_response(&context, &query).is_none());
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.rate_limiter.action = RateLimitAction::Truncate;
+            },
+            None => panic!()
+        }
+
+        let res = rate_limited_response(&context, &query).unwrap();
+        assert_eq!(query.header.id, res.header.id);
+        assert!(res.header.truncated_message);
+        assert_eq!(query.questions, res.questions);
+        assert!(res.answers.is_empty());
+    }
+
+    #[test]
+    fn test_tcp_idle_timeout() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Ok(DnsPacket::new())
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.tcp_idle_timeout = Duration::from_millis(200);
+            },
+            None => panic!()
+        }
+
+        let addr = run_tcp_handler(context);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // The server closes the connection once the timeout has passed,
+        // rather than our read timing out
+        let start = Instant::now();
+        let mut buf = [0; 2];
+        assert_eq!(0, stream.read(&mut buf).unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_tcp_keepalive() {
+        let mut context = create_test_context(
+            Box::new(|qname, _, _, _| {
+                let mut packet = DnsPacket::new();
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        host: "127.0.0.1".to_string(),
+                        port: 53
+                    };
+                ctx.tcp_idle_timeout = Duration::from_millis(100);
+                ctx.tcp_keepalive_timeout = Duration::from_secs(25);
+            },
+            None => panic!()
+        }
+
+        let addr = run_tcp_handler(context);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // Without the option, no OPT record is added to the response
+        let mut query = build_query("google.com", QueryType::A);
+        let res = tcp_query(&mut stream, &mut query);
+        assert_eq!(1, res.answers.len());
+        assert!(res.get_edns_option(EDNS_TCP_KEEPALIVE).is_none());
+
+        // With the option, the timeout is announced in units of 100ms
+        let mut query = build_query("yahoo.com", QueryType::A);
+        query.resources.push(DnsRecord::OPT {
+            packet_len: 4096,
+            flags: 0,
+            options: vec![EdnsOption { code: EDNS_TCP_KEEPALIVE, data: Vec::new() }]
+        });
+        let res = tcp_query(&mut stream, &mut query);
+        assert_eq!(1, res.answers.len());
+        assert_eq!(Some(&EdnsOption { code: EDNS_TCP_KEEPALIVE, data: vec![0x00, 0xFA] }),
+                   res.get_edns_option(EDNS_TCP_KEEPALIVE));
+
+        // and the connection is kept open past the regular idle timeout
+        sleep(Duration::from_millis(300));
+        let mut query = build_query("bing.com", QueryType::A);
+        let res = tcp_query(&mut stream, &mut query);
+        assert_eq!(1, res.answers.len());
+    }
+
+    #[test]
+    fn test_tcp_max_lifetime() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Ok(DnsPacket::new())
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        host: "127.0.0.1".to_string(),
+                        port: 53
+                    };
+                ctx.tcp_idle_timeout = Duration::from_secs(5);
+                ctx.tcp_max_lifetime = Duration::from_millis(300);
+            },
+            None => panic!()
+        }
+
+        let addr = run_tcp_handler(context.clone());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // Queries are answered while the connection is young
+        let mut query = build_query("google.com", QueryType::A);
+        let _ = tcp_query(&mut stream, &mut query);
+
+        // but it's closed once its lifetime is up, despite the idle timeout
+        let start = Instant::now();
+        let mut buf = [0; 2];
+        assert_eq!(0, stream.read(&mut buf).unwrap());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        sleep(Duration::from_millis(50));
+        assert_eq!(0, context.tcp_connections.count());
+    }
+
+    #[test]
+    fn test_tcp_truncated_query() {
+        let context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Ok(DnsPacket::new())
+            }));
+
+        let addr = run_tcp_handler(context);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // A client announcing a longer query than it sends is disconnected,
+        // rather than answered based on a zero filled buffer
+        write_packet_length(&mut stream, 100).unwrap();
+        stream.write_all(&[0; 10]).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut buf = Vec::new();
+        assert_eq!(0, stream.read_to_end(&mut buf).unwrap());
+    }
+
+    fn udp_query(socket: &UdpSocket, addr: SocketAddr, query: &mut DnsPacket) -> DnsPacket {
+        let mut req_buffer = VectorPacketBuffer::new();
+        query.write(&mut req_buffer, 512).unwrap();
+        socket.send_to(req_buffer.get_range(0, req_buffer.pos()).unwrap(), addr).unwrap();
+
+        let mut res_buffer = VectorPacketBuffer::new();
+        res_buffer.buffer.resize(512, 0);
+        let _ = socket.recv_from(&mut res_buffer.buffer).unwrap();
+
+        DnsPacket::from_buffer(&mut res_buffer).unwrap()
+    }
+
+    #[test]
+    fn test_udp_concurrent_queries() {
+        let mut context = create_test_context(
+            Box::new(|qname, _, _, _| {
+                // Every lookup is slow, as if the upstream server was far away
+                sleep(Duration::from_millis(300));
+
+                let mut packet = DnsPacket::new();
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
+                    ttl: TransientTtl(3600)
+                });
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                        host: "127.0.0.1".to_string(),
+                        port: 53
+                    };
+            },
+            None => panic!()
+        }
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        DnsUdpServer::new(context, 4).serve(socket).unwrap();
+
+        let start = Instant::now();
+
+        let clients: Vec<_> = ["google.com", "yahoo.com", "bing.com", "facebook.com"].iter()
+            .map(|qname| spawn(move || {
+                let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+                socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+                let mut query = build_query(qname, QueryType::A);
+                udp_query(&socket, addr, &mut query)
+            }))
+            .collect();
+
+        for client in clients {
+            let res = client.join().unwrap();
+            assert_eq!(ResultCode::NOERROR, res.header.rescode);
+            assert_eq!(1, res.answers.len());
+        }
+
+        // Serviced one after another, the lookups would take 1200ms
+        assert!(start.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_udp_queue_saturated() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Ok(DnsPacket::new())
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.max_udp_queue = 2;
+            },
+            None => panic!()
+        }
+
+        // Without any worker threads, nothing is taken off the queue
+        let server = DnsUdpServer::new(context, 0);
+        let src = "127.0.0.1:53".parse::<SocketAddr>().unwrap();
+
+        assert!(server.enqueue(src, build_query("google.com", QueryType::A)).is_none());
+        assert!(server.enqueue(src, build_query("yahoo.com", QueryType::A)).is_none());
+
+        let mut query = build_query("bing.com", QueryType::A);
+        query.header.id = 1234;
+
+        let res = server.enqueue(src, query).unwrap();
+        assert_eq!(1234, res.header.id);
+        assert!(res.header.response);
+        assert_eq!(ResultCode::SERVFAIL, res.header.rescode);
+        assert_eq!(1, res.questions.len());
+        assert_eq!(2, server.request_queue.lock().unwrap().len());
+    }
+}
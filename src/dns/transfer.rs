@@ -0,0 +1,848 @@
+//! zone transfers, both from a primary server for secondary zones, and to
+//! other servers
+
+use std::collections::BTreeSet;
+use std::io::{Result, Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use chrono::UTC;
+use rand::random;
+
+use dns::authority::{Authority, Zone};
+use dns::context::ServerContext;
+use dns::buffer::{PacketBuffer, VectorPacketBuffer};
+use dns::netutil::{read_packet_length, write_packet_length};
+use dns::protocol::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode};
+use dns::tsig::{verify_request, TsigKey, TsigSigner, TsigStatus};
+
+/// Timeout for reading from, and writing to, the primary
+const TRANSFER_TIMEOUT_SECS: u64 = 10;
+
+/// Upper bound on the number of messages in a single transfer
+const MAX_TRANSFER_MESSAGES: usize = 10_000;
+
+/// Size in bytes beyond which an outgoing transfer continues in a new message
+const TRANSFER_MESSAGE_SIZE: usize = 16_384;
+
+/// The outcome of a zone transfer
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct TransferResult {
+    pub serial_before: u32,
+    pub serial_after: u32,
+    pub added: usize,
+    pub removed: usize
+}
+
+/// Fetch the complete contents of `zone` from `primary` using AXFR
+///
+/// The first record returned is the SOA of the zone, and the transfer ends
+/// with a repeat of the same SOA, which isn't included in the result. With a
+/// `key`, the request is signed using TSIG, and every message of the
+/// response has to carry a valid signature made with the same key.
+///
+/// The transfer is abandoned with `InvalidData` as soon as the primary has
+/// sent more than `max_records` records besides the SOA.
+pub fn fetch_zone(zone: &str,
+                  primary: &SocketAddr,
+                  key: Option<&TsigKey>,
+                  max_records: usize) -> Result<Vec<DnsRecord>> {
+    let timeout = Duration::from_secs(TRANSFER_TIMEOUT_SECS);
+
+    let mut stream = try!(TcpStream::connect(primary));
+    try!(stream.set_read_timeout(Some(timeout)));
+    try!(stream.set_write_timeout(Some(timeout)));
+
+    // A random id makes it harder to inject responses into the transfer
+    let mut request = DnsPacket::new();
+    request.header.id = random::<u16>();
+    request.questions.push(DnsQuestion::new(zone.to_string(), QueryType::AXFR));
+
+    let mut req_buffer = VectorPacketBuffer::new();
+    try!(request.write(&mut req_buffer, 0xFFFF));
+
+    let mut signer = key.map(|x| TsigSigner::new(x.clone(), UTC::now().timestamp() as u64));
+    if let Some(ref mut signer) = signer {
+        try!(signer.sign(&mut req_buffer));
+    }
+
+    let len = req_buffer.pos();
+    try!(write_packet_length(&mut stream, len));
+    try!(stream.write_all(try!(req_buffer.get_range(0, len))));
+    try!(stream.flush());
+
+    let mut records = Vec::new();
+    for _ in 0..MAX_TRANSFER_MESSAGES {
+        let len = try!(read_packet_length(&mut stream)) as usize;
+
+        let mut res_buffer = VectorPacketBuffer::new();
+        res_buffer.buffer.resize(len, 0);
+        try!(stream.read_exact(&mut res_buffer.buffer));
+
+        if let Some(ref mut signer) = signer {
+            if !signer.verify(&res_buffer.buffer) {
+                return Err(Error::new(ErrorKind::InvalidData, "Transfer response failed TSIG verification"));
+            }
+        }
+
+        let response = try!(DnsPacket::from_buffer(&mut res_buffer));
+        if response.header.id != request.header.id {
+            return Err(Error::new(ErrorKind::InvalidData, "Mismatched transfer response id"));
+        }
+        if response.header.rescode != ResultCode::NOERROR {
+            return Err(Error::new(ErrorKind::Other,
+                                  format!("Transfer refused with {:?}", response.header.rescode)));
+        }
+
+        for rec in response.answers {
+            let is_soa = rec.get_querytype() == QueryType::SOA;
+
+            if records.is_empty() && !is_soa {
+                return Err(Error::new(ErrorKind::InvalidData, "Transfer didn't start with a SOA record"));
+            }
+
+            // The closing SOA marks the end of the transfer
+            if is_soa && !records.is_empty() {
+                return Ok(records);
+            }
+
+            // The SOA at the start doesn't count against the limit
+            if records.len() > max_records {
+                return Err(Error::new(ErrorKind::InvalidData,
+                                      format!("Transfer exceeds the limit of {} records for zone {}",
+                                              max_records, zone)));
+            }
+
+            records.push(rec);
+        }
+    }
+
+    Err(Error::new(ErrorKind::InvalidData, "Transfer didn't complete"))
+}
+
+/// Transfer the secondary zone `domain` from its primary, replacing the
+/// current contents
+///
+/// Fails with `NotFound` if there's no such zone, and with `InvalidInput` if
+/// the zone isn't a secondary. The zone is left as is if the primary sends
+/// more than `max_records_per_zone` records, or records outside of the zone,
+/// which fails with `InvalidData`.
+pub fn transfer_zone(authority: &Authority, domain: &str) -> Result<TransferResult> {
+    let (primary, key, max_records) = {
+        let zones = match authority.read() {
+            Ok(x) => x,
+            Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+        };
+
+        let zone = match zones.get_zone(domain) {
+            Some(x) => x,
+            None => return Err(Error::new(ErrorKind::NotFound, "Zone not found"))
+        };
+
+        match zone.primary {
+            Some(x) => (x, zone.primary_key.clone(), zones.max_records_per_zone),
+            None => return Err(Error::new(ErrorKind::InvalidInput, "Zone isn't a secondary"))
+        }
+    };
+
+    // The lock isn't held while talking to the primary
+    let mut records = try!(fetch_zone(domain, &primary, key.as_ref(), max_records));
+    let soa = records.remove(0);
+
+    let mut zones = match authority.write() {
+        Ok(x) => x,
+        Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to acquire lock"))
+    };
+
+    let zone = match zones.get_zone_mut(domain) {
+        Some(x) => x,
+        None => return Err(Error::new(ErrorKind::NotFound, "Zone not found"))
+    };
+
+    // Records for other names would be served as if we were authoritative
+    // for them
+    let outside = Some(&soa).into_iter().chain(records.iter())
+        .filter_map(|x| x.get_domain())
+        .find(|x| !zone.contains_name(x));
+    if let Some(name) = outside {
+        return Err(Error::new(ErrorKind::InvalidData,
+                              format!("Transfer contains {}, which is outside of zone {}", name, domain)));
+    }
+
+    let serial_before = zone.serial;
+
+    if let DnsRecord::SOA { ref m_name, ref r_name, serial, refresh, retry, expire, minimum, .. } = soa {
+        zone.m_name = m_name.clone();
+        zone.r_name = r_name.clone();
+        zone.serial = serial;
+        zone.refresh = refresh;
+        zone.retry = retry;
+        zone.expire = expire;
+        zone.minimum = minimum;
+    }
+
+    let new_records = records.into_iter().collect::<BTreeSet<DnsRecord>>();
+    let added = new_records.difference(&zone.records).cloned().collect::<Vec<DnsRecord>>();
+    let removed = zone.records.difference(&new_records).cloned().collect::<Vec<DnsRecord>>();
+    let (added_count, removed_count) = (added.len(), removed.len());
+    zone.records = new_records;
+
+    // Keep the journal going, so that the zone can be transferred on
+    // incrementally in turn
+    if zone.serial != serial_before {
+        zone.journal_change(serial_before, removed, added);
+    } else if added_count > 0 || removed_count > 0 {
+        zone.journal.clear();
+    }
+
+    Ok(TransferResult {
+        serial_before: serial_before,
+        serial_after: zone.serial,
+        added: added_count,
+        removed: removed_count
+    })
+}
+
+/// The serial that the client of an IXFR `request` has, given by the SOA in
+/// its authority section
+fn requested_serial(request: &DnsPacket) -> Option<u32> {
+    request.authorities.iter()
+        .filter_map(|x| match *x {
+            DnsRecord::SOA { serial, .. } => Some(serial),
+            _ => None
+        })
+        .next()
+}
+
+/// Build the messages answering the AXFR or IXFR `request` from `client`
+///
+/// The records of the zone are preceded and followed by its SOA, and split
+/// across as many messages as needed. IXFR requests get only the changes
+/// since the serial of the client, when the journal of the zone goes back
+/// that far, and the whole zone otherwise. Requests for zones that we aren't
+/// authoritative for, or from clients that aren't allowed to transfer the
+/// zone, get a single REFUSED response. Requests signed with the TSIG `key`
+/// may also transfer the zones that allow the key, from any network.
+pub fn serve_transfer(context: &ServerContext,
+                      request: &DnsPacket,
+                      client: &IpAddr,
+                      key: Option<&str>) -> Vec<DnsPacket> {
+    let mut response = DnsPacket::new();
+    response.header.id = request.header.id;
+    response.header.response = true;
+    response.header.authoritative_answer = true;
+    response.questions = request.questions.clone();
+
+    let (qname, qtype) = match request.questions.first() {
+        Some(x) => (x.name.to_lowercase(), x.qtype),
+        None => {
+            response.header.rescode = ResultCode::FORMERR;
+            return vec![response];
+        }
+    };
+
+    let zones = match context.authority.read() {
+        Ok(x) => x,
+        Err(_) => {
+            response.header.rescode = ResultCode::SERVFAIL;
+            return vec![response];
+        }
+    };
+
+    let is_allowed = |zone: &Zone| {
+        key.map_or(false, |x| zone.is_transfer_key_allowed(x, &context.allow_transfer_keys)) ||
+            zone.is_transfer_allowed(client, &context.allow_transfer)
+    };
+
+    let zone = match zones.get_zone(&qname) {
+        Some(x) if is_allowed(x) => x,
+        _ => {
+            response.header.rescode = ResultCode::REFUSED;
+            return vec![response];
+        }
+    };
+
+    let records = match qtype {
+        QueryType::IXFR => requested_serial(request)
+            .and_then(|x| zone.incremental_transfer_records(x))
+            .unwrap_or_else(|| zone.transfer_records()),
+        _ => zone.transfer_records()
+    };
+
+    let mut messages = Vec::new();
+    let mut size = 0;
+    for rec in records {
+        let mut buffer = VectorPacketBuffer::new();
+        let len = rec.write(&mut buffer).unwrap_or(0);
+
+        if !response.answers.is_empty() && size + len > TRANSFER_MESSAGE_SIZE {
+            let mut next = DnsPacket::new();
+            next.header.id = response.header.id;
+            next.header.response = true;
+            next.header.authoritative_answer = true;
+
+            messages.push(response);
+            response = next;
+            size = 0;
+        }
+
+        size += len;
+        response.answers.push(rec);
+    }
+    messages.push(response);
+
+    messages
+}
+
+/// Answer the zone transfer `request` from `client`, returning the encoded
+/// messages
+///
+/// `raw` is the request as received, which is checked for a TSIG signature
+/// made with one of our keys, at the time `now`. Responses to signed
+/// requests are signed in turn, and requests with a signature that doesn't
+/// check out get a NOTAUTH response holding the reason. That response is
+/// only signed when the signature was made at the wrong time.
+pub fn encode_transfer(context: &ServerContext,
+                       request: &DnsPacket,
+                       raw: &[u8],
+                       client: &IpAddr,
+                       now: u64) -> Result<Vec<Vec<u8>>> {
+    let error_response = |rescode: ResultCode| {
+        let mut response = DnsPacket::new();
+        response.header.id = request.header.id;
+        response.header.response = true;
+        response.header.rescode = rescode;
+        response.questions = request.questions.clone();
+        response
+    };
+
+    let (messages, mut signer) = match verify_request(&context.tsig_keys, raw, now) {
+        TsigStatus::Unsigned => (serve_transfer(context, request, client, None), None),
+        TsigStatus::Signed(signer) => {
+            let messages = serve_transfer(context, request, client, Some(&signer.key.name));
+            (messages, Some(signer))
+        },
+        TsigStatus::Failed(tsig) => {
+            let mut response = error_response(ResultCode::NOTAUTH);
+            response.resources.push(tsig);
+
+            (vec![response], None)
+        },
+        TsigStatus::BadTime(signer) => (vec![error_response(ResultCode::NOTAUTH)], Some(signer)),
+        TsigStatus::Malformed => (vec![error_response(ResultCode::FORMERR)], None)
+    };
+
+    let mut result = Vec::new();
+    for mut message in messages {
+        let mut buffer = VectorPacketBuffer::new();
+        try!(message.write(&mut buffer, 0xFFFF));
+
+        if let Some(ref mut signer) = signer {
+            try!(signer.sign(&mut buffer));
+        }
+
+        let len = buffer.pos();
+        result.push(try!(buffer.get_range(0, len)).to_vec());
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    use dns::authority::DEFAULT_MAX_RECORDS_PER_ZONE;
+    use dns::context::tests::create_test_context;
+    use dns::protocol::TransientTtl;
+    use dns::server::handle_tcp_connection;
+    use dns::tsig::{BADSIG, BADTIME};
+
+    use super::*;
+
+    fn build_a(domain: &str, addr: &str) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            addr: addr.parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }
+    }
+
+    fn build_soa(domain: &str, serial: u32) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: domain.to_string(),
+            m_name: "ns1.example.com".to_string(),
+            r_name: "admin.example.com".to_string(),
+            serial: serial,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: TransientTtl(3600)
+        }
+    }
+
+    /// Serve a single AXFR request, split across the given messages
+    fn run_mock_primary(messages: Vec<Vec<DnsRecord>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let len = read_packet_length(&mut stream).unwrap() as usize;
+            let mut req_buffer = VectorPacketBuffer::new();
+            req_buffer.buffer.resize(len, 0);
+            stream.read_exact(&mut req_buffer.buffer).unwrap();
+            let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+            assert_eq!(QueryType::AXFR, request.questions[0].qtype);
+
+            for answers in messages {
+                let mut packet = DnsPacket::new();
+                packet.header.id = request.header.id;
+                packet.header.response = true;
+                packet.header.authoritative_answer = true;
+                packet.questions = request.questions.clone();
+                packet.answers = answers;
+
+                let mut res_buffer = VectorPacketBuffer::new();
+                packet.write(&mut res_buffer, 0xFFFF).unwrap();
+
+                let len = res_buffer.pos();
+                write_packet_length(&mut stream, len).unwrap();
+                stream.write(res_buffer.get_range(0, len).unwrap()).unwrap();
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn test_transfer_zone() {
+        let primary = run_mock_primary(vec![
+            vec![build_soa("example.com", 2),
+                 build_a("www.example.com", "127.0.0.1"),
+                 build_a("mail.example.com", "127.0.0.2")],
+            vec![build_a("ftp.example.com", "127.0.0.3"),
+                 build_soa("example.com", 2)]
+        ]);
+
+        let authority = Authority::new();
+        {
+            let mut zones = authority.write().unwrap();
+
+            let mut zone = Zone::new("example.com".to_string(), String::new(), String::new());
+            zone.serial = 1;
+            zone.primary = Some(primary);
+            zone.add_record(&build_a("www.example.com", "127.0.0.1"));
+            zone.add_record(&build_a("old.example.com", "127.0.0.4"));
+            zones.add_zone(zone).unwrap();
+        }
+
+        let result = transfer_zone(&authority, "example.com").unwrap();
+        assert_eq!(TransferResult {
+            serial_before: 1,
+            serial_after: 2,
+            added: 2,
+            removed: 1
+        }, result);
+
+        let zones = authority.read().unwrap();
+        let zone = zones.get_zone("example.com").unwrap();
+        assert_eq!(2, zone.serial);
+        assert_eq!("ns1.example.com", zone.m_name);
+        assert_eq!(3, zone.records.len());
+        assert!(zone.records.contains(&build_a("ftp.example.com", "127.0.0.3")));
+        assert!(!zone.records.contains(&build_a("old.example.com", "127.0.0.4")));
+    }
+
+    #[test]
+    fn test_transfer_zone_errors() {
+        let authority = Authority::new();
+        {
+            let mut zones = authority.write().unwrap();
+            zones.add_zone(Zone::new("example.com".to_string(), String::new(), String::new())).unwrap();
+        }
+
+        match transfer_zone(&authority, "google.com") {
+            Ok(_) => panic!(),
+            Err(e) => assert_eq!(ErrorKind::NotFound, e.kind())
+        }
+
+        match transfer_zone(&authority, "example.com") {
+            Ok(_) => panic!(),
+            Err(e) => assert_eq!(ErrorKind::InvalidInput, e.kind())
+        }
+    }
+
+    #[test]
+    fn test_transfer_zone_rejected() {
+        let authority = Authority::new();
+        {
+            let mut zones = authority.write().unwrap();
+            zones.max_records_per_zone = 2;
+
+            let mut zone = Zone::new("example.com".to_string(), String::new(), String::new());
+            zone.serial = 1;
+            zone.add_record(&build_a("www.example.com", "127.0.0.1"));
+            zones.add_zone(zone).unwrap();
+        }
+
+        let transfer = |messages: Vec<Vec<DnsRecord>>| {
+            let primary = run_mock_primary(messages);
+            authority.write().unwrap().get_zone_mut("example.com").unwrap().primary = Some(primary);
+            transfer_zone(&authority, "example.com")
+        };
+
+        // Records for names outside of the zone aren't accepted
+        match transfer(vec![vec![build_soa("example.com", 2),
+                                 build_a("www.Example.com", "127.0.0.2"),
+                                 build_a("www.google.com", "127.0.0.3"),
+                                 build_soa("example.com", 2)]]) {
+            Ok(_) => panic!(),
+            Err(e) => assert_eq!(ErrorKind::InvalidData, e.kind())
+        }
+
+        // and neither are more records than the zone may hold
+        match transfer(vec![vec![build_soa("example.com", 2),
+                                 build_a("a.example.com", "127.0.0.1"),
+                                 build_a("b.example.com", "127.0.0.2"),
+                                 build_a("c.example.com", "127.0.0.3"),
+                                 build_soa("example.com", 2)]]) {
+            Ok(_) => panic!(),
+            Err(e) => assert_eq!(ErrorKind::InvalidData, e.kind())
+        }
+
+        // Either way, the zone is left as it was
+        {
+            let zones = authority.read().unwrap();
+            let zone = zones.get_zone("example.com").unwrap();
+            assert_eq!(1, zone.serial);
+            assert_eq!(1, zone.records.len());
+        }
+
+        let result = transfer(vec![vec![build_soa("example.com", 2),
+                                        build_a("a.example.com", "127.0.0.1"),
+                                        build_a("b.example.com", "127.0.0.2"),
+                                        build_soa("example.com", 2)]]).unwrap();
+        assert_eq!(2, result.serial_after);
+    }
+
+    #[test]
+    fn test_serve_transfer() {
+        let mut context = create_test_context(Box::new(|_, _, _, _| {
+            panic!("No upstream queries expected");
+        }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                let mut zones = ctx.authority.write().unwrap();
+
+                let mut zone = Zone::new("example.com".to_string(), "ns1.example.com".to_string(), "admin.example.com".to_string());
+                for i in 0..1000 {
+                    zone.add_record(&build_a(&format!("host{}.example.com", i), "127.0.0.1"));
+                }
+                zone.add_record(&DnsRecord::ANAME {
+                    domain: "example.com".to_string(),
+                    host: "example.cdn.net".to_string(),
+                    ttl: TransientTtl(300)
+                });
+                zones.add_zone(zone).unwrap();
+
+                ctx.allow_transfer = vec!["192.0.2.0/24".parse().unwrap()];
+            },
+            None => panic!()
+        }
+
+        let mut request = DnsPacket::new();
+        request.header.id = 1234;
+        request.questions.push(DnsQuestion::new("Example.com".to_string(), QueryType::AXFR));
+
+        // The records are split across messages, surrounded by the SOA
+        let messages = serve_transfer(&context, &request, &"192.0.2.1".parse().unwrap(), None);
+        assert!(messages.len() > 1);
+        assert!(messages.iter().all(|x| x.header.id == 1234 && x.header.rescode == ResultCode::NOERROR));
+
+        let records = messages.into_iter().flat_map(|x| x.answers).collect::<Vec<DnsRecord>>();
+        assert_eq!(1002, records.len());
+        assert_eq!(QueryType::SOA, records[0].get_querytype());
+        assert_eq!(QueryType::SOA, records[1001].get_querytype());
+        assert!(records.iter().all(|x| x.get_querytype() != QueryType::ANAME));
+
+        // Clients not on the list are refused, as are zones we don't have
+        let messages = serve_transfer(&context, &request, &"198.51.100.1".parse().unwrap(), None);
+        assert_eq!(1, messages.len());
+        assert_eq!(ResultCode::REFUSED, messages[0].header.rescode);
+        assert!(messages[0].answers.is_empty());
+
+        request.questions[0].name = "google.com".to_string();
+        let messages = serve_transfer(&context, &request, &"192.0.2.1".parse().unwrap(), None);
+        assert_eq!(ResultCode::REFUSED, messages[0].header.rescode);
+    }
+
+    #[test]
+    fn test_incremental_transfer() {
+        let mut context = create_test_context(Box::new(|_, _, _, _| {
+            panic!("No upstream queries expected");
+        }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                let mut zones = ctx.authority.write().unwrap();
+
+                let mut zone = Zone::new("example.com".to_string(), "ns1.example.com".to_string(), "admin.example.com".to_string());
+                zone.serial = 1;
+                for i in 0..10 {
+                    zone.add_record(&build_a(&format!("host{}.example.com", i), "127.0.0.1"));
+                }
+                zones.add_zone(zone).unwrap();
+
+                zones.add_record("example.com", &build_a("www.example.com", "127.0.0.2")).unwrap();
+
+                ctx.allow_transfer = vec!["192.0.2.0/24".parse().unwrap()];
+            },
+            None => panic!()
+        }
+
+        let client = "192.0.2.1".parse().unwrap();
+
+        let mut request = DnsPacket::new();
+        request.header.id = 1234;
+        request.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::IXFR));
+        request.authorities.push(build_soa("example.com", 1));
+
+        let serials = |records: &[DnsRecord]| {
+            records.iter()
+                .filter_map(|x| match *x {
+                    DnsRecord::SOA { serial, .. } => Some(serial),
+                    _ => None
+                })
+                .collect::<Vec<u32>>()
+        };
+
+        // Only the added record is sent, between the SOAs of the change
+        let messages = serve_transfer(&context, &request, &client, None);
+        assert_eq!(1, messages.len());
+        assert_eq!(ResultCode::NOERROR, messages[0].header.rescode);
+
+        let records = &messages[0].answers;
+        assert_eq!(5, records.len());
+        assert_eq!(vec![2, 1, 2, 2], serials(records));
+        assert_eq!(build_a("www.example.com", "127.0.0.2"), records[3]);
+
+        // A client that is up to date gets the current SOA alone
+        request.authorities = vec![build_soa("example.com", 2)];
+        let messages = serve_transfer(&context, &request, &client, None);
+        assert_eq!(vec![2], serials(&messages[0].answers));
+        assert_eq!(1, messages[0].answers.len());
+
+        // Without history for the serial, the whole zone is sent instead
+        request.authorities = vec![build_soa("example.com", 0)];
+        let messages = serve_transfer(&context, &request, &client, None);
+        assert_eq!(13, messages[0].answers.len());
+        assert_eq!(vec![2, 2], serials(&messages[0].answers));
+
+        request.authorities.clear();
+        let messages = serve_transfer(&context, &request, &client, None);
+        assert_eq!(13, messages[0].answers.len());
+    }
+
+    #[test]
+    fn test_signed_transfer() {
+        let key = TsigKey::new("transfer.example.com", b"secret");
+
+        let mut context = create_test_context(Box::new(|_, _, _, _| {
+            panic!("No upstream queries expected");
+        }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                let mut zones = ctx.authority.write().unwrap();
+
+                let mut zone = Zone::new("example.com".to_string(), "ns1.example.com".to_string(), "admin.example.com".to_string());
+                zone.add_record(&build_a("www.example.com", "127.0.0.1"));
+                zones.add_zone(zone).unwrap();
+
+                // Only allows a key that the request isn't signed with
+                let mut zone = Zone::new("internal.com".to_string(), "ns1.internal.com".to_string(), "admin.internal.com".to_string());
+                zone.allow_transfer_keys = Some(vec!["internal.example.com".to_string()]);
+                zones.add_zone(zone).unwrap();
+
+                ctx.tsig_keys = vec![key.clone(), TsigKey::new("internal.example.com", b"internal")];
+                ctx.allow_transfer_keys = vec!["transfer.example.com".to_string()];
+            },
+            None => panic!()
+        }
+
+        let mut request = DnsPacket::new();
+        request.header.id = 1234;
+        request.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::AXFR));
+
+        let sign = |request: &DnsPacket, secret: &[u8]| {
+            let mut signer = TsigSigner::new(TsigKey::new("transfer.example.com", secret), 1_500_000_000);
+            let mut buffer = VectorPacketBuffer::new();
+            request.clone().write(&mut buffer, 0xFFFF).unwrap();
+            signer.sign(&mut buffer).unwrap();
+            (buffer.buffer, signer)
+        };
+        let sign_request = |secret: &[u8]| sign(&request, secret);
+
+        // A correctly signed request is accepted, although the client isn't
+        // on any list, and the response is signed using the same key
+        let (raw, mut signer) = sign_request(b"secret");
+        let messages = encode_transfer(&context, &request, &raw, &"198.51.100.1".parse().unwrap(), 1_500_000_010).unwrap();
+        assert_eq!(1, messages.len());
+        assert!(signer.verify(&messages[0]));
+
+        let mut res_buffer = VectorPacketBuffer::new();
+        res_buffer.buffer = messages[0].clone();
+        let response = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+        assert_eq!(ResultCode::NOERROR, response.header.rescode);
+        assert_eq!(3, response.answers.len());
+
+        // A bad signature is rejected with the reason in the TSIG record
+        let (raw, _) = sign_request(b"wrong");
+        let messages = encode_transfer(&context, &request, &raw, &"198.51.100.1".parse().unwrap(), 1_500_000_010).unwrap();
+        assert_eq!(1, messages.len());
+
+        let mut res_buffer = VectorPacketBuffer::new();
+        res_buffer.buffer = messages[0].clone();
+        let response = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+        assert_eq!(ResultCode::NOTAUTH, response.header.rescode);
+        assert!(response.answers.is_empty());
+        match response.resources.last() {
+            Some(&DnsRecord::TSIG { error, ref mac, .. }) => {
+                assert_eq!(BADSIG, error);
+                assert!(mac.is_empty());
+            },
+            _ => panic!()
+        }
+
+        // As is one signed too long ago, although that response is signed
+        let (raw, _) = sign_request(b"secret");
+        let messages = encode_transfer(&context, &request, &raw, &"198.51.100.1".parse().unwrap(), 1_500_001_000).unwrap();
+        let mut res_buffer = VectorPacketBuffer::new();
+        res_buffer.buffer = messages[0].clone();
+        let response = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+        assert_eq!(ResultCode::NOTAUTH, response.header.rescode);
+        match response.resources.last() {
+            Some(&DnsRecord::TSIG { error, ref mac, .. }) => {
+                assert_eq!(BADTIME, error);
+                assert!(!mac.is_empty());
+            },
+            _ => panic!()
+        }
+
+        // A valid key only grants access to the zones that allow it
+        let mut other_request = request.clone();
+        other_request.questions[0].name = "internal.com".to_string();
+        let (raw, _) = sign(&other_request, b"secret");
+        let messages = encode_transfer(&context, &other_request, &raw, &"198.51.100.1".parse().unwrap(), 1_500_000_010).unwrap();
+        let mut res_buffer = VectorPacketBuffer::new();
+        res_buffer.buffer = messages[0].clone();
+        let response = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+        assert_eq!(ResultCode::REFUSED, response.header.rescode);
+
+        // Without a signature, the client is refused as usual
+        let mut buffer = VectorPacketBuffer::new();
+        request.clone().write(&mut buffer, 0xFFFF).unwrap();
+        let messages = encode_transfer(&context, &request, &buffer.buffer, &"198.51.100.1".parse().unwrap(), 1_500_000_010).unwrap();
+        let mut res_buffer = VectorPacketBuffer::new();
+        res_buffer.buffer = messages[0].clone();
+        let response = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+        assert_eq!(ResultCode::REFUSED, response.header.rescode);
+    }
+
+    #[test]
+    fn test_fetch_zone_signed() {
+        let key = TsigKey::new("transfer.example.com", b"secret");
+
+        let mut context = create_test_context(Box::new(|_, _, _, _| {
+            panic!("No upstream queries expected");
+        }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                let mut zones = ctx.authority.write().unwrap();
+
+                let mut zone = Zone::new("example.com".to_string(), "ns1.example.com".to_string(), "admin.example.com".to_string());
+                zone.allow_transfer_keys = Some(vec!["transfer.example.com".to_string()]);
+                zone.add_record(&build_a("www.example.com", "127.0.0.1"));
+                zones.add_zone(zone).unwrap();
+
+                ctx.tsig_keys = vec![key.clone()];
+            },
+            None => panic!()
+        }
+
+        let run_primary = |context: Arc<ServerContext>| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                handle_tcp_connection(context, stream);
+            });
+
+            addr
+        };
+
+        // Signed with the key that the zone allows, the transfer succeeds
+        // and the signed response checks out
+        let primary = run_primary(context.clone());
+        let records = fetch_zone("example.com", &primary, Some(&key), DEFAULT_MAX_RECORDS_PER_ZONE).unwrap();
+        assert_eq!(2, records.len());
+
+        // The primary refuses a key it doesn't know, which answers unsigned
+        let primary = run_primary(context.clone());
+        let other_key = TsigKey::new("transfer.example.com", b"other");
+        match fetch_zone("example.com", &primary, Some(&other_key), DEFAULT_MAX_RECORDS_PER_ZONE) {
+            Ok(_) => panic!(),
+            Err(e) => assert_eq!(ErrorKind::InvalidData, e.kind())
+        }
+
+        // and unsigned responses are rejected when expecting signed ones
+        let primary = run_mock_primary(vec![
+            vec![build_soa("example.com", 2), build_soa("example.com", 2)]
+        ]);
+        match fetch_zone("example.com", &primary, Some(&key), DEFAULT_MAX_RECORDS_PER_ZONE) {
+            Ok(_) => panic!(),
+            Err(e) => assert_eq!(ErrorKind::InvalidData, e.kind())
+        }
+    }
+
+    #[test]
+    fn test_fetch_zone_requires_soa() {
+        let primary = run_mock_primary(vec![
+            vec![build_a("www.example.com", "127.0.0.1")]
+        ]);
+
+        match fetch_zone("example.com", &primary, None, DEFAULT_MAX_RECORDS_PER_ZONE) {
+            Ok(_) => panic!(),
+            Err(e) => assert_eq!(ErrorKind::InvalidData, e.kind())
+        }
+    }
+    #[test]
+    fn test_fetch_zone_record_limit() {
+        // The transfer never ends, but is given up once there are too many
+        // records, without waiting for the rest
+        let primary = run_mock_primary(vec![
+            vec![build_soa("example.com", 2),
+                 build_a("a.example.com", "127.0.0.1"),
+                 build_a("b.example.com", "127.0.0.2"),
+                 build_a("c.example.com", "127.0.0.3")]
+        ]);
+
+        match fetch_zone("example.com", &primary, None, 2) {
+            Ok(_) => panic!(),
+            Err(e) => {
+                assert_eq!(ErrorKind::InvalidData, e.kind());
+                assert_eq!("Transfer exceeds the limit of 2 records for zone example.com", e.to_string());
+            }
+        }
+    }
+}
@@ -0,0 +1,615 @@
+//! transaction signatures (TSIG), as specified in RFC 8945, used to
+//! authenticate zone transfers using a secret shared between two servers
+
+use std::io::{Result, Error, ErrorKind};
+use std::str::FromStr;
+
+use hmac::{Hmac, Mac};
+use rustc_serialize::base64::FromBase64;
+use sha2::Sha256;
+
+use dns::buffer::{PacketBuffer, VectorPacketBuffer};
+use dns::protocol::{DnsRecord, QueryType, CLASS_ANY};
+
+macro_rules! try_opt {
+    ( $x:expr ) => {
+        match $x {
+            Some(x) => x,
+            None => return None
+        }
+    }
+}
+
+/// The only supported algorithm
+pub const HMAC_SHA256: &'static str = "hmac-sha256";
+
+/// TSIG error code for a MAC that failed to verify
+pub const BADSIG: u16 = 16;
+
+/// TSIG error code for a key that isn't known to us
+pub const BADKEY: u16 = 17;
+
+/// TSIG error code for a signature made outside of the permitted fudge
+pub const BADTIME: u16 = 18;
+
+/// Permitted difference in seconds between our clock and that of the signer
+pub const DEFAULT_FUDGE: u16 = 300;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Compute the HMAC-SHA256 (RFC 2104) of `data` using `key`
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    // Keys of any length are accepted by HMAC, so this can't fail
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// A key shared with another server
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct TsigKey {
+    pub name: String,
+    pub algorithm: String,
+    pub secret: Vec<u8>
+}
+
+impl TsigKey {
+    pub fn new(name: &str, secret: &[u8]) -> TsigKey {
+        TsigKey {
+            name: name.to_lowercase(),
+            algorithm: HMAC_SHA256.to_string(),
+            secret: secret.to_vec()
+        }
+    }
+}
+
+impl FromStr for TsigKey {
+    type Err = Error;
+
+    /// Parse a key in the format `[algorithm:]name:secret`, with the secret
+    /// encoded using base64
+    fn from_str(s: &str) -> Result<TsigKey> {
+        let invalid = |reason: &str| Error::new(ErrorKind::InvalidInput, format!("Invalid TSIG key {}: {}", s, reason));
+
+        let parts = s.trim().split(':').collect::<Vec<&str>>();
+        let (algorithm, name, secret) = match parts.len() {
+            2 => (HMAC_SHA256, parts[0], parts[1]),
+            3 => (parts[0], parts[1], parts[2]),
+            _ => return Err(invalid("expected [algorithm:]name:secret"))
+        };
+
+        if algorithm.to_lowercase() != HMAC_SHA256 {
+            return Err(invalid("unsupported algorithm"));
+        }
+
+        if name.is_empty() {
+            return Err(invalid("missing name"));
+        }
+
+        let secret = match secret.from_base64() {
+            Ok(ref x) if !x.is_empty() => x.clone(),
+            _ => return Err(invalid("secret isn't valid base64"))
+        };
+
+        Ok(TsigKey::new(name, &secret))
+    }
+}
+
+/// Append `name` to `data` in canonical form: uncompressed and in lower case
+fn push_canonical_name(data: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|x| !x.is_empty()) {
+        data.push(label.len() as u8);
+        data.extend(label.to_lowercase().bytes());
+    }
+    data.push(0);
+}
+
+fn push_u16(data: &mut Vec<u8>, val: u16) {
+    data.push((val >> 8) as u8);
+    data.push(val as u8);
+}
+
+fn push_time(data: &mut Vec<u8>, time_signed: u64, fudge: u16) {
+    push_u16(data, (time_signed >> 32) as u16);
+    push_u16(data, (time_signed >> 16) as u16);
+    push_u16(data, time_signed as u16);
+    push_u16(data, fudge);
+}
+
+/// Build the data covered by the MAC of a message
+///
+/// `prior_mac` is the MAC of the request for a response, or of the previous
+/// message when several are sent in a row. All but the first of such a
+/// sequence only cover the timers of the TSIG record.
+fn digest_data(key: &TsigKey,
+               prior_mac: Option<&[u8]>,
+               message: &[u8],
+               time_signed: u64,
+               fudge: u16,
+               error: u16,
+               other: &[u8],
+               timers_only: bool) -> Vec<u8> {
+    let mut data = Vec::with_capacity(message.len() + 128);
+
+    if let Some(mac) = prior_mac {
+        push_u16(&mut data, mac.len() as u16);
+        data.extend_from_slice(mac);
+    }
+
+    data.extend_from_slice(message);
+
+    if !timers_only {
+        push_canonical_name(&mut data, &key.name);
+        push_u16(&mut data, CLASS_ANY);
+        push_u16(&mut data, 0);
+        push_u16(&mut data, 0);
+        push_canonical_name(&mut data, &key.algorithm);
+    }
+
+    push_time(&mut data, time_signed, fudge);
+
+    if !timers_only {
+        push_u16(&mut data, error);
+        push_u16(&mut data, other.len() as u16);
+        data.extend_from_slice(other);
+    }
+
+    data
+}
+
+/// Compute the MAC of a message that isn't part of a sequence, such as a
+/// request, from the message without its TSIG record and the record itself
+///
+/// The MAC is empty if the algorithm of the record isn't supported.
+pub fn compute_tsig_mac(message_without_tsig: &[u8], key: &TsigKey, tsig: &DnsRecord) -> Vec<u8> {
+    match *tsig {
+        DnsRecord::TSIG { ref algorithm, time_signed, fudge, error, ref other, .. } if algorithm.to_lowercase() == HMAC_SHA256 => {
+            let data = digest_data(key, None, message_without_tsig, time_signed, fudge, error, other, false);
+            hmac_sha256(&key.secret, &data)
+        },
+        _ => Vec::new()
+    }
+}
+
+/// Compare two MACs without bailing out at the first difference
+fn mac_equals(a: &[u8], b: &[u8]) -> bool {
+    if a.is_empty() || a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn skip_name(raw: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = match raw.get(pos) {
+            Some(x) => *x as usize,
+            None => return None
+        };
+
+        if len & 0xC0 == 0xC0 {
+            return Some(pos + 2);
+        }
+
+        pos += len + 1;
+        if len == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+fn get_u16(raw: &[u8], pos: usize) -> Option<u16> {
+    if pos + 2 > raw.len() {
+        return None;
+    }
+
+    Some(((raw[pos] as u16) << 8) | (raw[pos + 1] as u16))
+}
+
+/// Locate the TSIG record of a message, which has to be the last record of
+/// the additional section
+///
+/// Returns the offset of the record along with the record itself.
+fn find_tsig(raw: &[u8]) -> Option<(usize, DnsRecord)> {
+    let questions = try_opt!(get_u16(raw, 4));
+    let records = try_opt!(get_u16(raw, 6)) as usize +
+                  try_opt!(get_u16(raw, 8)) as usize +
+                  try_opt!(get_u16(raw, 10)) as usize;
+
+    if records == 0 {
+        return None;
+    }
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = try_opt!(skip_name(raw, pos)) + 4;
+    }
+    for _ in 0..(records - 1) {
+        pos = try_opt!(skip_name(raw, pos)) + 8;
+        pos += try_opt!(get_u16(raw, pos)) as usize + 2;
+    }
+
+    let mut buffer = VectorPacketBuffer::new();
+    buffer.buffer = raw.to_vec();
+    if buffer.seek(pos).is_err() {
+        return None;
+    }
+
+    match DnsRecord::read(&mut buffer) {
+        Ok(rec @ DnsRecord::TSIG { .. }) => Some((pos, rec)),
+        _ => None
+    }
+}
+
+/// Locate a TSIG record anywhere but as the last record of a message, where
+/// it doesn't belong, returning its offset
+fn find_misplaced_tsig(raw: &[u8]) -> Option<usize> {
+    let questions = try_opt!(get_u16(raw, 4));
+    let records = try_opt!(get_u16(raw, 6)) as usize +
+                  try_opt!(get_u16(raw, 8)) as usize +
+                  try_opt!(get_u16(raw, 10)) as usize;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = try_opt!(skip_name(raw, pos)) + 4;
+    }
+    for _ in 0..records.saturating_sub(1) {
+        let name_end = try_opt!(skip_name(raw, pos));
+        if try_opt!(get_u16(raw, name_end)) == QueryType::TSIG.to_num() {
+            return Some(pos);
+        }
+
+        pos = name_end + 8;
+        pos += try_opt!(get_u16(raw, pos)) as usize + 2;
+    }
+
+    None
+}
+
+/// Split a signed message into the message as it was before signing, and
+/// its TSIG record
+///
+/// The TSIG record is removed, and the id and record count that the signer
+/// used are restored, which is what the MAC is computed over.
+pub fn split_tsig(raw: &[u8]) -> Option<(Vec<u8>, DnsRecord)> {
+    let (offset, tsig) = try_opt!(find_tsig(raw));
+
+    let original_id = match tsig {
+        DnsRecord::TSIG { original_id, .. } => original_id,
+        _ => return None
+    };
+
+    let mut message = raw[..offset].to_vec();
+
+    let additional = ((message[10] as u16) << 8 | (message[11] as u16)).saturating_sub(1);
+    message[0] = (original_id >> 8) as u8;
+    message[1] = original_id as u8;
+    message[10] = (additional >> 8) as u8;
+    message[11] = additional as u8;
+
+    Some((message, tsig))
+}
+
+/// Signs a sequence of messages with a key
+///
+/// For a server, this is the sequence of responses to a signed request. For
+/// a client, it's the request followed by the responses, which are checked
+/// using `verify`.
+pub struct TsigSigner {
+    pub key: TsigKey,
+    pub time_signed: u64,
+    prior_mac: Option<Vec<u8>>,
+    count: usize,
+
+    /// The TSIG error reported in the signed messages, along with its Other
+    /// Data, which is only set when answering a request with `BADTIME`
+    error: u16,
+    other: Vec<u8>
+}
+
+impl TsigSigner {
+    pub fn new(key: TsigKey, time_signed: u64) -> TsigSigner {
+        TsigSigner {
+            key: key,
+            time_signed: time_signed,
+            prior_mac: None,
+            count: 0,
+            error: 0,
+            other: Vec::new()
+        }
+    }
+
+    fn compute_mac(&self, message: &[u8], time_signed: u64, fudge: u16, error: u16, other: &[u8]) -> Vec<u8> {
+        let prior_mac = self.prior_mac.as_ref().map(|x| &x[..]);
+        let data = digest_data(&self.key, prior_mac, message, time_signed, fudge, error, other, self.count > 0);
+
+        hmac_sha256(&self.key.secret, &data)
+    }
+
+    /// Append a TSIG record to the message written to `buffer`
+    pub fn sign(&mut self, buffer: &mut VectorPacketBuffer) -> Result<()> {
+        let len = buffer.pos();
+        if len < 12 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Message is too short to sign"));
+        }
+
+        let original_id = get_u16(&buffer.buffer, 0).unwrap_or(0);
+        let mac = self.compute_mac(&buffer.buffer[..len], self.time_signed, DEFAULT_FUDGE, self.error, &self.other);
+
+        let tsig = DnsRecord::TSIG {
+            domain: self.key.name.clone(),
+            algorithm: self.key.algorithm.clone(),
+            time_signed: self.time_signed,
+            fudge: DEFAULT_FUDGE,
+            mac: mac.clone(),
+            original_id: original_id,
+            error: self.error,
+            other: self.other.clone()
+        };
+        try!(tsig.write(buffer));
+
+        let additional = get_u16(&buffer.buffer, 10).unwrap_or(0);
+        try!(buffer.set_u16(10, additional + 1));
+
+        // Signing a request starts a new sequence
+        if self.prior_mac.is_some() {
+            self.count += 1;
+        }
+        self.prior_mac = Some(mac);
+
+        Ok(())
+    }
+
+    /// Check the TSIG record of a message received in response to what
+    /// we've signed
+    pub fn verify(&mut self, raw: &[u8]) -> bool {
+        let (message, tsig) = match split_tsig(raw) {
+            Some(x) => x,
+            None => return false
+        };
+
+        if let DnsRecord::TSIG { ref domain, time_signed, fudge, ref mac, error, ref other, .. } = tsig {
+            if domain.to_lowercase() != self.key.name || error != 0 {
+                return false;
+            }
+
+            if !mac_equals(mac, &self.compute_mac(&message, time_signed, fudge, error, other)) {
+                return false;
+            }
+
+            self.prior_mac = Some(mac.clone());
+            self.count += 1;
+
+            return true;
+        }
+
+        false
+    }
+}
+
+/// The outcome of checking the signature of a request
+pub enum TsigStatus {
+    /// The request isn't signed
+    Unsigned,
+
+    /// The request is correctly signed, and responses should be signed using
+    /// the provided signer
+    Signed(TsigSigner),
+
+    /// The request is signed, but the signature doesn't check out. The
+    /// response should carry the provided TSIG record, which holds the
+    /// reason.
+    Failed(DnsRecord),
+
+    /// The request is correctly signed, but too long ago or too far ahead.
+    /// The response should be signed using the provided signer, which
+    /// reports `BADTIME` along with our current time (RFC 8945, 5.2.3).
+    BadTime(TsigSigner),
+
+    /// The request has a TSIG record other than as its last record, and
+    /// should be answered with FORMERR
+    Malformed
+}
+
+/// Check the TSIG record of the request in `raw` against our `keys`,
+/// given the current time `now` in seconds since the epoch
+pub fn verify_request(keys: &[TsigKey], raw: &[u8], now: u64) -> TsigStatus {
+    if find_misplaced_tsig(raw).is_some() {
+        return TsigStatus::Malformed;
+    }
+
+    let (message, tsig) = match split_tsig(raw) {
+        Some(x) => x,
+        None => return TsigStatus::Unsigned
+    };
+
+    if let DnsRecord::TSIG { ref domain, ref algorithm, time_signed, fudge, ref mac, original_id, .. } = tsig {
+        let failed = |error: u16| {
+            TsigStatus::Failed(DnsRecord::TSIG {
+                domain: domain.clone(),
+                algorithm: algorithm.clone(),
+                time_signed: time_signed,
+                fudge: fudge,
+                mac: Vec::new(),
+                original_id: original_id,
+                error: error,
+                other: Vec::new()
+            })
+        };
+
+        let key = match keys.iter().find(|x| x.name == domain.to_lowercase() && x.algorithm == algorithm.to_lowercase()) {
+            Some(x) => x,
+            None => return failed(BADKEY)
+        };
+
+        if !mac_equals(mac, &compute_tsig_mac(&message, key, &tsig)) {
+            return failed(BADSIG);
+        }
+
+        let skew = if now > time_signed { now - time_signed } else { time_signed - now };
+        if skew > fudge as u64 {
+            // The signing time of the request is kept, so that the client
+            // can verify the response using its own clock
+            let mut other = Vec::with_capacity(6);
+            push_u16(&mut other, (now >> 32) as u16);
+            push_u16(&mut other, (now >> 16) as u16);
+            push_u16(&mut other, now as u16);
+
+            return TsigStatus::BadTime(TsigSigner {
+                key: key.clone(),
+                time_signed: time_signed,
+                prior_mac: Some(mac.clone()),
+                count: 0,
+                error: BADTIME,
+                other: other
+            });
+        }
+
+        return TsigStatus::Signed(TsigSigner {
+            key: key.clone(),
+            time_signed: now,
+            prior_mac: Some(mac.clone()),
+            count: 0,
+            error: 0,
+            other: Vec::new()
+        });
+    }
+
+    TsigStatus::Unsigned
+}
+
+#[cfg(test)]
+mod tests {
+
+    use dns::protocol::{DnsPacket, DnsQuestion};
+
+    use super::*;
+
+    fn to_hex(data: &[u8]) -> String {
+        data.iter().map(|x| format!("{:02x}", x)).collect::<Vec<String>>().join("")
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+                   to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")));
+    }
+
+    #[test]
+    fn test_compute_tsig_mac() {
+        let key = TsigKey::new("transfer.example.com", b"secret");
+
+        let mut request = DnsPacket::new();
+        request.header.id = 4321;
+        request.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::AXFR));
+
+        let mut buffer = VectorPacketBuffer::new();
+        request.write(&mut buffer, 0xFFFF).unwrap();
+        let unsigned = buffer.buffer.clone();
+
+        let mut signer = TsigSigner::new(key.clone(), 1_500_000_000);
+        signer.sign(&mut buffer).unwrap();
+
+        // The TSIG record is read back as written, and removing it yields
+        // the message as it was before signing
+        let (message, tsig) = split_tsig(&buffer.buffer).unwrap();
+        assert_eq!(unsigned, message);
+        assert_eq!(DnsRecord::TSIG {
+            domain: "transfer.example.com".to_string(),
+            algorithm: HMAC_SHA256.to_string(),
+            time_signed: 1_500_000_000,
+            fudge: DEFAULT_FUDGE,
+            mac: compute_tsig_mac(&message, &key, &tsig),
+            original_id: 4321,
+            error: 0,
+            other: Vec::new()
+        }, tsig);
+
+        // Computed independently, over the message followed by the TSIG
+        // variables
+        assert_eq!("c7bac43c7a2c45ff571cbdb4cb7b6e8e30849754ca2baf2920e572be5a00bbcc",
+                   to_hex(&compute_tsig_mac(&message, &key, &tsig)));
+
+        // Any change to the message, or a different key, yields another MAC
+        let mut tampered = message.clone();
+        tampered[3] ^= 1;
+        assert!(compute_tsig_mac(&tampered, &key, &tsig) != compute_tsig_mac(&message, &key, &tsig));
+
+        let other_key = TsigKey::new("transfer.example.com", b"other");
+        assert!(compute_tsig_mac(&message, &other_key, &tsig) != compute_tsig_mac(&message, &key, &tsig));
+
+        // Unsigned messages have nothing to split off
+        assert!(split_tsig(&unsigned).is_none());
+    }
+
+    #[test]
+    fn test_verify_request_errors() {
+        let key = TsigKey::new("transfer.example.com", b"secret");
+
+        let mut request = DnsPacket::new();
+        request.header.id = 4321;
+        request.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::AXFR));
+
+        let mut buffer = VectorPacketBuffer::new();
+        request.write(&mut buffer, 0xFFFF).unwrap();
+        let mut client = TsigSigner::new(key.clone(), 1_500_000_000);
+        client.sign(&mut buffer).unwrap();
+
+        // A request signed too long ago gets a signed response, carrying the
+        // time of the request and our own
+        let now = 1_500_001_000;
+        let mut server = match verify_request(&[key.clone()], &buffer.buffer, now) {
+            TsigStatus::BadTime(x) => x,
+            _ => panic!()
+        };
+
+        let mut response = DnsPacket::new();
+        response.header.id = 4321;
+        response.header.response = true;
+        let mut res_buffer = VectorPacketBuffer::new();
+        response.write(&mut res_buffer, 0xFFFF).unwrap();
+        server.sign(&mut res_buffer).unwrap();
+
+        let (message, tsig) = split_tsig(&res_buffer.buffer).unwrap();
+        match tsig {
+            DnsRecord::TSIG { time_signed, fudge, ref mac, error, ref other, .. } => {
+                assert_eq!(1_500_000_000, time_signed);
+                assert_eq!(BADTIME, error);
+                assert_eq!(vec![0x00, 0x00, 0x59, 0x68, 0x32, 0xE8], *other);
+                assert_eq!(client.compute_mac(&message, time_signed, fudge, error, other), *mac);
+            },
+            _ => panic!()
+        }
+
+        // A TSIG record has to be the last one of the message
+        let (_, tsig) = split_tsig(&buffer.buffer).unwrap();
+        request.resources.push(tsig);
+        request.resources.push(DnsRecord::OPT {
+            packet_len: 4096,
+            flags: 0,
+            options: Vec::new()
+        });
+
+        let mut buffer = VectorPacketBuffer::new();
+        request.write(&mut buffer, 0xFFFF).unwrap();
+        match verify_request(&[key], &buffer.buffer, 1_500_000_000) {
+            TsigStatus::Malformed => {},
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key = "Transfer.Example.com:c2VjcmV0".parse::<TsigKey>().unwrap();
+        assert_eq!("transfer.example.com", key.name);
+        assert_eq!(HMAC_SHA256, key.algorithm);
+        assert_eq!(b"secret".to_vec(), key.secret);
+
+        assert!("hmac-sha256:transfer:c2VjcmV0".parse::<TsigKey>().is_ok());
+        assert!("hmac-md5:transfer:c2VjcmV0".parse::<TsigKey>().is_err());
+        assert!("transfer".parse::<TsigKey>().is_err());
+        assert!("transfer:".parse::<TsigKey>().is_err());
+    }
+}
@@ -0,0 +1,184 @@
+//! heuristic detection of DNS tunneling, which encodes data in long, random
+//! looking labels
+
+use std::collections::HashMap;
+use std::io::{Result, Error, ErrorKind};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Default length above which a label is considered suspicious
+pub const DEFAULT_MAX_LABEL_LEN: usize = 40;
+
+/// Default Shannon entropy, in bits per character, above which a label is
+/// considered suspicious
+pub const DEFAULT_MAX_ENTROPY: f64 = 4.0;
+
+/// Labels shorter than this aren't checked for entropy, since a handful of
+/// characters says little about randomness
+const MIN_ENTROPY_LABEL_LEN: usize = 16;
+
+/// What to do with queries flagged as tunneling
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum TunnelAction {
+    /// Only count them
+    Count,
+
+    /// Count and log them
+    Log,
+
+    /// Count them, and answer with REFUSED
+    Refuse
+}
+
+impl TunnelAction {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            TunnelAction::Count => "count",
+            TunnelAction::Log => "log",
+            TunnelAction::Refuse => "refuse"
+        }
+    }
+}
+
+impl FromStr for TunnelAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<TunnelAction> {
+        match s {
+            "count" => Ok(TunnelAction::Count),
+            "log" => Ok(TunnelAction::Log),
+            "refuse" => Ok(TunnelAction::Refuse),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "Unknown tunnel detection action"))
+        }
+    }
+}
+
+/// Flags queries with labels that are longer, or more random, than the
+/// configured thresholds
+///
+/// Detection is disabled while `action` is unset.
+pub struct TunnelDetector {
+    pub action: Option<TunnelAction>,
+    pub max_label_len: usize,
+    pub max_entropy: f64,
+    flagged_count: AtomicUsize
+}
+
+impl Default for TunnelDetector {
+    fn default() -> Self {
+        TunnelDetector::new()
+    }
+}
+
+impl TunnelDetector {
+    pub fn new() -> TunnelDetector {
+        TunnelDetector {
+            action: None,
+            max_label_len: DEFAULT_MAX_LABEL_LEN,
+            max_entropy: DEFAULT_MAX_ENTROPY,
+            flagged_count: AtomicUsize::new(0)
+        }
+    }
+
+    /// Whether any label of `qname` exceeds the thresholds
+    pub fn is_suspicious(&self, qname: &str) -> bool {
+        qname.split('.').any(|label| {
+            label.len() > self.max_label_len ||
+                (label.len() >= MIN_ENTROPY_LABEL_LEN && label_entropy(label) > self.max_entropy)
+        })
+    }
+
+    /// Check a query for `qname`, counting and logging it if flagged
+    ///
+    /// Returns whether the query should be refused.
+    pub fn inspect(&self, qname: &str) -> bool {
+        let action = match self.action {
+            Some(x) => x,
+            None => return false
+        };
+
+        if !self.is_suspicious(qname) {
+            return false;
+        }
+
+        let _ = self.flagged_count.fetch_add(1, Ordering::Release);
+
+        if action == TunnelAction::Log {
+            println!("Possible DNS tunneling in query for {}", qname);
+        }
+
+        action == TunnelAction::Refuse
+    }
+
+    pub fn get_flagged_count(&self) -> usize {
+        self.flagged_count.load(Ordering::Acquire)
+    }
+}
+
+/// The Shannon entropy of `label`, in bits per character
+///
+/// Case is ignored, since it's not preserved by all resolvers anyway.
+pub fn label_entropy(label: &str) -> f64 {
+    let mut counts = HashMap::new();
+    for c in label.chars().flat_map(|c| c.to_lowercase()) {
+        *counts.entry(c).or_insert(0usize) += 1;
+    }
+
+    let total = counts.values().fold(0, |acc, x| acc + x) as f64;
+
+    counts.values().fold(0.0, |acc, &count| {
+        let p = count as f64 / total;
+        acc - p * p.log2()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_label_entropy() {
+        assert_eq!(0.0, label_entropy(""));
+        assert_eq!(0.0, label_entropy("aaaa"));
+        assert_eq!(1.0, label_entropy("abAB"));
+        assert_eq!(2.0, label_entropy("abcd"));
+    }
+
+    #[test]
+    fn test_tunnel_detection() {
+        let mut detector = TunnelDetector::new();
+
+        let tunnel = "mzxw6ytboi4dqnzyhe2tsmrvgi3dknjqgu4tmnrs.t.example.com";
+        let long = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.example.com";
+        let normal = "www.google.com";
+
+        // Nothing is flagged while detection is disabled
+        assert!(!detector.inspect(tunnel));
+        assert_eq!(0, detector.get_flagged_count());
+
+        detector.action = Some(TunnelAction::Count);
+
+        assert!(detector.is_suspicious(tunnel));
+        assert!(detector.is_suspicious(long));
+        assert!(!detector.is_suspicious(normal));
+        assert!(!detector.is_suspicious("an-unusually-long-but-ordinary-name.com"));
+
+        assert!(!detector.inspect(tunnel));
+        assert!(!detector.inspect(normal));
+        assert_eq!(1, detector.get_flagged_count());
+
+        // The thresholds are tunable
+        detector.max_label_len = 64;
+        assert!(!detector.is_suspicious(long));
+
+        detector.max_entropy = 5.0;
+        assert!(!detector.is_suspicious(tunnel));
+
+        detector.max_entropy = DEFAULT_MAX_ENTROPY;
+        detector.action = Some(TunnelAction::Refuse);
+        assert!(detector.inspect(tunnel));
+        assert!(!detector.inspect(normal));
+        assert_eq!(2, detector.get_flagged_count());
+    }
+}
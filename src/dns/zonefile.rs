@@ -0,0 +1,669 @@
+//! zones in the master file format of RFC 1035, for backups
+
+use std::fmt::Write;
+use std::io::{Result, Error, ErrorKind};
+
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use rustc_serialize::hex::FromHex;
+
+use dns::authority::Zone;
+use dns::import::build_record;
+use dns::protocol::{DnsRecord, QueryType, TransientTtl};
+
+/// Write `name` as an absolute name
+fn absolute_name(name: &str) -> String {
+    format!("{}.", name)
+}
+
+/// Quote a character-string, escaping quotes and backslashes
+fn quote(data: &str) -> String {
+    let mut result = String::from("\"");
+    for c in data.chars() {
+        if c == '"' || c == '\\' {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result.push('"');
+
+    result
+}
+
+/// The type and data of `rec` as written in a master file, or `None` for
+/// records that don't belong in one
+fn record_data(rec: &DnsRecord) -> Option<(String, String)> {
+    let rtype = format!("{:?}", rec.get_querytype());
+
+    let data = match *rec {
+        DnsRecord::A { addr, .. } => addr.to_string(),
+        DnsRecord::AAAA { addr, .. } => addr.to_string(),
+        DnsRecord::NS { ref host, .. } |
+        DnsRecord::CNAME { ref host, .. } |
+        DnsRecord::PTR { ref host, .. } |
+        DnsRecord::DNAME { ref host, .. } |
+        DnsRecord::ANAME { ref host, .. } => absolute_name(host),
+        DnsRecord::MX { priority, ref host, .. } => format!("{} {}", priority, absolute_name(host)),
+        DnsRecord::SRV { priority, weight, port, ref host, .. } => {
+            format!("{} {} {} {}", priority, weight, port, absolute_name(host))
+        },
+        DnsRecord::NAPTR { order, preference, ref flags, ref services, ref regexp, ref replacement, .. } => {
+            format!("{} {} {} {} {} {}", order, preference, quote(flags), quote(services), quote(regexp), absolute_name(replacement))
+        },
+        DnsRecord::TXT { ref data, .. } |
+        DnsRecord::SPF { ref data, .. } => {
+            data.iter().map(|x| quote(x)).collect::<Vec<String>>().join(" ")
+        },
+        DnsRecord::DHCID { ref data, .. } => data.to_base64(STANDARD),
+        DnsRecord::SSHFP { algorithm, fp_type, ref fingerprint, .. } => format!("{} {} {}", algorithm, fp_type, fingerprint),
+        DnsRecord::TLSA { usage, selector, matching_type, ref cert_data, .. } => {
+            format!("{} {} {} {}", usage, selector, matching_type, cert_data)
+        },
+        DnsRecord::CAA { flags, ref tag, ref value, .. } => format!("{} {} {}", flags, tag, quote(value)),
+        DnsRecord::SOA { ref m_name, ref r_name, serial, refresh, retry, expire, minimum, .. } => {
+            format!("{} {} {} {} {} {} {}",
+                    absolute_name(m_name), absolute_name(r_name),
+                    serial, refresh, retry, expire, minimum)
+        },
+        DnsRecord::UNKNOWN { .. } |
+        DnsRecord::OPT { .. } |
+        DnsRecord::TSIG { .. } => return None
+    };
+
+    Some((rtype, data))
+}
+
+/// Write `zone` as a master file
+///
+/// The SOA comes first, followed by the other records in canonical order, each
+/// on a line of its own with an absolute owner name and an explicit TTL and
+/// class. Pools and health checks aren't part of the format, so they're left
+/// out, and so are records of unknown types.
+pub fn export_zone(zone: &Zone) -> String {
+    let mut output = String::new();
+
+    let _ = writeln!(output, "$ORIGIN {}", absolute_name(&zone.domain));
+    if let Some(ttl) = zone.default_ttl {
+        let _ = writeln!(output, "$TTL {}", ttl);
+    }
+
+    let soa = zone.soa_record();
+    let records = Some(&soa).into_iter()
+        .chain(zone.sorted_records().into_iter().filter(|x| x.get_querytype() != QueryType::SOA));
+
+    for rec in records {
+        let (rtype, data) = match record_data(rec) {
+            Some(x) => x,
+            None => continue
+        };
+
+        let _ = writeln!(output, "{} {} IN {} {}",
+                         absolute_name(&rec.get_domain().unwrap_or_default()),
+                         rec.get_ttl(),
+                         rtype,
+                         data);
+    }
+
+    output
+}
+
+/// Split a line into fields, separated by whitespace
+///
+/// Quoted fields may contain whitespace, and backslash escapes the next
+/// character. Everything after an unquoted `;` is a comment. Unquoted
+/// parentheses separate fields as well, and `depth` keeps track of how many
+/// are open, so that an entry can continue on the following lines.
+fn split_fields(line: &str, depth: &mut u32) -> Result<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = None;
+    let mut in_quotes = false;
+
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(x) => field.get_or_insert_with(String::new).push(x),
+                None => return Err(Error::new(ErrorKind::InvalidData, "Trailing backslash"))
+            },
+            '"' => {
+                in_quotes = !in_quotes;
+                let _ = field.get_or_insert_with(String::new);
+            },
+            ';' if !in_quotes => break,
+            '(' | ')' if !in_quotes => {
+                if let Some(x) = field.take() {
+                    fields.push(x);
+                }
+
+                if c == '(' {
+                    *depth += 1;
+                } else if *depth == 0 {
+                    return Err(Error::new(ErrorKind::InvalidData, "Unbalanced parenthesis"));
+                } else {
+                    *depth -= 1;
+                }
+            },
+            c if c.is_whitespace() && !in_quotes => {
+                if let Some(x) = field.take() {
+                    fields.push(x);
+                }
+            },
+            c => field.get_or_insert_with(String::new).push(c)
+        }
+    }
+
+    if in_quotes {
+        return Err(Error::new(ErrorKind::InvalidData, "Unterminated quote"));
+    }
+
+    if let Some(x) = field {
+        fields.push(x);
+    }
+
+    Ok(fields)
+}
+
+/// Resolve `name` against `origin`, unless it's absolute
+fn resolve_name(name: &str, origin: &str) -> String {
+    if name == "@" {
+        origin.to_string()
+    } else if name.ends_with('.') {
+        name.trim_right_matches('.').to_lowercase()
+    } else if origin.is_empty() {
+        name.to_lowercase()
+    } else {
+        format!("{}.{}", name.to_lowercase(), origin)
+    }
+}
+
+/// Parse a TTL, given either in seconds or with BIND style units, such as
+/// `1h30m`
+fn parse_ttl(value: &str) -> Option<u32> {
+    if let Ok(x) = value.parse::<u32>() {
+        return Some(x);
+    }
+
+    let mut total: u32 = 0;
+    let mut current: Option<u32> = None;
+    for c in value.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            current = match current.unwrap_or(0).checked_mul(10).and_then(|x| x.checked_add(digit)) {
+                Some(x) => Some(x),
+                None => return None
+            };
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return None
+        };
+
+        total = match current.take().and_then(|x| x.checked_mul(unit)).and_then(|x| total.checked_add(x)) {
+            Some(x) => x,
+            None => return None
+        };
+    }
+
+    // A trailing number without a unit is in seconds
+    match current {
+        Some(x) => total.checked_add(x),
+        None if !value.is_empty() => Some(total),
+        None => None
+    }
+}
+
+/// An entry of a master file, which may span several lines
+struct Entry {
+    /// The line the entry starts on
+    line: usize,
+
+    /// Whether the entry starts with whitespace, in which case the owner is
+    /// that of the previous record
+    owner_omitted: bool,
+
+    fields: Vec<String>
+}
+
+/// Split a master file into entries, joining the lines of entries that
+/// continue within parentheses
+fn read_entries(data: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut depth = 0;
+    let mut current: Option<Entry> = None;
+
+    for (idx, line) in data.lines().enumerate() {
+        let fields = match split_fields(line, &mut depth) {
+            Ok(x) => x,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, format!("{} on line {}", e, idx + 1)))
+        };
+
+        match current {
+            Some(ref mut entry) => entry.fields.extend(fields),
+            None if !fields.is_empty() => {
+                current = Some(Entry {
+                    line: idx + 1,
+                    owner_omitted: line.starts_with(|c: char| c.is_whitespace()),
+                    fields: fields
+                });
+            },
+            None => {}
+        }
+
+        if depth == 0 {
+            if let Some(entry) = current.take() {
+                entries.push(entry);
+            }
+        }
+    }
+
+    if depth > 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "Unbalanced parenthesis"));
+    }
+
+    Ok(entries)
+}
+
+/// Read a zone from a master file
+///
+/// Besides records, the `$ORIGIN` and `$TTL` directives and comments are
+/// supported. Records can span several lines inside parentheses, as is common
+/// for the SOA. The owner, TTL and class of records are optional, with
+/// records lacking an owner belonging to that of the previous record, and
+/// TTLs may be given with units, as in `1h`. The zone is named after the
+/// owner of the SOA record, which has to be present.
+pub fn import_zone(data: &str) -> Result<Zone> {
+    let mut origin = String::new();
+    let mut default_ttl = None;
+    let mut zone = None;
+    let mut records = Vec::new();
+    let mut last_domain: Option<String> = None;
+
+    for entry in try!(read_entries(data)) {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, format!("{} on line {}", msg, entry.line));
+
+        let fields = &entry.fields;
+
+        match fields[0].as_str() {
+            "$ORIGIN" => {
+                origin = match fields.get(1) {
+                    Some(x) => resolve_name(x, &origin),
+                    None => return Err(invalid("Missing origin"))
+                };
+                continue;
+            },
+            "$TTL" => {
+                default_ttl = match fields.get(1).and_then(|x| parse_ttl(x)) {
+                    Some(x) => Some(x),
+                    None => return Err(invalid("Invalid TTL"))
+                };
+                continue;
+            },
+            _ => {}
+        }
+
+        let (domain, mut rest) = if entry.owner_omitted {
+            match last_domain {
+                Some(ref x) => (x.clone(), &fields[..]),
+                None => return Err(invalid("Missing owner"))
+            }
+        } else {
+            (resolve_name(&fields[0], &origin), &fields[1..])
+        };
+        last_domain = Some(domain.clone());
+
+        // The TTL and class may come in either order
+        let mut ttl = None;
+        for _ in 0..2 {
+            match rest.first() {
+                Some(x) if ttl.is_none() && parse_ttl(x).is_some() => ttl = parse_ttl(x),
+                Some(x) if x.eq_ignore_ascii_case("IN") => {},
+                _ => break
+            }
+            rest = &rest[1..];
+        }
+
+        let rtype = match rest.first() {
+            Some(x) => x.to_uppercase(),
+            None => return Err(invalid("Missing type"))
+        };
+        let rdata = &rest[1..];
+
+        if rtype == "SOA" {
+            let num = |i: usize| rdata.get(i).and_then(|x| parse_ttl(x));
+            match (rdata.get(0), rdata.get(1), num(2), num(3), num(4), num(5), num(6)) {
+                (Some(m_name), Some(r_name), Some(serial), Some(refresh), Some(retry), Some(expire), Some(minimum)) => {
+                    let mut soa = Zone::new(domain,
+                                            resolve_name(m_name, &origin),
+                                            resolve_name(r_name, &origin));
+                    soa.serial = serial;
+                    soa.refresh = refresh;
+                    soa.retry = retry;
+                    soa.expire = expire;
+                    soa.minimum = minimum;
+                    zone = Some(soa);
+                },
+                _ => return Err(invalid("Invalid SOA record"))
+            }
+            continue;
+        }
+
+        let ttl = match ttl.or(default_ttl) {
+            Some(x) => x,
+            None => return Err(invalid("Missing TTL"))
+        };
+
+        let host = |i: usize| rdata.get(i).map(|x| resolve_name(x, &origin));
+        let num = |i: usize| rdata.get(i).and_then(|x| x.parse::<u16>().ok());
+
+        let rec = match rtype.as_str() {
+            "TXT" => DnsRecord::TXT { domain: domain, data: rdata.to_vec(), ttl: TransientTtl(ttl) },
+            "SPF" => DnsRecord::SPF { domain: domain, data: rdata.to_vec(), ttl: TransientTtl(ttl) },
+            "MX" => match (num(0), host(1)) {
+                (Some(priority), Some(host)) => try!(build_record(&domain, "MX", ttl, &host, Some(priority))),
+                _ => return Err(invalid("Invalid MX record"))
+            },
+            "SRV" => match (num(0), num(1), num(2), host(3)) {
+                (Some(priority), Some(weight), Some(port), Some(host)) => DnsRecord::SRV {
+                    domain: domain,
+                    priority: priority,
+                    weight: weight,
+                    port: port,
+                    host: host,
+                    ttl: TransientTtl(ttl)
+                },
+                _ => return Err(invalid("Invalid SRV record"))
+            },
+            "NAPTR" => match (num(0), num(1), rdata.get(2), rdata.get(3), rdata.get(4), host(5)) {
+                (Some(order), Some(preference), Some(flags), Some(services), Some(regexp), Some(replacement)) => DnsRecord::NAPTR {
+                    domain: domain,
+                    order: order,
+                    preference: preference,
+                    flags: flags.to_string(),
+                    services: services.to_string(),
+                    regexp: regexp.to_string(),
+                    replacement: replacement,
+                    ttl: TransientTtl(ttl)
+                },
+                _ => return Err(invalid("Invalid NAPTR record"))
+            },
+            "DHCID" => match rdata.concat().from_base64() {
+                Ok(data) => DnsRecord::DHCID { domain: domain, data: data, ttl: TransientTtl(ttl) },
+                Err(_) => return Err(invalid("Invalid DHCID record"))
+            },
+            "SSHFP" => match (rdata.get(0).and_then(|x| x.parse::<u8>().ok()),
+                              rdata.get(1).and_then(|x| x.parse::<u8>().ok()),
+                              rdata.get(2..).map(|x| x.concat())) {
+                (Some(algorithm), Some(fp_type), Some(ref fingerprint)) if fingerprint.from_hex().is_ok() => DnsRecord::SSHFP {
+                    domain: domain,
+                    algorithm: algorithm,
+                    fp_type: fp_type,
+                    fingerprint: fingerprint.to_lowercase(),
+                    ttl: TransientTtl(ttl)
+                },
+                _ => return Err(invalid("Invalid SSHFP record"))
+            },
+            "TLSA" => match (rdata.get(0).and_then(|x| x.parse::<u8>().ok()),
+                             rdata.get(1).and_then(|x| x.parse::<u8>().ok()),
+                             rdata.get(2).and_then(|x| x.parse::<u8>().ok()),
+                             rdata.get(3..).map(|x| x.concat())) {
+                (Some(usage), Some(selector), Some(matching_type), Some(ref cert_data)) if cert_data.from_hex().is_ok() => DnsRecord::TLSA {
+                    domain: domain,
+                    usage: usage,
+                    selector: selector,
+                    matching_type: matching_type,
+                    cert_data: cert_data.to_lowercase(),
+                    ttl: TransientTtl(ttl)
+                },
+                _ => return Err(invalid("Invalid TLSA record"))
+            },
+            "CAA" => match (rdata.get(0).and_then(|x| x.parse::<u8>().ok()), rdata.get(1), rdata.get(2)) {
+                (Some(flags), Some(tag), Some(value)) => DnsRecord::CAA {
+                    domain: domain,
+                    flags: flags,
+                    tag: tag.to_lowercase(),
+                    value: value.to_string(),
+                    ttl: TransientTtl(ttl)
+                },
+                _ => return Err(invalid("Invalid CAA record"))
+            },
+            "NS" | "CNAME" | "PTR" | "DNAME" | "ANAME" => match host(0) {
+                Some(host) => try!(build_record(&domain, &rtype, ttl, &host, None)),
+                None => return Err(invalid("Missing host"))
+            },
+            _ => match rdata.first() {
+                Some(value) => match build_record(&domain, &rtype, ttl, value, None) {
+                    Ok(x) => x,
+                    Err(e) => return Err(invalid(&e.to_string()))
+                },
+                None => return Err(invalid("Missing data"))
+            }
+        };
+
+        records.push(rec);
+    }
+
+    let mut zone = match zone {
+        Some(x) => x,
+        None => return Err(Error::new(ErrorKind::InvalidData, "Missing SOA record"))
+    };
+
+    zone.default_ttl = default_ttl;
+    for rec in records {
+        let _ = zone.add_record(&rec);
+    }
+
+    Ok(zone)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn build_zone() -> Zone {
+        let mut zone = Zone::new("example.com".to_string(),
+                                 "ns1.example.com".to_string(),
+                                 "admin.example.com".to_string());
+        zone.serial = 2018010101;
+        zone.refresh = 3600;
+        zone.retry = 600;
+        zone.expire = 86400;
+        zone.minimum = 300;
+        zone.default_ttl = Some(3600);
+
+        let ttl = TransientTtl(3600);
+        let domain = "example.com".to_string();
+
+        zone.add_record(&DnsRecord::NS { domain: domain.clone(), host: "ns1.example.com".to_string(), ttl: ttl });
+        zone.add_record(&DnsRecord::A { domain: "ns1.example.com".to_string(), addr: "192.0.2.1".parse().unwrap(), ttl: ttl });
+        zone.add_record(&DnsRecord::AAAA { domain: "www.example.com".to_string(), addr: "2001:db8::1".parse().unwrap(), ttl: TransientTtl(60) });
+        zone.add_record(&DnsRecord::CNAME { domain: "ftp.example.com".to_string(), host: "www.example.com".to_string(), ttl: ttl });
+        zone.add_record(&DnsRecord::ANAME { domain: domain.clone(), host: "lb.example.net".to_string(), ttl: ttl });
+        zone.add_record(&DnsRecord::MX { domain: domain.clone(), priority: 10, host: "mail.example.com".to_string(), ttl: ttl });
+        zone.add_record(&DnsRecord::SRV {
+            domain: "_sip._udp.example.com".to_string(),
+            priority: 10,
+            weight: 5,
+            port: 5060,
+            host: "sip.example.com".to_string(),
+            ttl: ttl
+        });
+        zone.add_record(&DnsRecord::NAPTR {
+            domain: domain.clone(),
+            order: 100,
+            preference: 10,
+            flags: "S".to_string(),
+            services: "SIP+D2U".to_string(),
+            regexp: String::new(),
+            replacement: "_sip._udp.example.com".to_string(),
+            ttl: ttl
+        });
+        zone.add_record(&DnsRecord::TXT {
+            domain: domain.clone(),
+            data: vec!["v=spf1 -all".to_string(), "say \"hi\"; or \\ not".to_string()],
+            ttl: ttl
+        });
+        zone.add_record(&DnsRecord::DHCID { domain: "client.example.com".to_string(), data: vec![0, 1, 2, 254, 255], ttl: ttl });
+        zone.add_record(&DnsRecord::SSHFP {
+            domain: "www.example.com".to_string(),
+            algorithm: 4,
+            fp_type: 2,
+            fingerprint: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string(),
+            ttl: ttl
+        });
+        zone.add_record(&DnsRecord::TLSA {
+            domain: "_443._tcp.www.example.com".to_string(),
+            usage: 3,
+            selector: 1,
+            matching_type: 1,
+            cert_data: "d2abde240d7cd3ee6b4b28c54df034b97983a1d16e8a410e4561cb106618e971".to_string(),
+            ttl: ttl
+        });
+        zone.add_record(&DnsRecord::CAA {
+            domain: domain.clone(),
+            flags: 128,
+            tag: "issue".to_string(),
+            value: "letsencrypt.org; validationmethods=dns-01".to_string(),
+            ttl: ttl
+        });
+
+        zone
+    }
+
+    #[test]
+    fn test_export_zone() {
+        let output = export_zone(&build_zone());
+        let lines = output.lines().collect::<Vec<&str>>();
+
+        assert_eq!("$ORIGIN example.com.", lines[0]);
+        assert_eq!("$TTL 3600", lines[1]);
+        assert_eq!("example.com. 300 IN SOA ns1.example.com. admin.example.com. 2018010101 3600 600 86400 300", lines[2]);
+        assert!(lines.contains(&"example.com. 3600 IN MX 10 mail.example.com."));
+        assert!(lines.contains(&"www.example.com. 60 IN AAAA 2001:db8::1"));
+        assert!(lines.contains(&r#"example.com. 3600 IN TXT "v=spf1 -all" "say \"hi\"; or \\ not""#));
+        assert!(lines.contains(&r#"example.com. 3600 IN CAA 128 issue "letsencrypt.org; validationmethods=dns-01""#));
+        assert!(lines.contains(&"www.example.com. 3600 IN SSHFP 4 2 e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"));
+    }
+
+    #[test]
+    fn test_zone_roundtrip() {
+        let zone = build_zone();
+        let imported = import_zone(&export_zone(&zone)).unwrap();
+
+        assert_eq!(zone.domain, imported.domain);
+        assert_eq!(zone.soa_record(), imported.soa_record());
+        assert_eq!(zone.default_ttl, imported.default_ttl);
+        assert_eq!(zone.records, imported.records);
+
+        // Records compare equal regardless of their TTL
+        let ttls = |zone: &Zone| zone.records.iter().map(|x| x.get_ttl()).collect::<Vec<u32>>();
+        assert_eq!(ttls(&zone), ttls(&imported));
+    }
+
+    #[test]
+    fn test_import_bind_zone() {
+        let zone = import_zone("$TTL 1d
+$ORIGIN example.org.
+@   IN  SOA ns1.example.org. hostmaster.example.org. (
+            2018010101  ; serial
+            3h          ; refresh
+            15m         ; retry
+            1w          ; expire
+            1h )        ; minimum
+    IN  NS      ns1
+    IN  NS      ns2.example.net.
+    IN  MX      10 mail
+            IN  TXT     \"v=spf1 mx -all\"
+ns1     IN  A       192.0.2.1
+www     300 IN  A   192.0.2.2
+        IN 300 AAAA 2001:db8::2
+ftp     IN  CNAME   www
+_sip._tcp   SRV ( 10 5 5060
+                  sip.example.org. )
+").unwrap();
+
+        assert_eq!("example.org", zone.domain);
+        assert_eq!("ns1.example.org", zone.m_name);
+        assert_eq!("hostmaster.example.org", zone.r_name);
+        assert_eq!(2018010101, zone.serial);
+        assert_eq!(10800, zone.refresh);
+        assert_eq!(900, zone.retry);
+        assert_eq!(604800, zone.expire);
+        assert_eq!(3600, zone.minimum);
+        assert_eq!(Some(86400), zone.default_ttl);
+
+        let expected = vec![
+            DnsRecord::NS { domain: "example.org".to_string(), host: "ns1.example.org".to_string(), ttl: TransientTtl(86400) },
+            DnsRecord::NS { domain: "example.org".to_string(), host: "ns2.example.net".to_string(), ttl: TransientTtl(86400) },
+            DnsRecord::MX { domain: "example.org".to_string(), priority: 10, host: "mail.example.org".to_string(), ttl: TransientTtl(86400) },
+            DnsRecord::TXT { domain: "example.org".to_string(), data: vec!["v=spf1 mx -all".to_string()], ttl: TransientTtl(86400) },
+            DnsRecord::A { domain: "ns1.example.org".to_string(), addr: "192.0.2.1".parse().unwrap(), ttl: TransientTtl(86400) },
+            DnsRecord::A { domain: "www.example.org".to_string(), addr: "192.0.2.2".parse().unwrap(), ttl: TransientTtl(300) },
+            DnsRecord::AAAA { domain: "www.example.org".to_string(), addr: "2001:db8::2".parse().unwrap(), ttl: TransientTtl(300) },
+            DnsRecord::CNAME { domain: "ftp.example.org".to_string(), host: "www.example.org".to_string(), ttl: TransientTtl(86400) },
+            DnsRecord::SRV {
+                domain: "_sip._tcp.example.org".to_string(),
+                priority: 10,
+                weight: 5,
+                port: 5060,
+                host: "sip.example.org".to_string(),
+                ttl: TransientTtl(86400)
+            }
+        ];
+
+        assert_eq!(expected.len(), zone.records.len());
+        for rec in &expected {
+            assert!(zone.records.contains(rec), "missing {:?}", rec);
+        }
+
+        // TTLs don't take part in comparisons, so check them separately
+        let www_ttls = zone.records.iter()
+            .filter(|x| x.get_domain() == Some("www.example.org".to_string()))
+            .map(|x| x.get_ttl())
+            .collect::<Vec<u32>>();
+        assert_eq!(vec![300, 300], www_ttls);
+
+        // and the zone survives a round trip through the export
+        let reimported = import_zone(&export_zone(&zone)).unwrap();
+        assert_eq!(zone.records, reimported.records);
+        assert_eq!(zone.soa_record(), reimported.soa_record());
+    }
+
+    #[test]
+    fn test_import_zone() {
+        let zone = import_zone("; a hand written zone
+$ORIGIN example.com.
+$TTL 300
+@ IN SOA ns1 admin 1 2 3 4 5
+www A 192.0.2.1 ; the web server
+mail 60 IN A 192.0.2.2
+").unwrap();
+
+        assert_eq!("example.com", zone.domain);
+        assert_eq!("ns1.example.com", zone.m_name);
+        assert_eq!(5, zone.minimum);
+        assert!(zone.records.contains(&DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl: TransientTtl(300)
+        }));
+        assert!(zone.records.contains(&DnsRecord::A {
+            domain: "mail.example.com".to_string(),
+            addr: "192.0.2.2".parse().unwrap(),
+            ttl: TransientTtl(60)
+        }));
+
+        assert!(import_zone("www.example.com. 300 IN A 192.0.2.1").is_err());
+        assert!(import_zone("example.com. 300 IN SOA ns1 admin ( 1 2 3 4 5").is_err());
+        assert!(import_zone("example.com. 300 IN SOA ns1 admin 1 2 3 4 5 )").is_err());
+        assert!(import_zone("example.com. 300 IN SOA ns1 admin 1 2 3 4 5\n  1h IN A 192.0.2.1").is_ok());
+        assert!(import_zone("  300 IN A 192.0.2.1\nexample.com. 300 IN SOA ns1 admin 1 2 3 4 5").is_err());
+        assert!(import_zone("example.com. 300 IN SOA ns1 admin 1 2 3").is_err());
+        assert!(import_zone("example.com. 300 IN SOA ns1 admin 1 2 3 4 5\nwww A 192.0.2.1").is_err());
+        assert!(import_zone("example.com. 300 IN SOA ns1 admin 1 2 3 4 5\nwww 300 TXT \"unterminated").is_err());
+    }
+}
@@ -0,0 +1,21 @@
+//! hermes documentation
+
+#![feature(plugin)]
+#![plugin(clippy)]
+
+pub mod dns;
+pub mod web;
+
+extern crate rand;
+extern crate chrono;
+extern crate tiny_http;
+extern crate rustc_serialize;
+extern crate ascii;
+extern crate handlebars;
+extern crate regex;
+extern crate sha2;
+extern crate hmac;
+
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
@@ -1,33 +1,47 @@
-//! hermes documentation
+//! hermes command line interface
 
 #![feature(plugin)]
 #![plugin(clippy)]
 
-pub mod dns;
-pub mod web;
-
-extern crate rand;
-extern crate chrono;
-extern crate tiny_http;
-extern crate rustc_serialize;
-extern crate ascii;
-extern crate handlebars;
-extern crate regex;
+extern crate hermes;
 extern crate getopts;
 
 use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
 
 use getopts::Options;
 
-use dns::server::{DnsServer,DnsUdpServer,DnsTcpServer};
-use dns::protocol::{DnsRecord,TransientTtl};
-use dns::context::{ServerContext, ResolveStrategy};
-use web::server::WebServer;
-use web::cache::CacheAction;
-use web::authority::{AuthorityAction,ZoneAction};
-use web::index::IndexAction;
+use hermes::dns::server::{DnsServer,DnsUdpServer,DnsTcpServer};
+use hermes::dns::protocol::{DnsRecord,TransientTtl,ResultCode,QueryType};
+use hermes::dns::answermap::AnswerMap;
+use hermes::dns::client::DnsNetworkClient;
+use hermes::dns::authority::Zone;
+use hermes::dns::context::{ServerContext, ResolveStrategy, AnyPolicy};
+use hermes::dns::blocklist::{Blocklist, BlockPolicy};
+use hermes::dns::import::import_csv;
+use hermes::dns::zonefile::import_zone;
+use hermes::dns::netutil::Network;
+use hermes::dns::querylog::{IpDatabase, ClientInfoLookup};
+use hermes::dns::tsig::TsigKey;
+use hermes::dns::health::start_health_checker;
+use hermes::dns::cache::start_cache_cleaner;
+use hermes::dns::cachelog::start_cache_logger;
+use hermes::dns::tunnel::TunnelAction;
+use hermes::dns::ratelimit::RateLimitAction;
+use hermes::web::server::WebServer;
+use hermes::web::cache::CacheAction;
+use hermes::web::authority::{AuthorityAction,ZoneAction,PoolAction,TransferAction,ZoneExportAction};
+use hermes::web::index::IndexAction;
+use hermes::web::metrics::MetricsAction;
+use hermes::web::resolve::ResolveAction;
+use hermes::web::doh::DohAction;
+use hermes::web::blocklist::{BlocklistAction,BlockedDomainAction};
+use hermes::web::config::ConfigAction;
 
 fn print_usage(program: &str, opts: Options) {
     let brief = format!("Usage: {} [options]", program);
@@ -43,6 +57,57 @@ fn main() {
     opts.optflag("h", "help", "print this help menu");
     opts.optflag("a", "authority", "disable support for recursive lookups, and serve only local zones");
     opts.optopt("f", "forward", "forward replies to specified dns server", "SERVER");
+    opts.optopt("", "answer-map", "answer only from FILE, a JSON list of names and types with their answers, for testing", "FILE");
+    opts.optopt("", "any-policy", "response to ANY queries over UDP: full, minimal or tc-on-udp", "POLICY");
+    opts.optopt("", "unsupported-opcode", "response code for requests other than standard queries: notimp or refused (default notimp)", "RCODE");
+    opts.optopt("", "whoami", "answer TXT queries for NAME with the address of the client", "NAME");
+    opts.optopt("", "max-upstream", "maximum number of concurrent upstream queries", "COUNT");
+    opts.optopt("", "max-upstream-queue", "maximum number of upstream queries waiting to be sent", "COUNT");
+    opts.optflag("", "randomize-source-port", "send each upstream query over UDP from a random source port");
+    opts.optflag("", "randomize-case", "randomize the case of the name in upstream queries over UDP, and reject responses which don't echo it exactly");
+    opts.optflag("", "resolve-any-upstream", "let the /resolve endpoint query upstreams on any port, rather than only port 53 and the forwarder");
+    opts.optopt("", "query-timeout", "seconds to wait for a response to an upstream query over UDP (default 3)", "SECONDS");
+    opts.optopt("", "servfail-ttl", "seconds to remember failed upstream lookups, or 0 to disable (default 5)", "SECONDS");
+    opts.optopt("", "max-udp-queue", "maximum number of UDP queries waiting to be serviced, beyond which SERVFAIL is returned (default 1024)", "COUNT");
+    opts.optopt("", "tcp-idle-timeout", "seconds before closing an idle TCP connection (default 10)", "SECONDS");
+    opts.optopt("", "tcp-max-lifetime", "seconds before closing a TCP connection, however active (default 120)", "SECONDS");
+    opts.optopt("", "max-tcp-connections", "maximum number of open TCP connections, beyond which the idlest is closed (default 256)", "COUNT");
+    opts.optopt("", "max-tcp-connections-per-client", "maximum number of open TCP connections from a single client (default 16)", "COUNT");
+    opts.optopt("", "tcp-keepalive", "idle timeout in seconds for clients using EDNS TCP keepalive (default 30)", "SECONDS");
+    opts.optopt("", "health-check-interval", "seconds between health checks of records that have one (default 10)", "SECONDS");
+    opts.optopt("", "cache-log-interval", "log a snapshot of the cache statistics every given number of seconds", "SECONDS");
+    opts.optopt("", "cache-file", "save the cache to PATH periodically, and load it from there on startup", "PATH");
+    opts.optopt("", "cache-size", "maximum number of domains in the cache, beyond which the least recently used are evicted", "COUNT");
+    opts.optflag("", "response-cache", "reuse serialized responses for authoritative answers");
+    opts.optflag("", "round-robin", "rotate the order of addresses in authoritative answers between queries");
+    opts.optflag("", "strip-additional", "remove all but the OPT record from the additional section of responses");
+    opts.optflag("", "log-dropped-responses", "log packets with the QR bit set, which are dropped rather than answered");
+    opts.optmulti("", "pin", "keep the records of NAME in the cache, serving them past their expiry if they can't be refreshed", "NAME[:TYPE]");
+    opts.optmulti("", "sortlist", "answer clients on NETWORK with the addresses on NETWORK first", "NETWORK");
+    opts.optmulti("", "allow-transfer", "allow zone transfers from NETWORK, for zones without their own list", "NETWORK");
+    opts.optmulti("", "tsig-key", "add the TSIG key NAME for zone transfers, with KEY a base64 encoded hmac-sha256 secret", "[ALG:]NAME:KEY");
+    opts.optmulti("", "allow-transfer-key", "allow zone transfers signed with the TSIG key NAME, for zones without their own list", "NAME");
+    opts.optmulti("", "secondary", "serve ZONE as a secondary, transferred from the PRIMARY ip and port", "ZONE@PRIMARY");
+    opts.optmulti("", "secondary-key", "sign transfers of the secondary ZONE with the TSIG key NAME", "ZONE@NAME");
+    opts.optopt("", "query-log", "append a JSON line for each query to FILE", "FILE");
+    opts.optopt("", "ip-database", "enrich the query log with the ASN and country of clients, from a CSV of network,asn,country", "FILE");
+    opts.optopt("", "default-ttl", "TTL for records added without one, in zones without a default (default 3600)", "SECONDS");
+    opts.optflag("", "lowercase-names", "lowercase owner names of records, in zones without their own setting");
+    opts.optflag("", "manual-serial", "leave zone serials alone when records are added or deleted, rather than incrementing them");
+    opts.optopt("", "max-zones", "maximum number of zones, beyond which adding a zone fails (default 10000)", "COUNT");
+    opts.optopt("", "max-records-per-zone", "maximum number of records in a zone, beyond which adding a record fails (default 100000)", "COUNT");
+    opts.optmulti("", "import-csv", "import records from FILE, with the columns zone,name,type,ttl,value[,priority]", "FILE");
+    opts.optmulti("", "import-zone", "import a zone from FILE, in the BIND master file format, replacing any zone of the same name", "FILE");
+    opts.optmulti("b", "blocklist", "block the domains listed in FILE, one per line", "FILE");
+    opts.optopt("", "runtime-blocklist", "persist domains blocked through the API to FILE", "FILE");
+    opts.optopt("", "block-policy", "response to blocked names: nxdomain, refused, nodata or sinkhole:IP", "POLICY");
+    opts.optopt("", "tunnel-detection", "flag queries with long or random looking labels, as used for DNS tunneling, and count, log or refuse them", "ACTION");
+    opts.optopt("", "tunnel-max-label-length", "length above which labels are flagged as tunneling (default 40)", "LENGTH");
+    opts.optopt("", "tunnel-max-entropy", "entropy in bits per character above which labels are flagged as tunneling (default 4.0)", "BITS");
+    opts.optopt("", "rate-limit", "answer at most QPS queries a second over UDP from each client", "QPS");
+    opts.optopt("", "rate-limit-burst", "queries a client may send at once before the rate limit applies (default 20)", "COUNT");
+    opts.optopt("", "rate-limit-action", "response to clients over the rate limit: drop or truncate (default drop)", "ACTION");
+    opts.optopt("", "api-auth", "require HTTP Basic auth with the given credentials for API requests which change anything", "USER:PASSWORD");
 
     let opt_matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
@@ -76,10 +141,459 @@ fn main() {
             }
         }
 
+        if let Some(filename) = opt_matches.opt_str("answer-map") {
+            match AnswerMap::load(&filename) {
+                Ok(map) => {
+                    println!("Answering from {} entries in {}", map.len(), filename);
+                    ctx.answer_map = map;
+                    ctx.resolve_strategy = ResolveStrategy::Static;
+                    index_rootservers = false;
+                },
+                Err(e) => {
+                    println!("Failed to load answer map {}: {}", filename, e);
+                    return;
+                }
+            }
+        }
+
         if opt_matches.opt_present("a") {
             ctx.allow_recursive = false;
         }
 
+        if let Some(policy_str) = opt_matches.opt_str("any-policy") {
+            match policy_str.parse::<AnyPolicy>() {
+                Ok(policy) => ctx.any_policy = policy,
+                Err(e) => {
+                    println!("Invalid ANY policy: {}", e);
+                    return;
+                }
+            }
+        }
+
+        if let Some(rescode_str) = opt_matches.opt_str("unsupported-opcode") {
+            match rescode_str.as_str() {
+                "notimp" => ctx.unsupported_opcode_rescode = ResultCode::NOTIMP,
+                "refused" => ctx.unsupported_opcode_rescode = ResultCode::REFUSED,
+                _ => {
+                    println!("Response code for unsupported opcodes must be notimp or refused");
+                    return;
+                }
+            }
+        }
+
+        if let Some(count_str) = opt_matches.opt_str("max-upstream") {
+            match count_str.parse::<usize>() {
+                Ok(count) if count > 0 => ctx.query_limiter.max_concurrent = count,
+                _ => {
+                    println!("Maximum number of upstream queries must be a positive number");
+                    return;
+                }
+            }
+        }
+
+        if let Some(count_str) = opt_matches.opt_str("max-upstream-queue") {
+            match count_str.parse::<usize>() {
+                Ok(count) => ctx.query_limiter.max_queued = count,
+                Err(_) => {
+                    println!("Maximum upstream queue size must be a number");
+                    return;
+                }
+            }
+        }
+
+        if let Some(ttl_str) = opt_matches.opt_str("servfail-ttl") {
+            match ttl_str.parse::<u32>() {
+                Ok(ttl) => ctx.servfail_ttl = ttl,
+                Err(_) => {
+                    println!("SERVFAIL TTL must be a number");
+                    return;
+                }
+            }
+        }
+
+        if let Some(ttl_str) = opt_matches.opt_str("default-ttl") {
+            match ttl_str.parse::<u32>() {
+                Ok(ttl) => ctx.default_ttl = ttl,
+                Err(_) => {
+                    println!("Default TTL must be a number");
+                    return;
+                }
+            }
+        }
+
+        if let Some(count_str) = opt_matches.opt_str("max-udp-queue") {
+            match count_str.parse::<usize>() {
+                Ok(count) => ctx.max_udp_queue = count,
+                Err(_) => {
+                    println!("Maximum UDP queue size must be a number");
+                    return;
+                }
+            }
+        }
+
+        if let Some(timeout_str) = opt_matches.opt_str("tcp-idle-timeout") {
+            match timeout_str.parse::<u64>() {
+                Ok(timeout) if timeout > 0 => ctx.tcp_idle_timeout = Duration::from_secs(timeout),
+                _ => {
+                    println!("TCP idle timeout must be a positive number");
+                    return;
+                }
+            }
+        }
+
+        if let Some(timeout_str) = opt_matches.opt_str("tcp-max-lifetime") {
+            match timeout_str.parse::<u64>() {
+                Ok(timeout) if timeout > 0 => ctx.tcp_max_lifetime = Duration::from_secs(timeout),
+                _ => {
+                    println!("TCP connection lifetime must be a positive number");
+                    return;
+                }
+            }
+        }
+
+        if let Some(count_str) = opt_matches.opt_str("max-tcp-connections") {
+            match count_str.parse::<usize>() {
+                Ok(count) if count > 0 => ctx.tcp_connections.max_connections = count,
+                _ => {
+                    println!("Maximum number of TCP connections must be a positive number");
+                    return;
+                }
+            }
+        }
+
+        if let Some(count_str) = opt_matches.opt_str("max-tcp-connections-per-client") {
+            match count_str.parse::<usize>() {
+                Ok(count) if count > 0 => ctx.tcp_connections.max_per_client = count,
+                _ => {
+                    println!("Maximum number of TCP connections per client must be a positive number");
+                    return;
+                }
+            }
+        }
+
+        if let Some(interval_str) = opt_matches.opt_str("health-check-interval") {
+            match interval_str.parse::<u64>() {
+                Ok(interval) if interval > 0 => ctx.health_check_interval = Duration::from_secs(interval),
+                _ => {
+                    println!("Health check interval must be a positive number");
+                    return;
+                }
+            }
+        }
+
+        if let Some(interval_str) = opt_matches.opt_str("cache-log-interval") {
+            match interval_str.parse::<u64>() {
+                Ok(interval) if interval > 0 => ctx.cache_log_interval = Some(Duration::from_secs(interval)),
+                _ => {
+                    println!("Cache log interval must be a positive number");
+                    return;
+                }
+            }
+        }
+
+        if let Some(filename) = opt_matches.opt_str("cache-file") {
+            let path = PathBuf::from(filename);
+            if path.exists() {
+                match ctx.cache.load_from(&path) {
+                    Ok(count) => println!("Loaded {} cached records from {:?}", count, path),
+                    Err(e) => println!("Failed to load cache from {:?}: {:?}", path, e)
+                }
+            }
+            ctx.cache_path = Some(path);
+        }
+
+        if let Some(size_str) = opt_matches.opt_str("cache-size") {
+            match size_str.parse::<usize>() {
+                Ok(size) if size > 0 => { let _ = ctx.cache.set_capacity(size); },
+                _ => {
+                    println!("Cache size must be a positive number");
+                    return;
+                }
+            }
+        }
+
+        for pin in opt_matches.opt_strs("pin") {
+            let mut parts = pin.splitn(2, ':');
+            let name = parts.next().unwrap_or("").trim_right_matches('.');
+            let qtype = match parts.next().map(|x| x.to_uppercase().parse::<QueryType>()) {
+                Some(Ok(qtype)) => qtype,
+                Some(Err(_)) => {
+                    println!("Invalid type for pinned entry {}", pin);
+                    return;
+                },
+                None => QueryType::A
+            };
+
+            let _ = ctx.cache.pin(name, qtype);
+        }
+
+        if let Some(timeout_str) = opt_matches.opt_str("tcp-keepalive") {
+            match timeout_str.parse::<u64>() {
+                Ok(timeout) if timeout > 0 => ctx.tcp_keepalive_timeout = Duration::from_secs(timeout),
+                _ => {
+                    println!("TCP keepalive timeout must be a positive number");
+                    return;
+                }
+            }
+        }
+
+        if let Some(action_str) = opt_matches.opt_str("tunnel-detection") {
+            match action_str.parse::<TunnelAction>() {
+                Ok(action) => ctx.tunnel_detector.action = Some(action),
+                Err(e) => {
+                    println!("Invalid tunnel detection action: {}", e);
+                    return;
+                }
+            }
+        }
+
+        if let Some(len_str) = opt_matches.opt_str("tunnel-max-label-length") {
+            match len_str.parse::<usize>() {
+                Ok(len) if len > 0 => ctx.tunnel_detector.max_label_len = len,
+                _ => {
+                    println!("Maximum label length must be a positive number");
+                    return;
+                }
+            }
+        }
+
+        if let Some(entropy_str) = opt_matches.opt_str("tunnel-max-entropy") {
+            match entropy_str.parse::<f64>() {
+                Ok(entropy) if entropy > 0.0 => ctx.tunnel_detector.max_entropy = entropy,
+                _ => {
+                    println!("Maximum entropy must be a positive number");
+                    return;
+                }
+            }
+        }
+
+        if let Some(rate_str) = opt_matches.opt_str("rate-limit") {
+            match rate_str.parse::<u32>() {
+                Ok(rate) if rate > 0 => ctx.rate_limiter.queries_per_second = Some(rate),
+                _ => {
+                    println!("Rate limit must be a positive number");
+                    return;
+                }
+            }
+        }
+
+        if let Some(burst_str) = opt_matches.opt_str("rate-limit-burst") {
+            match burst_str.parse::<u32>() {
+                Ok(burst) if burst > 0 => ctx.rate_limiter.burst = burst,
+                _ => {
+                    println!("Rate limit burst must be a positive number");
+                    return;
+                }
+            }
+        }
+
+        if let Some(action_str) = opt_matches.opt_str("rate-limit-action") {
+            match action_str.parse::<RateLimitAction>() {
+                Ok(action) => ctx.rate_limiter.action = action,
+                Err(e) => {
+                    println!("Invalid rate limit action: {}", e);
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("response-cache") {
+            ctx.enable_response_cache = true;
+        }
+
+        if opt_matches.opt_present("randomize-source-port") {
+            ctx.client = Box::new(DnsNetworkClient::with_random_ports());
+        }
+
+        if opt_matches.opt_present("randomize-case") {
+            ctx.client.set_randomize_case(true);
+        }
+
+        if opt_matches.opt_present("resolve-any-upstream") {
+            ctx.resolve_any_upstream = true;
+        }
+
+        if let Some(timeout_str) = opt_matches.opt_str("query-timeout") {
+            match timeout_str.parse::<u64>() {
+                Ok(timeout) if timeout > 0 => ctx.client.set_query_timeout(Duration::from_secs(timeout)),
+                _ => {
+                    println!("Query timeout must be a positive number");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("round-robin") {
+            ctx.round_robin = true;
+        }
+
+        if opt_matches.opt_present("strip-additional") {
+            ctx.strip_additional = true;
+        }
+
+        if opt_matches.opt_present("log-dropped-responses") {
+            ctx.log_dropped_responses = true;
+        }
+
+        for network in opt_matches.opt_strs("sortlist") {
+            match network.parse::<Network>() {
+                Ok(network) => ctx.sortlist.push(network),
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            }
+        }
+
+        for network in opt_matches.opt_strs("allow-transfer") {
+            match network.parse::<Network>() {
+                Ok(network) => ctx.allow_transfer.push(network),
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            }
+        }
+
+        for key in opt_matches.opt_strs("tsig-key") {
+            match key.parse::<TsigKey>() {
+                Ok(key) => ctx.tsig_keys.push(key),
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            }
+        }
+
+        for name in opt_matches.opt_strs("allow-transfer-key") {
+            if !ctx.tsig_keys.iter().any(|x| x.name == name.to_lowercase()) {
+                println!("Unknown TSIG key {}", name);
+                return;
+            }
+
+            ctx.allow_transfer_keys.push(name.to_lowercase());
+        }
+
+        if let Some(name) = opt_matches.opt_str("whoami") {
+            ctx.whoami_name = Some(name);
+        }
+
+        if let Some(filename) = opt_matches.opt_str("query-log") {
+            let lookup = match opt_matches.opt_str("ip-database") {
+                Some(db_filename) => match IpDatabase::load(&db_filename) {
+                    Ok(db) => {
+                        println!("Loaded {} networks from {}", db.len(), db_filename);
+                        Some(Box::new(db) as Box<ClientInfoLookup + Send>)
+                    },
+                    Err(e) => {
+                        println!("Failed to load ip database {}: {:?}", db_filename, e);
+                        return;
+                    }
+                },
+                None => None
+            };
+
+            let output = match OpenOptions::new().create(true).append(true).open(&filename) {
+                Ok(x) => x,
+                Err(e) => {
+                    println!("Failed to open query log {}: {:?}", filename, e);
+                    return;
+                }
+            };
+
+            if let Err(e) = ctx.query_log.start(output, lookup) {
+                println!("Failed to start query log: {:?}", e);
+                return;
+            }
+        } else if opt_matches.opt_present("ip-database") {
+            println!("An ip database is only used together with --query-log");
+        }
+
+        if let Some(policy_str) = opt_matches.opt_str("block-policy") {
+            match policy_str.parse::<BlockPolicy>() {
+                Ok(policy) => ctx.blocklists.default_policy = policy,
+                Err(e) => {
+                    println!("Invalid block policy: {}", e);
+                    return;
+                }
+            }
+        }
+
+        if let Some(filename) = opt_matches.opt_str("runtime-blocklist") {
+            ctx.blocklists.runtime_path = Some(filename.into());
+            if let Err(e) = ctx.blocklists.load_runtime() {
+                println!("Failed to load runtime blocklist: {:?}", e);
+                return;
+            }
+        }
+
+        for filename in opt_matches.opt_strs("b") {
+            match Blocklist::load(&filename, None).and_then(|x| ctx.blocklists.add_list(x)) {
+                Ok(_) => println!("Loaded blocklist {}", filename),
+                Err(e) => {
+                    println!("Failed to load blocklist {}: {:?}", filename, e);
+                    return;
+                }
+            }
+        }
+
+        // Zones are normalized as they're loaded, so this has to be set first
+        if opt_matches.opt_present("lowercase-names") {
+            match ctx.authority.write() {
+                Ok(mut zones) => zones.lowercase_names = true,
+                Err(_) => {
+                    println!("Failed to access authority");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("manual-serial") {
+            match ctx.authority.write() {
+                Ok(mut zones) => zones.auto_serial = false,
+                Err(_) => {
+                    println!("Failed to access authority");
+                    return;
+                }
+            }
+        }
+
+        if let Some(count_str) = opt_matches.opt_str("max-zones") {
+            let count = match count_str.parse::<usize>() {
+                Ok(count) if count > 0 => count,
+                _ => {
+                    println!("Maximum number of zones must be a positive number");
+                    return;
+                }
+            };
+
+            match ctx.authority.write() {
+                Ok(mut zones) => zones.max_zones = count,
+                Err(_) => {
+                    println!("Failed to access authority");
+                    return;
+                }
+            }
+        }
+
+        if let Some(count_str) = opt_matches.opt_str("max-records-per-zone") {
+            let count = match count_str.parse::<usize>() {
+                Ok(count) if count > 0 => count,
+                _ => {
+                    println!("Maximum number of records per zone must be a positive number");
+                    return;
+                }
+            };
+
+            match ctx.authority.write() {
+                Ok(mut zones) => zones.max_records_per_zone = count,
+                Err(_) => {
+                    println!("Failed to access authority");
+                    return;
+                }
+            }
+        }
+
         match ctx.initialize() {
             Ok(_) => {},
             Err(e) => {
@@ -88,16 +602,164 @@ fn main() {
             }
         }
 
+        let csv_files = opt_matches.opt_strs("import-csv");
+        if !csv_files.is_empty() {
+            let mut zones = match ctx.authority.write() {
+                Ok(x) => x,
+                Err(_) => {
+                    println!("Failed to access authority");
+                    return;
+                }
+            };
+
+            for filename in csv_files {
+                let mut data = String::new();
+                if let Err(e) = File::open(&filename).and_then(|mut x| x.read_to_string(&mut data)) {
+                    println!("Failed to read {}: {:?}", filename, e);
+                    return;
+                }
+
+                let report = import_csv(&mut zones, &data, ctx.default_ttl);
+                println!("Imported {} records from {}, creating {} zones",
+                         report.added, filename, report.zones_created);
+                for (line, error) in report.errors {
+                    println!("{}:{}: {}", filename, line, error);
+                }
+            }
+
+            match zones.save() {
+                Ok(_) => println!("Zones saved!"),
+                Err(e) =>  println!("Zone Saving failed: {:?}", e)
+            }
+        }
+
+        let zone_files = opt_matches.opt_strs("import-zone");
+        if !zone_files.is_empty() {
+            let mut zones = match ctx.authority.write() {
+                Ok(x) => x,
+                Err(_) => {
+                    println!("Failed to access authority");
+                    return;
+                }
+            };
+
+            for filename in zone_files {
+                let mut data = String::new();
+                if let Err(e) = File::open(&filename).and_then(|mut x| x.read_to_string(&mut data)) {
+                    println!("Failed to read {}: {:?}", filename, e);
+                    return;
+                }
+
+                let zone = match import_zone(&data) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        println!("{}: {}", filename, e);
+                        return;
+                    }
+                };
+
+                println!("Imported zone {} with {} records from {}", zone.domain, zone.records.len(), filename);
+                if let Err(e) = zones.add_zone(zone) {
+                    println!("Failed to add zone from {}: {}", filename, e);
+                    return;
+                }
+            }
+
+            match zones.save() {
+                Ok(_) => println!("Zones saved!"),
+                Err(e) =>  println!("Zone Saving failed: {:?}", e)
+            }
+        }
+
+        for secondary in opt_matches.opt_strs("secondary") {
+            let parts = secondary.splitn(2, '@').collect::<Vec<&str>>();
+            let primary = match parts.get(1).and_then(|x| x.parse::<SocketAddr>().ok()) {
+                Some(x) => x,
+                None => {
+                    println!("Secondary zones must be specified as ZONE@IP:PORT");
+                    return;
+                }
+            };
+
+            match ctx.authority.write() {
+                Ok(mut zones) => {
+                    if zones.get_zone(parts[0]).is_none() {
+                        let zone = Zone::new(parts[0].to_string(), String::new(), String::new());
+                        if let Err(e) = zones.add_zone(zone) {
+                            println!("Failed to add secondary zone {}: {:?}", parts[0], e);
+                            return;
+                        }
+                    }
+
+                    if let Some(zone) = zones.get_zone_mut(parts[0]) {
+                        zone.primary = Some(primary);
+                    }
+                },
+                Err(_) => {
+                    println!("Failed to access authority");
+                    return;
+                }
+            }
+        }
+
+        for secondary_key in opt_matches.opt_strs("secondary-key") {
+            let parts = secondary_key.splitn(2, '@').collect::<Vec<&str>>();
+            let key = match parts.get(1).and_then(|name| ctx.tsig_keys.iter().find(|x| x.name == name.to_lowercase())) {
+                Some(x) => x.clone(),
+                None => {
+                    println!("Secondary keys must be specified as ZONE@NAME, with NAME a TSIG key");
+                    return;
+                }
+            };
+
+            match ctx.authority.write() {
+                Ok(mut zones) => {
+                    match zones.get_zone_mut(parts[0]) {
+                        Some(ref mut zone) if zone.is_secondary() => zone.primary_key = Some(key),
+                        _ => {
+                            println!("{} isn't a secondary zone", parts[0]);
+                            return;
+                        }
+                    }
+                },
+                Err(_) => {
+                    println!("Failed to access authority");
+                    return;
+                }
+            }
+        }
+
         if index_rootservers {
-            let _ = ctx.cache.store(&get_rootservers());
+            ctx.root_hints = get_rootservers();
+            let _ = ctx.cache.store(&ctx.root_hints);
         }
     }
 
 
+    let api_auth = opt_matches.opt_str("api-auth");
+    if let Some(ref credentials) = api_auth {
+        if !credentials.contains(':') {
+            println!("API credentials must be specified as USER:PASSWORD");
+            return;
+        }
+    }
+
     let port = 53;
 
     println!("Listening on port {}", port);
 
+    if let Err(e) = start_health_checker(context.clone()) {
+        println!("Failed to start health checker: {:?}", e);
+    }
+
+    if let Err(e) = start_cache_logger(context.clone()) {
+        println!("Failed to start cache logger: {:?}", e);
+    }
+
+    if let Err(e) = start_cache_cleaner(context.clone()) {
+        println!("Failed to start cache cleaner: {:?}", e);
+    }
+
     // Start DNS servers
     if context.enable_udp {
         let udp_server = DnsUdpServer::new(context.clone(), 20);
@@ -107,7 +769,7 @@ fn main() {
     }
 
     if context.enable_tcp {
-        let tcp_server = DnsTcpServer::new(context.clone(), 20);
+        let tcp_server = DnsTcpServer::new(context.clone());
         if let Err(e) = tcp_server.run_server() {
             println!("Failed to bind TCP listener: {:?}", e);
         }
@@ -120,9 +782,18 @@ fn main() {
         webserver.register_action(Box::new(CacheAction::new(context.clone())));
         webserver.register_action(Box::new(AuthorityAction::new(context.clone())));
         webserver.register_action(Box::new(ZoneAction::new(context.clone())));
+        webserver.register_action(Box::new(PoolAction::new(context.clone())));
+        webserver.register_action(Box::new(TransferAction::new(context.clone())));
+        webserver.register_action(Box::new(ZoneExportAction::new(context.clone())));
         webserver.register_action(Box::new(IndexAction::new(context.clone())));
-
-        webserver.run_webserver();
+        webserver.register_action(Box::new(MetricsAction::new(context.clone())));
+        webserver.register_action(Box::new(BlocklistAction::new(context.clone())));
+        webserver.register_action(Box::new(BlockedDomainAction::new(context.clone())));
+        webserver.register_action(Box::new(ConfigAction::new(context.clone())));
+        webserver.register_action(Box::new(ResolveAction::new(context.clone())));
+        webserver.register_action(Box::new(DohAction::new(context.clone())));
+
+        webserver.run_webserver(api_auth);
     }
 }
 
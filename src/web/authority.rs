@@ -2,18 +2,22 @@ use std::io::{Result, Error, ErrorKind, Read};
 use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::error::Error as RealError;
-use std::net::{Ipv4Addr,Ipv6Addr};
+use std::net::{IpAddr,Ipv4Addr,Ipv6Addr};
 
 use regex::{Regex,Captures};
 use tiny_http::{Response, Header, HeaderField, Request, Method, StatusCode};
 use ascii::AsciiString;
-use rustc_serialize::json::{self, ToJson, Json};
+use rustc_serialize::json::{self, ToJson, Json, DecodeResult};
+use rustc_serialize::Decodable;
 
 use dns::context::ServerContext;
-use dns::authority::Zone;
-use dns::protocol::{DnsRecord,TransientTtl};
+use dns::authority::{Zone, Pool, PoolPolicy};
+use dns::health::HealthCheck;
+use dns::netutil::Network;
+use dns::transfer::transfer_zone;
+use dns::protocol::{DnsRecord,TransientTtl,MAX_CHARACTER_STRING_LEN,split_character_strings};
 
-use web::util::{FormDataDecodable,rr_to_json,decode_json,parse_formdata};
+use web::util::{FormDataDecodable,rr_to_json,decode_json,read_json,parse_formdata};
 use web::server::{Action,WebServer};
 
 #[derive(Debug,RustcDecodable)]
@@ -26,7 +30,21 @@ pub struct ZoneCreateRequest
     pub refresh: Option<u32>,
     pub retry: Option<u32>,
     pub expire: Option<u32>,
-    pub minimum: Option<u32>
+    pub minimum: Option<u32>,
+    pub default_ttl: Option<u32>,
+
+    /// Networks allowed to transfer the zone, separated by commas
+    pub allow_transfer: Option<String>,
+
+    /// Names of the TSIG keys allowed to transfer the zone, separated by
+    /// commas
+    pub allow_transfer_keys: Option<String>,
+
+    pub pool_policy: Option<String>,
+
+    /// Whether to lowercase owner names, with the server wide setting
+    /// applying when unset
+    pub lowercase_names: Option<bool>
 }
 
 impl FormDataDecodable<ZoneCreateRequest> for ZoneCreateRequest {
@@ -59,7 +77,16 @@ impl FormDataDecodable<ZoneCreateRequest> for ZoneCreateRequest {
             refresh: d.get("refresh").and_then(|x| x.parse::<u32>().ok()),
             retry: d.get("retry").and_then(|x| x.parse::<u32>().ok()),
             expire: d.get("expire").and_then(|x| x.parse::<u32>().ok()),
-            minimum: d.get("minimum").and_then(|x| x.parse::<u32>().ok())
+            minimum: d.get("minimum").and_then(|x| x.parse::<u32>().ok()),
+            default_ttl: d.get("default_ttl").and_then(|x| x.parse::<u32>().ok()),
+            allow_transfer: d.get("allow_transfer").and_then(|x| {
+                if x.trim().is_empty() { None } else { Some(x.clone()) }
+            }),
+            allow_transfer_keys: d.get("allow_transfer_keys").and_then(|x| {
+                if x.trim().is_empty() { None } else { Some(x.clone()) }
+            }),
+            pool_policy: d.get("pool_policy").cloned(),
+            lowercase_names: d.get("lowercase_names").and_then(|x| x.parse::<bool>().ok())
         })
     }
 }
@@ -70,15 +97,42 @@ pub struct RecordRequest
     pub delete_record: Option<bool>,
     pub recordtype: String,
     pub domain: String,
-    pub ttl: u32,
-    pub host: Option<String>
+    pub ttl: Option<u32>,
+    pub host: Option<String>,
+
+    /// The character-strings of a TXT record
+    pub txt: Option<Vec<String>>,
+
+    /// The priority of MX and SRV records
+    pub priority: Option<u16>,
+
+    /// The weight and port of SRV records
+    pub weight: Option<u16>,
+    pub port: Option<u16>,
+
+    /// The pool to assign the record to, when adding it
+    pub pool: Option<String>,
+
+    /// A health check for the address of an A or AAAA record, such as
+    /// `tcp:80` or `http:80/health`
+    pub health_check: Option<String>
 }
 
 impl FormDataDecodable<RecordRequest> for RecordRequest {
     fn from_formdata(fields: Vec<(String, String)>) -> Result<RecordRequest> {
+        // TXT records have one field per string, each of which may span
+        // several lines
+        let mut txt = Vec::new();
+
         let mut d = BTreeMap::new();
         for (k,v) in fields {
-            d.insert(k, v);
+            if k == "txt" {
+                for line in v.lines().filter(|x| !x.is_empty()) {
+                    txt.extend(split_character_strings(line));
+                }
+            } else {
+                d.insert(k, v);
+            }
         }
 
         let recordtype = match d.get("recordtype") {
@@ -91,25 +145,119 @@ impl FormDataDecodable<RecordRequest> for RecordRequest {
             None => return Err(Error::new(ErrorKind::InvalidInput, "missing domain"))
         };
 
-        let ttl = match d.get("ttl").and_then(|x| x.parse::<u32>().ok()) {
-            Some(x) => x,
-            None => return Err(Error::new(ErrorKind::InvalidInput, "missing ttl"))
+        let ttl = match d.get("ttl").map(|x| x.as_str()) {
+            None | Some("") => None,
+            Some(x) => match x.parse::<u32>() {
+                Ok(x) => Some(x),
+                Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "invalid ttl"))
+            }
         };
 
-        let delete_record = d.get("delete_record").and_then(|x| x.parse::<bool>().ok());
+        let priority = try!(parse_u16_field(&d, "priority"));
+        let weight = try!(parse_u16_field(&d, "weight"));
+        let port = try!(parse_u16_field(&d, "port"));
+
+        // HTML forms can't send DELETE, so they may ask for it with `_method`
+        let delete_record = match d.get("_method") {
+            Some(x) if x.eq_ignore_ascii_case("delete") => Some(true),
+            _ => d.get("delete_record").and_then(|x| x.parse::<bool>().ok())
+        };
 
         Ok(RecordRequest {
             delete_record: delete_record,
             recordtype: recordtype.clone(),
             domain: domain.clone(),
             ttl: ttl,
-            host: d.get("host").cloned()
-        })
+            host: d.get("host").cloned(),
+            txt: if txt.is_empty() { None } else { Some(txt) },
+            priority: priority,
+            weight: weight,
+            port: port,
+            pool: d.get("pool").and_then(|x| {
+                if x.trim().is_empty() { None } else { Some(x.clone()) }
+            }),
+            health_check: d.get("health_check").and_then(|x| {
+                if x.trim().is_empty() { None } else { Some(x.clone()) }
+            })
+        }).and_then(|x| x.check_required_fields().map(|_| x))
+    }
+}
+
+fn parse_u16_field(d: &BTreeMap<String, String>, name: &str) -> Result<Option<u16>> {
+    match d.get(name).map(|x| x.as_str()) {
+        None | Some("") => Ok(None),
+        Some(x) => match x.parse::<u16>() {
+            Ok(x) => Ok(Some(x)),
+            Err(_) => Err(Error::new(ErrorKind::InvalidInput, format!("invalid {}", name)))
+        }
     }
 }
 
 impl RecordRequest {
-    fn into_resourcerecord(self) -> Option<DnsRecord> {
+    /// Decode a request from JSON
+    ///
+    /// The `txt` field is either an array of strings, which are used as is, or
+    /// a single string which is split into character-strings as needed.
+    pub fn from_json(json: Json) -> DecodeResult<RecordRequest> {
+        let json = match json {
+            Json::Object(mut d) => {
+                let txt = match d.get("txt") {
+                    Some(&Json::String(ref txt)) => Some(split_character_strings(txt)),
+                    _ => None
+                };
+
+                if let Some(txt) = txt {
+                    d.insert("txt".to_string(), txt.to_json());
+                }
+                Json::Object(d)
+            },
+            x => x
+        };
+
+        let mut decoder = json::Decoder::new(json);
+        Decodable::decode(&mut decoder)
+    }
+
+    /// Make sure the fields needed by the record type are present
+    ///
+    /// The priority of an MX record may also precede the host, which is how
+    /// the records are listed.
+    pub fn check_required_fields(&self) -> Result<()> {
+        match self.recordtype.as_str() {
+            "MX" if self.mx_priority_and_host().is_none() => {
+                Err(Error::new(ErrorKind::InvalidInput, "missing priority"))
+            },
+            "SRV" if self.priority.is_none() => {
+                Err(Error::new(ErrorKind::InvalidInput, "missing priority"))
+            },
+            "SRV" if self.port.is_none() => {
+                Err(Error::new(ErrorKind::InvalidInput, "missing port"))
+            },
+            _ => Ok(())
+        }
+    }
+
+    fn mx_priority_and_host(&self) -> Option<(u16, String)> {
+        let host = match self.host {
+            Some(ref x) => x.trim(),
+            None => return None
+        };
+
+        if let Some(priority) = self.priority {
+            return Some((priority, host.to_string()));
+        }
+
+        let parts = host.splitn(2, ' ').collect::<Vec<&str>>();
+        match (parts.get(0).and_then(|x| x.parse::<u16>().ok()), parts.get(1)) {
+            (Some(priority), Some(host)) => Some((priority, host.trim().to_string())),
+            _ => None
+        }
+    }
+
+    /// Build the record, using `default_ttl` if the request has no TTL
+    fn into_resourcerecord(self, default_ttl: u32) -> Option<DnsRecord> {
+        let ttl = TransientTtl(self.ttl.unwrap_or(default_ttl));
+
         match self.recordtype.as_str() {
             "A" => {
                 let host = match self.host.and_then(|x| x.parse::<Ipv4Addr>().ok()) {
@@ -120,7 +268,7 @@ impl RecordRequest {
                 Some(DnsRecord::A {
                     domain: self.domain,
                     addr: host,
-                    ttl: TransientTtl(self.ttl)
+                    ttl: ttl
                 })
             },
             "AAAA" => {
@@ -132,7 +280,7 @@ impl RecordRequest {
                 Some(DnsRecord::AAAA {
                     domain: self.domain,
                     addr: host,
-                    ttl: TransientTtl(self.ttl)
+                    ttl: ttl
                 })
             },
             "CNAME" => {
@@ -144,7 +292,99 @@ impl RecordRequest {
                 Some(DnsRecord::CNAME {
                     domain: self.domain,
                     host: host,
-                    ttl: TransientTtl(self.ttl)
+                    ttl: ttl
+                })
+            },
+            "PTR" => {
+                let host = match self.host {
+                    Some(x) => x,
+                    None => return None
+                };
+
+                Some(DnsRecord::PTR {
+                    domain: self.domain,
+                    host: host,
+                    ttl: ttl
+                })
+            },
+            "DNAME" => {
+                let host = match self.host {
+                    Some(x) => x,
+                    None => return None
+                };
+
+                Some(DnsRecord::DNAME {
+                    domain: self.domain,
+                    host: host,
+                    ttl: ttl
+                })
+            },
+            "ANAME" | "ALIAS" => {
+                let host = match self.host {
+                    Some(x) => x,
+                    None => return None
+                };
+
+                Some(DnsRecord::ANAME {
+                    domain: self.domain,
+                    host: host,
+                    ttl: ttl
+                })
+            },
+            "NS" => {
+                let host = match self.host {
+                    Some(x) => x,
+                    None => return None
+                };
+
+                Some(DnsRecord::NS {
+                    domain: self.domain,
+                    host: host,
+                    ttl: ttl
+                })
+            },
+            "MX" => {
+                let (priority, host) = match self.mx_priority_and_host() {
+                    Some(x) => x,
+                    None => return None
+                };
+
+                Some(DnsRecord::MX {
+                    domain: self.domain,
+                    priority: priority,
+                    host: host,
+                    ttl: ttl
+                })
+            },
+            "SRV" => {
+                let (priority, port, host) = match (self.priority, self.port, self.host) {
+                    (Some(priority), Some(port), Some(host)) => (priority, port, host),
+                    _ => return None
+                };
+
+                Some(DnsRecord::SRV {
+                    domain: self.domain,
+                    priority: priority,
+                    weight: self.weight.unwrap_or(0),
+                    port: port,
+                    host: host,
+                    ttl: ttl
+                })
+            },
+            "TXT" => {
+                let data = match self.txt {
+                    Some(x) => x,
+                    None => return None
+                };
+
+                if data.iter().any(|x| x.len() > MAX_CHARACTER_STRING_LEN) {
+                    return None;
+                }
+
+                Some(DnsRecord::TXT {
+                    domain: self.domain,
+                    data: data,
+                    ttl: ttl
                 })
             },
             _ => None
@@ -204,6 +444,13 @@ impl Action for AuthorityAction {
                     d.insert("retry".to_string(), zone.retry.to_json());
                     d.insert("expire".to_string(), zone.expire.to_json());
                     d.insert("minimum".to_string(), zone.minimum.to_json());
+                    d.insert("default_ttl".to_string(), zone.default_ttl.to_json());
+                    d.insert("allow_transfer".to_string(), zone.allow_transfer.as_ref().map(|x| {
+                        x.iter().map(|x| x.to_string()).collect::<Vec<String>>()
+                    }).to_json());
+                    d.insert("allow_transfer_keys".to_string(), zone.allow_transfer_keys.to_json());
+                    d.insert("pool_policy".to_string(), zone.pool_policy.as_str().to_json());
+                    d.insert("lowercase_names".to_string(), zone.lowercase_names.to_json());
                     zones_json.push(Json::Object(d));
                 }
 
@@ -253,6 +500,28 @@ impl Action for AuthorityAction {
                     }
                 };
 
+                let allow_transfer = match request_data.allow_transfer {
+                    Some(ref x) => {
+                        match x.split(',').map(|x| x.parse::<Network>()).collect::<Result<Vec<Network>>>() {
+                            Ok(x) => Some(x),
+                            Err(e) => return server.error_response(request, e.description())
+                        }
+                    },
+                    None => None
+                };
+
+                let allow_transfer_keys = request_data.allow_transfer_keys.as_ref().map(|x| {
+                    x.split(',').map(|x| x.trim().to_lowercase()).filter(|x| !x.is_empty()).collect::<Vec<String>>()
+                });
+
+                let pool_policy = match request_data.pool_policy.as_ref().map(|x| x.as_str()) {
+                    None | Some("") => PoolPolicy::Weighted,
+                    Some(x) => match x.parse::<PoolPolicy>() {
+                        Ok(x) => x,
+                        Err(e) => return server.error_response(request, e.description())
+                    }
+                };
+
                 let mut zones = match self.context.authority.write().ok() {
                     Some(x) => x,
                     None => return server.error_response(request, "Failed to access authority")
@@ -261,12 +530,20 @@ impl Action for AuthorityAction {
                 let mut zone = Zone::new(request_data.domain,
                                          request_data.m_name,
                                          request_data.r_name);
-                zone.serial = 0;
+                zone.serial = request_data.serial.unwrap_or(0);
                 zone.refresh = request_data.refresh.unwrap_or(3600);
                 zone.retry = request_data.retry.unwrap_or(3600);
                 zone.expire = request_data.expire.unwrap_or(3600);
                 zone.minimum = request_data.minimum.unwrap_or(3600);
-                zones.add_zone(zone);
+                zone.default_ttl = request_data.default_ttl;
+                zone.allow_transfer = allow_transfer;
+                zone.allow_transfer_keys = allow_transfer_keys;
+                zone.pool_policy = pool_policy;
+                zone.lowercase_names = request_data.lowercase_names;
+
+                if let Err(e) = zones.add_zone(zone) {
+                    return server.error_response_with_status(request, 409, e.description());
+                }
 
                 match zones.save() {
                     Ok(_) => println!("Zones saved!"),
@@ -302,7 +579,7 @@ impl ZoneAction {
 
 impl Action for ZoneAction {
     fn get_regex(&self) -> Regex {
-        Regex::new(r"^/authority/([A-Za-z0-9-.]+)$").unwrap()
+        Regex::new(r"^/authority/([A-Za-z0-9-.]+)(\?.*)?$").unwrap()
     }
 
     fn initialize(&self, server: &mut WebServer) {
@@ -313,6 +590,16 @@ impl Action for ZoneAction {
         }
     }
 
+    /// Exporting the zone as a master file hands out all of it, so it's
+    /// protected even though it's a GET
+    fn requires_auth(&self, request: &Request) -> bool {
+        match *request.method() {
+            Method::Get => wants_zonefile(request.url()),
+            Method::Head => false,
+            _ => true
+        }
+    }
+
     fn handle(&self,
               server: &WebServer,
               mut request: Request,
@@ -326,6 +613,9 @@ impl Action for ZoneAction {
         };
 
         match *request.method() {
+            Method::Get if wants_zonefile(request.url()) => {
+                return zonefile_response(&self.context, server, request, zone, false);
+            },
             Method::Get => {
                 let zones = match self.context.authority.read().ok() {
                     Some(x) => x,
@@ -338,8 +628,16 @@ impl Action for ZoneAction {
                 };
 
                 let mut records = Vec::new();
-                for (id, rr) in zone.records.iter().enumerate() {
-                    records.push(rr_to_json(id as u32, rr));
+                for (id, rr) in zone.sorted_records().into_iter().enumerate() {
+                    let mut json = rr_to_json(id as u32, rr);
+                    if let Json::Object(ref mut d) = json {
+                        d.insert("pool".to_string(), zone.get_pool(rr).map(|x| x.to_string()).to_json());
+                        if let Some(check) = zone.health_checks.get(rr) {
+                            d.insert("health_check".to_string(), check.to_string().to_json());
+                            d.insert("healthy".to_string(), (!self.context.authority.is_down(rr)).to_json());
+                        }
+                    }
+                    records.push(json);
                 }
 
                 let records_arr = Json::Array(records);
@@ -378,8 +676,11 @@ impl Action for ZoneAction {
             },
             Method::Post | Method::Delete => {
                 let request_data = if json_input {
-                    match decode_json::<RecordRequest>(&mut request) {
-                        Ok(x) => x,
+                    match read_json(&mut request).and_then(RecordRequest::from_json) {
+                        Ok(x) => match x.check_required_fields() {
+                            Ok(_) => x,
+                            Err(e) => return server.error_response(request, e.description())
+                        },
                         Err(e) => return server.error_response(request, e.description())
                     }
                 } else {
@@ -395,35 +696,60 @@ impl Action for ZoneAction {
                     request_data.delete_record.unwrap_or(false)
                 };
 
-                let rr = match request_data.into_resourcerecord() {
+                let mut zones = match self.context.authority.write().ok() {
                     Some(x) => x,
-                    None => return server.error_response(request, "Invalid record specification")
+                    None => return server.error_response(request, "Failed to access authority")
                 };
 
-                let mut zones = match self.context.authority.write().ok() {
+                let default_ttl = zones.get_zone(zone)
+                    .and_then(|x| x.default_ttl)
+                    .unwrap_or(self.context.default_ttl);
+
+                let pool = request_data.pool.clone();
+
+                let health_check = match request_data.health_check.as_ref().map(|x| x.parse::<HealthCheck>()) {
+                    Some(Ok(x)) => Some(x),
+                    Some(Err(e)) => return server.error_response(request, e.description()),
+                    None => None
+                };
+
+                if health_check.is_some() && request_data.recordtype != "A" && request_data.recordtype != "AAAA" {
+                    return server.error_response(request, "Health checks are only supported for A and AAAA records");
+                }
+
+                let rr = match request_data.into_resourcerecord(default_ttl) {
                     Some(x) => x,
-                    None => return server.error_response(request, "Failed to access authority")
+                    None => return server.error_response(request, "Invalid record specification")
                 };
 
-                {
-                    let zone = match zones.get_zone_mut(zone) {
-                        Some(x) => x,
-                        None => return server.error_response(request, "Zone not found")
+                if delete_record {
+                    match zones.delete_record(zone, &rr) {
+                        Ok(true) => {},
+                        Ok(false) => return server.error_response_with_status(request, 404, "Record not found"),
+                        Err(e) => return server.error_response(request, e.description())
+                    }
+                } else if let Err(e) = zones.add_record(zone, &rr) {
+                    return match e.kind() {
+                        ErrorKind::NotFound => server.error_response(request, e.description()),
+                        _ => server.error_response_with_status(request, 409, e.description())
                     };
-
-                    if delete_record {
-                        zone.delete_record(&rr);
-                    } else {
-                        zone.add_record(&rr);
+                } else if let Some(x) = zones.get_zone_mut(zone) {
+                    if let Some(pool) = pool {
+                        x.assign_pool(&pool, &rr);
                     }
-                };
+                    if let Some(check) = health_check {
+                        x.health_checks.insert(rr.clone(), check);
+                    }
+                }
 
                 match zones.save() {
                     Ok(_) => println!("Zones saved!"),
                     Err(e) =>  println!("Zone Saving failed: {:?}", e)
                 }
 
-                let mut response = Response::empty(StatusCode(201));
+                let status = if delete_record { 200 } else { 201 };
+
+                let mut response = Response::empty(StatusCode(status));
                 response.add_header(Header{
                     field: "Refresh".parse::<HeaderField>().unwrap(),
                     value: ("0; url=/authority/".to_string() + zone).parse::<AsciiString>().unwrap()
@@ -436,3 +762,778 @@ impl Action for ZoneAction {
         server.error_response(request, "Invalid method")
     }
 }
+
+#[derive(Debug,RustcDecodable)]
+pub struct PoolRequest
+{
+    pub name: String,
+    pub weight: Option<u32>,
+
+    /// Networks of clients answered from the pool, separated by commas
+    pub networks: Option<String>,
+
+    /// The policy for picking between the pools of the zone
+    pub policy: Option<String>
+}
+
+impl FormDataDecodable<PoolRequest> for PoolRequest {
+    fn from_formdata(fields: Vec<(String, String)>) -> Result<PoolRequest> {
+        let mut d = BTreeMap::new();
+        for (k,v) in fields {
+            d.insert(k, v);
+        }
+
+        let name = match d.get("name") {
+            Some(x) => x,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "missing name"))
+        };
+
+        let weight = match d.get("weight").map(|x| x.as_str()) {
+            None | Some("") => None,
+            Some(x) => match x.parse::<u32>() {
+                Ok(x) => Some(x),
+                Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "invalid weight"))
+            }
+        };
+
+        Ok(PoolRequest {
+            name: name.clone(),
+            weight: weight,
+            networks: d.get("networks").cloned(),
+            policy: d.get("policy").and_then(|x| {
+                if x.trim().is_empty() { None } else { Some(x.clone()) }
+            })
+        })
+    }
+}
+
+impl PoolRequest {
+    /// Apply the request to `zone`, creating the pool if needed
+    fn apply(self, zone: &mut Zone) -> Result<()> {
+        if let Some(ref policy) = self.policy {
+            zone.pool_policy = try!(policy.parse::<PoolPolicy>());
+        }
+
+        let networks = match self.networks {
+            Some(ref x) if !x.trim().is_empty() => {
+                Some(try!(x.split(',').map(|x| x.parse::<Network>()).collect::<Result<Vec<Network>>>()))
+            },
+            Some(_) => Some(Vec::new()),
+            None => None
+        };
+
+        let pool = zone.pools.entry(self.name).or_insert_with(|| Pool::new(1));
+        if let Some(weight) = self.weight {
+            pool.weight = weight;
+        }
+        if let Some(networks) = networks {
+            pool.networks = networks;
+        }
+
+        Ok(())
+    }
+}
+
+fn pools_to_json(zone: &Zone) -> Json {
+    let mut pools = Vec::new();
+    for (name, pool) in &zone.pools {
+        let mut d = BTreeMap::new();
+        d.insert("name".to_string(), name.to_json());
+        d.insert("weight".to_string(), pool.weight.to_json());
+        d.insert("networks".to_string(), pool.networks.iter().map(|x| x.to_string()).collect::<Vec<String>>().to_json());
+        d.insert("records".to_string(), pool.records.len().to_json());
+        pools.push(Json::Object(d));
+    }
+
+    let mut result_dict = BTreeMap::new();
+    result_dict.insert("ok".to_string(), true.to_json());
+    result_dict.insert("zone".to_string(), zone.domain.to_json());
+    result_dict.insert("policy".to_string(), zone.pool_policy.as_str().to_json());
+    result_dict.insert("pools".to_string(), Json::Array(pools));
+
+    Json::Object(result_dict)
+}
+
+pub struct PoolAction {
+    context: Arc<ServerContext>
+}
+
+impl PoolAction {
+    pub fn new(context: Arc<ServerContext>) -> PoolAction {
+        PoolAction {
+            context: context
+        }
+    }
+}
+
+impl Action for PoolAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/authority/([A-Za-z0-9-.]+)/pools$").unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) {
+    }
+
+    fn handle(&self,
+              server: &WebServer,
+              mut request: Request,
+              caps: &Captures,
+              json_input: bool,
+              _: bool) -> Result<()> {
+
+        let zone = match caps.at(1) {
+            Some(x) => x,
+            None => return server.error_response(request, "Missing zone name")
+        };
+
+        let result_obj = match *request.method() {
+            Method::Get => {
+                let zones = match self.context.authority.read().ok() {
+                    Some(x) => x,
+                    None => return server.error_response(request, "Failed to access authority")
+                };
+
+                match zones.get_zone(zone) {
+                    Some(x) => pools_to_json(x),
+                    None => return server.error_response_with_status(request, 404, "Zone not found")
+                }
+            },
+            Method::Post | Method::Delete => {
+                let request_data = if json_input {
+                    match decode_json::<PoolRequest>(&mut request) {
+                        Ok(x) => x,
+                        Err(e) => return server.error_response(request, e.description())
+                    }
+                } else {
+                    match parse_formdata(&mut request.as_reader()).and_then(PoolRequest::from_formdata) {
+                        Ok(x) => x,
+                        Err(e) => return server.error_response(request, e.description())
+                    }
+                };
+
+                let mut zones = match self.context.authority.write().ok() {
+                    Some(x) => x,
+                    None => return server.error_response(request, "Failed to access authority")
+                };
+
+                let result_obj = {
+                    let zone = match zones.get_zone_mut(zone) {
+                        Some(x) => x,
+                        None => return server.error_response_with_status(request, 404, "Zone not found")
+                    };
+
+                    // Deleting a pool leaves its records in the zone
+                    if request.method() == &Method::Delete {
+                        zone.pools.remove(&request_data.name);
+                    } else if let Err(e) = request_data.apply(zone) {
+                        return server.error_response(request, e.description());
+                    }
+
+                    pools_to_json(zone)
+                };
+
+                match zones.save() {
+                    Ok(_) => println!("Zones saved!"),
+                    Err(e) =>  println!("Zone Saving failed: {:?}", e)
+                }
+
+                result_obj
+            },
+            _ => return server.error_response(request, "Invalid method")
+        };
+
+        let output = match json::encode(&result_obj).ok() {
+            Some(x) => x,
+            None => return server.error_response(request, "Failed to encode response")
+        };
+
+        let mut response = Response::from_string(output);
+        response.add_header(Header{
+            field: "Content-Type".parse::<HeaderField>().unwrap(),
+            value: "application/json".parse::<AsciiString>().unwrap()
+        });
+        request.respond(response)
+    }
+}
+
+pub struct TransferAction {
+    context: Arc<ServerContext>
+}
+
+impl TransferAction {
+    pub fn new(context: Arc<ServerContext>) -> TransferAction {
+        TransferAction {
+            context: context
+        }
+    }
+}
+
+impl Action for TransferAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/authority/([A-Za-z0-9-.]+)/transfer$").unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) {
+    }
+
+    fn handle(&self,
+              server: &WebServer,
+              request: Request,
+              caps: &Captures,
+              _: bool,
+              _: bool) -> Result<()> {
+
+        let zone = match caps.at(1) {
+            Some(x) => x,
+            None => return server.error_response(request, "Missing zone name")
+        };
+
+        if *request.method() != Method::Post {
+            return server.error_response(request, "Invalid method");
+        }
+
+        let result = match transfer_zone(&self.context.authority, zone) {
+            Ok(x) => x,
+            Err(e) => {
+                return match e.kind() {
+                    ErrorKind::NotFound => server.error_response_with_status(request, 404, e.description()),
+                    ErrorKind::InvalidInput => server.error_response_with_status(request, 409, e.description()),
+                    _ => server.error_response_with_status(request, 502, e.description())
+                };
+            }
+        };
+
+        match self.context.authority.write().ok().map(|mut x| x.save()) {
+            Some(Ok(_)) => println!("Zones saved!"),
+            _ => println!("Zone Saving failed")
+        }
+
+        let mut result_dict = BTreeMap::new();
+        result_dict.insert("ok".to_string(), true.to_json());
+        result_dict.insert("zone".to_string(), zone.to_json());
+        result_dict.insert("serial_before".to_string(), result.serial_before.to_json());
+        result_dict.insert("serial_after".to_string(), result.serial_after.to_json());
+        result_dict.insert("added".to_string(), result.added.to_json());
+        result_dict.insert("removed".to_string(), result.removed.to_json());
+        let result_obj = Json::Object(result_dict);
+
+        let output = match json::encode(&result_obj).ok() {
+            Some(x) => x,
+            None => return server.error_response(request, "Failed to encode response")
+        };
+
+        let mut response = Response::from_string(output);
+        response.add_header(Header{
+            field: "Content-Type".parse::<HeaderField>().unwrap(),
+            value: "application/json".parse::<AsciiString>().unwrap()
+        });
+        request.respond(response)
+    }
+}
+
+pub struct ZoneExportAction {
+    context: Arc<ServerContext>
+}
+
+impl ZoneExportAction {
+    pub fn new(context: Arc<ServerContext>) -> ZoneExportAction {
+        ZoneExportAction {
+            context: context
+        }
+    }
+
+    /// Export `zone` as a master file, if `client` is allowed to transfer it
+    pub fn export(&self, zone: &str, client: &IpAddr) -> Result<String> {
+        export_zonefile(&self.context, zone, client)
+    }
+}
+
+/// Export `zone` as a master file, if `client` is allowed to transfer it
+fn export_zonefile(context: &ServerContext, zone: &str, client: &IpAddr) -> Result<String> {
+    let zones = match context.authority.read() {
+        Ok(x) => x,
+        Err(_) => return Err(Error::new(ErrorKind::Other, "Failed to access zones"))
+    };
+
+    match zones.get_zone(zone) {
+        Some(x) if x.is_transfer_allowed(client, &context.allow_transfer) => Ok(x.to_zonefile()),
+        Some(_) => Err(Error::new(ErrorKind::PermissionDenied, "Transfer not allowed")),
+        None => Err(Error::new(ErrorKind::NotFound, "Zone not found"))
+    }
+}
+
+/// Respond to `request` with `zone` as a master file, either as plain text
+/// or as a file download
+///
+/// Every way of exporting a zone goes through here. The actions doing so
+/// require credentials for it, since this hands out the entire zone.
+fn zonefile_response(context: &ServerContext,
+                     server: &WebServer,
+                     request: Request,
+                     zone: &str,
+                     attachment: bool) -> Result<()> {
+
+    let client = request.remote_addr().ip();
+    let output = match export_zonefile(context, zone, &client) {
+        Ok(x) => x,
+        Err(e) => return zonefile_error_response(server, request, e)
+    };
+
+    let mut response = Response::from_string(output);
+    if attachment {
+        response.add_header(Header{
+            field: "Content-Type".parse::<HeaderField>().unwrap(),
+            value: "text/dns".parse::<AsciiString>().unwrap()
+        });
+        response.add_header(Header{
+            field: "Content-Disposition".parse::<HeaderField>().unwrap(),
+            value: format!("attachment; filename=\"{}.zone\"", zone).parse::<AsciiString>().unwrap()
+        });
+    } else {
+        response.add_header(Header{
+            field: "Content-Type".parse::<HeaderField>().unwrap(),
+            value: "text/plain".parse::<AsciiString>().unwrap()
+        });
+    }
+
+    request.respond(response)
+}
+
+fn zonefile_error_response(server: &WebServer, request: Request, e: Error) -> Result<()> {
+    match e.kind() {
+        ErrorKind::NotFound => server.error_response_with_status(request, 404, e.description()),
+        ErrorKind::PermissionDenied => server.error_response_with_status(request, 403, e.description()),
+        _ => server.error_response_with_status(request, 500, e.description())
+    }
+}
+
+/// Whether the query string of `url` asks for the zone as a master file,
+/// with `format=zonefile`
+fn wants_zonefile(url: &str) -> bool {
+    let query = url.splitn(2, '?').nth(1).unwrap_or("");
+    match parse_formdata(&mut query.as_bytes()) {
+        Ok(params) => params.iter().any(|&(ref k, ref v)| k == "format" && v == "zonefile"),
+        Err(_) => false
+    }
+}
+
+impl Action for ZoneExportAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/authority/([A-Za-z0-9-.]+)/axfr$").unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) {
+    }
+
+    /// Even when reading, this hands out the entire zone
+    fn requires_auth(&self, _: &Request) -> bool {
+        true
+    }
+
+    fn handle(&self,
+              server: &WebServer,
+              request: Request,
+              caps: &Captures,
+              _: bool,
+              _: bool) -> Result<()> {
+
+        let zone = match caps.at(1) {
+            Some(x) => x,
+            None => return server.error_response(request, "Missing zone name")
+        };
+
+        if *request.method() != Method::Get {
+            return server.error_response(request, "Invalid method");
+        }
+
+        zonefile_response(&self.context, server, request, zone, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::mpsc::channel;
+    use std::thread::spawn;
+
+    use rustc_serialize::base64::{ToBase64, STANDARD};
+    use tiny_http::Server;
+
+    use dns::context::DEFAULT_TTL;
+    use dns::context::tests::create_test_context;
+    use dns::zonefile::import_zone;
+
+    use super::*;
+
+    fn send_get(addr: SocketAddr, path: &str, authorization: Option<&str>) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n", path).unwrap();
+        if let Some(authorization) = authorization {
+            write!(stream, "Authorization: {}\r\n", authorization).unwrap();
+        }
+        write!(stream, "\r\n").unwrap();
+
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_alias_record_request() {
+        let fields = vec![("recordtype".to_string(), "ALIAS".to_string()),
+                          ("domain".to_string(), "example.com".to_string()),
+                          ("ttl".to_string(), "300".to_string()),
+                          ("host".to_string(), "example.cdn.net".to_string())];
+
+        let request = RecordRequest::from_formdata(fields).unwrap();
+        assert_eq!(Some(DnsRecord::ANAME {
+            domain: "example.com".to_string(),
+            host: "example.cdn.net".to_string(),
+            ttl: TransientTtl(300)
+        }), request.into_resourcerecord(DEFAULT_TTL));
+    }
+
+    #[test]
+    fn test_txt_record_request() {
+        let mut zone = Zone::new("example.com".to_string(), String::new(), String::new());
+
+        // An explicit array is kept as is
+        let json = Json::from_str(r#"{
+            "recordtype": "TXT",
+            "domain": "example.com",
+            "ttl": 300,
+            "txt": ["v=DKIM1; k=rsa; p=MIIBIjANBgkqh", "kiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEA"]
+        }"#).unwrap();
+
+        let request = RecordRequest::from_json(json).unwrap();
+        zone.add_record(&request.into_resourcerecord(DEFAULT_TTL).unwrap());
+
+        let records = zone.sorted_records();
+        assert_eq!(1, records.len());
+
+        let json = rr_to_json(0, records[0]);
+        assert_eq!(Some("TXT"), json.find("type").and_then(|x| x.as_string()));
+        assert_eq!(Some(&Json::from_str(r#"["v=DKIM1; k=rsa; p=MIIBIjANBgkqh", "kiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEA"]"#).unwrap()),
+                   json.find("txt"));
+
+        // A single string is split into character-strings
+        let long = "a".repeat(300);
+        let json = Json::from_str(&format!(r#"{{
+            "recordtype": "TXT",
+            "domain": "example.com",
+            "txt": "{}"
+        }}"#, long)).unwrap();
+
+        let request = RecordRequest::from_json(json).unwrap();
+        assert_eq!(Some(vec!["a".repeat(255), "a".repeat(45)]), request.txt);
+
+        // Strings exceeding the limit can't be passed explicitly
+        let json = Json::from_str(&format!(r#"{{
+            "recordtype": "TXT",
+            "domain": "example.com",
+            "txt": ["{}"]
+        }}"#, long)).unwrap();
+
+        let request = RecordRequest::from_json(json).unwrap();
+        assert_eq!(None, request.into_resourcerecord(DEFAULT_TTL));
+
+        // Each line of the form field is a string
+        let fields = vec![("recordtype".to_string(), "TXT".to_string()),
+                          ("domain".to_string(), "example.com".to_string()),
+                          ("txt".to_string(), "first\r\nsecond".to_string())];
+
+        let request = RecordRequest::from_formdata(fields).unwrap();
+        assert_eq!(Some(vec!["first".to_string(), "second".to_string()]), request.txt);
+    }
+
+    #[test]
+    fn test_mx_ns_srv_record_request() {
+        let mut zone = Zone::new("example.com".to_string(), String::new(), String::new());
+
+        let fields = vec![("recordtype".to_string(), "MX".to_string()),
+                          ("domain".to_string(), "example.com".to_string()),
+                          ("priority".to_string(), "10".to_string()),
+                          ("host".to_string(), "mail.example.com".to_string())];
+
+        let request = RecordRequest::from_formdata(fields).unwrap();
+        assert!(zone.add_record(&request.into_resourcerecord(DEFAULT_TTL).unwrap()));
+
+        let json = Json::from_str(r#"{
+            "recordtype": "SRV",
+            "domain": "_sip._tcp.example.com",
+            "priority": 0,
+            "weight": 5,
+            "port": 5060,
+            "host": "sip.example.com"
+        }"#).unwrap();
+
+        let request = RecordRequest::from_json(json).unwrap();
+        request.check_required_fields().unwrap();
+        assert!(zone.add_record(&request.into_resourcerecord(DEFAULT_TTL).unwrap()));
+
+        let fields = vec![("recordtype".to_string(), "NS".to_string()),
+                          ("domain".to_string(), "sub.example.com".to_string()),
+                          ("host".to_string(), "ns1.example.com".to_string())];
+
+        let request = RecordRequest::from_formdata(fields).unwrap();
+        assert!(zone.add_record(&request.into_resourcerecord(DEFAULT_TTL).unwrap()));
+
+        assert!(zone.records.contains(&DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: TransientTtl(DEFAULT_TTL)
+        }));
+        assert!(zone.records.contains(&DnsRecord::SRV {
+            domain: "_sip._tcp.example.com".to_string(),
+            priority: 0,
+            weight: 5,
+            port: 5060,
+            host: "sip.example.com".to_string(),
+            ttl: TransientTtl(DEFAULT_TTL)
+        }));
+        assert!(zone.records.contains(&DnsRecord::NS {
+            domain: "sub.example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: TransientTtl(DEFAULT_TTL)
+        }));
+
+        // The listing of an MX record carries its priority in the host,
+        // which the delete form posts back
+        let fields = vec![("recordtype".to_string(), "MX".to_string()),
+                          ("domain".to_string(), "example.com".to_string()),
+                          ("host".to_string(), "10 mail.example.com".to_string())];
+
+        let request = RecordRequest::from_formdata(fields).unwrap();
+        assert!(zone.delete_record(&request.into_resourcerecord(DEFAULT_TTL).unwrap()));
+        assert_eq!(2, zone.records.len());
+
+        // Types with a priority or port need them
+        let fields = vec![("recordtype".to_string(), "MX".to_string()),
+                          ("domain".to_string(), "example.com".to_string()),
+                          ("host".to_string(), "mail.example.com".to_string())];
+
+        match RecordRequest::from_formdata(fields) {
+            Err(e) => assert_eq!(ErrorKind::InvalidInput, e.kind()),
+            Ok(_) => panic!()
+        }
+
+        let fields = vec![("recordtype".to_string(), "SRV".to_string()),
+                          ("domain".to_string(), "_sip._tcp.example.com".to_string()),
+                          ("priority".to_string(), "0".to_string()),
+                          ("host".to_string(), "sip.example.com".to_string())];
+
+        match RecordRequest::from_formdata(fields) {
+            Err(e) => assert_eq!("missing port", e.to_string()),
+            Ok(_) => panic!()
+        }
+
+        let fields = vec![("recordtype".to_string(), "SRV".to_string()),
+                          ("domain".to_string(), "_sip._tcp.example.com".to_string()),
+                          ("port".to_string(), "99999".to_string())];
+
+        match RecordRequest::from_formdata(fields) {
+            Err(e) => assert_eq!("invalid port", e.to_string()),
+            Ok(_) => panic!()
+        }
+    }
+
+    #[test]
+    fn test_wants_zonefile() {
+        assert!(wants_zonefile("/authority/example.com?format=zonefile"));
+        assert!(wants_zonefile("/authority/example.com?foo=bar&format=zonefile"));
+        assert!(!wants_zonefile("/authority/example.com"));
+        assert!(!wants_zonefile("/authority/example.com?format=json"));
+    }
+
+    #[test]
+    fn test_delete_record_request() {
+        let fields = vec![("recordtype".to_string(), "A".to_string()),
+                          ("domain".to_string(), "www.example.com".to_string()),
+                          ("host".to_string(), "127.0.0.1".to_string())];
+
+        let request = RecordRequest::from_formdata(fields.clone()).unwrap();
+        assert_eq!(None, request.delete_record);
+
+        let mut method = fields.clone();
+        method.push(("_method".to_string(), "DELETE".to_string()));
+        let request = RecordRequest::from_formdata(method).unwrap();
+        assert_eq!(Some(true), request.delete_record);
+
+        let mut flag = fields.clone();
+        flag.push(("delete_record".to_string(), "true".to_string()));
+        let request = RecordRequest::from_formdata(flag).unwrap();
+        assert_eq!(Some(true), request.delete_record);
+    }
+
+    #[test]
+    fn test_pool_request() {
+        let mut zone = Zone::new("example.com".to_string(), String::new(), String::new());
+
+        let fields = vec![("recordtype".to_string(), "A".to_string()),
+                          ("domain".to_string(), "www.example.com".to_string()),
+                          ("host".to_string(), "127.0.0.1".to_string()),
+                          ("pool".to_string(), "east".to_string())];
+
+        let request = RecordRequest::from_formdata(fields).unwrap();
+        assert_eq!(Some("east".to_string()), request.pool);
+
+        let fields = vec![("name".to_string(), "east".to_string()),
+                          ("weight".to_string(), "3".to_string()),
+                          ("networks".to_string(), "192.0.2.0/24, 2001:db8::/32".to_string()),
+                          ("policy".to_string(), "subnet".to_string())];
+
+        PoolRequest::from_formdata(fields).unwrap().apply(&mut zone).unwrap();
+        assert_eq!(PoolPolicy::Subnet, zone.pool_policy);
+        assert_eq!(3, zone.pools["east"].weight);
+        assert_eq!(2, zone.pools["east"].networks.len());
+
+        // Fields that are left out are kept as is
+        let fields = vec![("name".to_string(), "east".to_string())];
+        PoolRequest::from_formdata(fields).unwrap().apply(&mut zone).unwrap();
+        assert_eq!(3, zone.pools["east"].weight);
+        assert_eq!(2, zone.pools["east"].networks.len());
+
+        let fields = vec![("name".to_string(), "west".to_string()),
+                          ("policy".to_string(), "nearest".to_string())];
+        assert!(PoolRequest::from_formdata(fields).unwrap().apply(&mut zone).is_err());
+
+        let fields = vec![("name".to_string(), "west".to_string()),
+                          ("weight".to_string(), "heavy".to_string())];
+        assert!(PoolRequest::from_formdata(fields).is_err());
+    }
+
+    #[test]
+    fn test_record_request_default_ttl() {
+        let mut zone = Zone::new("example.com".to_string(), String::new(), String::new());
+        zone.default_ttl = Some(600);
+
+        let fields = vec![("recordtype".to_string(), "A".to_string()),
+                          ("domain".to_string(), "www.example.com".to_string()),
+                          ("host".to_string(), "127.0.0.1".to_string())];
+
+        let request = RecordRequest::from_formdata(fields).unwrap();
+        assert_eq!(None, request.ttl);
+
+        // A record without a TTL inherits the zone default
+        let default_ttl = zone.default_ttl.unwrap_or(DEFAULT_TTL);
+        assert_eq!(Some(DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(600)
+        }), request.into_resourcerecord(default_ttl));
+
+        // An explicit TTL takes precedence
+        let fields = vec![("recordtype".to_string(), "A".to_string()),
+                          ("domain".to_string(), "www.example.com".to_string()),
+                          ("ttl".to_string(), "60".to_string()),
+                          ("host".to_string(), "127.0.0.1".to_string())];
+
+        let request = RecordRequest::from_formdata(fields).unwrap();
+        assert_eq!(Some(60), request.into_resourcerecord(default_ttl).map(|x| x.get_ttl()));
+
+        let fields = vec![("recordtype".to_string(), "A".to_string()),
+                          ("domain".to_string(), "www.example.com".to_string()),
+                          ("ttl".to_string(), "soon".to_string()),
+                          ("host".to_string(), "127.0.0.1".to_string())];
+
+        assert!(RecordRequest::from_formdata(fields).is_err());
+    }
+
+    #[test]
+    fn test_zone_export() {
+        let context = create_test_context(Box::new(|_, _, _, _| {
+            panic!("No upstream queries expected");
+        }));
+
+        let mut zone = Zone::new("example.com".to_string(),
+                                 "ns1.example.com".to_string(),
+                                 "admin.example.com".to_string());
+        zone.serial = 7;
+        zone.allow_transfer = Some(vec!["10.0.0.0/8".parse::<Network>().unwrap()]);
+        zone.add_record(&DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl: TransientTtl(300)
+        });
+        zone.add_record(&DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: TransientTtl(3600)
+        });
+        zone.add_record(&DnsRecord::TXT {
+            domain: "example.com".to_string(),
+            data: vec!["v=spf1 -all".to_string()],
+            ttl: TransientTtl(3600)
+        });
+
+        context.authority.write().unwrap().add_zone(zone.clone()).unwrap();
+
+        let action = ZoneExportAction::new(context.clone());
+
+        // The downloaded zone imports to the same records
+        let output = action.export("example.com", &"10.0.0.1".parse().unwrap()).unwrap();
+        let imported = import_zone(&output).unwrap();
+        assert_eq!(zone.records, imported.records);
+        assert_eq!(zone.soa_record(), imported.soa_record());
+
+        // but only for clients allowed to transfer it
+        match action.export("example.com", &"192.0.2.1".parse().unwrap()) {
+            Err(e) => assert_eq!(ErrorKind::PermissionDenied, e.kind()),
+            Ok(_) => panic!()
+        }
+
+        match action.export("example.org", &"10.0.0.1".parse().unwrap()) {
+            Err(e) => assert_eq!(ErrorKind::NotFound, e.kind()),
+            Ok(_) => panic!()
+        }
+    }
+    #[test]
+    fn test_export_auth() {
+        let context = create_test_context(Box::new(|_, _, _, _| {
+            panic!("No upstream queries expected");
+        }));
+
+        let mut zone = Zone::new("example.com".to_string(),
+                                 "ns1.example.com".to_string(),
+                                 "admin.example.com".to_string());
+        zone.allow_transfer = Some(vec!["127.0.0.0/8".parse::<Network>().unwrap()]);
+        context.authority.write().unwrap().add_zone(zone).unwrap();
+
+        let (tx, rx) = channel();
+        spawn(move || {
+            let server = Server::http("127.0.0.1:0").unwrap();
+            tx.send(server.server_addr()).unwrap();
+
+            let mut webserver = WebServer::new(context.clone());
+            webserver.register_action(Box::new(ZoneExportAction::new(context.clone())));
+            webserver.register_action(Box::new(ZoneAction::new(context)));
+
+            for request in server.incoming_requests() {
+                webserver.handle_request(request, Some("admin:secret"));
+            }
+        });
+
+        let addr = rx.recv().unwrap();
+        let valid = format!("Basic {}", b"admin:secret".to_base64(STANDARD));
+
+        // Downloading a zone needs credentials, even though it's a GET
+        let output = send_get(addr, "/authority/example.com/axfr", None);
+        assert!(output.starts_with("HTTP/1.1 401"));
+
+        let output = send_get(addr, "/authority/example.com/axfr", Some(&valid));
+        assert!(output.starts_with("HTTP/1.1 200"));
+        assert!(output.contains("ns1.example.com."));
+
+        // and so does exporting it from the zone itself
+        let output = send_get(addr, "/authority/example.com?format=zonefile", None);
+        assert!(output.starts_with("HTTP/1.1 401"));
+
+        let output = send_get(addr, "/authority/example.com?format=zonefile", Some(&valid));
+        assert!(output.starts_with("HTTP/1.1 200"));
+        assert!(output.contains("ns1.example.com."));
+    }
+}
@@ -0,0 +1,207 @@
+use std::io::{Result, Error, ErrorKind};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::error::Error as RealError;
+
+use regex::{Regex,Captures};
+use tiny_http::{Response, Header, Request, Method, StatusCode};
+use rustc_serialize::json::{self, ToJson, Json};
+
+use dns::context::ServerContext;
+
+use web::util::{FormDataDecodable,decode_json,parse_formdata};
+use web::server::{Action,WebServer};
+
+#[derive(Debug,RustcDecodable)]
+pub struct BlockRequest
+{
+    pub domain: String,
+    pub delete_domain: Option<bool>
+}
+
+impl FormDataDecodable<BlockRequest> for BlockRequest {
+    fn from_formdata(fields: Vec<(String, String)>) -> Result<BlockRequest> {
+        let mut d = BTreeMap::new();
+        for (k,v) in fields {
+            d.insert(k, v);
+        }
+
+        let domain = match d.get("domain") {
+            Some(x) => x,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "missing domain"))
+        };
+
+        Ok(BlockRequest {
+            domain: domain.clone(),
+            delete_domain: d.get("delete_domain").and_then(|x| x.parse::<bool>().ok())
+        })
+    }
+}
+
+pub struct BlocklistAction {
+    context: Arc<ServerContext>
+}
+
+impl BlocklistAction {
+    pub fn new(context: Arc<ServerContext>) -> BlocklistAction {
+        BlocklistAction {
+            context: context
+        }
+    }
+}
+
+impl Action for BlocklistAction {
+
+    #[allow(trivial_regex)]
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/blocklist$").unwrap()
+    }
+
+    fn initialize(&self, server: &mut WebServer) {
+        let tpl_data = include_str!("templates/blocklist.html").to_string();
+        if !server.handlebars.register_template_string("blocklist", tpl_data).is_ok() {
+            println!("Failed to register blocklist template");
+            return;
+        }
+    }
+
+    fn handle(&self,
+              server: &WebServer,
+              mut request: Request,
+              _: &Captures,
+              json_input: bool,
+              json_output: bool) -> Result<()> {
+
+        match *request.method() {
+            Method::Get => {
+                let lists = match self.context.blocklists.read().ok() {
+                    Some(x) => x,
+                    None => return server.error_response(request, "Failed to access blocklists")
+                };
+
+                let default_policy = self.context.blocklists.default_policy;
+
+                let mut lists_json = Vec::new();
+                for list in lists.iter() {
+                    let policy = list.policy.unwrap_or(default_policy);
+
+                    let mut d = BTreeMap::new();
+                    d.insert("name".to_string(), list.name.to_json());
+                    d.insert("policy".to_string(), policy.to_string().to_json());
+                    d.insert("domains".to_string(), list.domains.iter().cloned().collect::<Vec<String>>().to_json());
+                    lists_json.push(Json::Object(d));
+                }
+
+                let mut result_dict = BTreeMap::new();
+                result_dict.insert("ok".to_string(), true.to_json());
+                result_dict.insert("default_policy".to_string(), default_policy.to_string().to_json());
+                result_dict.insert("lists".to_string(), Json::Array(lists_json));
+                let result_obj = Json::Object(result_dict);
+
+                if json_output {
+                    let output = match json::encode(&result_obj).ok() {
+                        Some(x) => x,
+                        None => return server.error_response(request, "Failed to encode response")
+                    };
+
+                    let mut response = Response::from_string(output);
+                    response.add_header(Header{
+                        field: "Content-Type".parse().unwrap(),
+                        value: "application/json".parse().unwrap()
+                    });
+                    return request.respond(response);
+                } else {
+                    let html_data = match server.handlebars.render("blocklist", &result_obj) {
+                        Ok(x) => x,
+                        Err(e) => return server.error_response(request, &("Failed to encode response: ".to_string() + e.description()))
+                    };
+
+                    let mut response = Response::from_string(html_data);
+                    response.add_header(Header{
+                        field: "Content-Type".parse().unwrap(),
+                        value: "text/html".parse().unwrap()
+                    });
+                    return request.respond(response);
+                }
+            },
+            Method::Post => {
+                let request_data = if json_input {
+                    match decode_json::<BlockRequest>(&mut request) {
+                        Ok(x) => x,
+                        Err(e) => return server.error_response(request, e.description())
+                    }
+                } else {
+                    match parse_formdata(&mut request.as_reader()).and_then(BlockRequest::from_formdata) {
+                        Ok(x) => x,
+                        Err(e) => return server.error_response(request, e.description())
+                    }
+                };
+
+                let result = if request_data.delete_domain.unwrap_or(false) {
+                    self.context.blocklists.remove_domain(&request_data.domain)
+                } else {
+                    self.context.blocklists.add_domain(&request_data.domain)
+                };
+
+                if let Err(e) = result {
+                    return server.error_response(request, e.description());
+                }
+
+                let mut response = Response::empty(StatusCode(201));
+                response.add_header(Header{
+                    field: "Refresh".parse().unwrap(),
+                    value: "0; url=/blocklist".parse().unwrap()
+                });
+                return request.respond(response);
+            },
+            _ => {
+            }
+        }
+
+        server.error_response(request, "Invalid method")
+    }
+}
+
+pub struct BlockedDomainAction {
+    context: Arc<ServerContext>
+}
+
+impl BlockedDomainAction {
+    pub fn new(context: Arc<ServerContext>) -> BlockedDomainAction {
+        BlockedDomainAction {
+            context: context
+        }
+    }
+}
+
+impl Action for BlockedDomainAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/blocklist/([A-Za-z0-9-.]+)$").unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) {
+    }
+
+    fn handle(&self,
+              server: &WebServer,
+              request: Request,
+              caps: &Captures,
+              _: bool,
+              _: bool) -> Result<()> {
+
+        let domain = match caps.at(1) {
+            Some(x) => x,
+            None => return server.error_response(request, "Missing domain")
+        };
+
+        if *request.method() != Method::Delete {
+            return server.error_response(request, "Invalid method");
+        }
+
+        match self.context.blocklists.remove_domain(domain) {
+            Ok(true) => request.respond(Response::empty(StatusCode(204))),
+            Ok(false) => server.error_response_with_status(request, 404, "Domain not blocked"),
+            Err(e) => server.error_response(request, e.description())
+        }
+    }
+}
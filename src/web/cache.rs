@@ -1,14 +1,16 @@
 use std::io::Result;
 use std::collections::BTreeMap;
+use std::fmt::Write;
 use std::sync::Arc;
 
 use regex::{Regex,Captures};
 use tiny_http::{Response, Header, Request};
-//use chrono::*;
+use chrono::{DateTime, Local};
 use rustc_serialize::json::{self, ToJson, Json};
 
 use dns::context::ServerContext;
-use dns::cache::RecordSet;
+use dns::cache::{CacheSource, DomainEntry, RecordSet};
+use dns::protocol::ResultCode;
 
 use web::util::rr_to_json;
 use web::server::{Action,WebServer};
@@ -49,6 +51,131 @@ impl ToJson for CacheResponse {
     }
 }
 
+/// A single entry of the cache, as returned by `/cache?v=2`
+#[derive(RustcEncodable)]
+pub struct CacheEntryV2
+{
+    domain: String,
+    qtype: String,
+
+    /// `records`, `nxdomain` or `servfail`
+    status: String,
+
+    /// `authoritative` or `upstream`
+    source: String,
+
+    remaining_ttl: u32,
+
+    /// Lookups of the domain and type answered from the cache
+    hits: u32,
+
+    /// The record, for positive entries
+    record: Option<Json>
+}
+
+impl ToJson for CacheEntryV2 {
+    fn to_json(&self) -> Json {
+        let mut d = BTreeMap::new();
+        d.insert("domain".to_string(), self.domain.to_json());
+        d.insert("qtype".to_string(), self.qtype.to_json());
+        d.insert("status".to_string(), self.status.to_json());
+        d.insert("source".to_string(), self.source.to_json());
+        d.insert("remaining_ttl".to_string(), self.remaining_ttl.to_json());
+        d.insert("hits".to_string(), self.hits.to_json());
+        d.insert("record".to_string(), self.record.to_json());
+        Json::Object(d)
+    }
+}
+
+#[derive(RustcEncodable)]
+pub struct CacheResponseV2
+{
+    ok: bool,
+    version: u32,
+    entries: Vec<CacheEntryV2>
+}
+
+impl ToJson for CacheResponseV2 {
+    fn to_json(&self) -> Json {
+        let mut d = BTreeMap::new();
+        d.insert("ok".to_string(), self.ok.to_json());
+        d.insert("version".to_string(), self.version.to_json());
+        d.insert("entries".to_string(), self.entries.to_json());
+        Json::Object(d)
+    }
+}
+
+impl CacheResponseV2 {
+    /// Describe the cached domains as of `now`, with one entry per record
+    /// or negative answer
+    pub fn build(domains: &[Arc<DomainEntry>], now: DateTime<Local>) -> CacheResponseV2 {
+        let mut entries = Vec::new();
+
+        let mut id = 0;
+        for rs in domains {
+            for (qtype, set) in &rs.record_types {
+                let mut qtype_str = String::new();
+                let _ = write!(&mut qtype_str, "{:?}", qtype);
+
+                let hits = rs.type_hits.get(qtype).cloned().unwrap_or(0);
+
+                let mut entry = CacheEntryV2 {
+                    domain: rs.domain.clone(),
+                    qtype: qtype_str,
+                    status: String::new(),
+                    source: CacheSource::Upstream.as_str().to_string(),
+                    remaining_ttl: rs.remaining_ttl(*qtype, now),
+                    hits: hits,
+                    record: None
+                };
+
+                match *set {
+                    RecordSet::NoRecords { rescode, .. } => {
+                        entry.status = if rescode == ResultCode::NXDOMAIN {
+                            "nxdomain".to_string()
+                        } else {
+                            "nodata".to_string()
+                        };
+                        entries.push(entry);
+                    },
+                    RecordSet::Failure { .. } => {
+                        entry.status = "servfail".to_string();
+                        entries.push(entry);
+                    },
+                    RecordSet::Records { ref records, .. } => {
+                        for record in records {
+                            entries.push(CacheEntryV2 {
+                                domain: entry.domain.clone(),
+                                qtype: entry.qtype.clone(),
+                                status: "records".to_string(),
+                                source: record.source.as_str().to_string(),
+                                remaining_ttl: record.remaining_ttl(now),
+                                hits: hits,
+                                record: Some(rr_to_json(id, &record.record))
+                            });
+                            id += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        CacheResponseV2 {
+            ok: true,
+            version: 2,
+            entries: entries
+        }
+    }
+}
+
+/// Whether the query string of `url` asks for version 2 of the response
+fn wants_v2(url: &str) -> bool {
+    url.splitn(2, '?')
+        .nth(1)
+        .map(|query| query.split('&').any(|x| x == "v=2"))
+        .unwrap_or(false)
+}
+
 pub struct CacheAction {
     context: Arc<ServerContext>
 }
@@ -92,6 +219,22 @@ impl Action for CacheAction {
 
         //let end_of_list = Local::now();
 
+        // Version 2 is only available as JSON
+        if wants_v2(request.url()) {
+            let response_v2 = CacheResponseV2::build(&cached_records, Local::now());
+            let output = match json::encode(&response_v2).ok() {
+                Some(x) => x,
+                None => return server.error_response(request, "Failed to encode response")
+            };
+
+            let mut response = Response::from_string(output);
+            response.add_header(Header{
+                field: "Content-Type".parse().unwrap(),
+                value: "application/json".parse().unwrap()
+            });
+            return request.respond(response);
+        }
+
         let mut cache_response = CacheResponse {
             ok: true,
             records: Vec::new()
@@ -109,7 +252,8 @@ impl Action for CacheAction {
             for entry in rs.record_types.values() {
 
                 match *entry {
-                    RecordSet::NoRecords { .. } => {},
+                    RecordSet::NoRecords { .. } |
+                    RecordSet::Failure { .. } => {},
                     RecordSet::Records { ref records, .. } => {
                         for entry in records {
                             cache_record.entries.push(rr_to_json(id, &entry.record));
@@ -161,3 +305,57 @@ impl Action for CacheAction {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use chrono::Duration;
+
+    use dns::cache::SynchronizedCache;
+    use dns::protocol::{DnsRecord, QueryType, TransientTtl};
+
+    use super::*;
+
+    #[test]
+    fn test_wants_v2() {
+        assert!(wants_v2("/cache?v=2"));
+        assert!(wants_v2("/cache?foo=bar&v=2"));
+        assert!(!wants_v2("/cache"));
+        assert!(!wants_v2("/cache?v=1"));
+    }
+
+    #[test]
+    fn test_cache_response_v2() {
+        let cache = SynchronizedCache::new();
+        cache.store_from(&[DnsRecord::AAAA {
+            domain: "www.google.com".to_string(),
+            addr: "::1".parse().unwrap(),
+            ttl: TransientTtl(3600)
+        }], CacheSource::Authoritative).unwrap();
+
+        assert!(cache.lookup("www.google.com", QueryType::AAAA).is_some());
+        assert!(cache.lookup("www.google.com", QueryType::AAAA).is_some());
+
+        let domains = cache.list().unwrap();
+        let response = CacheResponseV2::build(&domains, Local::now() + Duration::seconds(600));
+        let json = response.to_json();
+
+        assert_eq!(Some(2), json.find("version").and_then(|x| x.as_u64()));
+
+        let entries = json.find("entries").and_then(|x| x.as_array()).unwrap();
+        assert_eq!(1, entries.len());
+
+        let entry = &entries[0];
+        assert_eq!(Some("www.google.com"), entry.find("domain").and_then(|x| x.as_string()));
+        assert_eq!(Some("AAAA"), entry.find("qtype").and_then(|x| x.as_string()));
+        assert_eq!(Some("records"), entry.find("status").and_then(|x| x.as_string()));
+        assert_eq!(Some("authoritative"), entry.find("source").and_then(|x| x.as_string()));
+        assert_eq!(Some(2), entry.find("hits").and_then(|x| x.as_u64()));
+
+        let remaining_ttl = entry.find("remaining_ttl").and_then(|x| x.as_u64()).unwrap();
+        assert!(remaining_ttl <= 3000 && remaining_ttl > 2990);
+
+        let record = entry.find("record").unwrap();
+        assert_eq!(Some("::1"), record.find("host").and_then(|x| x.as_string()));
+    }
+}
@@ -0,0 +1,216 @@
+use std::io::Result;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use regex::{Regex,Captures};
+use tiny_http::{Response, Header, Request};
+use rustc_serialize::json::{self, ToJson, Json};
+
+use dns::context::{ServerContext, ResolveStrategy, AnyPolicy};
+
+use web::server::{Action,WebServer};
+
+/// Placeholder for configured values that shouldn't be disclosed, such as
+/// paths on the local file system
+pub const REDACTED: &'static str = "[redacted]";
+
+pub struct ConfigAction {
+    context: Arc<ServerContext>
+}
+
+impl ConfigAction {
+    pub fn new(context: Arc<ServerContext>) -> ConfigAction {
+        ConfigAction {
+            context: context
+        }
+    }
+
+    /// Describe the effective runtime configuration
+    ///
+    /// Sensitive values are replaced with `REDACTED`, so only their presence
+    /// is revealed.
+    pub fn render_config(&self) -> Json {
+        let context = &self.context;
+
+        let mut resolve = BTreeMap::new();
+        match context.resolve_strategy {
+            ResolveStrategy::Recursive => {
+                resolve.insert("strategy".to_string(), "recursive".to_json());
+            },
+            ResolveStrategy::Forward { ref host, port } => {
+                resolve.insert("strategy".to_string(), "forward".to_json());
+                resolve.insert("upstream".to_string(), format!("{}:{}", host, port).to_json());
+            },
+            ResolveStrategy::Static => {
+                resolve.insert("strategy".to_string(), "static".to_json());
+                resolve.insert("answers".to_string(), context.answer_map.len().to_json());
+            }
+        }
+        resolve.insert("allow_recursive".to_string(), context.allow_recursive.to_json());
+        resolve.insert("query_timeout".to_string(), context.client.get_query_timeout().as_secs().to_json());
+        resolve.insert("randomize_case".to_string(), context.client.get_randomize_case().to_json());
+        resolve.insert("resolve_any_upstream".to_string(), context.resolve_any_upstream.to_json());
+        resolve.insert("servfail_ttl".to_string(), context.servfail_ttl.to_json());
+        resolve.insert("max_upstream".to_string(), context.query_limiter.max_concurrent.to_json());
+        resolve.insert("max_upstream_queue".to_string(), context.query_limiter.max_queued.to_json());
+
+        let any_policy = match context.any_policy {
+            AnyPolicy::Full => "full",
+            AnyPolicy::Minimal => "minimal",
+            AnyPolicy::TruncateUdp => "tc-on-udp"
+        };
+
+        let detector = &context.tunnel_detector;
+        let mut tunnel_detection = BTreeMap::new();
+        tunnel_detection.insert("action".to_string(), detector.action.map(|x| x.as_str().to_string()).to_json());
+        tunnel_detection.insert("max_label_length".to_string(), detector.max_label_len.to_json());
+        tunnel_detection.insert("max_entropy".to_string(), detector.max_entropy.to_json());
+
+        let limiter = &context.rate_limiter;
+        let mut rate_limit = BTreeMap::new();
+        rate_limit.insert("queries_per_second".to_string(), limiter.queries_per_second.to_json());
+        rate_limit.insert("burst".to_string(), limiter.burst.to_json());
+        rate_limit.insert("action".to_string(), limiter.action.as_str().to_json());
+
+        let mut listeners = BTreeMap::new();
+        listeners.insert("dns_port".to_string(), context.dns_port.to_json());
+        listeners.insert("api_port".to_string(), context.api_port.to_json());
+        listeners.insert("udp".to_string(), context.enable_udp.to_json());
+        listeners.insert("tcp".to_string(), context.enable_tcp.to_json());
+        listeners.insert("api".to_string(), context.enable_api.to_json());
+        listeners.insert("max_udp_queue".to_string(), context.max_udp_queue.to_json());
+        listeners.insert("tcp_idle_timeout".to_string(), context.tcp_idle_timeout.as_secs().to_json());
+        listeners.insert("tcp_max_lifetime".to_string(), context.tcp_max_lifetime.as_secs().to_json());
+        listeners.insert("max_tcp_connections".to_string(), context.tcp_connections.max_connections.to_json());
+        listeners.insert("max_tcp_connections_per_client".to_string(), context.tcp_connections.max_per_client.to_json());
+        listeners.insert("tcp_keepalive_timeout".to_string(), context.tcp_keepalive_timeout.as_secs().to_json());
+
+        let mut blocklist = BTreeMap::new();
+        blocklist.insert("default_policy".to_string(), context.blocklists.default_policy.to_string().to_json());
+        blocklist.insert("runtime_path".to_string(), match context.blocklists.runtime_path {
+            Some(_) => REDACTED.to_json(),
+            None => Json::Null
+        });
+
+        let (max_zones, max_records_per_zone, lowercase_names, auto_serial) = match context.authority.read() {
+            Ok(zones) => (zones.max_zones.to_json(),
+                          zones.max_records_per_zone.to_json(),
+                          zones.lowercase_names.to_json(),
+                          zones.auto_serial.to_json()),
+            Err(_) => (Json::Null, Json::Null, Json::Null, Json::Null)
+        };
+
+        let mut result_dict = BTreeMap::new();
+        result_dict.insert("ok".to_string(), true.to_json());
+        result_dict.insert("resolve".to_string(), Json::Object(resolve));
+        result_dict.insert("listeners".to_string(), Json::Object(listeners));
+        result_dict.insert("blocklist".to_string(), Json::Object(blocklist));
+        result_dict.insert("any_policy".to_string(), any_policy.to_json());
+        result_dict.insert("unsupported_opcode_rescode".to_string(), format!("{:?}", context.unsupported_opcode_rescode).to_json());
+        result_dict.insert("tunnel_detection".to_string(), Json::Object(tunnel_detection));
+        result_dict.insert("rate_limit".to_string(), Json::Object(rate_limit));
+        result_dict.insert("whoami".to_string(), context.whoami_name.to_json());
+        result_dict.insert("sortlist".to_string(), context.sortlist.iter().map(|x| x.to_string()).collect::<Vec<String>>().to_json());
+        result_dict.insert("response_cache".to_string(), context.enable_response_cache.to_json());
+        result_dict.insert("round_robin".to_string(), context.round_robin.to_json());
+        result_dict.insert("strip_additional".to_string(), context.strip_additional.to_json());
+        result_dict.insert("log_dropped_responses".to_string(), context.log_dropped_responses.to_json());
+        result_dict.insert("query_log".to_string(), context.query_log.is_enabled().to_json());
+        result_dict.insert("default_ttl".to_string(), context.default_ttl.to_json());
+        result_dict.insert("health_check_interval".to_string(), context.health_check_interval.as_secs().to_json());
+        result_dict.insert("pinned".to_string(), context.cache.pinned().unwrap_or_default().iter()
+            .map(|&(ref name, qtype)| format!("{}:{:?}", name, qtype))
+            .collect::<Vec<String>>().to_json());
+        result_dict.insert("cache_log_interval".to_string(), context.cache_log_interval.map(|x| x.as_secs()).to_json());
+        result_dict.insert("cache_file".to_string(), match context.cache_path {
+            Some(_) => REDACTED.to_json(),
+            None => Json::Null
+        });
+        result_dict.insert("cache_size".to_string(), context.cache.capacity().unwrap_or_default().to_json());
+        result_dict.insert("max_zones".to_string(), max_zones);
+        result_dict.insert("max_records_per_zone".to_string(), max_records_per_zone);
+        result_dict.insert("lowercase_names".to_string(), lowercase_names);
+        result_dict.insert("auto_serial".to_string(), auto_serial);
+
+        Json::Object(result_dict)
+    }
+}
+
+impl Action for ConfigAction {
+
+    #[allow(trivial_regex)]
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/config$").unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) {
+    }
+
+    fn handle(&self,
+              server: &WebServer,
+              request: Request,
+              _: &Captures,
+              _: bool,
+              _: bool) -> Result<()> {
+
+        let output = match json::encode(&self.render_config()).ok() {
+            Some(x) => x,
+            None => return server.error_response(request, "Failed to encode response")
+        };
+
+        let mut response = Response::from_string(output);
+        response.add_header(Header{
+            field: "Content-Type".parse().unwrap(),
+            value: "application/json".parse().unwrap()
+        });
+        request.respond(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use dns::context::tests::create_test_context;
+    use dns::context::ResolveStrategy;
+
+    use super::*;
+
+    #[test]
+    fn test_render_config() {
+        let mut context = create_test_context(Box::new(|_, _, _, _| {
+            panic!("No upstream queries expected");
+        }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                    host: "127.0.0.1".to_string(),
+                    port: 53
+                };
+                ctx.blocklists.runtime_path = Some(PathBuf::from("/etc/hermes/secret-blocklist"));
+            },
+            None => panic!()
+        }
+
+        let action = ConfigAction::new(context);
+        let config = action.render_config();
+        let output = json::encode(&config).unwrap();
+
+        // Key fields are present
+        assert_eq!(Some("forward"), config.find_path(&["resolve", "strategy"]).and_then(|x| x.as_string()));
+        assert_eq!(Some("127.0.0.1:53"), config.find_path(&["resolve", "upstream"]).and_then(|x| x.as_string()));
+        assert_eq!(Some(true), config.find_path(&["resolve", "allow_recursive"]).and_then(|x| x.as_boolean()));
+        assert_eq!(Some(53), config.find_path(&["listeners", "dns_port"]).and_then(|x| x.as_u64()));
+        assert_eq!(Some(5380), config.find_path(&["listeners", "api_port"]).and_then(|x| x.as_u64()));
+        assert_eq!(Some("full"), config.find("any_policy").and_then(|x| x.as_string()));
+        assert!(config.find("max_zones").is_some());
+        assert!(config.find("max_records_per_zone").is_some());
+
+        // Sensitive values are redacted
+        assert_eq!(Some(REDACTED), config.find_path(&["blocklist", "runtime_path"]).and_then(|x| x.as_string()));
+        assert!(!output.contains("secret-blocklist"));
+    }
+}
@@ -0,0 +1,285 @@
+use std::io::{Result, Error, ErrorKind, Read};
+use std::error::Error as RealError;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use regex::{Regex,Captures};
+use tiny_http::{Response, Header, Request, Method};
+use rustc_serialize::base64::FromBase64;
+
+use dns::buffer::{PacketBuffer, VectorPacketBuffer};
+use dns::context::ServerContext;
+use dns::protocol::DnsPacket;
+use dns::server::{execute_and_write, Transport};
+
+use web::server::{Action,WebServer};
+use web::util::parse_formdata;
+
+/// The media type of DNS messages in wire format, as given in RFC 8484
+pub const DNS_MESSAGE_TYPE: &'static str = "application/dns-message";
+
+/// Get the query of a GET request from the `dns` parameter of the query
+/// string of `url`, which holds the message encoded with base64url
+pub fn query_from_url(url: &str) -> Result<Vec<u8>> {
+    let query = url.splitn(2, '?').nth(1).unwrap_or("");
+    let params = try!(parse_formdata(&mut query.as_bytes()));
+
+    let encoded = match params.iter().find(|x| x.0 == "dns") {
+        Some(x) => x.1.clone(),
+        None => return Err(Error::new(ErrorKind::InvalidInput, "Missing dns parameter"))
+    };
+
+    // The decoder accepts the url safe alphabet, and doesn't require the
+    // padding which is left out by base64url
+    match encoded.from_base64() {
+        Ok(x) => Ok(x),
+        Err(_) => Err(Error::new(ErrorKind::InvalidInput, "Invalid dns parameter"))
+    }
+}
+
+fn is_dns_message(request: &Request) -> bool {
+    request.headers().iter()
+        .filter(|x| x.field.equiv("Content-Type"))
+        .any(|x| x.value.as_str().starts_with(DNS_MESSAGE_TYPE))
+}
+
+/// DNS over HTTPS (RFC 8484) endpoint
+pub struct DohAction {
+    context: Arc<ServerContext>
+}
+
+impl DohAction {
+    pub fn new(context: Arc<ServerContext>) -> DohAction {
+        DohAction {
+            context: context
+        }
+    }
+
+    /// Answer the wire format query `data` from `client` as any other query,
+    /// and return the response in wire format
+    pub fn resolve(&self, data: &[u8], client: SocketAddr) -> Result<Vec<u8>> {
+        let mut req_buffer = VectorPacketBuffer::new();
+        req_buffer.buffer.extend_from_slice(data);
+
+        let request = try!(DnsPacket::from_buffer(&mut req_buffer));
+        if request.header.response || request.questions.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Not a query"));
+        }
+
+        let mut res_buffer = VectorPacketBuffer::new();
+        try!(execute_and_write(self.context.clone(),
+                               &request,
+                               Transport::Https,
+                               client,
+                               &mut res_buffer,
+                               0xFFFF));
+
+        let len = res_buffer.pos();
+        let data = try!(res_buffer.get_range(0, len));
+
+        Ok(data.to_vec())
+    }
+}
+
+impl Action for DohAction {
+
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/dns-query(\?.*)?$").unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) {
+    }
+
+    /// Queries are posted by ordinary DNS clients, which have no credentials
+    fn requires_auth(&self, _: &Request) -> bool {
+        false
+    }
+
+    fn handle(&self,
+              server: &WebServer,
+              mut request: Request,
+              _: &Captures,
+              _: bool,
+              _: bool) -> Result<()> {
+
+        let data = match *request.method() {
+            Method::Get => match query_from_url(request.url()) {
+                Ok(x) => x,
+                Err(e) => return server.error_response(request, e.description())
+            },
+            Method::Post => {
+                if !is_dns_message(&request) {
+                    return server.error_response_with_status(request, 415, "Unsupported content type");
+                }
+
+                let mut data = Vec::new();
+                if let Err(e) = request.as_reader().read_to_end(&mut data) {
+                    return server.error_response(request, e.description());
+                }
+
+                data
+            },
+            _ => return server.error_response_with_status(request, 405, "Method not allowed")
+        };
+
+        let client = *request.remote_addr();
+        let output = match self.resolve(&data, client) {
+            Ok(x) => x,
+            Err(e) => return server.error_response(request, e.description())
+        };
+
+        let mut response = Response::from_data(output);
+        response.add_header(Header{
+            field: "Content-Type".parse().unwrap(),
+            value: DNS_MESSAGE_TYPE.parse().unwrap()
+        });
+        request.respond(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::Write;
+    use std::net::{Ipv4Addr, TcpStream};
+    use std::sync::mpsc::channel;
+    use std::thread::spawn;
+
+    use rustc_serialize::base64::{ToBase64, URL_SAFE};
+    use tiny_http::Server;
+
+    use dns::context::ResolveStrategy;
+    use dns::context::tests::create_test_context;
+    use dns::protocol::{DnsRecord, QueryType, DnsQuestion, ResultCode, TransientTtl};
+
+    use super::*;
+
+    fn build_query(qname: &str, qtype: QueryType) -> Vec<u8> {
+        let mut packet = DnsPacket::new();
+        packet.header.recursion_desired = true;
+        packet.questions.push(DnsQuestion::new(qname.to_string(), qtype));
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        buffer.buffer
+    }
+
+    fn create_action() -> DohAction {
+        let mut context = create_test_context(
+            Box::new(|qname, qtype, _, _| {
+                let mut packet = DnsPacket::new();
+                packet.header.response = true;
+
+                if qname == "google.com" && qtype == QueryType::A {
+                    packet.answers.push(DnsRecord::A {
+                        domain: "google.com".to_string(),
+                        addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
+                        ttl: TransientTtl(3600)
+                    });
+                } else {
+                    packet.header.rescode = ResultCode::NXDOMAIN;
+                }
+
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                    host: "127.0.0.1".to_string(),
+                    port: 53
+                };
+            },
+            None => panic!()
+        }
+
+        DohAction::new(context)
+    }
+
+    #[test]
+    fn test_query_from_url() {
+        let query = build_query("google.com", QueryType::A);
+
+        // base64url leaves out the padding
+        let encoded = query.to_base64(URL_SAFE);
+        assert!(!encoded.ends_with('='));
+        assert_eq!(query, query_from_url(&format!("/dns-query?dns={}", encoded)).unwrap());
+
+        assert!(query_from_url("/dns-query").is_err());
+        assert!(query_from_url("/dns-query?name=google.com").is_err());
+        assert!(query_from_url("/dns-query?dns=%21%21").is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let action = create_action();
+        let client = "127.0.0.1:1234".parse().unwrap();
+
+        let mut data = build_query("google.com", QueryType::A);
+        data[0] = 0x12;
+        data[1] = 0x34;
+
+        let mut res_buffer = VectorPacketBuffer::new();
+        res_buffer.buffer = action.resolve(&data, client).unwrap();
+        let res = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+
+        assert_eq!(0x1234, res.header.id);
+        assert_eq!(1, res.answers.len());
+
+        // Garbage, and packets which aren't queries, are rejected
+        assert!(action.resolve(&[0x00, 0x01], client).is_err());
+
+        let mut res_buffer = VectorPacketBuffer::new();
+        res_buffer.buffer = action.resolve(&data, client).unwrap();
+        assert!(action.resolve(&res_buffer.buffer, client).is_err());
+    }
+
+    #[test]
+    fn test_post_query() {
+        let action = create_action();
+
+        let (tx, rx) = channel();
+        spawn(move || {
+            let context = action.context.clone();
+            let server = Server::http("127.0.0.1:0").unwrap();
+            tx.send(server.server_addr()).unwrap();
+
+            let webserver = WebServer::new(context);
+            let request = server.recv().unwrap();
+            let caps = action.get_regex().captures("/dns-query").unwrap();
+            let _ = action.handle(&webserver, request, &caps, false, false);
+        });
+
+        let addr = rx.recv().unwrap();
+        let query = build_query("google.com", QueryType::A);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream,
+               "POST /dns-query HTTP/1.1\r\nHost: localhost\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+               DNS_MESSAGE_TYPE,
+               query.len()).unwrap();
+        stream.write_all(&query).unwrap();
+
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).unwrap();
+
+        let split = output.windows(4).position(|x| x == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&output[..split]).to_string();
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert!(head.contains(DNS_MESSAGE_TYPE));
+
+        let mut res_buffer = VectorPacketBuffer::new();
+        res_buffer.buffer = output[split+4..].to_vec();
+        let res = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+
+        assert_eq!(1, res.answers.len());
+        match res.answers[0] {
+            DnsRecord::A { ref domain, addr, .. } => {
+                assert_eq!("google.com", domain);
+                assert_eq!("127.0.0.1".parse::<Ipv4Addr>().unwrap(), addr);
+            },
+            _ => panic!()
+        }
+    }
+}
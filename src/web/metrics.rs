@@ -0,0 +1,253 @@
+use std::io::Result;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use regex::{Regex,Captures};
+use tiny_http::{Response, Header, Request};
+use rustc_serialize::json::{self, ToJson, Json};
+
+use dns::context::ServerContext;
+use dns::protocol::ResultCode;
+
+use web::server::{Action,WebServer};
+
+/// The name of a result code, or its number for codes without one
+fn rescode_name(rescode: ResultCode) -> String {
+    match rescode {
+        ResultCode::UNKNOWN(x) => x.to_string(),
+        x => format!("{:?}", x)
+    }
+}
+
+pub struct MetricsAction {
+    context: Arc<ServerContext>
+}
+
+impl MetricsAction {
+    pub fn new(context: Arc<ServerContext>) -> MetricsAction {
+        MetricsAction {
+            context: context
+        }
+    }
+
+    /// Render the server statistics in the Prometheus text exposition format
+    pub fn render_prometheus(&self) -> String {
+        let statistics = &self.context.statistics;
+
+        let mut output = String::new();
+
+        let _ = writeln!(output, "# HELP hermes_queries_total Queries received, by transport");
+        let _ = writeln!(output, "# TYPE hermes_queries_total counter");
+        let _ = writeln!(output, "hermes_queries_total{{transport=\"udp\"}} {}", statistics.get_udp_query_count());
+        let _ = writeln!(output, "hermes_queries_total{{transport=\"tcp\"}} {}", statistics.get_tcp_query_count());
+
+        let _ = writeln!(output, "# HELP hermes_responses_total Responses sent, by result code");
+        let _ = writeln!(output, "# TYPE hermes_responses_total counter");
+        for (rescode, count) in statistics.get_rescode_counts() {
+            let _ = writeln!(output, "hermes_responses_total{{rescode=\"{}\"}} {}", rescode_name(rescode), count);
+        }
+
+        if let Ok(stats) = self.context.cache.stats(0) {
+            let _ = writeln!(output, "# HELP hermes_cache_hits_total Lookups answered from the cache");
+            let _ = writeln!(output, "# TYPE hermes_cache_hits_total counter");
+            let _ = writeln!(output, "hermes_cache_hits_total {}", stats.hits);
+
+            let _ = writeln!(output, "# HELP hermes_cache_misses_total Lookups that found nothing in the cache");
+            let _ = writeln!(output, "# TYPE hermes_cache_misses_total counter");
+            let _ = writeln!(output, "hermes_cache_misses_total {}", stats.misses);
+
+            let _ = writeln!(output, "# HELP hermes_cache_domains Domains held in the cache");
+            let _ = writeln!(output, "# TYPE hermes_cache_domains gauge");
+            let _ = writeln!(output, "hermes_cache_domains {}", stats.size);
+        }
+
+        statistics.response_size.write_prometheus("hermes_response_size_bytes",
+                                                  "Size of DNS responses in bytes",
+                                                  &mut output);
+        statistics.query_latency.write_prometheus("hermes_query_latency_milliseconds",
+                                                  "End to end query latency in milliseconds",
+                                                  &mut output);
+
+        let _ = writeln!(output, "# HELP hermes_coalesced_queries_total Queries answered by sharing an identical query in progress");
+        let _ = writeln!(output, "# TYPE hermes_coalesced_queries_total counter");
+        let _ = writeln!(output, "hermes_coalesced_queries_total {}", self.context.inflight.get_coalesced_count());
+
+        let _ = writeln!(output, "# HELP hermes_dropped_responses_total Packets dropped for having the QR bit set");
+        let _ = writeln!(output, "# TYPE hermes_dropped_responses_total counter");
+        let _ = writeln!(output, "hermes_dropped_responses_total {}", statistics.get_dropped_response_count());
+
+        let _ = writeln!(output, "# HELP hermes_tunnel_queries_total Queries flagged as possible DNS tunneling");
+        let _ = writeln!(output, "# TYPE hermes_tunnel_queries_total counter");
+        let _ = writeln!(output, "hermes_tunnel_queries_total {}", self.context.tunnel_detector.get_flagged_count());
+
+        let _ = writeln!(output, "# HELP hermes_rate_limited_queries_total Queries over UDP from clients over their rate limit");
+        let _ = writeln!(output, "# TYPE hermes_rate_limited_queries_total counter");
+        let _ = writeln!(output, "hermes_rate_limited_queries_total {}", self.context.rate_limiter.get_limited_count());
+
+        output
+    }
+
+    /// Render the counters of the server statistics as JSON
+    pub fn render_json(&self) -> Json {
+        let statistics = &self.context.statistics;
+
+        let mut queries = BTreeMap::new();
+        queries.insert("udp".to_string(), statistics.get_udp_query_count().to_json());
+        queries.insert("tcp".to_string(), statistics.get_tcp_query_count().to_json());
+
+        let mut responses = BTreeMap::new();
+        for (rescode, count) in statistics.get_rescode_counts() {
+            responses.insert(rescode_name(rescode), count.to_json());
+        }
+
+        let mut cache = BTreeMap::new();
+        if let Ok(stats) = self.context.cache.stats(0) {
+            cache.insert("hits".to_string(), stats.hits.to_json());
+            cache.insert("misses".to_string(), stats.misses.to_json());
+            cache.insert("hit_ratio".to_string(), stats.hit_ratio(None).to_json());
+            cache.insert("domains".to_string(), stats.size.to_json());
+        }
+
+        let mut result_dict = BTreeMap::new();
+        result_dict.insert("queries".to_string(), Json::Object(queries));
+        result_dict.insert("responses".to_string(), Json::Object(responses));
+        result_dict.insert("cache".to_string(), Json::Object(cache));
+        result_dict.insert("coalesced_queries".to_string(), self.context.inflight.get_coalesced_count().to_json());
+        result_dict.insert("dropped_responses".to_string(), statistics.get_dropped_response_count().to_json());
+        result_dict.insert("tunnel_queries".to_string(), self.context.tunnel_detector.get_flagged_count().to_json());
+        result_dict.insert("rate_limited_queries".to_string(), self.context.rate_limiter.get_limited_count().to_json());
+
+        Json::Object(result_dict)
+    }
+}
+
+impl Action for MetricsAction {
+
+    #[allow(trivial_regex)]
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/metrics$").unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) {
+    }
+
+    fn handle(&self,
+              server: &WebServer,
+              request: Request,
+              _: &Captures,
+              _: bool,
+              json_output: bool) -> Result<()> {
+
+        if json_output {
+            let output = match json::encode(&self.render_json()).ok() {
+                Some(x) => x,
+                None => return server.error_response(request, "Failed to encode response")
+            };
+
+            let mut response = Response::from_string(output);
+            response.add_header(Header{
+                field: "Content-Type".parse().unwrap(),
+                value: "application/json".parse().unwrap()
+            });
+            return request.respond(response);
+        }
+
+        let mut response = Response::from_string(self.render_prometheus());
+        response.add_header(Header{
+            field: "Content-Type".parse().unwrap(),
+            value: "text/plain; version=0.0.4".parse().unwrap()
+        });
+        request.respond(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc::channel;
+    use std::thread::spawn;
+
+    use tiny_http::Server;
+
+    use dns::context::tests::create_test_context;
+    use dns::protocol::{DnsPacket, QueryType};
+
+    use super::*;
+
+    #[test]
+    fn test_json_metrics() {
+        let context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Ok(DnsPacket::new())
+            }));
+
+        context.statistics.udp_query_count.fetch_add(3, Ordering::Release);
+        context.statistics.tcp_query_count.fetch_add(1, Ordering::Release);
+        context.statistics.record_rescode(ResultCode::NOERROR);
+        context.statistics.record_rescode(ResultCode::NOERROR);
+        context.statistics.record_rescode(ResultCode::NXDOMAIN);
+
+        // One miss
+        assert!(context.cache.lookup("google.com", QueryType::A).is_none());
+
+        let action = MetricsAction::new(context.clone());
+
+        let (tx, rx) = channel();
+        spawn(move || {
+            let server = Server::http("127.0.0.1:0").unwrap();
+            tx.send(server.server_addr()).unwrap();
+
+            let webserver = WebServer::new(context);
+            let request = server.recv().unwrap();
+            let caps = action.get_regex().captures("/metrics").unwrap();
+            let _ = action.handle(&webserver, request, &caps, false, true);
+        });
+
+        let addr = rx.recv().unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream,
+               "GET /metrics HTTP/1.1\r\nHost: localhost\r\nAccept: application/json\r\nConnection: close\r\n\r\n").unwrap();
+
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+
+        let split = output.find("\r\n\r\n").unwrap();
+        assert!(output.starts_with("HTTP/1.1 200"));
+        assert!(output[..split].contains("application/json"));
+
+        let json = Json::from_str(&output[split+4..]).unwrap();
+
+        assert_eq!(Some(3), json.find_path(&["queries", "udp"]).and_then(|x| x.as_u64()));
+        assert_eq!(Some(1), json.find_path(&["queries", "tcp"]).and_then(|x| x.as_u64()));
+        assert_eq!(Some(2), json.find_path(&["responses", "NOERROR"]).and_then(|x| x.as_u64()));
+        assert_eq!(Some(1), json.find_path(&["responses", "NXDOMAIN"]).and_then(|x| x.as_u64()));
+        assert!(json.find_path(&["responses", "SERVFAIL"]).is_none());
+        assert_eq!(Some(0), json.find_path(&["cache", "hits"]).and_then(|x| x.as_u64()));
+        assert_eq!(Some(1), json.find_path(&["cache", "misses"]).and_then(|x| x.as_u64()));
+    }
+
+    #[test]
+    fn test_prometheus_metrics() {
+        let context = create_test_context(
+            Box::new(|_, _, _, _| {
+                Ok(DnsPacket::new())
+            }));
+
+        context.statistics.record_rescode(ResultCode::SERVFAIL);
+        context.statistics.record_rescode(ResultCode::BADVERS);
+
+        let action = MetricsAction::new(context);
+        let output = action.render_prometheus();
+
+        assert!(output.contains("hermes_responses_total{rescode=\"SERVFAIL\"} 1\n"));
+        assert!(output.contains("hermes_queries_total{transport=\"udp\"} 0\n"));
+
+        // Only the four bits of the header are kept apart
+        assert!(output.contains("hermes_responses_total{rescode=\"NOERROR\"} 1\n"));
+    }
+}
@@ -3,3 +3,8 @@ pub mod util;
 pub mod cache;
 pub mod authority;
 pub mod index;
+pub mod metrics;
+pub mod blocklist;
+pub mod config;
+pub mod resolve;
+pub mod doh;
@@ -0,0 +1,323 @@
+use std::io::{Result, Error, ErrorKind};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use chrono::*;
+use regex::{Regex,Captures};
+use tiny_http::{Response, Header, Request};
+use rustc_serialize::json::{self, ToJson, Json};
+
+use dns::context::{ServerContext, ResolveStrategy};
+use dns::protocol::{DnsPacket, DnsRecord, QueryType};
+
+use web::server::{Action,WebServer};
+use web::util::{parse_formdata, rr_to_json};
+
+/// A query to send to a specific upstream, from the parameters of the url
+#[derive(Debug,PartialEq)]
+pub struct ResolveRequest {
+    pub name: String,
+    pub qtype: QueryType,
+    pub upstream: SocketAddr
+}
+
+impl ResolveRequest {
+    /// Parse the query string of `url`, which requires a `name` and an
+    /// `upstream` given as an address with an optional port. The `type`
+    /// defaults to A.
+    pub fn from_url(url: &str) -> Result<ResolveRequest> {
+        let query = url.splitn(2, '?').nth(1).unwrap_or("");
+        let params = try!(parse_formdata(&mut query.as_bytes()));
+
+        let param = |key: &str| params.iter().find(|x| x.0 == key).map(|x| x.1.clone());
+
+        let name = match param("name") {
+            Some(x) => x.trim_right_matches('.').to_lowercase(),
+            None => return Err(Error::new(ErrorKind::InvalidInput, "Missing name"))
+        };
+
+        let qtype = match param("type") {
+            Some(x) => try!(x.to_uppercase().parse::<QueryType>()),
+            None => QueryType::A
+        };
+
+        let upstream = match param("upstream") {
+            Some(x) => match (x.parse::<SocketAddr>(), x.parse::<IpAddr>()) {
+                (Ok(addr), _) => addr,
+                (_, Ok(ip)) => SocketAddr::new(ip, 53),
+                _ => return Err(Error::new(ErrorKind::InvalidInput, "Invalid upstream"))
+            },
+            None => return Err(Error::new(ErrorKind::InvalidInput, "Missing upstream"))
+        };
+
+        Ok(ResolveRequest {
+            name: name,
+            qtype: qtype,
+            upstream: upstream
+        })
+    }
+}
+
+fn records_to_json(records: &[DnsRecord]) -> Json {
+    records.iter()
+        .enumerate()
+        .map(|(idx, rr)| rr_to_json(idx as u32, rr))
+        .collect::<Vec<Json>>()
+        .to_json()
+}
+
+/// Describe the full contents of `packet`
+pub fn packet_to_json(packet: &DnsPacket) -> Json {
+    let header = &packet.header;
+
+    let mut flags = BTreeMap::new();
+    flags.insert("authoritative_answer".to_string(), header.authoritative_answer.to_json());
+    flags.insert("truncated_message".to_string(), header.truncated_message.to_json());
+    flags.insert("recursion_desired".to_string(), header.recursion_desired.to_json());
+    flags.insert("recursion_available".to_string(), header.recursion_available.to_json());
+    flags.insert("authed_data".to_string(), header.authed_data.to_json());
+    flags.insert("checking_disabled".to_string(), header.checking_disabled.to_json());
+
+    let questions = packet.questions.iter().map(|q| {
+        let mut d = BTreeMap::new();
+        d.insert("name".to_string(), q.name.to_json());
+        d.insert("type".to_string(), format!("{:?}", q.qtype).to_json());
+        Json::Object(d)
+    }).collect::<Vec<Json>>();
+
+    let mut d = BTreeMap::new();
+    d.insert("id".to_string(), header.id.to_json());
+    d.insert("opcode".to_string(), header.opcode.to_num().to_json());
+    d.insert("rescode".to_string(), format!("{:?}", header.rescode).to_json());
+    d.insert("flags".to_string(), Json::Object(flags));
+    d.insert("questions".to_string(), questions.to_json());
+    d.insert("answers".to_string(), records_to_json(&packet.answers));
+    d.insert("authorities".to_string(), records_to_json(&packet.authorities));
+    d.insert("resources".to_string(), records_to_json(&packet.resources));
+
+    Json::Object(d)
+}
+
+pub struct ResolveAction {
+    context: Arc<ServerContext>
+}
+
+impl ResolveAction {
+    pub fn new(context: Arc<ServerContext>) -> ResolveAction {
+        ResolveAction {
+            context: context
+        }
+    }
+
+    /// Check if `upstream` may be queried, which is limited to port 53 and
+    /// the forwarder unless `resolve_any_upstream` is set, so that the
+    /// endpoint can't be used to probe arbitrary services
+    pub fn is_upstream_allowed(&self, upstream: &SocketAddr) -> bool {
+        if self.context.resolve_any_upstream || upstream.port() == 53 {
+            return true;
+        }
+
+        match self.context.resolve_strategy {
+            ResolveStrategy::Forward { ref host, port } => {
+                upstream.port() == port && host.parse::<IpAddr>().ok() == Some(upstream.ip())
+            },
+            _ => false
+        }
+    }
+
+    /// Send the query straight to the requested upstream, bypassing the cache
+    /// and the configured resolve strategy, and describe the response along
+    /// with the time it took
+    ///
+    /// Fails with `PermissionDenied` if the upstream isn't allowed.
+    pub fn resolve(&self, req: &ResolveRequest) -> Result<Json> {
+        if !self.is_upstream_allowed(&req.upstream) {
+            return Err(Error::new(ErrorKind::PermissionDenied,
+                                  format!("Upstream {} isn't allowed", req.upstream)));
+        }
+
+        let host = req.upstream.ip().to_string();
+
+        let start = Local::now();
+        let packet = try!(self.context.client.send_query(&req.name,
+                                                         req.qtype,
+                                                         (&host, req.upstream.port()),
+                                                         true));
+        let elapsed = (Local::now() - start).num_milliseconds();
+
+        let mut d = BTreeMap::new();
+        d.insert("ok".to_string(), true.to_json());
+        d.insert("upstream".to_string(), req.upstream.to_string().to_json());
+        d.insert("elapsed_ms".to_string(), elapsed.to_json());
+        d.insert("packet".to_string(), packet_to_json(&packet));
+
+        Ok(Json::Object(d))
+    }
+}
+
+impl Action for ResolveAction {
+
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/resolve(\?.*)?$").unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) {
+    }
+
+    /// Even when reading, this sends queries to servers of the caller's
+    /// choosing
+    fn requires_auth(&self, _: &Request) -> bool {
+        true
+    }
+
+    fn handle(&self,
+              server: &WebServer,
+              request: Request,
+              _: &Captures,
+              _: bool,
+              _: bool) -> Result<()> {
+
+        let req = match ResolveRequest::from_url(request.url()) {
+            Ok(x) => x,
+            Err(e) => return server.error_response(request, &e.to_string())
+        };
+
+        let result = match self.resolve(&req) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == ErrorKind::PermissionDenied => {
+                return server.error_response_with_status(request, 403, &e.to_string())
+            },
+            Err(e) => return server.error_response_with_status(request, 502, &e.to_string())
+        };
+
+        let output = match json::encode(&result).ok() {
+            Some(x) => x,
+            None => return server.error_response(request, "Failed to encode response")
+        };
+
+        let mut response = Response::from_string(output);
+        response.add_header(Header{
+            field: "Content-Type".parse().unwrap(),
+            value: "application/json".parse().unwrap()
+        });
+        request.respond(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::net::Ipv4Addr;
+
+    use dns::context::tests::create_test_context;
+    use dns::protocol::{DnsRecord, TransientTtl};
+
+    use super::*;
+
+    #[test]
+    fn test_resolve_request() {
+        assert_eq!(ResolveRequest {
+            name: "www.google.com".to_string(),
+            qtype: QueryType::AAAA,
+            upstream: "127.0.0.1:5353".parse().unwrap()
+        }, ResolveRequest::from_url("/resolve?name=WWW.google.com.&type=aaaa&upstream=127.0.0.1:5353").unwrap());
+
+        // The type defaults to A, and the port to 53
+        assert_eq!(ResolveRequest {
+            name: "google.com".to_string(),
+            qtype: QueryType::A,
+            upstream: "[::1]:53".parse().unwrap()
+        }, ResolveRequest::from_url("/resolve?upstream=::1&name=google.com").unwrap());
+
+        assert!(ResolveRequest::from_url("/resolve?name=google.com").is_err());
+        assert!(ResolveRequest::from_url("/resolve?upstream=127.0.0.1").is_err());
+        assert!(ResolveRequest::from_url("/resolve?name=google.com&upstream=localhost").is_err());
+        assert!(ResolveRequest::from_url("/resolve?name=google.com&type=BOGUS&upstream=127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_resolve_upstream() {
+        let mut context = create_test_context(
+            Box::new(|qname, qtype, server, _| {
+                assert_eq!(("127.0.0.1", 5353), server);
+
+                let mut packet = DnsPacket::new();
+                packet.header.id = 1234;
+                packet.header.response = true;
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: "10.0.0.1".parse::<Ipv4Addr>().unwrap(),
+                    ttl: TransientTtl(300)
+                });
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: "10.0.0.2".parse::<Ipv4Addr>().unwrap(),
+                    ttl: TransientTtl(300)
+                });
+
+                assert_eq!(QueryType::A, qtype);
+
+                Ok(packet)
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_any_upstream = true;
+            },
+            None => panic!()
+        }
+
+        let action = ResolveAction::new(context.clone());
+        let req = ResolveRequest::from_url("/resolve?name=www.example.com&upstream=127.0.0.1:5353").unwrap();
+        let result = action.resolve(&req).unwrap();
+
+        assert_eq!(Some("127.0.0.1:5353"), result.find("upstream").and_then(|x| x.as_string()));
+        assert!(result.find("elapsed_ms").and_then(|x| x.as_i64()).is_some());
+        assert_eq!(Some(1234), result.find_path(&["packet", "id"]).and_then(|x| x.as_u64()));
+        assert_eq!(Some("NOERROR"), result.find_path(&["packet", "rescode"]).and_then(|x| x.as_string()));
+
+        let answers = result.find_path(&["packet", "answers"]).and_then(|x| x.as_array()).unwrap();
+        let addrs = answers.iter()
+            .filter_map(|x| x.find("host").and_then(|x| x.as_string()))
+            .collect::<Vec<&str>>();
+        assert_eq!(vec!["10.0.0.1", "10.0.0.2"], addrs);
+
+        // The cache is bypassed entirely
+        assert!(context.cache.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_allowed_upstreams() {
+        let mut context = create_test_context(
+            Box::new(|_, _, _, _| {
+                panic!("No upstream queries expected");
+            }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                    host: "127.0.0.1".to_string(),
+                    port: 5353
+                };
+            },
+            None => panic!()
+        }
+
+        let action = ResolveAction::new(context);
+
+        // Port 53 anywhere, and the forwarder, may be queried
+        assert!(action.is_upstream_allowed(&"192.0.2.1:53".parse().unwrap()));
+        assert!(action.is_upstream_allowed(&"127.0.0.1:5353".parse().unwrap()));
+
+        // but nothing else
+        assert!(!action.is_upstream_allowed(&"192.0.2.1:5353".parse().unwrap()));
+        assert!(!action.is_upstream_allowed(&"127.0.0.1:6379".parse().unwrap()));
+
+        let req = ResolveRequest::from_url("/resolve?name=www.example.com&upstream=127.0.0.1:6379").unwrap();
+        match action.resolve(&req) {
+            Ok(_) => panic!(),
+            Err(e) => assert_eq!(ErrorKind::PermissionDenied, e.kind())
+        }
+    }
+}
@@ -2,8 +2,9 @@ use std::io::{Result, Error, ErrorKind};
 use std::sync::Arc;
 
 use regex::{Regex,Captures};
-use tiny_http::{Server, Response, StatusCode, Request};
+use tiny_http::{Server, Response, StatusCode, Request, Header, Method};
 use handlebars::Handlebars;
+use rustc_serialize::base64::FromBase64;
 
 use dns::context::ServerContext;
 
@@ -16,6 +17,50 @@ pub trait Action {
               path_match: &Captures,
               json_input: bool,
               json_output: bool) -> Result<()>;
+
+    /// Whether `request` needs credentials, when the web server has them set
+    ///
+    /// Anything but reading is protected by default. Actions which hand out
+    /// data that isn't public, or act on the caller's behalf, protect reads
+    /// as well.
+    fn requires_auth(&self, request: &Request) -> bool {
+        match *request.method() {
+            Method::Get | Method::Head => false,
+            _ => true
+        }
+    }
+}
+
+/// Compare the bytes of `a` and `b` in time independent of where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Check that `request` carries HTTP Basic credentials matching
+/// `credentials`, given as `user:password`
+pub fn is_authorized(request: &Request, credentials: &str) -> bool {
+    let header = request.headers().iter()
+        .find(|x| x.field.equiv("Authorization"));
+
+    let value = match header {
+        Some(x) => x.value.as_str().to_string(),
+        None => return false
+    };
+
+    let mut parts = value.trim().splitn(2, ' ');
+    let encoded = match (parts.next(), parts.next()) {
+        (Some(scheme), Some(encoded)) if scheme.eq_ignore_ascii_case("Basic") => encoded.trim(),
+        _ => return false
+    };
+
+    match encoded.from_base64() {
+        Ok(decoded) => constant_time_eq(&decoded, credentials.as_bytes()),
+        Err(_) => false
+    }
 }
 
 pub struct WebServer {
@@ -46,7 +91,11 @@ impl WebServer {
         self.actions.push(action);
     }
 
-    pub fn run_webserver(self)
+    /// Serve the API until the process exits
+    ///
+    /// With `credentials` set, as `user:password`, requests which change
+    /// anything are rejected unless they carry them using HTTP Basic auth.
+    pub fn run_webserver(self, credentials: Option<String>)
     {
         let webserver = match Server::http(("0.0.0.0", self.context.api_port)) {
             Ok(x) => x,
@@ -57,50 +106,160 @@ impl WebServer {
         };
 
         for request in webserver.incoming_requests() {
-            println!("HTTP {:?} {:?}", request.method(), request.url());
-
-            let accept_header = request.headers().iter()
-                .filter(|x| x.field.as_str() == "Accept").cloned().next();
-
-            let json_output = match accept_header {
-                Some(ah) => {
-                    let value : String = ah.value.into();
-                    value.contains("application/json")
-                },
-                None => false
-            };
-
-            let content_type_header = request.headers().iter()
-                .filter(|x| x.field.as_str() == "Content-Type").cloned().next();
-
-            let json_input = match content_type_header {
-                Some(ah) => {
-                    let value : String = ah.value.into();
-                    value.contains("application/json")
-                },
-                None => false
-            };
-
-            let matching_actions : Vec<&Box<Action>> =
-                self.actions.iter().filter(|x| x.get_regex().is_match(&request.url())).collect();
-
-            if matching_actions.is_empty() {
-                let response = Response::empty(StatusCode(404));
-                let _ = request.respond(response);
-            } else {
-                let action = &matching_actions[0];
-                if let Some(caps) = action.get_regex().captures(&request.url().to_string()) {
-                    let _ = action.handle(&self, request, &caps, json_input, json_output);
+            self.handle_request(request, credentials.as_ref().map(|x| x.as_str()));
+        }
+    }
+
+    pub fn handle_request(&self, request: Request, credentials: Option<&str>)
+    {
+        println!("HTTP {:?} {:?}", request.method(), request.url());
+
+        let accept_header = request.headers().iter()
+            .filter(|x| x.field.as_str() == "Accept").cloned().next();
+
+        let json_output = match accept_header {
+            Some(ah) => {
+                let value : String = ah.value.into();
+                value.contains("application/json")
+            },
+            None => false
+        };
+
+        let content_type_header = request.headers().iter()
+            .filter(|x| x.field.as_str() == "Content-Type").cloned().next();
+
+        let json_input = match content_type_header {
+            Some(ah) => {
+                let value : String = ah.value.into();
+                value.contains("application/json")
+            },
+            None => false
+        };
+
+        let matching_actions : Vec<&Box<Action>> =
+            self.actions.iter().filter(|x| x.get_regex().is_match(&request.url())).collect();
+
+        if matching_actions.is_empty() {
+            let response = Response::empty(StatusCode(404));
+            let _ = request.respond(response);
+        } else {
+            let action = &matching_actions[0];
+
+            if let Some(credentials) = credentials {
+                if action.requires_auth(&request) && !is_authorized(&request, credentials) {
+                    let mut response = Response::empty(StatusCode(401));
+                    response.add_header(Header{
+                        field: "WWW-Authenticate".parse().unwrap(),
+                        value: "Basic realm=\"hermes\"".parse().unwrap()
+                    });
+                    let _ = request.respond(response);
+                    return;
                 }
             }
+
+            if let Some(caps) = action.get_regex().captures(&request.url().to_string()) {
+                let _ = action.handle(self, request, &caps, json_input, json_output);
+            }
         }
     }
 
     pub fn error_response(&self, request: Request, error: &str) -> Result<()>
     {
-        let response = Response::empty(StatusCode(400));
+        self.error_response_with_status(request, 400, error)
+    }
+
+    pub fn error_response_with_status(&self,
+                                      request: Request,
+                                      status: u16,
+                                      error: &str) -> Result<()>
+    {
+        let response = Response::empty(StatusCode(status));
         let _ = request.respond(response);
         Err(Error::new(ErrorKind::InvalidInput, error))
     }
 }
 
+#[cfg(test)]
+mod tests {
+
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::mpsc::channel;
+    use std::thread::spawn;
+
+    use rustc_serialize::base64::{ToBase64, STANDARD};
+
+    use dns::context::tests::create_test_context;
+
+    use super::*;
+
+    struct TestAction;
+
+    impl Action for TestAction {
+        fn get_regex(&self) -> Regex {
+            Regex::new(r"^/test$").unwrap()
+        }
+
+        fn initialize(&self, _: &mut WebServer) {
+        }
+
+        fn handle(&self,
+                  _: &WebServer,
+                  request: Request,
+                  _: &Captures,
+                  _: bool,
+                  _: bool) -> Result<()> {
+            request.respond(Response::from_string("ok"))
+        }
+    }
+
+    fn send_request(addr: SocketAddr, method: &str, authorization: Option<&str>) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "{} /test HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n", method).unwrap();
+        if let Some(authorization) = authorization {
+            write!(stream, "Authorization: {}\r\n", authorization).unwrap();
+        }
+        write!(stream, "\r\n").unwrap();
+
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_auth() {
+        let context = create_test_context(Box::new(|_, _, _, _| {
+            panic!("No upstream queries expected");
+        }));
+
+        let (tx, rx) = channel();
+        spawn(move || {
+            let server = Server::http("127.0.0.1:0").unwrap();
+            tx.send(server.server_addr()).unwrap();
+
+            let mut webserver = WebServer::new(context);
+            webserver.register_action(Box::new(TestAction));
+
+            for request in server.incoming_requests() {
+                webserver.handle_request(request, Some("admin:secret"));
+            }
+        });
+
+        let addr = rx.recv().unwrap();
+        let valid = format!("Basic {}", b"admin:secret".to_base64(STANDARD));
+        let invalid = format!("Basic {}", b"admin:guess".to_base64(STANDARD));
+
+        // Reading doesn't need credentials
+        assert!(send_request(addr, "GET", None).starts_with("HTTP/1.1 200"));
+
+        let output = send_request(addr, "POST", None);
+        assert!(output.starts_with("HTTP/1.1 401"));
+        assert!(output.contains("WWW-Authenticate: Basic"));
+
+        assert!(send_request(addr, "POST", Some(&invalid)).starts_with("HTTP/1.1 401"));
+        assert!(send_request(addr, "POST", Some("Bearer admin:secret")).starts_with("HTTP/1.1 401"));
+        assert!(send_request(addr, "POST", Some(&valid)).starts_with("HTTP/1.1 200"));
+        assert!(send_request(addr, "DELETE", Some(&valid)).starts_with("HTTP/1.1 200"));
+    }
+}
+
@@ -4,6 +4,7 @@ use std::fmt::Write;
 
 use rustc_serialize::json::{self,ToJson,Json,DecodeResult,DecoderError};
 use rustc_serialize::Decodable;
+use rustc_serialize::base64::{ToBase64, STANDARD};
 use tiny_http::Request;
 
 use dns::protocol::{DnsRecord,TransientTtl};
@@ -12,29 +13,37 @@ pub trait FormDataDecodable<T> {
     fn from_formdata(fields: Vec<(String, String)>) -> Result<T>;
 }
 
-fn hex_to_num(c: char) -> u8 {
+fn hex_to_num(c: u8) -> Option<u8> {
     match c {
-        '0'...'9' => (c as u8) - (b'0' as u8),
-        'a'...'f' => (c as u8) - (b'a' as u8) + 0xA,
-        'A'...'F' => (c as u8) - (b'A' as u8) + 0xA,
-        _ => 0
+        b'0'...b'9' => Some(c - b'0'),
+        b'a'...b'f' => Some(c - b'a' + 0xA),
+        b'A'...b'F' => Some(c - b'A' + 0xA),
+        _ => None
     }
 }
 
+/// Decode a form encoded value, where `+` is a space
+///
+/// Escapes that are truncated or aren't valid hex are kept as they are, and
+/// the decoded bytes are read as UTF-8.
 pub fn url_decode(instr: &str) -> String {
     let src_buffer = instr.as_bytes();
 
     let mut pos = 0;
-    let len = instr.len();
-    let mut buffer = String::new();
+    let len = src_buffer.len();
+    let mut buffer = Vec::with_capacity(len);
     while pos < len {
-        let cur = src_buffer[pos] as char;
-        if cur == '%' {
-            let a = hex_to_num(src_buffer[pos+1] as char);
-            let b = hex_to_num(src_buffer[pos+2] as char);
-            let new_char = ((a << 4) | b) as char;
-            buffer.push(new_char);
-            pos += 2;
+        let cur = src_buffer[pos];
+        if cur == b'%' && pos + 2 < len {
+            match (hex_to_num(src_buffer[pos+1]), hex_to_num(src_buffer[pos+2])) {
+                (Some(a), Some(b)) => {
+                    buffer.push((a << 4) | b);
+                    pos += 2;
+                },
+                _ => buffer.push(cur)
+            }
+        } else if cur == b'+' {
+            buffer.push(b' ');
         } else {
             buffer.push(cur);
         }
@@ -42,7 +51,7 @@ pub fn url_decode(instr: &str) -> String {
         pos += 1;
     }
 
-    buffer
+    String::from_utf8_lossy(&buffer).into_owned()
 }
 
 pub fn parse_formdata<R: Read>(reader: &mut R) -> Result<Vec<(String, String)>> {
@@ -81,7 +90,10 @@ pub fn rr_to_json(id: u32, rr: &DnsRecord) -> Json {
             d.insert("ttl".to_string(), ttl.to_json());
         },
         DnsRecord::NS { ref domain, ref host, ttl: TransientTtl(ttl) } |
-        DnsRecord::CNAME { ref domain, ref host, ttl: TransientTtl(ttl) } => {
+        DnsRecord::CNAME { ref domain, ref host, ttl: TransientTtl(ttl) } |
+        DnsRecord::PTR { ref domain, ref host, ttl: TransientTtl(ttl) } |
+        DnsRecord::DNAME { ref domain, ref host, ttl: TransientTtl(ttl) } |
+        DnsRecord::ANAME { ref domain, ref host, ttl: TransientTtl(ttl) } => {
             d.insert("domain".to_string(), domain.to_json());
             d.insert("host".to_string(), host.to_json());
             d.insert("ttl".to_string(), ttl.to_json());
@@ -94,6 +106,16 @@ pub fn rr_to_json(id: u32, rr: &DnsRecord) -> Json {
             d.insert("weight".to_string(), weight.to_json());
             d.insert("port".to_string(), port.to_json());
         },
+        DnsRecord::NAPTR { ref domain, order, preference, ref flags, ref services, ref regexp, ref replacement, ttl: TransientTtl(ttl) } => {
+            d.insert("domain".to_string(), domain.to_json());
+            d.insert("ttl".to_string(), ttl.to_json());
+            d.insert("order".to_string(), order.to_json());
+            d.insert("preference".to_string(), preference.to_json());
+            d.insert("flags".to_string(), flags.to_json());
+            d.insert("services".to_string(), services.to_json());
+            d.insert("regexp".to_string(), regexp.to_json());
+            d.insert("replacement".to_string(), replacement.to_json());
+        },
         DnsRecord::MX { ref domain, priority, ref host, ttl: TransientTtl(ttl) } => {
             d.insert("domain".to_string(), domain.to_json());
             d.insert("host".to_string(), (priority.to_string() + " " + host).to_json());
@@ -105,25 +127,59 @@ pub fn rr_to_json(id: u32, rr: &DnsRecord) -> Json {
             d.insert("type".to_string(), qtype.to_json());
             d.insert("len".to_string(), data_len.to_json());
         },
-        DnsRecord::TXT { ref domain, ref data, ttl: TransientTtl(ttl) } => {
+        DnsRecord::TXT { ref domain, ref data, ttl: TransientTtl(ttl) } |
+        DnsRecord::SPF { ref domain, ref data, ttl: TransientTtl(ttl) } => {
             d.insert("domain".to_string(), domain.to_json());
             d.insert("ttl".to_string(), ttl.to_json());
             d.insert("txt".to_string(), data.to_json());
+        },
+        DnsRecord::DHCID { ref domain, ref data, ttl: TransientTtl(ttl) } => {
+            d.insert("domain".to_string(), domain.to_json());
+            d.insert("ttl".to_string(), ttl.to_json());
+            d.insert("data".to_string(), data.to_base64(STANDARD).to_json());
+        },
+        DnsRecord::SSHFP { ref domain, algorithm, fp_type, ref fingerprint, ttl: TransientTtl(ttl) } => {
+            d.insert("domain".to_string(), domain.to_json());
+            d.insert("ttl".to_string(), ttl.to_json());
+            d.insert("algorithm".to_string(), algorithm.to_json());
+            d.insert("fp_type".to_string(), fp_type.to_json());
+            d.insert("fingerprint".to_string(), fingerprint.to_json());
+        },
+        DnsRecord::TLSA { ref domain, usage, selector, matching_type, ref cert_data, ttl: TransientTtl(ttl) } => {
+            d.insert("domain".to_string(), domain.to_json());
+            d.insert("ttl".to_string(), ttl.to_json());
+            d.insert("usage".to_string(), usage.to_json());
+            d.insert("selector".to_string(), selector.to_json());
+            d.insert("matching_type".to_string(), matching_type.to_json());
+            d.insert("cert_data".to_string(), cert_data.to_json());
+        },
+        DnsRecord::CAA { ref domain, flags, ref tag, ref value, ttl: TransientTtl(ttl) } => {
+            d.insert("domain".to_string(), domain.to_json());
+            d.insert("ttl".to_string(), ttl.to_json());
+            d.insert("flags".to_string(), flags.to_json());
+            d.insert("tag".to_string(), tag.to_json());
+            d.insert("value".to_string(), value.to_json());
         }
         DnsRecord::SOA { .. } |
-        DnsRecord::OPT { .. } => {
+        DnsRecord::OPT { .. } |
+        DnsRecord::TSIG { .. } => {
         }
     }
 
     Json::Object(d)
 }
 
+pub fn read_json(request: &mut Request) -> DecodeResult<Json>
+{
+    match Json::from_reader(request.as_reader()) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(DecoderError::ParseError(e))
+    }
+}
+
 pub fn decode_json<T: Decodable>(request: &mut Request) -> DecodeResult<T>
 {
-    let json = match Json::from_reader(request.as_reader()) {
-        Ok(x) => x,
-        Err(e) => return Err(DecoderError::ParseError(e))
-    };
+    let json = try!(read_json(request));
 
     let mut decoder = json::Decoder::new(json);
     Decodable::decode(&mut decoder)
@@ -139,6 +195,14 @@ mod tests {
     #[test]
     fn test_url_decode() {
         assert_eq!("@foo barA", url_decode("%40foo%20bar%41"));
+        assert_eq!("a b", url_decode("a+b"));
+        assert_eq!("a+b", url_decode("a%2Bb"));
+        assert_eq!("caf\u{e9}", url_decode("caf%C3%A9"));
+
+        // Truncated and invalid escapes are passed through
+        assert_eq!("foo%2", url_decode("foo%2"));
+        assert_eq!("foo%", url_decode("foo%"));
+        assert_eq!("%zz%", url_decode("%zz%"));
     }
 
     #[test]